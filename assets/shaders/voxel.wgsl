@@ -1,15 +1,21 @@
 #import bevy_pbr::mesh_functions;
+#import bevy_pbr::mesh_view_bindings::view;
 #import bevy_pbr::view_transformations;
 
 @group(2) @binding(1) var<uniform> light_color: vec4<f32>;
 @group(2) @binding(2) var<uniform> light_dir: vec3<f32>;
-@group(2) @binding(3) var<uniform> selected_voxel: vec3<f32>;
-@group(2) @binding(4) var<uniform> has_selected: u32;
 @group(2) @binding(5) var texture: texture_2d_array<f32>;
 @group(2) @binding(6) var texture_sampler: sampler;
+@group(2) @binding(7) var<uniform> camera_medium: u32;
+// Fog start and end distances in the air (xy) and underwater (zw)
+@group(2) @binding(8) var<uniform> fog_params: vec4<f32>;
+@group(2) @binding(9) var<uniform> fog_color: vec4<f32>;
+// Colour of each voxel kind, drawn instead of textures with FLAT_COLORS
+@group(2) @binding(10) var<uniform> palette: array<vec4<f32>, 16>;
 
 const AMBIENT_STRENGTH: f32 = 0.1;
-const OUTLINE_THICKNESS: f32 = 0.1;
+// Ambient light never drops below this, so the world is navigable at night
+const MIN_AMBIENT: f32 = 0.08;
 // Y component of the vector from the player to the sun at which point
 // the sun stops lighting things
 const SUN_MIN_ANGLE: f32 = -0.3;
@@ -21,10 +27,21 @@ const SUN_MIN_STRENGTH: f32 = 0.0;
 
 const SUN_MAX_STRENGTH: f32 = 1.0;
 
+// Brightness lost for each level of sky light below the maximum
+const SKY_LIGHT_FALLOFF: f32 = 0.8;
+const MAX_SKY_LIGHT: u32 = 15u;
+
+const MEDIUM_WATER: u32 = 1u;
+const WATER_FOG_COLOR: vec4<f32> = vec4(0.02, 0.08, 0.3, 1.0);
+
 
 struct Vertex {
     @builtin(instance_index) instance_index: u32,
+#ifdef PACKED_POSITIONS
+    @location(0) packed_position: u32,
+#else
     @location(0) position: vec3<f32>,
+#endif
     @location(1) vertex_data: u32,
 }
 
@@ -51,19 +68,38 @@ struct VertexOut {
     @location(2) normal: vec3<f32>,
     @location(3) uv: vec2<f32>,
     @location(4) ao_level: f32,
+    // Brightness from the sky light of this vertex
+    @location(5) sky_light: f32,
 }
 
 // vertex_data bitfield
 // N - Normal index
-// M - Material
 // U - UV index
 // O - Number of neighbours (for AO)
-// XXXXXXXX XXXXXXXX XXXXXXXO OUUMMMNNN
+// T - Texture index
+// L - Sky light level
+// M - Voxel kind (index into the palette)
+// XXXXXXXX XMMMMLLL LTTTTTTT TOOUUNNN
+
+// packed_position bitfield
+// X - X coordinate
+// Y - Y coordinate
+// Z - Z coordinate
+// XXXXXXXX XXXXXZZZ ZZYYYYYY YYYXXXXX
 
 @vertex
 fn vertex(vertex: Vertex) -> VertexOut {
+#ifdef PACKED_POSITIONS
+    let position = vec3<f32>(
+        f32(extractBits(vertex.packed_position, 0u, 5u)),
+        f32(extractBits(vertex.packed_position, 5u, 9u)),
+        f32(extractBits(vertex.packed_position, 14u, 5u)),
+    );
+#else
+    let position = vertex.position;
+#endif
     let model = mesh_functions::get_world_from_local(vertex.instance_index);
-    let world_position = mesh_functions::mesh_position_local_to_world(model, vec4<f32>(vertex.position, 1.0));
+    let world_position = mesh_functions::mesh_position_local_to_world(model, vec4<f32>(position, 1.0));
     let normal_idx = extractBits(vertex.vertex_data, 0u, 3u);
 
     var out: VertexOut;
@@ -71,8 +107,8 @@ fn vertex(vertex: Vertex) -> VertexOut {
     out.position = world_position.xyz;
     out.vertex_data = vertex.vertex_data;
     out.normal = VOXEL_NORMALS[normal_idx];
-    out.uv = VOXEL_UVS[extractBits(vertex.vertex_data, 6u, 2u)];
-    switch extractBits(vertex.vertex_data, 8u, 2u) {
+    out.uv = VOXEL_UVS[extractBits(vertex.vertex_data, 3u, 2u)];
+    switch extractBits(vertex.vertex_data, 5u, 2u) {
         // Number of non-occluding neighbours of this vertex
         case 0u {
             out.ao_level = 0.1;
@@ -87,16 +123,11 @@ fn vertex(vertex: Vertex) -> VertexOut {
             out.ao_level = 1.0;
         }
     }
+    let sky_light = extractBits(vertex.vertex_data, 15u, 4u);
+    out.sky_light = pow(SKY_LIGHT_FALLOFF, f32(MAX_SKY_LIGHT - sky_light));
     return out;
 }
 
-fn is_between(v: vec3<f32>, min: vec3<f32>, max: vec3<f32>) -> bool {
-    // Fuzz this detection a bit to avoid z fighting
-    let greater_than_min = all(v >= min - vec3(0.001));
-    let less_than_max = all(v <= max + vec3(0.001));
-    return greater_than_min && less_than_max;
-}
-
 fn map_range(value: f32, min_in: f32, max_in: f32, min_out: f32, max_out: f32) -> f32 {
     let factor = (value - min_in) / (max_in - min_in);
     return mix(min_out, max_out, factor);
@@ -111,12 +142,17 @@ fn fragment(
     // Strength of diffuse lighting according to angle between it and normal
     let diff_strength = max(dot(norm, light_dir), 0.0);
     // Strength of diffuse lighting based on sun direction - disabled below the horizon
-    let diff_brightness = map_range(light_dir.y, SUN_MIN_ANGLE, SUN_MAX_ANGLE, SUN_MIN_STRENGTH, SUN_MAX_STRENGTH);
+    let diff_brightness = max(map_range(light_dir.y, SUN_MIN_ANGLE, SUN_MAX_ANGLE, SUN_MIN_STRENGTH, SUN_MAX_STRENGTH), 0.0);
 
-    let diff_color = light_color * diff_strength * diff_brightness;
-    let ambient_color = light_color * AMBIENT_STRENGTH;
-    let material_idx = extractBits(mesh.vertex_data, 3u, 3u);
-    let material_color = textureSample(texture, texture_sampler, mesh.uv, material_idx);
+    // Neither the sun nor the sky reach far into caves or under overhangs
+    let diff_color = light_color * diff_strength * diff_brightness * mesh.sky_light;
+    let ambient_color = max(light_color * AMBIENT_STRENGTH * mesh.sky_light, vec4(MIN_AMBIENT));
+#ifdef FLAT_COLORS
+    let material_color = palette[extractBits(mesh.vertex_data, 19u, 4u)];
+#else
+    let texture_idx = extractBits(mesh.vertex_data, 7u, 8u);
+    let material_color = textureSample(texture, texture_sampler, mesh.uv, texture_idx);
+#endif
 
 #ifdef AO_DEBUG
     var out = vec4<f32>(mesh.ao_level);
@@ -124,9 +160,21 @@ fn fragment(
     var out = material_color * (ambient_color + diff_color);
     out *= mesh.ao_level;
 #endif
-
-    if bool(has_selected) && is_between(mesh.position, selected_voxel, selected_voxel + vec3(1.0)) {
-        out *= 0.7;
+    // Only used when water is drawn with transparency
+    out.a = material_color.a;
+
+    // Only the fog of the medium the camera is in applies, so the water
+    // surface seen from below isn't fogged by both
+    let distance = length(mesh.position - view.world_position);
+    var fog: f32;
+    var out_fog_color: vec4<f32>;
+    if camera_medium == MEDIUM_WATER {
+        fog = smoothstep(fog_params.z, fog_params.w, distance);
+        out_fog_color = WATER_FOG_COLOR;
+    } else {
+        fog = smoothstep(fog_params.x, fog_params.y, distance);
+        out_fog_color = fog_color;
     }
+    out = vec4(mix(out.rgb, out_fog_color.rgb, fog), out.a);
     return out;
 }
\ No newline at end of file
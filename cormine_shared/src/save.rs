@@ -1,23 +1,134 @@
-use crate::voxel::VoxelKind;
+use crate::voxel::{
+    VoxelKind,
+    MAX_HEIGHT,
+};
 
 use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
     io::{
+        self,
         Read,
-        Seek,
         Write,
     },
     path::Path,
 };
 
-use anyhow::Result;
+use anyhow::{
+    anyhow,
+    Result,
+};
 use glam::{
     ivec3,
+    vec3,
     IVec3,
+    Vec3,
 };
 
+/// Marks a save using the versioned format. Saves without it are from before
+/// the format was versioned and are a seed followed by individual voxels
+const MAGIC: [u8; 4] = *b"CMSv";
+/// Version 2 added waypoint records, version 3 the size record and version 4
+/// the generator record
+const FORMAT_VERSION: u8 = 4;
+
+/// Tags distinguishing each record in a versioned save
+const RECORD_VOXEL: u8 = 0;
+const RECORD_BOX: u8 = 1;
+const RECORD_WAYPOINT: u8 = 2;
+const RECORD_SIZE: u8 = 3;
+const RECORD_GENERATOR: u8 = 4;
+
+/// Tags distinguishing each kind of generator in a generator record
+const GENERATOR_NOISE: u8 = 0;
+const GENERATOR_BLENDED: u8 = 1;
+const GENERATOR_DENSITY: u8 = 2;
+const GENERATOR_FLAT: u8 = 3;
+
+/// Most voxels a single box record can hold, and most voxels a save can hold
+/// once its boxes are expanded. Saves are loaded over the network, so a few
+/// bytes of box records mustn't be able to fill memory
+const MAX_BOX_VOLUME: u128 = 1 << 20;
+const MAX_VOXELS: usize = 1 << 24;
+
+/// Offsets to the 6 voxels sharing a face with a voxel
+const NEIGHBOURS: [IVec3; 6] = [
+    IVec3::X,
+    IVec3::NEG_X,
+    IVec3::Y,
+    IVec3::NEG_Y,
+    IVec3::Z,
+    IVec3::NEG_Z,
+];
+
+#[derive(Debug, PartialEq)]
 pub struct SaveData {
     pub seed: u32,
     pub voxels: Vec<(IVec3, VoxelKind)>,
+    /// Cuboids of a single kind, from `start` to `end` inclusive. These are
+    /// written as a single record and expanded into `voxels` when loaded, so
+    /// this is always empty after reading a save
+    pub boxes: Vec<VoxelBox>,
+    pub waypoints: Vec<Waypoint>,
+    /// Size in chunks along X of the area the world is played in, centred
+    /// on the origin. `0` leaves the world unbounded along X
+    pub width: u32,
+    /// Size in chunks along Z of the area the world is played in, centred
+    /// on the origin. `0` leaves the world unbounded along Z
+    pub length: u32,
+    /// How the world's terrain is generated from `seed`, so it is generated
+    /// the same way again when loaded
+    pub generator: SavedGenerator,
+}
+
+/// Which of the game's terrain generators a world was made with. Saves
+/// from before this was recorded are all noise terrain
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SavedGenerator {
+    /// Rolling hills
+    #[default]
+    Noise,
+    /// Rolling hills blended with those of a second seed
+    Blended { blend_seed: u32 },
+    /// Terrain from 3D noise, with overhangs and caves
+    Density,
+    /// Flat layers, which don't depend on the seed
+    Flat,
+}
+
+/// A named position the player can return to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Waypoint {
+    pub name: String,
+    pub position: Vec3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VoxelBox {
+    pub start: IVec3,
+    pub end: IVec3,
+    pub kind: VoxelKind,
+}
+
+impl VoxelBox {
+    /// Number of voxels within the box
+    pub fn volume(&self) -> u128 {
+        let side = |start: i32, end: i32| u128::from(start.abs_diff(end)) + 1;
+        side(self.start.x, self.end.x)
+            * side(self.start.y, self.end.y)
+            * side(self.start.z, self.end.z)
+    }
+
+    /// Iterate over every position within the box
+    pub fn positions(&self) -> impl Iterator<Item = IVec3> {
+        let start = self.start.min(self.end);
+        let end = self.start.max(self.end);
+        (start.x..=end.x).flat_map(move |x| {
+            (start.y..=end.y).flat_map(move |y| (start.z..=end.z).map(move |z| ivec3(x, y, z)))
+        })
+    }
 }
 
 impl SaveData {
@@ -38,10 +149,97 @@ impl SaveData {
 
     fn from_reader<Cursor>(mut reader: Serializer<Cursor>) -> Result<Self>
     where
-        Cursor: Seek + Read,
+        Cursor: Read,
     {
+        let header = reader.read_bytes::<4>()?;
+        if header != MAGIC {
+            // Unversioned save, where the first 4 bytes are the seed
+            return Self::from_reader_legacy(u32::from_le_bytes(header), reader);
+        }
+        let version = reader.read_byte()?;
+        if !(1..=FORMAT_VERSION).contains(&version) {
+            return Err(anyhow!("unsupported save format version `{version}`"));
+        }
         let seed = reader.read_u32()?;
         let mut voxels = Vec::new();
+        let mut waypoints = Vec::new();
+        let (mut width, mut length) = (0, 0);
+        let mut generator = SavedGenerator::Noise;
+
+        loop {
+            let Ok(tag) = reader.read_byte() else {
+                break;
+            };
+            match tag {
+                RECORD_VOXEL => {
+                    if voxels.len() >= MAX_VOXELS {
+                        return Err(anyhow!("save holds more than {MAX_VOXELS} voxels"));
+                    }
+                    let pos = reader.read_ivec3()?;
+                    let kind = (reader.read_byte()?).try_into()?;
+                    voxels.push((pos, kind));
+                }
+                RECORD_BOX => {
+                    let start = reader.read_ivec3()?;
+                    let end = reader.read_ivec3()?;
+                    let kind = (reader.read_byte()?).try_into()?;
+                    let voxel_box = VoxelBox { start, end, kind };
+                    let height = 0..MAX_HEIGHT as i32;
+                    if !height.contains(&start.y) || !height.contains(&end.y) {
+                        return Err(anyhow!(
+                            "box from {start} to {end} is outside the world height"
+                        ));
+                    }
+                    let volume = voxel_box.volume();
+                    if volume > MAX_BOX_VOLUME {
+                        return Err(anyhow!(
+                            "box from {start} to {end} holds more than {MAX_BOX_VOLUME} voxels"
+                        ));
+                    }
+                    if voxels.len() as u128 + volume > MAX_VOXELS as u128 {
+                        return Err(anyhow!("save holds more than {MAX_VOXELS} voxels"));
+                    }
+                    voxels.extend(voxel_box.positions().map(|pos| (pos, kind)));
+                }
+                RECORD_WAYPOINT => {
+                    let name = reader.read_string()?;
+                    let position = reader.read_vec3()?;
+                    waypoints.push(Waypoint { name, position });
+                }
+                RECORD_SIZE => {
+                    width = u32::try_from(reader.read_leb128_unsigned()?)?;
+                    length = u32::try_from(reader.read_leb128_unsigned()?)?;
+                }
+                RECORD_GENERATOR => {
+                    generator = match reader.read_byte()? {
+                        GENERATOR_NOISE => SavedGenerator::Noise,
+                        GENERATOR_BLENDED => SavedGenerator::Blended {
+                            blend_seed: reader.read_u32()?,
+                        },
+                        GENERATOR_DENSITY => SavedGenerator::Density,
+                        GENERATOR_FLAT => SavedGenerator::Flat,
+                        x => return Err(anyhow!("invalid generator `{x}`")),
+                    };
+                }
+                x => return Err(anyhow!("invalid save record `{x}`")),
+            }
+        }
+        Ok(Self {
+            seed,
+            voxels,
+            boxes: Vec::new(),
+            waypoints,
+            width,
+            length,
+            generator,
+        })
+    }
+
+    fn from_reader_legacy<Cursor>(seed: u32, mut reader: Serializer<Cursor>) -> Result<Self>
+    where
+        Cursor: Read,
+    {
+        let mut voxels = Vec::new();
 
         loop {
             let Ok(x) = reader.read_leb128_signed().map(|x| x as i32) else {
@@ -52,7 +250,55 @@ impl SaveData {
             let kind = (reader.read_byte()?).try_into()?;
             voxels.push((ivec3(x, y, z), kind))
         }
-        Ok(Self { seed, voxels })
+        Ok(Self {
+            seed,
+            voxels,
+            boxes: Vec::new(),
+            waypoints: Vec::new(),
+            width: 0,
+            length: 0,
+            generator: SavedGenerator::Noise,
+        })
+    }
+
+    /// Positions of solid voxels in the save with no solid voxel beside them
+    /// in any of the 6 directions, such as a block left hanging after
+    /// whatever held it up was removed. Neighbours that aren't in the save,
+    /// being whatever the world generated there, are solid if `solid_outside`
+    /// returns true for them
+    pub fn floating_voxels(&self, solid_outside: impl Fn(IVec3) -> bool) -> Vec<IVec3> {
+        // Later voxels replace earlier ones at the same position
+        let kinds: HashMap<_, _> = self.voxels.iter().copied().collect();
+        let solid = |pos| {
+            kinds
+                .get(&pos)
+                .map_or_else(|| solid_outside(pos), VoxelKind::has_collision)
+        };
+        let mut floating: Vec<_> = kinds
+            .iter()
+            .filter(|(_, kind)| kind.has_collision())
+            .map(|(&pos, _)| pos)
+            .filter(|&pos| !NEIGHBOURS.iter().any(|&dir| solid(pos + dir)))
+            .collect();
+        floating.sort_by_key(|pos| pos.to_array());
+        floating
+    }
+
+    /// Remove every voxel found by [`SaveData::floating_voxels`], returning
+    /// their positions. Voxels where the world generated nothing solid are
+    /// dropped from the save, restoring what was generated, while the rest
+    /// are replaced with air
+    pub fn remove_floating_voxels(&mut self, solid_outside: impl Fn(IVec3) -> bool) -> Vec<IVec3> {
+        let floating = self.floating_voxels(&solid_outside);
+        let removed: HashSet<_> = floating.iter().copied().collect();
+        self.voxels.retain(|(pos, _)| !removed.contains(pos));
+        self.voxels.extend(
+            floating
+                .iter()
+                .filter(|&&pos| solid_outside(pos))
+                .map(|&pos| (pos, VoxelKind::Air)),
+        );
+        floating
     }
 
     pub fn to_file<P: AsRef<Path>>(&self, path: P, replace: bool) -> Result<()> {
@@ -68,17 +314,83 @@ impl SaveData {
         self.to_writer(Serializer { cursor: file })
     }
 
+    /// The save as it would be written to a file, such as to send it over
+    /// the network
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        self.to_writer(Serializer::new(&mut cursor))?;
+        Ok(cursor.into_inner())
+    }
+
+    /// Number of bytes the save takes up once written, without writing it
+    /// anywhere
+    pub fn serialized_size(&self) -> Result<u64> {
+        let mut counter = ByteCounter(0);
+        self.to_writer(Serializer::new(&mut counter))?;
+        Ok(counter.0)
+    }
+
     fn to_writer<Cursor>(&self, mut writer: Serializer<Cursor>) -> Result<()>
     where
-        Cursor: Seek + Write,
+        Cursor: Write,
     {
+        writer.write_bytes(MAGIC)?;
+        writer.write_byte(FORMAT_VERSION)?;
         writer.write_u32(self.seed)?;
+        // Unbounded worlds, which most are, leave the record out
+        if self.width != 0 || self.length != 0 {
+            writer.write_byte(RECORD_SIZE)?;
+            writer.write_leb128_unsigned(self.width as u64)?;
+            writer.write_leb128_unsigned(self.length as u64)?;
+        }
+        // Plain noise terrain, the default, leaves the record out too
+        match self.generator {
+            SavedGenerator::Noise => {}
+            SavedGenerator::Blended { blend_seed } => {
+                writer.write_byte(RECORD_GENERATOR)?;
+                writer.write_byte(GENERATOR_BLENDED)?;
+                writer.write_u32(blend_seed)?;
+            }
+            SavedGenerator::Density => {
+                writer.write_byte(RECORD_GENERATOR)?;
+                writer.write_byte(GENERATOR_DENSITY)?;
+            }
+            SavedGenerator::Flat => {
+                writer.write_byte(RECORD_GENERATOR)?;
+                writer.write_byte(GENERATOR_FLAT)?;
+            }
+        }
         for &(vox_pos, vox) in &self.voxels {
-            writer.write_leb128_signed((vox_pos.x) as i64)?;
-            writer.write_leb128_signed((vox_pos.y) as i64)?;
-            writer.write_leb128_signed((vox_pos.z) as i64)?;
+            writer.write_byte(RECORD_VOXEL)?;
+            writer.write_ivec3(vox_pos)?;
             writer.write_byte(vox as u8)?;
         }
+        for voxel_box in &self.boxes {
+            writer.write_byte(RECORD_BOX)?;
+            writer.write_ivec3(voxel_box.start)?;
+            writer.write_ivec3(voxel_box.end)?;
+            writer.write_byte(voxel_box.kind as u8)?;
+        }
+        for waypoint in &self.waypoints {
+            writer.write_byte(RECORD_WAYPOINT)?;
+            writer.write_string(&waypoint.name)?;
+            writer.write_vec3(waypoint.position)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writer which discards its output, keeping only a count of the bytes
+/// written
+struct ByteCounter(u64);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
         Ok(())
     }
 }
@@ -95,7 +407,7 @@ impl<Cursor> Serializer<Cursor> {
 
 impl<Cursor> Serializer<Cursor>
 where
-    Cursor: Seek + Read,
+    Cursor: Read,
 {
     pub fn read_byte(&mut self) -> Result<u8> {
         let mut byte = [0];
@@ -120,11 +432,48 @@ where
     pub fn read_leb128_unsigned(&mut self) -> Result<u64> {
         Ok(leb128::read::unsigned(&mut self.cursor)?)
     }
+
+    pub fn read_ivec3(&mut self) -> Result<IVec3> {
+        let x = self.read_leb128_signed()? as i32;
+        let y = self.read_leb128_signed()? as i32;
+        let z = self.read_leb128_signed()? as i32;
+        Ok(ivec3(x, y, z))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.read_bytes()?))
+    }
+
+    pub fn read_vec3(&mut self) -> Result<Vec3> {
+        Ok(vec3(self.read_f32()?, self.read_f32()?, self.read_f32()?))
+    }
+
+    /// Read a UTF-8 string prefixed with its length in bytes
+    pub fn read_string(&mut self) -> Result<String> {
+        Ok(String::from_utf8(self.read_framed()?)?)
+    }
+
+    /// Read a message written by [`Serializer::write_framed`], so a stream
+    /// can carry several messages one after another
+    pub fn read_framed(&mut self) -> Result<Vec<u8>> {
+        let len = self.read_leb128_unsigned()?;
+        // The length can't be trusted, so only what actually arrives is
+        // allocated rather than the whole message up front
+        let mut bytes = Vec::new();
+        (&mut self.cursor).take(len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != len {
+            return Err(anyhow!(
+                "message ended after {} of {len} bytes",
+                bytes.len()
+            ));
+        }
+        Ok(bytes)
+    }
 }
 
 impl<Cursor> Serializer<Cursor>
 where
-    Cursor: Seek + Write,
+    Cursor: Write,
 {
     pub fn write_byte(&mut self, byte: u8) -> Result<()> {
         let byte = [byte];
@@ -150,4 +499,312 @@ where
         leb128::write::unsigned(&mut self.cursor, value)?;
         Ok(())
     }
+
+    pub fn write_ivec3(&mut self, value: IVec3) -> Result<()> {
+        self.write_leb128_signed(value.x as i64)?;
+        self.write_leb128_signed(value.y as i64)?;
+        self.write_leb128_signed(value.z as i64)
+    }
+
+    pub fn write_f32(&mut self, val: f32) -> Result<()> {
+        self.write_bytes(f32::to_le_bytes(val))
+    }
+
+    pub fn write_vec3(&mut self, value: Vec3) -> Result<()> {
+        self.write_f32(value.x)?;
+        self.write_f32(value.y)?;
+        self.write_f32(value.z)
+    }
+
+    /// Write a UTF-8 string prefixed with its length in bytes
+    pub fn write_string(&mut self, value: &str) -> Result<()> {
+        self.write_framed(value.as_bytes())
+    }
+
+    /// Write a message prefixed with its length in bytes
+    pub fn write_framed(&mut self, bytes: &[u8]) -> Result<()> {
+        self.write_leb128_unsigned(bytes.len() as u64)?;
+        self.cursor.write_all(bytes)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn box_records() {
+        let save = SaveData {
+            seed: 1234,
+            voxels: vec![(ivec3(-1, 2, 3), VoxelKind::Grass)],
+            boxes: vec![VoxelBox {
+                start: ivec3(0, 0, 0),
+                end: ivec3(15, 3, -15),
+                kind: VoxelKind::Bedrock,
+            }],
+            waypoints: Vec::new(),
+            width: 0,
+            length: 0,
+            generator: SavedGenerator::Noise,
+        };
+        let mut bytes = Vec::new();
+        save.to_writer(Serializer::new(std::io::Cursor::new(&mut bytes)))
+            .unwrap();
+        let loaded = SaveData::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.seed, 1234);
+        assert!(loaded.boxes.is_empty());
+        assert_eq!(loaded.voxels.len(), 1 + 16 * 4 * 16);
+        assert_eq!(loaded.voxels[0], (ivec3(-1, 2, 3), VoxelKind::Grass));
+        assert!(loaded.voxels[1..]
+            .iter()
+            .all(|&(pos, kind)| kind == VoxelKind::Bedrock && pos.z <= 0));
+        // The box costs a fraction of the space of its individual voxels
+        assert!(bytes.len() < 64);
+        assert_eq!(save.serialized_size().unwrap(), bytes.len() as u64);
+    }
+
+    #[test]
+    pub fn oversized_box_records() {
+        let load_box = |start, end| {
+            let save = SaveData {
+                seed: 0,
+                voxels: Vec::new(),
+                boxes: vec![VoxelBox {
+                    start,
+                    end,
+                    kind: VoxelKind::Stone,
+                }],
+                waypoints: Vec::new(),
+                width: 0,
+                length: 0,
+                generator: SavedGenerator::Noise,
+            };
+            SaveData::from_bytes(&save.to_bytes().unwrap())
+        };
+        // A few bytes describing more voxels than could ever fit in memory
+        assert!(load_box(
+            ivec3(i32::MIN, 0, i32::MIN),
+            ivec3(i32::MAX, MAX_HEIGHT as i32 - 1, i32::MAX)
+        )
+        .is_err());
+        assert!(load_box(ivec3(0, 0, 0), ivec3(1024, 0, 1024)).is_err());
+        // Reaching above or below the world
+        assert!(load_box(ivec3(0, -1, 0), ivec3(1, 1, 1)).is_err());
+        assert!(load_box(ivec3(0, 0, 0), ivec3(1, MAX_HEIGHT as i32, 1)).is_err());
+        assert!(load_box(ivec3(-512, 0, 7), ivec3(511, 0, 6)).is_ok());
+    }
+
+    #[test]
+    pub fn legacy_format() {
+        let mut bytes = 42u32.to_le_bytes().to_vec();
+        let mut writer = Serializer::new(std::io::Cursor::new(&mut bytes));
+        writer.cursor.set_position(4);
+        writer.write_ivec3(ivec3(1, -2, 3)).unwrap();
+        writer.write_byte(VoxelKind::Snow as u8).unwrap();
+        let loaded = SaveData::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.seed, 42);
+        assert_eq!(loaded.voxels, vec![(ivec3(1, -2, 3), VoxelKind::Snow)]);
+    }
+
+    #[test]
+    pub fn waypoint_records() {
+        let waypoints = vec![
+            Waypoint {
+                name: "Home".into(),
+                position: vec3(1.5, 70.0, -3.25),
+            },
+            Waypoint {
+                name: "Mine ⛏".into(),
+                position: vec3(-100.0, 12.0, 4.0),
+            },
+        ];
+        let save = SaveData {
+            seed: 7,
+            voxels: vec![(ivec3(0, 1, 2), VoxelKind::Dirt)],
+            boxes: Vec::new(),
+            waypoints: waypoints.clone(),
+            width: 0,
+            length: 0,
+            generator: SavedGenerator::Noise,
+        };
+        let mut bytes = Vec::new();
+        save.to_writer(Serializer::new(std::io::Cursor::new(&mut bytes)))
+            .unwrap();
+        let loaded = SaveData::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.voxels, save.voxels);
+        assert_eq!(loaded.waypoints, waypoints);
+    }
+
+    #[test]
+    pub fn floating_voxels_removed() {
+        let mut save = SaveData {
+            seed: 0,
+            voxels: vec![
+                // A tower on the ground, which is solid below Y 0
+                (ivec3(0, 0, 0), VoxelKind::Stone),
+                (ivec3(0, 1, 0), VoxelKind::Wood),
+                // Placed in the air, then in the ground
+                (ivec3(5, 10, 5), VoxelKind::Dirt),
+                (ivec3(-3, -4, 2), VoxelKind::Tnt),
+                // Resting on water doesn't hold a voxel up
+                (ivec3(9, 3, 9), VoxelKind::Water),
+                (ivec3(9, 4, 9), VoxelKind::Stone),
+                // Only supported by a voxel which was later broken
+                (ivec3(0, 3, 0), VoxelKind::Leaves),
+                (ivec3(0, 2, 0), VoxelKind::Stone),
+                (ivec3(0, 2, 0), VoxelKind::Air),
+            ],
+            boxes: Vec::new(),
+            waypoints: Vec::new(),
+            width: 0,
+            length: 0,
+            generator: SavedGenerator::Noise,
+        };
+        // Dug out from all sides underground
+        let dug = ivec3(20, -5, 20);
+        save.voxels.push((dug, VoxelKind::Dirt));
+        save.voxels
+            .extend(NEIGHBOURS.map(|dir| (dug + dir, VoxelKind::Air)));
+        let ground = |pos: IVec3| pos.y < 0;
+        assert_eq!(
+            save.floating_voxels(|_| false),
+            vec![
+                ivec3(-3, -4, 2),
+                ivec3(0, 3, 0),
+                ivec3(5, 10, 5),
+                ivec3(9, 4, 9),
+                dug,
+            ]
+        );
+
+        let removed = save.remove_floating_voxels(ground);
+        assert_eq!(
+            removed,
+            vec![ivec3(0, 3, 0), ivec3(5, 10, 5), ivec3(9, 4, 9), dug]
+        );
+        // The dug out voxel would be regenerated if it was left out of the
+        // save, so it is replaced with air instead
+        assert_eq!(
+            save.voxels[..6],
+            [
+                (ivec3(0, 0, 0), VoxelKind::Stone),
+                (ivec3(0, 1, 0), VoxelKind::Wood),
+                (ivec3(-3, -4, 2), VoxelKind::Tnt),
+                (ivec3(9, 3, 9), VoxelKind::Water),
+                (ivec3(0, 2, 0), VoxelKind::Stone),
+                (ivec3(0, 2, 0), VoxelKind::Air),
+            ]
+        );
+        assert_eq!(save.voxels.last(), Some(&(dug, VoxelKind::Air)));
+        assert_eq!(save.voxels.len(), 6 + 6 + 1);
+        assert!(save.floating_voxels(ground).is_empty());
+    }
+
+    /// Save with a few of each kind of record, picked by a small LCG so the
+    /// tests are deterministic
+    fn random_save(seed: u32) -> SaveData {
+        let mut state = seed;
+        let mut next = move || {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            state
+        };
+        let mut next_coord = move || (next() >> 16) as i32 - 0x8000;
+        let voxels = (0..next_coord().unsigned_abs() % 100)
+            .map(|_| {
+                let pos = ivec3(next_coord(), next_coord(), next_coord());
+                let kind = (next_coord().unsigned_abs() % 11) as u8;
+                (pos, kind.try_into().unwrap_or(VoxelKind::Air))
+            })
+            .collect();
+        let waypoints = (0..3)
+            .map(|i| Waypoint {
+                name: format!("Waypoint {i}"),
+                position: ivec3(next_coord(), next_coord(), next_coord()).as_vec3() / 4.0,
+            })
+            .collect();
+        // Every other save is bounded
+        let (width, length) = match seed % 2 {
+            0 => (0, 0),
+            _ => (
+                next_coord().unsigned_abs() % 64,
+                next_coord().unsigned_abs() % 64,
+            ),
+        };
+        let generator = match seed % 4 {
+            0 => SavedGenerator::Noise,
+            1 => SavedGenerator::Blended {
+                blend_seed: next_coord().unsigned_abs(),
+            },
+            2 => SavedGenerator::Density,
+            _ => SavedGenerator::Flat,
+        };
+        SaveData {
+            seed,
+            voxels,
+            boxes: Vec::new(),
+            waypoints,
+            width,
+            length,
+            generator,
+        }
+    }
+
+    #[test]
+    pub fn bytes_round_trip() {
+        for seed in [0, 1, 2, 0xdead_beef] {
+            let save = random_save(seed);
+            let bytes = save.to_bytes().unwrap();
+            assert_eq!(bytes.len() as u64, save.serialized_size().unwrap());
+            assert_eq!(SaveData::from_bytes(&bytes).unwrap(), save);
+        }
+
+        // A size that doesn't fit in a `u32` is an error rather than truncated
+        let mut bytes = Vec::new();
+        let mut writer = Serializer::new(&mut bytes);
+        writer.write_bytes(MAGIC).unwrap();
+        writer.write_byte(FORMAT_VERSION).unwrap();
+        writer.write_u32(0).unwrap();
+        writer.write_byte(RECORD_SIZE).unwrap();
+        writer.write_leb128_unsigned(1 << 32).unwrap();
+        writer.write_leb128_unsigned(4).unwrap();
+        assert!(SaveData::from_bytes(&bytes).is_err());
+
+        // So is a generator the game doesn't know
+        let mut bytes = Vec::new();
+        let mut writer = Serializer::new(&mut bytes);
+        writer.write_bytes(MAGIC).unwrap();
+        writer.write_byte(FORMAT_VERSION).unwrap();
+        writer.write_u32(0).unwrap();
+        writer.write_byte(RECORD_GENERATOR).unwrap();
+        writer.write_byte(GENERATOR_FLAT + 1).unwrap();
+        assert!(SaveData::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    pub fn framed_stream() {
+        let saves = [random_save(3), random_save(4), random_save(5)];
+        let mut stream = Vec::new();
+        let mut writer = Serializer::new(&mut stream);
+        for save in &saves {
+            writer.write_framed(&save.to_bytes().unwrap()).unwrap();
+        }
+
+        let mut reader = Serializer::new(stream.as_slice());
+        for save in &saves {
+            let bytes = reader.read_framed().unwrap();
+            assert_eq!(&SaveData::from_bytes(&bytes).unwrap(), save);
+        }
+        // Nothing is left after the last message
+        assert!(reader.read_framed().is_err());
+
+        // A corrupt length is an error rather than a huge allocation
+        let mut stream = Vec::new();
+        let mut writer = Serializer::new(&mut stream);
+        writer.write_leb128_unsigned(u64::MAX).unwrap();
+        writer.write_bytes(*b"short").unwrap();
+        let mut reader = Serializer::new(stream.as_slice());
+        assert!(reader.read_framed().is_err());
+        assert!(Serializer::new(stream.as_slice()).read_string().is_err());
+    }
 }
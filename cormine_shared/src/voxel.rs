@@ -1,5 +1,9 @@
 use anyhow::anyhow;
 
+/// Height of the world. Voxels lie at Y positions from 0 up to, but not
+/// including, this
+pub const MAX_HEIGHT: usize = 256;
+
 #[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(u8)]
 pub enum VoxelKind {
@@ -11,6 +15,10 @@ pub enum VoxelKind {
     Snow = 3,
     Dirt = 4,
     Bedrock = 5,
+    Wood = 6,
+    Leaves = 7,
+    Tnt = 8,
+    Glass = 9,
 }
 
 impl TryFrom<u8> for VoxelKind {
@@ -25,6 +33,10 @@ impl TryFrom<u8> for VoxelKind {
             3 => Snow,
             4 => Dirt,
             5 => Bedrock,
+            6 => Wood,
+            7 => Leaves,
+            8 => Tnt,
+            9 => Glass,
             255 => Air,
             x => return Err(anyhow!("invalid voxel kind `{x}`")),
         })
@@ -37,7 +49,7 @@ impl VoxelKind {
     }
 
     pub fn transparent(&self) -> bool {
-        matches!(self, VoxelKind::Air | VoxelKind::Water)
+        matches!(self, VoxelKind::Air | VoxelKind::Water | VoxelKind::Glass)
     }
 
     pub fn has_collision(&self) -> bool {
@@ -45,14 +57,30 @@ impl VoxelKind {
     }
 
     pub fn casts_shadow(&self) -> bool {
-        !matches!(self, VoxelKind::Air | VoxelKind::Water)
+        !matches!(
+            self,
+            VoxelKind::Air | VoxelKind::Water | VoxelKind::Leaves | VoxelKind::Glass
+        )
     }
 
     pub fn receives_shadow(&self) -> bool {
-        !matches!(self, VoxelKind::Air | VoxelKind::Water)
+        !matches!(self, VoxelKind::Air | VoxelKind::Water | VoxelKind::Glass)
     }
 
     pub fn breakable(&self) -> bool {
         !matches!(self, VoxelKind::Bedrock)
     }
+
+    /// Seconds the break button has to be held to break a voxel of this kind
+    pub fn hardness(&self) -> f32 {
+        match self {
+            VoxelKind::Air | VoxelKind::Tnt => 0.0,
+            VoxelKind::Water | VoxelKind::Leaves => 0.2,
+            VoxelKind::Snow | VoxelKind::Glass => 0.3,
+            VoxelKind::Dirt | VoxelKind::Grass => 0.5,
+            VoxelKind::Wood => 1.0,
+            VoxelKind::Stone => 1.5,
+            VoxelKind::Bedrock => f32::INFINITY,
+        }
+    }
 }
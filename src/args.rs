@@ -1,19 +1,249 @@
 use argh::FromArgs;
-use std::path::PathBuf;
+use bevy::window::PresentMode;
+use std::{
+    path::PathBuf,
+    time::Duration,
+};
+
+use crate::{
+    material::BlockPalette,
+    mesh::WaterTransparency,
+    world::WorldBorder,
+};
 
 /// CoRmine.
 #[derive(FromArgs)]
 pub struct Arguments {
-    /// enable vsync
+    /// enable vsync, the same as `--present-mode auto-vsync`
     #[argh(switch, short = 'v')]
     pub enable_vsync: bool,
+    /// how frames are presented: `auto-vsync`, `auto-no-vsync`, `fifo`,
+    /// `mailbox` or `immediate`. Takes precedence over `--enable-vsync`
+    #[argh(option, from_str_fn(parse_present_mode))]
+    pub present_mode: Option<PresentMode>,
     /// save file to load
     #[argh(option, long = "load")]
     pub save_file: Option<PathBuf>,
+    /// size of the area the world is played in, as `<width>x<length>` in
+    /// chunks centred on the origin. Overrides the border of a loaded save,
+    /// and a size of 0 leaves that axis unbounded
+    #[argh(option)]
+    pub border: Option<WorldBorder>,
+    /// file to write saves to, keeping the previous save as `<file>.bak`
+    #[argh(option, default = "PathBuf::from(\"game.cms\")")]
+    pub save_path: PathBuf,
+    /// file the window's size and position are kept in between launches
+    #[argh(option, default = "PathBuf::from(\"window.cfg\")")]
+    pub window_state: PathBuf,
+    /// directory screenshots taken with F2 are written to
+    #[argh(option, default = "PathBuf::from(\"screenshots\")")]
+    pub screenshot_dir: PathBuf,
+    /// show how many voxels have been placed and broken this session
+    #[argh(switch)]
+    pub show_edit_counter: bool,
+    /// directory to write chunks to once they are unloaded, so coming back
+    /// to them reads them from disk instead of generating them again
+    #[argh(option)]
+    pub chunk_cache: Option<PathBuf>,
+    /// seconds between automatic saves, 0 to disable
+    #[argh(option, default = "0.0", from_str_fn(parse_interval))]
+    pub autosave: f32,
+    /// size in MiB above which saving the game warns
+    #[argh(option, default = "256")]
+    pub max_save_mib: u64,
+    /// refuse to write saves larger than `--max-save-mib`, rather than warn
+    #[argh(switch)]
+    pub refuse_large_saves: bool,
     /// world seed to use
     #[argh(option)]
     pub seed: Option<u32>,
-    /// radius in which to render chunks
+    /// initial horizontal look direction in degrees, anticlockwise from -Z
+    #[argh(option, default = "0.0")]
+    pub yaw: f32,
+    /// initial vertical look direction in degrees, positive looking up
+    #[argh(option, default = "0.0")]
+    pub pitch: f32,
+    /// seconds per full day/night cycle, 0 to keep it noon
+    #[argh(option, default = "60.0")]
+    pub day_length: f32,
+    /// seconds between the weather changing between clear skies and
+    /// snowfall, which covers grass on high ground in snow until it clears.
+    /// 0 keeps the weather clear
+    #[argh(option, default = "0.0", from_str_fn(parse_interval))]
+    pub weather_interval: f32,
+    /// seconds the underwater tint takes to fade in and out, 0 to show and
+    /// hide it at once
+    #[argh(option, default = "0.0")]
+    pub water_fade: f32,
+    /// generate a flat world instead of using noise
+    #[argh(switch)]
+    pub flat: bool,
+    /// generate terrain from 3D noise, with overhangs and caves
+    #[argh(switch)]
+    pub density_terrain: bool,
+    /// second world seed, whose terrain is blended with that of `seed`
+    /// across regions thousands of voxels wide
+    #[argh(option)]
+    pub blend_seed: Option<u32>,
+    /// radius in which to render chunks, from 2 to 64. Grown and shrunk
+    /// while playing with `=` and `-`
     #[argh(option, default = "16")]
     pub load_distance: usize,
+    /// radius, in the same units as `load-distance`, in which chunks beyond
+    /// the load distance are drawn as flat single coloured impostors. Off
+    /// unless larger than `load-distance`
+    #[argh(option, default = "0")]
+    pub impostor_distance: usize,
+    /// maximum number of times chunk octrees split before storing voxels
+    /// densely, up to 4
+    #[argh(option, default = "4")]
+    pub octree_max_depth: u32,
+    /// also emit standard normal vectors in chunk meshes
+    #[argh(switch)]
+    pub mesh_normals: bool,
+    /// cull hidden faces a row of voxels at a time when meshing
+    #[argh(switch)]
+    pub batched_culling: bool,
+    /// count the triangles, octants visited and faces culled while meshing
+    /// each chunk, shown in the debug overlay and by `--mesh-chunk-dump`
+    #[argh(switch)]
+    pub mesh_stats: bool,
+    /// leave out the faces on the bottom and top of the world, which can
+    /// only be seen from outside it
+    #[argh(switch)]
+    pub cull_world_edges: bool,
+    /// also remesh chunks once their diagonal neighbours load, or neighbours
+    /// that load while they are being meshed, rather than leaving those
+    /// corners and edges meshed as if next to air
+    #[argh(switch)]
+    pub remesh_load_edges: bool,
+    /// how water is drawn: alpha blended and sorted with glass with `blend`,
+    /// alpha to coverage with `coverage`, or with the opaque voxels with
+    /// `opaque`
+    #[argh(option, default = "WaterTransparency::Blend")]
+    pub water_transparency: WaterTransparency,
+    /// draw each voxel in a solid colour of its kind instead of its textures
+    #[argh(switch)]
+    pub flat_colors: bool,
+    /// colours of voxel kinds drawn with `--flat-colors`, as a list such as
+    /// `stone=#808080,grass=#3a9d23`. Kinds left out keep their default
+    #[argh(option)]
+    pub block_colors: Option<BlockPalette>,
+    /// side length, in chunks, of groups of chunks drawn as one mesh
+    #[argh(option, default = "1")]
+    pub mesh_batch_size: usize,
+    /// side length, in chunks, of groups of chunks generated by one task
+    #[argh(option, default = "1")]
+    pub terrain_group_size: usize,
+    /// maximum number of chunk meshing tasks to start per frame
+    #[argh(option, default = "8")]
+    pub mesh_tasks_per_frame: usize,
+    /// draw the X, Y and Z axes of this length at the world origin, with the
+    /// debug feature
+    #[argh(option)]
+    pub axis_gizmo: Option<f32>,
+    /// mesh a chunk dumped with F10 on its own, print the size of its meshes
+    /// and exit, with the debug feature
+    #[argh(option)]
+    pub mesh_chunk_dump: Option<PathBuf>,
+    /// volume of sounds from 0 to 1, which can be muted with M. Sounds are
+    /// only played with the audio feature
+    #[argh(option, default = "1.0")]
+    pub volume: f32,
+    /// apply mouse look at a fixed rate rather than per motion event
+    #[argh(switch)]
+    pub fixed_rate_look: bool,
+    /// only search for the selected voxel again when the camera has moved
+    /// or voxels have changed since the last search
+    #[argh(switch)]
+    pub skip_unchanged_highlight: bool,
+}
+
+impl Arguments {
+    /// Present mode of the window, from `--present-mode` or else
+    /// `--enable-vsync`
+    pub fn present_mode(&self) -> PresentMode {
+        self.present_mode.unwrap_or(if self.enable_vsync {
+            PresentMode::AutoVsync
+        } else {
+            PresentMode::AutoNoVsync
+        })
+    }
+}
+
+/// Seconds between something happening, which has to fit in a `Duration`
+fn parse_interval(value: &str) -> Result<f32, String> {
+    let secs: f32 = value
+        .parse()
+        .map_err(|_| format!("`{value}` is not a number of seconds"))?;
+    if Duration::try_from_secs_f32(secs).is_err() {
+        return Err(format!(
+            "`{value}` seconds is out of range, expected a finite number of at least 0"
+        ));
+    }
+    Ok(secs)
+}
+
+fn parse_present_mode(value: &str) -> Result<PresentMode, String> {
+    match value {
+        "auto-vsync" => Ok(PresentMode::AutoVsync),
+        "auto-no-vsync" => Ok(PresentMode::AutoNoVsync),
+        "fifo" => Ok(PresentMode::Fifo),
+        "mailbox" => Ok(PresentMode::Mailbox),
+        "immediate" => Ok(PresentMode::Immediate),
+        _ => Err(format!(
+            "unknown present mode `{value}`, expected `auto-vsync`, `auto-no-vsync`, `fifo`, \
+             `mailbox` or `immediate`"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn present_mode_arg() {
+        let parse =
+            |args: &[&str]| Arguments::from_args(&["cormine"], args).map(|a| a.present_mode());
+        assert_eq!(parse(&[]), Ok(PresentMode::AutoNoVsync));
+        assert_eq!(parse(&["--enable-vsync"]), Ok(PresentMode::AutoVsync));
+        assert_eq!(parse(&["-v"]), Ok(PresentMode::AutoVsync));
+        for (arg, mode) in [
+            ("auto-vsync", PresentMode::AutoVsync),
+            ("auto-no-vsync", PresentMode::AutoNoVsync),
+            ("fifo", PresentMode::Fifo),
+            ("mailbox", PresentMode::Mailbox),
+            ("immediate", PresentMode::Immediate),
+        ] {
+            assert_eq!(parse(&["--present-mode", arg]), Ok(mode));
+        }
+        // The present mode wins over vsync
+        assert_eq!(
+            parse(&["-v", "--present-mode", "immediate"]),
+            Ok(PresentMode::Immediate)
+        );
+
+        let err = parse(&["--present-mode", "vsync"]).unwrap_err();
+        assert!(
+            err.output.contains("unknown present mode `vsync`"),
+            "{}",
+            err.output
+        );
+        assert!(parse(&["--present-mode"]).is_err());
+    }
+
+    #[test]
+    pub fn interval_args() {
+        let parse = |args: &[&str]| Arguments::from_args(&["cormine"], args).map(|a| a.autosave);
+        assert_eq!(parse(&[]), Ok(0.0));
+        assert_eq!(parse(&["--autosave", "30"]), Ok(30.0));
+        assert_eq!(parse(&["--autosave", "0.5"]), Ok(0.5));
+        for value in ["-1", "nan", "inf", "1e30", "soon"] {
+            let err = parse(&["--autosave", value]).unwrap_err();
+            assert!(err.output.contains(value), "{}", err.output);
+        }
+        let weather = Arguments::from_args(&["cormine"], &["--weather-interval", "nan"]);
+        assert!(weather.is_err());
+    }
 }
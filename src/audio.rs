@@ -0,0 +1,265 @@
+use bevy::prelude::*;
+#[cfg(feature = "audio")]
+use bevy::{
+    asset::embedded_asset,
+    audio::Volume,
+    utils::HashMap,
+};
+
+use crate::voxel::VoxelKind;
+
+/// Horizontal distance walked between footsteps
+const STEP_DISTANCE: f32 = 1.8;
+/// Slowest horizontal speed counted as walking, so pushing against a wall
+/// without moving doesn't make footsteps
+const MIN_STEP_SPEED: f32 = 1.0;
+/// Footsteps are quieter than breaking and placing voxels
+#[cfg(feature = "audio")]
+const STEP_VOLUME: f32 = 0.4;
+/// Placing plays the same sound as breaking, quieter and sped up
+#[cfg(feature = "audio")]
+const PLACE_VOLUME: f32 = 0.7;
+#[cfg(feature = "audio")]
+const PLACE_SPEED: f32 = 1.3;
+
+/// Family of sounds a voxel makes when walked on, broken or placed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SoundMaterial {
+    Stone,
+    Grass,
+    Dirt,
+    Snow,
+    Wood,
+    Glass,
+}
+
+impl SoundMaterial {
+    #[cfg(feature = "audio")]
+    const ALL: [SoundMaterial; 6] = [
+        Self::Stone,
+        Self::Grass,
+        Self::Dirt,
+        Self::Snow,
+        Self::Wood,
+        Self::Glass,
+    ];
+
+    /// Sounds made by voxels of `kind`, or `None` for voxels which can't be
+    /// stood on or broken
+    pub fn of(kind: VoxelKind) -> Option<Self> {
+        Some(match kind {
+            VoxelKind::Stone | VoxelKind::Bedrock => Self::Stone,
+            VoxelKind::Grass | VoxelKind::Leaves | VoxelKind::Tnt => Self::Grass,
+            VoxelKind::Dirt => Self::Dirt,
+            VoxelKind::Snow => Self::Snow,
+            VoxelKind::Wood => Self::Wood,
+            VoxelKind::Glass => Self::Glass,
+            VoxelKind::Water | VoxelKind::Air => return None,
+        })
+    }
+
+    #[cfg(feature = "audio")]
+    fn path(self) -> &'static str {
+        match self {
+            Self::Stone => "embedded://cormine/../assets/sounds/stone.wav",
+            Self::Grass => "embedded://cormine/../assets/sounds/grass.wav",
+            Self::Dirt => "embedded://cormine/../assets/sounds/dirt.wav",
+            Self::Snow => "embedded://cormine/../assets/sounds/snow.wav",
+            Self::Wood => "embedded://cormine/../assets/sounds/wood.wav",
+            Self::Glass => "embedded://cormine/../assets/sounds/glass.wav",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sound {
+    Step(SoundMaterial),
+    Break(SoundMaterial),
+    Place(SoundMaterial),
+    Splash,
+}
+
+/// Emitted to play a sound. Nothing is heard unless built with the `audio`
+/// feature
+#[derive(Event, Debug)]
+pub struct SoundEvent(pub Sound);
+
+/// Volume of every sound, from 0 to 1
+#[derive(Resource)]
+pub struct AudioSettings {
+    pub volume: f32,
+    pub muted: bool,
+}
+
+impl AudioSettings {
+    pub fn new(volume: f32) -> Self {
+        Self {
+            volume: volume.clamp(0.0, 1.0),
+            muted: false,
+        }
+    }
+
+    /// Volume sounds are played at, taking muting into account
+    pub fn volume(&self) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            self.volume
+        }
+    }
+}
+
+/// Tracks the player's movement to decide when they make a footstep or
+/// splash into water
+#[derive(Resource, Default)]
+pub struct PlayerSounds {
+    /// Distance walked since the last footstep
+    walked: f32,
+    in_water: bool,
+}
+
+impl PlayerSounds {
+    /// Track the player moving `moved` horizontally over `dt` seconds,
+    /// returning whether they made a footstep. Only distance actually
+    /// covered on the ground counts, so steps follow how far the player
+    /// walks rather than how long they hold a movement key
+    pub fn step(&mut self, moved: Vec2, dt: f32, grounded: bool) -> bool {
+        let distance = moved.length();
+        if !grounded || distance < MIN_STEP_SPEED * dt {
+            return false;
+        }
+        self.walked += distance;
+        if self.walked < STEP_DISTANCE {
+            return false;
+        }
+        self.walked %= STEP_DISTANCE;
+        true
+    }
+
+    /// Whether the player has just gone from out of the water to in it
+    pub fn splash(&mut self, in_water: bool) -> bool {
+        let splashed = in_water && !self.in_water;
+        self.in_water = in_water;
+        splashed
+    }
+}
+
+pub fn handle_mute_key(keys: Res<ButtonInput<KeyCode>>, mut settings: ResMut<AudioSettings>) {
+    if keys.just_pressed(KeyCode::KeyM) {
+        settings.muted = !settings.muted;
+        info!("Sound {}", if settings.muted { "muted" } else { "unmuted" });
+    }
+}
+
+/// Sounds loaded from the embedded assets
+#[cfg(feature = "audio")]
+#[derive(Resource)]
+pub struct Sounds {
+    materials: HashMap<SoundMaterial, Handle<AudioSource>>,
+    splash: Handle<AudioSource>,
+}
+
+#[cfg(feature = "audio")]
+pub fn embed_sounds(app: &mut App) {
+    embedded_asset!(app, "../assets/sounds/stone.wav");
+    embedded_asset!(app, "../assets/sounds/grass.wav");
+    embedded_asset!(app, "../assets/sounds/dirt.wav");
+    embedded_asset!(app, "../assets/sounds/snow.wav");
+    embedded_asset!(app, "../assets/sounds/wood.wav");
+    embedded_asset!(app, "../assets/sounds/glass.wav");
+    embedded_asset!(app, "../assets/sounds/splash.wav");
+}
+
+#[cfg(feature = "audio")]
+pub fn load_sounds(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(Sounds {
+        materials: SoundMaterial::ALL
+            .into_iter()
+            .map(|material| (material, asset_server.load(material.path())))
+            .collect(),
+        splash: asset_server.load("embedded://cormine/../assets/sounds/splash.wav"),
+    });
+}
+
+#[cfg(feature = "audio")]
+pub fn play_sounds(
+    mut commands: Commands,
+    mut ev_sound: EventReader<SoundEvent>,
+    sounds: Res<Sounds>,
+    settings: Res<AudioSettings>,
+) {
+    for &SoundEvent(sound) in ev_sound.read() {
+        let (source, volume, speed) = match sound {
+            Sound::Step(material) => (&sounds.materials[&material], STEP_VOLUME, 1.0),
+            Sound::Break(material) => (&sounds.materials[&material], 1.0, 1.0),
+            Sound::Place(material) => (&sounds.materials[&material], PLACE_VOLUME, PLACE_SPEED),
+            Sound::Splash => (&sounds.splash, 1.0, 1.0),
+        };
+        let volume = volume * settings.volume();
+        if volume <= 0.0 {
+            continue;
+        }
+        commands.spawn(AudioBundle {
+            source: source.clone(),
+            settings: PlaybackSettings::DESPAWN
+                .with_volume(Volume::new(volume))
+                .with_speed(speed),
+        });
+    }
+}
+
+/// Without the `audio` feature sounds can't be played, so they are only
+/// logged
+#[cfg(not(feature = "audio"))]
+pub fn log_sounds(mut ev_sound: EventReader<SoundEvent>, settings: Res<AudioSettings>) {
+    for &SoundEvent(sound) in ev_sound.read() {
+        trace!("Sound {sound:?} at volume {}", settings.volume());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn footsteps_follow_distance_walked() {
+        let mut sounds = PlayerSounds::default();
+        let dt = 1.0 / 60.0;
+        let walk = Vec2::new(4.0, 0.0) * dt;
+
+        // Walking against a wall, barely moving
+        for _ in 0..600 {
+            assert!(!sounds.step(Vec2::new(0.001, 0.0), dt, true));
+        }
+        // Walking for 3 seconds covers 12 blocks
+        let steps = (0..180).filter(|_| sounds.step(walk, dt, true)).count();
+        assert_eq!(steps, (12.0 / STEP_DISTANCE) as usize);
+        // Frame rate doesn't change how often steps are made
+        let mut slow = PlayerSounds::default();
+        let steps = (0..36)
+            .filter(|_| slow.step(walk * 5.0, dt * 5.0, true))
+            .count();
+        assert_eq!(steps, (12.0 / STEP_DISTANCE) as usize);
+        // Flying or falling past makes no sound
+        assert!((0..180).all(|_| !sounds.step(walk, dt, false)));
+
+        assert!(!sounds.splash(false));
+        assert!(sounds.splash(true));
+        assert!(!sounds.splash(true));
+        assert!(!sounds.splash(false));
+        assert!(sounds.splash(true));
+    }
+
+    #[test]
+    pub fn muting() {
+        let mut settings = AudioSettings::new(1.5);
+        assert_eq!(settings.volume(), 1.0);
+        settings.muted = true;
+        assert_eq!(settings.volume(), 0.0);
+        assert_eq!(SoundMaterial::of(VoxelKind::Water), None);
+        assert_eq!(
+            SoundMaterial::of(VoxelKind::Bedrock),
+            Some(SoundMaterial::Stone)
+        );
+    }
+}
@@ -0,0 +1,215 @@
+use bevy::{
+    math::ivec2,
+    prelude::*,
+    render::primitives::Aabb,
+    utils::HashMap,
+};
+
+use crate::{
+    chunk::{
+        ChunkPosition,
+        CHUNK_SIZE_I,
+    },
+    material::VoxelMaterialResource,
+    mesh,
+};
+
+/// A group of chunk meshes drawn as a single combined mesh
+#[derive(Default)]
+struct Batch {
+    /// Entity drawing the combined mesh, once it has been built
+    entity: Option<Entity>,
+    chunks: HashMap<ChunkPosition, Handle<Mesh>>,
+    dirty: bool,
+}
+
+/// Groups the meshes of `size`×`size` squares of chunks into single entities,
+/// trading remeshing granularity for fewer draw calls
+#[derive(Resource)]
+pub struct MeshBatches {
+    /// Side length of a batch, in chunks. `1` disables batching
+    size: i32,
+    batches: HashMap<IVec2, Batch>,
+}
+
+impl MeshBatches {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size: size.max(1) as i32,
+            batches: default(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.size > 1
+    }
+
+    /// Position of the batch containing a chunk, in units of batches
+    fn batch_of(&self, pos: ChunkPosition) -> IVec2 {
+        let batch_size = self.size * CHUNK_SIZE_I;
+        ivec2(
+            pos.x().div_euclid(batch_size),
+            pos.z().div_euclid(batch_size),
+        )
+    }
+
+    /// World position of the corner of a batch
+    fn origin(&self, batch: IVec2) -> Vec3 {
+        let corner = batch * self.size * CHUNK_SIZE_I;
+        Vec3::new(corner.x as f32, 0.0, corner.y as f32)
+    }
+
+    /// Set the mesh of a chunk, marking its batch for rebuilding
+    pub fn insert_mesh(&mut self, pos: ChunkPosition, mesh: Handle<Mesh>) {
+        let batch = self.batches.entry(self.batch_of(pos)).or_default();
+        batch.chunks.insert(pos, mesh);
+        batch.dirty = true;
+    }
+
+    /// Remove a chunk's mesh, marking its batch for rebuilding
+    pub fn remove_chunk(&mut self, pos: ChunkPosition) {
+        if let Some(batch) = self.batches.get_mut(&self.batch_of(pos)) {
+            batch.dirty |= batch.chunks.remove(&pos).is_some();
+        }
+    }
+
+    fn dirty_batches(&self) -> Vec<IVec2> {
+        self.batches
+            .iter()
+            .filter(|(_, batch)| batch.dirty)
+            .map(|(pos, _)| *pos)
+            .collect()
+    }
+}
+
+/// Combine the meshes of each batch with changed chunks, replacing the mesh
+/// of the batch entity
+pub fn rebuild_mesh_batches(
+    mut commands: Commands,
+    mut batches: ResMut<MeshBatches>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    materials: Res<VoxelMaterialResource>,
+) {
+    for batch_pos in batches.dirty_batches() {
+        let origin = batches.origin(batch_pos);
+        let batch = batches.batches.get_mut(&batch_pos).unwrap();
+        batch.dirty = false;
+
+        if batch.chunks.is_empty() {
+            if let Some(entity) = batch.entity {
+                commands.entity(entity).despawn();
+            }
+            batches.batches.remove(&batch_pos);
+            continue;
+        }
+
+        let chunk_meshes = batch
+            .chunks
+            .iter()
+            .filter_map(|(pos, handle)| Some((pos.as_vec3() - origin, meshes.get(handle)?)))
+            .collect::<Vec<_>>();
+        let combined = mesh::combine(&chunk_meshes);
+        let combined = meshes.add(combined);
+
+        match batch.entity {
+            Some(entity) => {
+                commands
+                    .entity(entity)
+                    .insert(combined)
+                    // Force AABB to be recalculated so we get correct frustrum culling
+                    .remove::<Aabb>();
+            }
+            None => {
+                let entity = commands
+                    .spawn((
+                        Name::new("Chunk Batch"),
+                        MaterialMeshBundle {
+                            mesh: combined,
+                            transform: Transform::from_translation(origin),
+                            material: materials.handle.clone(),
+                            ..default()
+                        },
+                    ))
+                    .id();
+                batch.entity = Some(entity);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        chunk::ChunkVoxels,
+        voxel::{
+            LocalVoxelPosition,
+            VoxelKind,
+        },
+    };
+
+    #[test]
+    pub fn combine_and_invalidate() {
+        let mut chunk = ChunkVoxels::default();
+        chunk.voxel_mut(LocalVoxelPosition::new(1, 1, 1)).kind = VoxelKind::Stone;
+        let chunk_mesh =
+            mesh::from_chunk((ChunkPosition::new(0, 0), &chunk), &[], default()).opaque;
+        chunk.voxel_mut(LocalVoxelPosition::new(8, 1, 8)).kind = VoxelKind::Stone;
+        let edited_mesh =
+            mesh::from_chunk((ChunkPosition::new(0, 0), &chunk), &[], default()).opaque;
+
+        let mut meshes = Assets::<Mesh>::default();
+        let mut batches = MeshBatches::new(2);
+        let positions = [(0, 0), (16, 0), (0, 16), (16, 16)].map(|(x, z)| ChunkPosition::new(x, z));
+        for pos in positions {
+            batches.insert_mesh(pos, meshes.add(chunk_mesh.clone()));
+        }
+        // A chunk in a different batch
+        batches.insert_mesh(ChunkPosition::new(-16, 0), meshes.add(chunk_mesh.clone()));
+        assert_eq!(batches.dirty_batches().len(), 2);
+
+        let batch = &batches.batches[&ivec2(0, 0)];
+        let chunk_meshes = batch
+            .chunks
+            .iter()
+            .map(|(pos, handle)| (pos.as_vec3(), meshes.get(handle).unwrap()))
+            .collect::<Vec<_>>();
+        let combined = mesh::combine(&chunk_meshes);
+        assert_eq!(combined.count_vertices(), 4 * chunk_mesh.count_vertices());
+        // Each chunk's indices are offset to its own vertices
+        let indices: Vec<_> = combined.indices().unwrap().iter().collect();
+        assert_eq!(indices.len(), 4 * chunk_mesh.indices().unwrap().len());
+        let last_chunk = &indices[indices.len() * 3 / 4..];
+        assert_eq!(
+            last_chunk.iter().min(),
+            Some(&(3 * chunk_mesh.count_vertices()))
+        );
+        assert_eq!(
+            last_chunk.iter().max(),
+            Some(&(combined.count_vertices() - 1))
+        );
+
+        for batch in batches.batches.values_mut() {
+            batch.dirty = false;
+        }
+
+        // Editing a chunk only invalidates its own batch
+        batches.insert_mesh(ChunkPosition::new(16, 16), meshes.add(edited_mesh.clone()));
+        assert_eq!(batches.dirty_batches(), vec![ivec2(0, 0)]);
+        let batch = &batches.batches[&ivec2(0, 0)];
+        let combined_vertices: usize = batch
+            .chunks
+            .values()
+            .map(|handle| meshes.get(handle).unwrap().count_vertices())
+            .sum();
+        assert_eq!(
+            combined_vertices,
+            3 * chunk_mesh.count_vertices() + edited_mesh.count_vertices()
+        );
+
+        // As does removing one
+        batches.batches.get_mut(&ivec2(0, 0)).unwrap().dirty = false;
+        batches.remove_chunk(ChunkPosition::new(-16, 0));
+        assert_eq!(batches.dirty_batches(), vec![ivec2(-1, 0)]);
+    }
+}
@@ -0,0 +1,128 @@
+extern crate test;
+
+use test::{
+    black_box,
+    Bencher,
+};
+
+use crate::{
+    chunk::{
+        ChunkPosition,
+        ChunkVoxels,
+        CHUNK_SIZE,
+        MAX_HEIGHT,
+    },
+    mesh::{
+        self,
+        MeshSettings,
+    },
+    terrain::{
+        self,
+        WorldGenerator,
+    },
+    voxel::{
+        LocalVoxelPosition,
+        VoxelKind,
+        VoxelPosition,
+    },
+};
+use bevy::math::ivec3;
+
+const SEED: u32 = 1234;
+const CHUNK_POS: (i32, i32) = (64, -32);
+
+fn chunk_pos() -> ChunkPosition {
+    ChunkPosition::new(CHUNK_POS.0, CHUNK_POS.1)
+}
+
+fn flat_chunk() -> ChunkVoxels {
+    terrain::generate_chunk(chunk_pos(), &WorldGenerator::Flat, 4)
+}
+
+fn noise_chunk() -> ChunkVoxels {
+    terrain::generate_chunk(chunk_pos(), &WorldGenerator::from_seed(SEED), 4)
+}
+
+/// Every other voxel is solid, so every face of every voxel is meshed. No
+/// chunk has larger meshes
+fn checkerboard_chunk() -> ChunkVoxels {
+    ChunkVoxels::from_fn(4, |pos: LocalVoxelPosition| {
+        if (pos.x() + pos.y() + pos.z()).is_multiple_of(2) {
+            VoxelKind::Stone
+        } else {
+            VoxelKind::Air
+        }
+    })
+}
+
+fn bench_meshing(b: &mut Bencher, chunk: ChunkVoxels) {
+    let vertices = mesh::from_chunk((chunk_pos(), &chunk), &[], MeshSettings::default())
+        .opaque
+        .count_vertices();
+    println!("{vertices} vertices");
+    b.iter(|| {
+        mesh::from_chunk(
+            (chunk_pos(), black_box(&chunk)),
+            &[],
+            MeshSettings::default(),
+        )
+    });
+}
+
+#[bench]
+pub fn mesh_flat(b: &mut Bencher) {
+    bench_meshing(b, flat_chunk());
+}
+
+#[bench]
+pub fn mesh_noise(b: &mut Bencher) {
+    bench_meshing(b, noise_chunk());
+}
+
+#[bench]
+pub fn mesh_checkerboard(b: &mut Bencher) {
+    bench_meshing(b, checkerboard_chunk());
+}
+
+#[bench]
+pub fn generate_flat_chunk(b: &mut Bencher) {
+    b.iter(flat_chunk);
+}
+
+#[bench]
+pub fn generate_noise_chunk(b: &mut Bencher) {
+    let generator = WorldGenerator::from_seed(SEED);
+    b.iter(|| terrain::generate_chunk(black_box(chunk_pos()), &generator, 4));
+}
+
+#[bench]
+pub fn generate_density_chunk(b: &mut Bencher) {
+    let generator = WorldGenerator::density_from_seed(SEED);
+    b.iter(|| terrain::generate_chunk(black_box(chunk_pos()), &generator, 4));
+}
+
+#[bench]
+pub fn noise_block_at_position(b: &mut Bencher) {
+    let generator = WorldGenerator::from_seed(SEED);
+    b.iter(|| {
+        for y in 0..MAX_HEIGHT as i32 {
+            let pos = VoxelPosition::new(ivec3(CHUNK_POS.0, y, CHUNK_POS.1));
+            black_box(terrain::block_at_position(black_box(pos), &generator));
+        }
+    });
+}
+
+#[test]
+pub fn checkerboard_meshes_every_face() {
+    let mesh = mesh::from_chunk(
+        (chunk_pos(), &checkerboard_chunk()),
+        &[],
+        MeshSettings::default(),
+    );
+    let solid = CHUNK_SIZE * CHUNK_SIZE * MAX_HEIGHT / 2;
+    // Faces are two triangles sharing 4 vertices
+    assert_eq!(mesh.opaque.count_vertices(), solid * 6 * 4);
+    assert_eq!(mesh.opaque.indices().unwrap().len(), solid * 6 * 6);
+    let noise = mesh::from_chunk((chunk_pos(), &noise_chunk()), &[], MeshSettings::default());
+    assert!(mesh.opaque.count_vertices() > noise.opaque.count_vertices());
+}
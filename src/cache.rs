@@ -0,0 +1,323 @@
+use std::{
+    fs::File,
+    io::{
+        BufReader,
+        BufWriter,
+        Read,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+
+use anyhow::{
+    anyhow,
+    Result,
+};
+use bevy::{
+    prelude::*,
+    tasks::IoTaskPool,
+    utils::{
+        HashMap,
+        HashSet,
+    },
+};
+use cormine_shared::save::Serializer;
+
+use crate::{
+    chunk::{
+        ChunkPosition,
+        ChunkVoxels,
+        CHUNK_SIZE,
+        CHUNK_SIZE_I,
+        MAX_HEIGHT,
+    },
+    octree::OctantKind,
+    terrain::WorldGenerator,
+    voxel::{
+        LocalVoxelPosition,
+        VoxelKind,
+    },
+};
+
+/// Marks a cached chunk, followed by the version of its format
+const MAGIC: [u8; 4] = *b"CMCv";
+const FORMAT_VERSION: u8 = 1;
+
+/// Tags distinguishing octants of a single kind from those storing each voxel
+const OCTANT_UNIFORM: u8 = 0;
+const OCTANT_DENSE: u8 = 1;
+
+/// Directory chunks are written to once unloaded, so coming back to them
+/// reads them from disk rather than generating their terrain again
+#[derive(Resource, Clone)]
+pub struct ChunkCache {
+    dir: PathBuf,
+    /// Chunks queued to be written in the background, which are loaded from
+    /// here until written so coming back to a chunk never reads an older copy
+    pending: Arc<Mutex<HashMap<ChunkPosition, Arc<ChunkVoxels>>>>,
+    /// Held while writing, so two copies of a chunk are never written at once
+    writing: Arc<Mutex<()>>,
+}
+
+impl ChunkCache {
+    /// Cache under `root` for the world made by `generator` from `seed`.
+    /// Each world has its own directory, so chunks from one are never loaded
+    /// into another
+    pub fn new(root: &Path, seed: u32, generator: &WorldGenerator) -> Self {
+        let mut name = format!("{}-{seed}", generator.name());
+        if let Some(blend_seed) = generator.blend_seed() {
+            name += &format!("-{blend_seed}");
+        }
+        Self {
+            dir: root.join(name),
+            pending: default(),
+            writing: default(),
+        }
+    }
+
+    /// Remove every cached chunk. Chunks cached by an earlier session may
+    /// hold edits that were never saved, so each session starts afresh
+    pub fn clear(&self) -> Result<()> {
+        match std::fs::remove_dir_all(&self.dir) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn path(&self, pos: ChunkPosition) -> PathBuf {
+        self.dir.join(format!("{}_{}.chunk", pos.x(), pos.z()))
+    }
+
+    /// Positions of every cached chunk, including those still being written
+    pub fn positions(&self) -> Vec<ChunkPosition> {
+        let mut positions: HashSet<_> = self.pending.lock().unwrap().keys().copied().collect();
+        // Nothing has been cached until the directory exists
+        let entries = std::fs::read_dir(&self.dir).into_iter().flatten().flatten();
+        positions.extend(entries.filter_map(|entry| {
+            let name = entry.file_name();
+            let (x, z) = name.to_str()?.strip_suffix(".chunk")?.split_once('_')?;
+            let (x, z): (i32, i32) = (x.parse().ok()?, z.parse().ok()?);
+            (x % CHUNK_SIZE_I == 0 && z % CHUNK_SIZE_I == 0).then(|| ChunkPosition::new(x, z))
+        }));
+        positions.into_iter().collect()
+    }
+
+    /// Cached voxels of the chunk at `pos`, if it has been cached. Unreadable
+    /// chunks are left to be generated again
+    pub fn load(&self, pos: ChunkPosition, max_depth: u32) -> Option<ChunkVoxels> {
+        if let Some(chunk) = self.pending.lock().unwrap().get(&pos) {
+            return Some(ChunkVoxels::clone(chunk));
+        }
+        let path = self.path(pos);
+        let file = File::open(&path).ok()?;
+        let mut reader = Serializer::new(BufReader::new(file));
+        read_chunk(&mut reader, max_depth)
+            .inspect_err(|err| warn!("Failed to read cached chunk `{}`: {err:#}", path.display()))
+            .ok()
+    }
+
+    /// Write the voxels of the chunk at `pos`, replacing any earlier copy so
+    /// edits made since it was last cached are kept
+    pub fn store(&self, pos: ChunkPosition, chunk: &ChunkVoxels) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.path(pos);
+        // Written alongside and moved into place, so a chunk loaded while
+        // this is being written is never read half finished
+        let partial = path.with_extension("partial");
+        let mut file = BufWriter::new(File::create(&partial)?);
+        write_chunk(chunk, &mut Serializer::new(&mut file))?;
+        file.flush()?;
+        drop(file);
+        std::fs::rename(partial, path)?;
+        Ok(())
+    }
+
+    /// [`ChunkCache::store`] the chunk without waiting for it to be written.
+    /// Until it is, loading the chunk returns this copy
+    pub fn store_in_background(&self, pos: ChunkPosition, chunk: ChunkVoxels) {
+        let chunk = Arc::new(chunk);
+        self.pending.lock().unwrap().insert(pos, chunk.clone());
+        let cache = self.clone();
+        IoTaskPool::get()
+            .spawn(async move {
+                let _writing = cache.writing.lock().unwrap();
+                let is_latest = |pending: &HashMap<_, Arc<_>>| {
+                    pending
+                        .get(&pos)
+                        .is_some_and(|queued| Arc::ptr_eq(queued, &chunk))
+                };
+                // A newer copy queued since will be written instead
+                if !is_latest(&cache.pending.lock().unwrap()) {
+                    return;
+                }
+                if let Err(err) = cache.store(pos, &chunk) {
+                    // Kept in memory instead, so the chunk's edits last the session
+                    warn!("Failed to cache chunk at {pos:?}: {err:#}");
+                    return;
+                }
+                let mut pending = cache.pending.lock().unwrap();
+                if is_latest(&pending) {
+                    pending.remove(&pos);
+                }
+            })
+            .detach();
+    }
+}
+
+/// Write the kinds of the voxels of `chunk` an octant at a time, so octants
+/// of a single kind take a few bytes. Light isn't written, as chunks are lit
+/// again once loaded
+fn write_chunk<Cursor: Write>(chunk: &ChunkVoxels, writer: &mut Serializer<Cursor>) -> Result<()> {
+    writer.write_bytes(MAGIC)?;
+    writer.write_byte(FORMAT_VERSION)?;
+    let octants: Vec<_> = chunk
+        .iter_octants()
+        .filter(|(_, octant)| !matches!(octant.kind, OctantKind::Node(_)))
+        .collect();
+    writer.write_leb128_unsigned(octants.len() as u64)?;
+    for (offset, octant) in octants {
+        let (x, y, z) = (offset + octant.position).into();
+        for value in [x, y, z, octant.size] {
+            writer.write_leb128_unsigned(value as u64)?;
+        }
+        match &octant.kind {
+            OctantKind::Chunk(voxel) => {
+                writer.write_byte(OCTANT_UNIFORM)?;
+                writer.write_byte(voxel.kind as u8)?;
+            }
+            OctantKind::Dense(voxels) => {
+                writer.write_byte(OCTANT_DENSE)?;
+                for voxel in voxels {
+                    writer.write_byte(voxel.kind as u8)?;
+                }
+            }
+            OctantKind::Node(_) => unreachable!(),
+        }
+    }
+    Ok(())
+}
+
+/// Read a chunk written by [`write_chunk`] into octrees which split at most
+/// `max_depth` times
+fn read_chunk<Cursor: Read>(
+    reader: &mut Serializer<Cursor>,
+    max_depth: u32,
+) -> Result<ChunkVoxels> {
+    if reader.read_bytes()? != MAGIC {
+        return Err(anyhow!("not a cached chunk"));
+    }
+    let version = reader.read_byte()?;
+    if version != FORMAT_VERSION {
+        return Err(anyhow!("unsupported cached chunk version {version}"));
+    }
+    let mut chunk = ChunkVoxels::with_max_depth(max_depth);
+    let mut dense = Vec::new();
+    let octants = reader.read_leb128_unsigned()?;
+    for _ in 0..octants {
+        let [x, y, z, size] = [(); 4].map(|_| reader.read_leb128_unsigned());
+        let (x, y, z, size) = (x? as usize, y? as usize, z? as usize, size? as usize);
+        if size == 0 || x + size > CHUNK_SIZE || y + size > MAX_HEIGHT || z + size > CHUNK_SIZE {
+            return Err(anyhow!(
+                "octant at {x}, {y}, {z} of size {size} is out of bounds"
+            ));
+        }
+        let min = LocalVoxelPosition::new(x as _, y as _, z as _);
+        match reader.read_byte()? {
+            OCTANT_UNIFORM => {
+                let kind = VoxelKind::try_from(reader.read_byte()?)?;
+                let last = (size - 1) as u32;
+                let max =
+                    LocalVoxelPosition::new(x as u32 + last, y as u32 + last, z as u32 + last);
+                chunk.fill_region(min, max, kind);
+            }
+            OCTANT_DENSE => {
+                // Ordered by X, then Y, then Z, as octants store them
+                for i in 0..size.pow(3) {
+                    let offset = (i / (size * size), i / size % size, i % size);
+                    let pos = LocalVoxelPosition::new(
+                        (x + offset.0) as _,
+                        (y + offset.1) as _,
+                        (z + offset.2) as _,
+                    );
+                    dense.push((pos, VoxelKind::try_from(reader.read_byte()?)?));
+                }
+            }
+            tag => return Err(anyhow!("invalid octant tag `{tag}`")),
+        }
+    }
+    chunk.set_many(&dense);
+    Ok(chunk)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn cached_chunks_round_trip() {
+        let dir = std::env::temp_dir().join(format!("cormine-cache-{}", std::process::id()));
+        let generator = WorldGenerator::from_seed(42);
+        let cache = ChunkCache::new(&dir, 42, &generator);
+        let pos = ChunkPosition::new(-32, 48);
+        assert!(cache.load(pos, 4).is_none());
+
+        // Dense octants as well as uniform ones
+        for max_depth in [2, 4] {
+            let mut chunk = crate::terrain::generate_chunk(pos, &generator, max_depth);
+            chunk.voxel_mut(LocalVoxelPosition::new(3, 200, 9)).kind = VoxelKind::Tnt;
+            cache.store(pos, &chunk).unwrap();
+            for load_depth in [2, 4] {
+                let loaded = cache.load(pos, load_depth).unwrap();
+                // Octrees of different depths iterate in different orders
+                assert!(chunk
+                    .iter_local_pos()
+                    .all(|(pos, voxel)| loaded.voxel(pos).kind == voxel.kind));
+            }
+        }
+
+        // Another world never sees the chunk
+        let other = ChunkCache::new(&dir, 43, &generator);
+        assert!(other.load(pos, 4).is_none());
+
+        // Chunks being written in the background are loaded as queued
+        IoTaskPool::get_or_init(bevy::tasks::TaskPool::new);
+        let mut chunk = cache.load(pos, 4).unwrap();
+        chunk.voxel_mut(LocalVoxelPosition::new(3, 200, 9)).kind = VoxelKind::Glass;
+        for _ in 0..3 {
+            cache.store_in_background(pos, chunk.clone());
+            assert_eq!(cache.positions(), [pos]);
+            let loaded = cache.load(pos, 4).unwrap();
+            assert_eq!(
+                loaded.voxel(LocalVoxelPosition::new(3, 200, 9)).kind,
+                VoxelKind::Glass
+            );
+        }
+        while !cache.pending.lock().unwrap().is_empty() {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let loaded = cache.load(pos, 4).unwrap();
+        assert_eq!(
+            loaded.voxel(LocalVoxelPosition::new(3, 200, 9)).kind,
+            VoxelKind::Glass
+        );
+        assert_eq!(cache.positions(), [pos]);
+        assert!(other.positions().is_empty());
+
+        std::fs::write(cache.path(pos), b"CMCv\x01\x05").unwrap();
+        assert!(cache.load(pos, 4).is_none());
+
+        // A new session starts without any cached chunks
+        cache.store(pos, &chunk).unwrap();
+        cache.clear().unwrap();
+        assert!(cache.load(pos, 4).is_none());
+        cache.clear().unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
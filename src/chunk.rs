@@ -9,6 +9,7 @@ use crate::{
     voxel::{
         LocalVoxelPosition,
         Voxel,
+        VoxelKind,
         VoxelPosition,
     },
 };
@@ -24,7 +25,9 @@ use bevy::{
 
 pub const CHUNK_SIZE: usize = 16;
 pub const CHUNK_SIZE_I: i32 = CHUNK_SIZE as i32;
-pub const MAX_HEIGHT: usize = 256;
+pub use cormine_shared::voxel::MAX_HEIGHT;
+/// Octrees stacked in each chunk of the world, to reach [`MAX_HEIGHT`]
+pub const CHUNK_SLICES: usize = MAX_HEIGHT / CHUNK_SIZE;
 
 /// X and Z positions of a chunk. Will always be multiples of [`CHUNK_SIZE`]
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash, Component)]
@@ -63,12 +66,27 @@ impl ChunkPosition {
         NeighbouringChunks { neg_x, x, neg_z, z }
     }
 
+    /// Distance in chunks to `other`, along whichever axis it is furthest
+    pub fn chunk_distance(&self, other: ChunkPosition) -> usize {
+        let offset = (self.0 - other.0).abs();
+        (offset.x.max(offset.y) / CHUNK_SIZE_I) as usize
+    }
+
+    /// Distance in chunks to the chunk at the origin
     pub fn spawn_distance(&self) -> usize {
-        std::cmp::max(self.x().abs(), self.z().abs()) as usize
+        self.chunk_distance(Self::default())
+    }
+
+    /// Whether this chunk is in the square of chunks reaching `range` chunks
+    /// out from `other` in each direction
+    pub fn in_range_of(&self, other: ChunkPosition, range: usize) -> bool {
+        let offset = (self.0 - other.0).abs();
+        let range = range as i32 * CHUNK_SIZE_I;
+        offset.x <= range && offset.y <= range
     }
 
     pub fn in_range_of_spawn(&self, range: usize) -> bool {
-        self.x() <= range as i32 && self.z() <= range as i32
+        self.in_range_of(Self::default(), range)
     }
 }
 
@@ -134,17 +152,77 @@ impl NeighbouringChunks {
     pub fn all(&self) -> [ChunkPosition; 4] {
         [self.neg_x, self.x, self.neg_z, self.z]
     }
+
+    /// The 4 chunks touching only the corners of the chunk
+    pub fn diagonals(&self) -> [ChunkPosition; 4] {
+        [
+            ChunkPosition::new(self.neg_x.x(), self.neg_z.z()),
+            ChunkPosition::new(self.x.x(), self.neg_z.z()),
+            ChunkPosition::new(self.neg_x.x(), self.z.z()),
+            ChunkPosition::new(self.x.x(), self.z.z()),
+        ]
+    }
+
+    /// All 8 chunks bordering the chunk, including diagonally
+    pub fn all8(&self) -> [ChunkPosition; 8] {
+        let [a, b, c, d] = self.all();
+        let [e, f, g, h] = self.diagonals();
+        [a, b, c, d, e, f, g, h]
+    }
 }
 
-#[derive(Component, Clone, Default)]
-pub struct ChunkVoxels {
-    // Stack of CHUNK_SIZE^3 cubes; starting at Y=0 and ending at Y=MAX
-    voxels: [Octree<CHUNK_SIZE, Voxel>; MAX_HEIGHT / CHUNK_SIZE],
+/// Voxels of a chunk `SLICES` octrees tall. Chunks of the world are as tall
+/// as the world, while taller ones can be made for testing
+#[derive(Component, Clone)]
+pub struct ChunkVoxels<const SLICES: usize = CHUNK_SLICES> {
+    // Stack of CHUNK_SIZE^3 cubes; starting at Y=0 and ending at
+    // Y=SLICES*CHUNK_SIZE
+    voxels: [Octree<CHUNK_SIZE, Voxel>; SLICES],
+    /// Sky light of each voxel, stacked the same way as `voxels`. See
+    /// [`crate::light`]
+    light: [Octree<CHUNK_SIZE, u8>; SLICES],
+}
+
+impl Default for ChunkVoxels {
+    fn default() -> Self {
+        Self::with_max_depth(CHUNK_SIZE.ilog2())
+    }
 }
 
 impl ChunkVoxels {
-    pub fn new() -> Self {
-        Self::default()
+    /// Create an empty chunk whose octrees split at most `max_depth` times
+    pub fn with_max_depth(max_depth: u32) -> Self {
+        Self::empty(max_depth)
+    }
+
+    /// Create a chunk whose octrees split at most `max_depth` times, with the
+    /// kind of each voxel given by `kind_at`
+    pub fn from_fn(
+        max_depth: u32,
+        mut kind_at: impl FnMut(LocalVoxelPosition) -> VoxelKind,
+    ) -> Self {
+        let mut voxels = Vec::with_capacity(CHUNK_SIZE * CHUNK_SIZE * MAX_HEIGHT);
+        for x in 0..CHUNK_SIZE as u32 {
+            for y in 0..MAX_HEIGHT as u32 {
+                for z in 0..CHUNK_SIZE as u32 {
+                    let pos = LocalVoxelPosition::new(x, y, z);
+                    voxels.push((pos, kind_at(pos)));
+                }
+            }
+        }
+        let mut chunk = Self::with_max_depth(max_depth);
+        chunk.set_many(&voxels);
+        chunk
+    }
+}
+
+impl<const SLICES: usize> ChunkVoxels<SLICES> {
+    /// Create an empty chunk whose octrees split at most `max_depth` times
+    pub fn empty(max_depth: u32) -> Self {
+        Self {
+            voxels: std::array::from_fn(|_| Octree::with_max_depth(max_depth)),
+            light: std::array::from_fn(|_| Octree::with_max_depth(max_depth)),
+        }
     }
 
     /// Iterate over voxels, returning their local index as a tuple
@@ -159,15 +237,28 @@ impl ChunkVoxels {
         })
     }
 
-    /// Iterate over the internal octants in no specific order
-    pub fn iter_octants(&self) -> impl Iterator<Item = Octant<Voxel>> + '_ {
-        self.voxels.iter().enumerate().flat_map(|(y_off, octree)| {
-            octree.iter_octants().map(move |octant| Octant {
-                position: octant.position + OctantPos::new(0, y_off * CHUNK_SIZE, 0),
-                ..*octant
+    /// Iterate over the light of each voxel, returning their local index as a
+    /// tuple
+    pub fn iter_light(&self) -> impl Iterator<Item = ((usize, usize, usize), u8)> + '_ {
+        self.light.iter().enumerate().flat_map(|(y_off, octree)| {
+            octree.iter().map(move |(pos, &light)| {
+                (
+                    (pos + OctantPos::new(0, y_off * CHUNK_SIZE, 0)).into(),
+                    light,
+                )
             })
         })
     }
+
+    /// Iterate over the internal octants in no specific order, along with the
+    /// offset of the octree they belong to, which their positions are relative
+    /// to
+    pub fn iter_octants(&self) -> impl Iterator<Item = (OctantPos, &Octant<Voxel>)> {
+        self.voxels.iter().enumerate().flat_map(|(y_off, octree)| {
+            let offset = OctantPos::new(0, y_off * CHUNK_SIZE, 0);
+            octree.iter_octants().map(move |octant| (offset, octant))
+        })
+    }
     /// Iterate over voxels, returning their [`LocalVoxelPosition`]
     pub fn iter_local_pos(&self) -> impl Iterator<Item = (LocalVoxelPosition, &Voxel)> {
         self.iter()
@@ -193,6 +284,110 @@ impl ChunkVoxels {
         self.voxels[idx].get_mut(pos)
     }
 
+    pub fn light(&self, position: LocalVoxelPosition) -> u8 {
+        let (idx, pos) = lvp_to_octree_idx(position);
+        *self.light[idx].get(pos)
+    }
+
+    pub fn set_light(&mut self, position: LocalVoxelPosition, light: u8) {
+        let (idx, pos) = lvp_to_octree_idx(position);
+        *self.light[idx].get_mut(pos) = light;
+    }
+
+    /// Set the light of every voxel to `light_at`. Octants lit evenly
+    /// throughout, such as open sky or solid ground, are set as a whole
+    /// rather than split
+    pub fn set_all_light(&mut self, light_at: impl Fn(LocalVoxelPosition) -> u8) {
+        // Side length of the octants of each octree
+        const OCTANT: usize = CHUNK_SIZE / 2;
+        let fill_uniform = |tree: &mut Octree<CHUNK_SIZE, u8>, values: &[(OctantPos, u8)]| {
+            let light = values[0].1;
+            if values.iter().any(|&(_, l)| l != light) {
+                return false;
+            }
+            // Values are of a whole octant, starting and ending at its corners
+            let (min, max) = (values[0].0, values[values.len() - 1].0);
+            tree.fill(min, max, light);
+            true
+        };
+        for (idx, tree) in self.light.iter_mut().enumerate() {
+            let mut values: Vec<_> = (0..CHUNK_SIZE.pow(3))
+                .map(|i| {
+                    let (x, y, z) = (
+                        i % CHUNK_SIZE,
+                        i / CHUNK_SIZE % CHUNK_SIZE,
+                        i / CHUNK_SIZE / CHUNK_SIZE,
+                    );
+                    let light = light_at(LocalVoxelPosition::new(
+                        x as _,
+                        (y + idx * CHUNK_SIZE) as _,
+                        z as _,
+                    ));
+                    (OctantPos::new(x, y, z), light)
+                })
+                .collect();
+            if fill_uniform(tree, &values) {
+                continue;
+            }
+            // Sorting by morton order groups the values of each octant together
+            values.sort_by_key(|(pos, _)| pos.morton());
+            for octant in values.chunks(OCTANT.pow(3)) {
+                if !fill_uniform(tree, octant) {
+                    tree.set_many(octant.iter().copied());
+                }
+            }
+        }
+    }
+
+    /// Set every voxel from `min` to `max` inclusive to `kind`, setting whole
+    /// octants at once where possible
+    pub fn fill_region(
+        &mut self,
+        min: LocalVoxelPosition,
+        max: LocalVoxelPosition,
+        kind: VoxelKind,
+    ) {
+        let (min_idx, min_pos) = lvp_to_octree_idx(min);
+        let (max_idx, max_pos) = lvp_to_octree_idx(max);
+        let (min_x, min_y, min_z) = min_pos.into();
+        let (max_x, max_y, max_z) = max_pos.into();
+        for idx in min_idx..=max_idx {
+            // Octrees between the first and last are filled to their full height
+            let bottom = if idx == min_idx { min_y } else { 0 };
+            let top = if idx == max_idx {
+                max_y
+            } else {
+                CHUNK_SIZE - 1
+            };
+            self.voxels[idx].fill(
+                OctantPos::new(min_x, bottom, min_z),
+                OctantPos::new(max_x, top, max_z),
+                Voxel { kind },
+            );
+        }
+    }
+
+    /// Set the kind of many voxels at once, in the order that traverses the
+    /// octrees the least
+    pub fn set_many(&mut self, voxels: &[(LocalVoxelPosition, VoxelKind)]) {
+        let mut sorted: Vec<_> = voxels
+            .iter()
+            .map(|&(pos, kind)| {
+                let (idx, pos) = lvp_to_octree_idx(pos);
+                (idx, pos, kind)
+            })
+            .collect();
+        // Stable, so the last of any duplicate positions still wins
+        sorted.sort_by_key(|&(idx, pos, _)| (idx, pos.morton()));
+        for tree_voxels in sorted.chunk_by(|a, b| a.0 == b.0) {
+            self.voxels[tree_voxels[0].0].set_many(
+                tree_voxels
+                    .iter()
+                    .map(|&(_, pos, kind)| (pos, Voxel { kind })),
+            );
+        }
+    }
+
     /// Try and merge the octants in each of the octrees in the chunk, returning
     /// `true` if any merges were possible.
     pub fn merge(&mut self) -> bool {
@@ -211,3 +406,148 @@ fn lvp_to_octree_idx(lvp: LocalVoxelPosition) -> (usize, OctantPos) {
         OctantPos::new_u32(lvp.x(), lvp.y() % CHUNK_SIZE as u32, lvp.z()),
     )
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::octree::OctantKind;
+
+    #[test]
+    pub fn fill_region_sets_whole_octants() {
+        let mut chunk = ChunkVoxels::default();
+        chunk.fill_region(
+            LocalVoxelPosition::new(8, 24, 0),
+            LocalVoxelPosition::new(15, 31, 7),
+            VoxelKind::Stone,
+        );
+        let stone_octants = chunk
+            .iter_octants()
+            .map(|(_, octant)| octant)
+            .filter(|octant| octant.leaves().any(|(_, _, v)| v.kind == VoxelKind::Stone))
+            .collect::<Vec<_>>();
+        assert_eq!(stone_octants.len(), 1);
+        assert!(matches!(
+            stone_octants[0].kind,
+            OctantKind::Chunk(Voxel {
+                kind: VoxelKind::Stone
+            })
+        ));
+        assert_eq!(stone_octants[0].size, 8);
+
+        // Regions spanning several octrees are filled in each of them
+        chunk.fill_region(
+            LocalVoxelPosition::new(1, 10, 2),
+            LocalVoxelPosition::new(3, 40, 2),
+            VoxelKind::Dirt,
+        );
+        for (pos, voxel) in chunk.iter_local_pos() {
+            let dirt = (1..=3).contains(&pos.x()) && (10..=40).contains(&pos.y()) && pos.z() == 2;
+            assert_eq!(voxel.kind == VoxelKind::Dirt, dirt, "at {pos:?}");
+        }
+    }
+
+    #[test]
+    pub fn taller_chunks() {
+        const SLICES: usize = CHUNK_SLICES * 2 + 1;
+        let height = SLICES * CHUNK_SIZE;
+        for max_depth in [2, 4] {
+            let mut chunk = ChunkVoxels::<SLICES>::empty(max_depth);
+            let top = LocalVoxelPosition::new(15, height as u32 - 1, 3);
+            chunk.voxel_mut(top).kind = VoxelKind::Glass;
+            chunk.set_light(top, 7);
+            assert_eq!(chunk.voxel(top).kind, VoxelKind::Glass);
+            assert_eq!(chunk.light(top), 7);
+            // Still on top once iterated over
+            let glass: Vec<_> = chunk
+                .iter_local_pos()
+                .filter(|(_, voxel)| voxel.kind == VoxelKind::Glass)
+                .map(|(pos, _)| pos.as_ivec3())
+                .collect();
+            assert_eq!(glass, [top.as_ivec3()]);
+            let (offset, octant) = chunk
+                .iter_octants()
+                .find(|(_, octant)| octant.leaves().any(|(_, _, v)| v.kind == VoxelKind::Glass))
+                .unwrap();
+            let (_, bottom, _) = (offset + octant.position).into();
+            assert_eq!(bottom + octant.size, height);
+
+            chunk.fill_region(
+                LocalVoxelPosition::new(0, MAX_HEIGHT as u32 - 8, 0),
+                LocalVoxelPosition::new(15, height as u32 - 2, 15),
+                VoxelKind::Stone,
+            );
+            assert_eq!(chunk.voxel(top).kind, VoxelKind::Glass);
+            let stone = chunk
+                .iter()
+                .filter(|(_, voxel)| voxel.kind == VoxelKind::Stone)
+                .count();
+            assert_eq!(stone, CHUNK_SIZE * CHUNK_SIZE * (height - MAX_HEIGHT + 7));
+        }
+    }
+
+    #[test]
+    pub fn set_many_matches_voxel_mut() {
+        let edits: Vec<_> = (0..500u32)
+            .map(|i| {
+                let pos = LocalVoxelPosition::new(i * 7 % 16, i * 13 % 256, i * 5 % 16);
+                let kind = [VoxelKind::Stone, VoxelKind::Water, VoxelKind::Air][i as usize % 3];
+                (pos, kind)
+            })
+            .collect();
+        let mut single = ChunkVoxels::default();
+        for &(pos, kind) in &edits {
+            single.voxel_mut(pos).kind = kind;
+        }
+        let mut many = ChunkVoxels::default();
+        many.set_many(&edits);
+        // Positions repeat after 256 edits, so some are set more than once
+        for (pos, voxel) in single.iter_local_pos() {
+            assert_eq!(many.voxel(pos).kind, voxel.kind, "at {pos:?}");
+        }
+    }
+
+    #[test]
+    pub fn chunks_in_range() {
+        let ring = |centre: ChunkPosition, range| {
+            let mut ring = Vec::new();
+            for z in -4..=4 {
+                for x in -4..=4 {
+                    let pos = ChunkPosition::new(
+                        centre.x() + x * CHUNK_SIZE_I,
+                        centre.z() + z * CHUNK_SIZE_I,
+                    );
+                    if pos.in_range_of(centre, range) {
+                        ring.push((x, z));
+                    }
+                    assert_eq!(
+                        pos.in_range_of(centre, range),
+                        pos.chunk_distance(centre) <= range
+                    );
+                }
+            }
+            ring
+        };
+        let square = |range: i32| {
+            (-range..=range)
+                .flat_map(|z| (-range..=range).map(move |x| (x, z)))
+                .collect::<Vec<_>>()
+        };
+
+        // The same square wherever it is centred, including at negative
+        // coordinates
+        for centre in [
+            ChunkPosition::new(0, 0),
+            ChunkPosition::new(-48, 32),
+            ChunkPosition::new(-160, -320),
+        ] {
+            assert_eq!(ring(centre, 0), vec![(0, 0)]);
+            assert_eq!(ring(centre, 2), square(2));
+        }
+
+        assert!(ChunkPosition::new(-32, 32).in_range_of_spawn(2));
+        assert!(!ChunkPosition::new(-48, 0).in_range_of_spawn(2));
+        assert!(!ChunkPosition::new(-16, -1600).in_range_of_spawn(2));
+        assert_eq!(ChunkPosition::new(-48, 16).spawn_distance(), 3);
+        assert_eq!(ChunkPosition::new(16, -160).spawn_distance(), 10);
+    }
+}
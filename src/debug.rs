@@ -1,8 +1,17 @@
 use bevy::{
+    color::palettes::css::{
+        BLUE,
+        LIME,
+        RED,
+    },
     diagnostic::{
+        Diagnostic,
+        DiagnosticPath,
+        Diagnostics,
         DiagnosticsStore,
         EntityCountDiagnosticsPlugin,
         FrameTimeDiagnosticsPlugin,
+        RegisterDiagnostic,
     },
     input::{
         keyboard::KeyboardInput,
@@ -12,6 +21,44 @@ use bevy::{
     prelude::*,
 };
 
+use std::{
+    fmt::Write,
+    path::Path,
+};
+
+use anyhow::anyhow;
+
+use crate::{
+    chunk::{
+        ChunkPosition,
+        ChunkVoxels,
+        CHUNK_SIZE,
+        MAX_HEIGHT,
+    },
+    highlight::SelectedVoxel,
+    input::EditCounter,
+    material::{
+        VoxelMaterial,
+        VoxelMaterialResource,
+    },
+    mesh::{
+        self,
+        MeshSettings,
+        MeshStats,
+    },
+    octree::OctantKind,
+    player::CameraFov,
+    voxel::{
+        LocalVoxelPosition,
+        VoxelKind,
+        VoxelPosition,
+    },
+    world::{
+        World,
+        WorldDiagnostics,
+    },
+};
+
 use bevy_egui::{
     egui,
     EguiContexts,
@@ -25,24 +72,114 @@ struct DebugUiState {
     player_info: bool,
 }
 
-fn display_perf_stats(mut egui: EguiContexts, diagnostics: Res<DiagnosticsStore>) {
+const LOADED_CHUNKS: DiagnosticPath = DiagnosticPath::const_new("world/loaded_chunks");
+const AWAITING_TERRAIN: DiagnosticPath = DiagnosticPath::const_new("world/awaiting_terrain");
+const AWAITING_MESH: DiagnosticPath = DiagnosticPath::const_new("world/awaiting_mesh");
+const MESHING_ASYNC: DiagnosticPath = DiagnosticPath::const_new("world/meshing_async");
+const MESHED_SYNC: DiagnosticPath = DiagnosticPath::const_new("world/meshed_sync");
+const MESHES_PER_SECOND: DiagnosticPath = DiagnosticPath::const_new("world/meshes_per_second");
+const OCTANTS: DiagnosticPath = DiagnosticPath::const_new("world/octants");
+const TRIANGLES_PER_MESH: DiagnosticPath = DiagnosticPath::const_new("mesh/triangles");
+const OCTANTS_VISITED_PER_MESH: DiagnosticPath = DiagnosticPath::const_new("mesh/octants_visited");
+const FACES_CULLED_PER_MESH: DiagnosticPath = DiagnosticPath::const_new("mesh/faces_culled");
+
+/// Record [`WorldDiagnostics`] as diagnostics, so they are averaged over
+/// recent frames
+fn measure_world(
+    mut diagnostics: Diagnostics,
+    world: Res<WorldDiagnostics>,
+    chunks: Query<&ChunkVoxels>,
+    time: Res<Time>,
+    ui_state: Res<DebugUiState>,
+    mut last_completed: Local<u64>,
+    mut last_mesh_stats: Local<MeshStats>,
+) {
+    let completed =
+        world.meshes_completed - std::mem::replace(&mut *last_completed, world.meshes_completed);
+    let last = std::mem::replace(&mut *last_mesh_stats, world.mesh_stats);
+    // Stats are only counted with `--mesh-stats`, and averaged over the
+    // meshes completed this frame
+    if world.mesh_stats != MeshStats::default() && completed > 0 {
+        let per_mesh = |total: u64, last: u64| (total - last) as f64 / completed as f64;
+        let stats = world.mesh_stats;
+        diagnostics.add_measurement(&TRIANGLES_PER_MESH, || {
+            per_mesh(stats.triangles, last.triangles)
+        });
+        diagnostics.add_measurement(&OCTANTS_VISITED_PER_MESH, || {
+            per_mesh(stats.octants_visited, last.octants_visited)
+        });
+        diagnostics.add_measurement(&FACES_CULLED_PER_MESH, || {
+            per_mesh(stats.faces_culled, last.faces_culled)
+        });
+    }
+    diagnostics.add_measurement(&LOADED_CHUNKS, || world.loaded_chunks as f64);
+    diagnostics.add_measurement(&AWAITING_TERRAIN, || world.awaiting_terrain as f64);
+    diagnostics.add_measurement(&AWAITING_MESH, || world.awaiting_mesh as f64);
+    diagnostics.add_measurement(&MESHING_ASYNC, || world.meshing_async as f64);
+    diagnostics.add_measurement(&MESHED_SYNC, || world.meshed_sync as f64);
+    if time.delta_seconds() > 0.0 {
+        diagnostics.add_measurement(&MESHES_PER_SECOND, || {
+            completed as f64 / time.delta_seconds_f64()
+        });
+    }
+    // Counting octants visits every chunk, so is only done while shown
+    if !ui_state.perf_stats {
+        return;
+    }
+    diagnostics.add_measurement(&OCTANTS, || {
+        chunks
+            .iter()
+            .map(|chunk| chunk.iter_octants().count())
+            .sum::<usize>() as f64
+    });
+}
+
+fn display_perf_stats(
+    mut egui: EguiContexts,
+    diagnostics: Res<DiagnosticsStore>,
+    mesh_settings: Res<MeshSettings>,
+) {
+    let average = |path: &DiagnosticPath| {
+        diagnostics
+            .get(path)
+            .and_then(Diagnostic::average)
+            .unwrap_or_default() as u32
+    };
     egui::Window::new("Perf Info").show(egui.ctx_mut(), |ui| {
         ui.label(format!(
             "Avg. FPS: {}",
-            diagnostics
-                .get(&FrameTimeDiagnosticsPlugin::FPS)
-                .unwrap()
-                .average()
-                .unwrap_or_default() as u32
+            average(&FrameTimeDiagnosticsPlugin::FPS)
         ));
         ui.label(format!(
             "Total Entity count: {}",
-            diagnostics
-                .get(&EntityCountDiagnosticsPlugin::ENTITY_COUNT)
-                .unwrap()
-                .average()
-                .unwrap_or_default() as u32
+            average(&EntityCountDiagnosticsPlugin::ENTITY_COUNT)
+        ));
+        ui.separator();
+        ui.label(format!("Loaded chunks: {}", average(&LOADED_CHUNKS)));
+        ui.label(format!("Awaiting terrain: {}", average(&AWAITING_TERRAIN)));
+        ui.label(format!("Awaiting meshing: {}", average(&AWAITING_MESH)));
+        ui.label(format!("Meshing async: {}", average(&MESHING_ASYNC)));
+        ui.label(format!("Meshed sync per frame: {}", average(&MESHED_SYNC)));
+        ui.label(format!(
+            "Meshes per second: {}",
+            average(&MESHES_PER_SECOND)
         ));
+        ui.label(format!("Octants: {}", average(&OCTANTS)));
+        if mesh_settings.stats {
+            ui.separator();
+            ui.label(format!(
+                "Triangles per mesh: {}",
+                average(&TRIANGLES_PER_MESH)
+            ));
+            ui.label(format!(
+                "Octants visited per mesh: {}",
+                average(&OCTANTS_VISITED_PER_MESH)
+            ));
+            ui.label(format!(
+                "Faces culled per mesh: {}",
+                average(&FACES_CULLED_PER_MESH)
+            ));
+        }
     });
 }
 
@@ -52,18 +189,21 @@ fn should_display_perf_stats(state: Res<DebugUiState>) -> bool {
 
 fn display_player_info(
     mut egui: EguiContexts,
-    mut player: Query<(&Transform, &mut Projection), With<Camera>>,
+    player: Query<&Transform, With<Camera>>,
+    mut fov: ResMut<CameraFov>,
+    counter: Res<EditCounter>,
 ) {
-    let (camera_trans, mut projection) = player.single_mut();
-    let Projection::Perspective(ref mut projection) = projection.as_mut() else {
-        unreachable!()
-    };
+    let camera_trans = player.single();
     let fov_low = f32::to_radians(20.0);
     let fov_high = f32::to_radians(140.0);
     egui::Window::new("Player Info").show(egui.ctx_mut(), |ui| {
         ui.label(format!("Position: {:.1}", camera_trans.translation));
         ui.label(format!("Facing: {:.1}", camera_trans.forward().as_vec3()));
-        let slider = egui::Slider::new(&mut projection.fov, fov_low..=fov_high)
+        ui.label(format!(
+            "Voxels placed: {}, broken: {}",
+            counter.placed, counter.broken
+        ));
+        let slider = egui::Slider::new(&mut fov.base, fov_low..=fov_high)
             .custom_formatter(|n, _| format!("{}", n.to_degrees().round()))
             .custom_parser(|n| n.parse().ok().map(f64::to_radians))
             .text("Field of View");
@@ -75,9 +215,184 @@ fn should_display_player_info(state: Res<DebugUiState>) -> bool {
     state.player_info
 }
 
+/// Length of the axes drawn at the world origin, if they are drawn
+#[derive(Resource)]
+pub struct AxisGizmo {
+    pub length: f32,
+}
+
+/// End of each axis drawn from the origin, coloured red for X, green for Y
+/// and blue for Z
+fn axis_endpoints(length: f32) -> [(Vec3, Srgba); 3] {
+    [(Vec3::X, RED), (Vec3::Y, LIME), (Vec3::Z, BLUE)].map(|(axis, color)| (axis * length, color))
+}
+
+fn draw_axis_gizmo(mut gizmos: Gizmos, axis: Res<AxisGizmo>) {
+    for (end, color) in axis_endpoints(axis.length) {
+        gizmos.line(Vec3::ZERO, end, color);
+    }
+}
+
+/// Character a voxel kind is drawn as in chunk dumps
+fn dump_char(kind: VoxelKind) -> char {
+    match kind {
+        VoxelKind::Air => '.',
+        VoxelKind::Stone => '#',
+        VoxelKind::Grass => 'G',
+        VoxelKind::Water => '~',
+        VoxelKind::Snow => '*',
+        VoxelKind::Dirt => 'D',
+        VoxelKind::Bedrock => 'B',
+        VoxelKind::Wood => 'W',
+        VoxelKind::Leaves => 'L',
+        VoxelKind::Tnt => 'T',
+        VoxelKind::Glass => '=',
+    }
+}
+
+/// Voxel kind drawn as `c` in chunk dumps
+fn kind_from_dump_char(c: char) -> anyhow::Result<VoxelKind> {
+    use VoxelKind::*;
+    [
+        Air, Stone, Grass, Water, Snow, Dirt, Bedrock, Wood, Leaves, Tnt, Glass,
+    ]
+    .into_iter()
+    .find(|&kind| dump_char(kind) == c)
+    .ok_or_else(|| anyhow!("invalid voxel `{c}` in chunk dump"))
+}
+
+/// Readable dump of a chunk's voxels, as a grid of X by Z for each layer
+/// that isn't only air, followed by the octants they are stored in
+fn dump_chunk(pos: ChunkPosition, chunk: &ChunkVoxels) -> String {
+    let mut layers = vec![[['.'; CHUNK_SIZE]; CHUNK_SIZE]; MAX_HEIGHT];
+    for (pos, voxel) in chunk.iter_local_pos() {
+        layers[pos.y() as usize][pos.z() as usize][pos.x() as usize] = dump_char(voxel.kind);
+    }
+
+    let mut dump = format!("Chunk at X {} Z {}\n", pos.x(), pos.z());
+    for (y, layer) in layers.iter().enumerate() {
+        if layer.iter().flatten().all(|&c| c == '.') {
+            continue;
+        }
+        writeln!(dump, "\nY {y}").unwrap();
+        for row in layer {
+            writeln!(dump, "{}", row.iter().collect::<String>()).unwrap();
+        }
+    }
+
+    dump.push_str("\nOctants\n");
+    for (offset, octant) in chunk.iter_octants() {
+        let position = IVec3::from(offset + octant.position);
+        let contents = match &octant.kind {
+            OctantKind::Chunk(voxel) => format!("{:?}", voxel.kind),
+            OctantKind::Node(_) => "split".to_string(),
+            OctantKind::Dense(voxels) => voxels.iter().map(|v| dump_char(v.kind)).collect(),
+        };
+        writeln!(dump, "{position} size {}: {contents}", octant.size).unwrap();
+    }
+    dump
+}
+
+/// Chunk written by [`dump_chunk`]. Its octants are rebuilt from its voxels
+/// rather than read from the dump
+fn load_chunk_dump(dump: &str) -> anyhow::Result<(ChunkPosition, ChunkVoxels)> {
+    let mut lines = dump.lines();
+    let header = lines.next().ok_or_else(|| anyhow!("empty chunk dump"))?;
+    let coords = header
+        .strip_prefix("Chunk at X ")
+        .and_then(|coords| coords.split_once(" Z "))
+        .ok_or_else(|| anyhow!("invalid chunk dump header `{header}`"))?;
+    let pos = ChunkPosition::new(coords.0.parse()?, coords.1.parse()?);
+
+    let mut voxels = Vec::new();
+    while let Some(line) = lines.next() {
+        if line == "Octants" {
+            break;
+        }
+        let Some(y) = line.strip_prefix("Y ") else {
+            continue;
+        };
+        let y: u32 = y.parse()?;
+        if y as usize >= MAX_HEIGHT {
+            return Err(anyhow!("layer {y} is above the world height"));
+        }
+        for z in 0..CHUNK_SIZE as u32 {
+            let row = lines
+                .next()
+                .ok_or_else(|| anyhow!("layer {y} is missing rows"))?;
+            if row.chars().count() != CHUNK_SIZE {
+                return Err(anyhow!(
+                    "row `{row}` of layer {y} isn't {CHUNK_SIZE} voxels"
+                ));
+            }
+            for (x, c) in (0..).zip(row.chars()) {
+                voxels.push((LocalVoxelPosition::new(x, y, z), kind_from_dump_char(c)?));
+            }
+        }
+    }
+    let mut chunk = ChunkVoxels::default();
+    chunk.set_many(&voxels);
+    Ok((pos, chunk))
+}
+
+/// Mesh a chunk dump on its own, without any neighbouring chunks, and print
+/// the size of its meshes
+pub fn mesh_chunk_dump(path: &Path, settings: MeshSettings) -> anyhow::Result<()> {
+    let (pos, chunk) = load_chunk_dump(&std::fs::read_to_string(path)?)?;
+    let meshes = mesh::from_chunk((pos, &chunk), &[], settings);
+    println!(
+        "Chunk at X {} Z {}: {} vertices",
+        pos.x(),
+        pos.z(),
+        meshes.opaque.count_vertices()
+    );
+    if let Some(water) = meshes.transparent {
+        println!("Water: {} vertices", water.count_vertices());
+    }
+    if let Some(stats) = meshes.stats {
+        println!(
+            "{} triangles, {} octants visited, {} faces culled",
+            stats.triangles, stats.octants_visited, stats.faces_culled
+        );
+    }
+    Ok(())
+}
+
+/// Write a dump of the chunk with the selected voxel, or the player if
+/// nothing is selected, to the working directory
+fn dump_targeted_chunk(
+    world: &World,
+    selected: &SelectedVoxel,
+    player: Vec3,
+    chunks: &Query<&ChunkVoxels>,
+) {
+    let target = selected
+        .to_break
+        .unwrap_or_else(|| VoxelPosition::new(player.floor().as_ivec3()));
+    let pos: ChunkPosition = target.into();
+    let Some(chunk) = world
+        .chunk_at(pos)
+        .and_then(|entity| chunks.get(entity).ok())
+    else {
+        warn!("No chunk loaded at {pos:?} to dump");
+        return;
+    };
+    let path = format!("chunk_{}_{}.txt", pos.x(), pos.z());
+    match std::fs::write(&path, dump_chunk(pos, chunk)) {
+        Ok(()) => info!("Dumped chunk to `{path}`"),
+        Err(err) => warn!("Failed to dump chunk to `{path}`: {err}"),
+    }
+}
+
 fn toggle_debug_ui_displays(
     mut inputs: EventReader<KeyboardInput>,
     mut ui_state: ResMut<DebugUiState>,
+    material_handle: Res<VoxelMaterialResource>,
+    mut materials: ResMut<Assets<VoxelMaterial>>,
+    world: Res<World>,
+    selected: Res<SelectedVoxel>,
+    player: Query<&Transform, With<Camera>>,
+    chunks: Query<&ChunkVoxels>,
     #[cfg(feature = "wireframe")] mut wireframe_cfg: ResMut<WireframeConfig>,
 ) {
     for input in inputs.read() {
@@ -92,6 +407,17 @@ fn toggle_debug_ui_displays(
             (KeyCode::F5, ButtonState::Pressed) => {
                 wireframe_cfg.global = !wireframe_cfg.global;
             }
+            // Show faces wound the wrong way round. Transparent faces are
+            // never culled
+            (KeyCode::F6, ButtonState::Pressed) => {
+                if let Some(material) = materials.get_mut(&material_handle.handle) {
+                    let cull = material.cull_backfaces();
+                    material.set_cull_backfaces(!cull);
+                }
+            }
+            (KeyCode::F10, ButtonState::Pressed) => {
+                dump_targeted_chunk(&world, &selected, player.single().translation, &chunks);
+            }
             _ => {}
         }
     }
@@ -111,12 +437,25 @@ impl Plugin for DebugUiPlugins {
         app.add_plugins(EguiPlugin)
             .add_plugins(FrameTimeDiagnosticsPlugin)
             .add_plugins(EntityCountDiagnosticsPlugin)
+            .register_diagnostic(Diagnostic::new(LOADED_CHUNKS))
+            .register_diagnostic(Diagnostic::new(AWAITING_TERRAIN))
+            .register_diagnostic(Diagnostic::new(AWAITING_MESH))
+            .register_diagnostic(Diagnostic::new(MESHING_ASYNC))
+            .register_diagnostic(Diagnostic::new(MESHED_SYNC))
+            .register_diagnostic(Diagnostic::new(MESHES_PER_SECOND))
+            .register_diagnostic(Diagnostic::new(OCTANTS))
+            .register_diagnostic(Diagnostic::new(TRIANGLES_PER_MESH))
+            .register_diagnostic(Diagnostic::new(OCTANTS_VISITED_PER_MESH))
+            .register_diagnostic(Diagnostic::new(FACES_CULLED_PER_MESH))
             .add_systems(Update, toggle_debug_ui_displays.in_set(DebugUiSet::Toggle))
             .add_systems(
                 Update,
-                display_perf_stats
-                    .in_set(DebugUiSet::Display)
-                    .run_if(should_display_perf_stats),
+                (
+                    measure_world,
+                    display_perf_stats.run_if(should_display_perf_stats),
+                )
+                    .chain()
+                    .in_set(DebugUiSet::Display),
             )
             .add_systems(
                 Update,
@@ -124,6 +463,7 @@ impl Plugin for DebugUiPlugins {
                     .in_set(DebugUiSet::Display)
                     .run_if(should_display_player_info),
             )
+            .add_systems(Update, draw_axis_gizmo.run_if(resource_exists::<AxisGizmo>))
             .configure_sets(
                 Update,
                 (DebugUiSet::Toggle, DebugUiSet::Display)
@@ -133,3 +473,81 @@ impl Plugin for DebugUiPlugins {
             .init_resource::<DebugUiState>();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn axis_endpoints_scale_with_length() {
+        let [x, y, z] = axis_endpoints(2.5);
+        assert_eq!(x.0, Vec3::new(2.5, 0.0, 0.0));
+        assert_eq!(y.0, Vec3::new(0.0, 2.5, 0.0));
+        assert_eq!(z.0, Vec3::new(0.0, 0.0, 2.5));
+        assert!(x.1 != y.1 && y.1 != z.1 && z.1 != x.1);
+    }
+
+    #[test]
+    pub fn chunk_dump_shows_voxels() {
+        let mut chunk = ChunkVoxels::default();
+        chunk.fill_region(
+            LocalVoxelPosition::new(0, 0, 0),
+            LocalVoxelPosition::new(15, 1, 15),
+            VoxelKind::Stone,
+        );
+        chunk.voxel_mut(LocalVoxelPosition::new(3, 2, 5)).kind = VoxelKind::Tnt;
+        chunk.voxel_mut(LocalVoxelPosition::new(15, 40, 0)).kind = VoxelKind::Water;
+        let dump = dump_chunk(ChunkPosition::new(16, -32), &chunk);
+        let mut lines = dump.lines();
+        assert_eq!(lines.next(), Some("Chunk at X 16 Z -32"));
+
+        // Each layer is headed by its height, with a row per Z
+        let layer = |y: usize| -> Vec<&str> {
+            dump.lines()
+                .skip_while(|&line| line != format!("Y {y}"))
+                .skip(1)
+                .take(CHUNK_SIZE)
+                .collect()
+        };
+        assert!(layer(0).iter().all(|&row| row == "#".repeat(CHUNK_SIZE)));
+        let tnt_layer = layer(2);
+        assert_eq!(tnt_layer[5], "...T............");
+        assert!(tnt_layer
+            .iter()
+            .enumerate()
+            .all(|(z, row)| z == 5 || !row.contains('T')));
+        assert_eq!(layer(40)[0], "...............~");
+        // Layers of only air are skipped
+        assert!(!dump.contains("Y 3\n"));
+
+        assert!(dump.contains("\nOctants\n"));
+        assert!(dump.contains("Stone"));
+    }
+
+    #[test]
+    pub fn chunk_dump_round_trips() {
+        let generator = crate::terrain::WorldGenerator::from_seed(7);
+        let pos = ChunkPosition::new(-48, 32);
+        let mut chunk = crate::terrain::generate_chunk(pos, &generator, 4);
+        chunk.voxel_mut(LocalVoxelPosition::new(4, 200, 9)).kind = VoxelKind::Tnt;
+
+        let (loaded_pos, loaded) = load_chunk_dump(&dump_chunk(pos, &chunk)).unwrap();
+        assert_eq!(loaded_pos, pos);
+        for (pos, voxel) in chunk.iter_local_pos() {
+            assert_eq!(loaded.voxel(pos).kind, voxel.kind, "at {pos:?}");
+        }
+        let settings = MeshSettings::default();
+        assert_eq!(
+            mesh::from_chunk((pos, &loaded), &[], settings)
+                .opaque
+                .count_vertices(),
+            mesh::from_chunk((pos, &chunk), &[], settings)
+                .opaque
+                .count_vertices()
+        );
+
+        assert!(load_chunk_dump("Chunk at X 0 Z 0\n\nY 3\n....").is_err());
+        let unknown_kind = format!("Chunk at X 0 Z 0\n\nY 0\n{}\n", "Q".repeat(CHUNK_SIZE));
+        assert!(load_chunk_dump(&unknown_kind).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use bevy::prelude::*;
+
+use crate::{
+    input::CameraVelocity,
+    player::{
+        self,
+        PlayerMovedEvent,
+    },
+    ui::ColorOverlay,
+    world::World,
+};
+
+/// Health the player starts with and is restored to on respawning
+pub const MAX_HEALTH: f32 = 20.0;
+/// Blocks the player can fall without taking damage
+const SAFE_FALL_DISTANCE: f32 = 4.0;
+/// Damage taken for each block fallen past [`SAFE_FALL_DISTANCE`]
+const FALL_DAMAGE_PER_BLOCK: f32 = 1.0;
+/// How long the screen flashes red after taking damage
+const DAMAGE_FLASH_SECONDS: f32 = 0.4;
+const DAMAGE_FLASH_COLOR: Color = Color::linear_rgba(0.8, 0.0, 0.0, 0.4);
+
+#[derive(Resource)]
+pub struct Health {
+    pub current: f32,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            current: MAX_HEALTH,
+        }
+    }
+}
+
+/// Emitted when the player takes damage
+#[derive(Event)]
+pub struct DamageEvent(pub f32);
+
+/// Damage taken from landing after falling `distance` blocks
+pub fn fall_damage(distance: f32) -> f32 {
+    (distance - SAFE_FALL_DISTANCE).max(0.0) * FALL_DAMAGE_PER_BLOCK
+}
+
+/// Distance the player has fallen since they last stood on the ground
+#[derive(Resource, Default)]
+pub struct FallTracker {
+    distance: f32,
+}
+
+impl FallTracker {
+    /// Track a frame of the player moving `moved_y` vertically, returning the
+    /// distance they fell if they just `landed`. Falls into water or while
+    /// flying are forgotten, so they never cause damage
+    pub fn track(&mut self, moved_y: f32, landed: bool, cushioned: bool) -> Option<f32> {
+        if cushioned {
+            self.distance = 0.0;
+            return None;
+        }
+        if moved_y < 0.0 {
+            self.distance -= moved_y;
+        } else if moved_y > 0.0 {
+            // Falls are measured from the top of a jump
+            self.distance = 0.0;
+        }
+        landed.then(|| std::mem::take(&mut self.distance))
+    }
+}
+
+/// Flash of red over the screen after taking damage
+#[derive(Resource)]
+pub struct DamageFlash {
+    timer: Timer,
+}
+
+impl Default for DamageFlash {
+    fn default() -> Self {
+        let mut timer = Timer::from_seconds(DAMAGE_FLASH_SECONDS, TimerMode::Once);
+        // Nothing to show until damage is first taken
+        timer.tick(timer.duration());
+        Self { timer }
+    }
+}
+
+/// Take damage from each [`DamageEvent`], respawning the player with full
+/// health once they run out
+pub fn apply_damage(
+    mut ev_damage: EventReader<DamageEvent>,
+    mut health: ResMut<Health>,
+    mut flash: ResMut<DamageFlash>,
+    mut fall: ResMut<FallTracker>,
+    mut camera: Query<&mut Transform, With<Camera>>,
+    mut velocity: ResMut<CameraVelocity>,
+    mut ev_move: EventWriter<PlayerMovedEvent>,
+    world: Res<World>,
+) {
+    let damage: f32 = ev_damage.read().map(|ev| ev.0).sum();
+    if damage <= 0.0 {
+        return;
+    }
+    health.current -= damage;
+    flash.timer.reset();
+    if health.current > 0.0 {
+        return;
+    }
+    info!("Died, respawning");
+    health.current = MAX_HEALTH;
+    *fall = FallTracker::default();
+    player::teleport(
+        &mut camera.single_mut(),
+        &mut velocity,
+        &mut ev_move,
+        player::spawn_position(&world.generator),
+    );
+}
+
+/// Fade out the red flash over the screen after taking damage. Runs after
+/// the water overlay is set, so the flash shows over it
+pub fn show_damage_flash(
+    time: Res<Time>,
+    mut flash: ResMut<DamageFlash>,
+    mut overlay: Query<&mut BackgroundColor, With<ColorOverlay>>,
+) {
+    if flash.timer.finished() {
+        return;
+    }
+    flash.timer.tick(time.delta());
+    let mut overlay = overlay.single_mut();
+    overlay.0 = if flash.timer.finished() {
+        Color::NONE
+    } else {
+        DAMAGE_FLASH_COLOR.with_alpha(DAMAGE_FLASH_COLOR.alpha() * flash.timer.fraction_remaining())
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+
+    #[test]
+    pub fn damage_scales_with_fall() {
+        let mut fall = FallTracker::default();
+        // Jumping up then landing back on the ground
+        assert_eq!(fall.track(1.0, false, false), None);
+        assert_eq!(fall.track(-1.0, true, false), Some(1.0));
+        assert_eq!(fall_damage(1.0), 0.0);
+
+        for _ in 0..10 {
+            assert_eq!(fall.track(-1.5, false, false), None);
+        }
+        let fallen = fall.track(-0.5, true, false).unwrap();
+        assert_eq!(fallen, 15.5);
+        assert_eq!(fall_damage(fallen), 11.5 * FALL_DAMAGE_PER_BLOCK);
+        assert!(fall_damage(20.0) > fall_damage(fallen));
+        // Nothing is carried over to the next fall
+        assert_eq!(fall.track(0.0, true, false), Some(0.0));
+
+        // Landing in water
+        for _ in 0..10 {
+            fall.track(-2.0, false, false);
+        }
+        assert_eq!(fall.track(-1.0, false, true), None);
+        assert_eq!(fall.track(-0.5, true, false), Some(0.5));
+    }
+
+    #[test]
+    pub fn respawn_on_running_out_of_health() {
+        let mut app = App::new();
+        let world = World::flat();
+        let spawn = player::spawn_position(&world.generator);
+        app.insert_resource(world)
+            .init_resource::<Health>()
+            .init_resource::<DamageFlash>()
+            .init_resource::<FallTracker>()
+            .insert_resource(CameraVelocity {
+                vel: Vec3::NEG_Y * 30.0,
+            })
+            .add_event::<DamageEvent>()
+            .add_event::<PlayerMovedEvent>();
+        let far_away = Vec3::new(500.0, 70.0, -300.0);
+        let camera = app
+            .world_mut()
+            .spawn((Camera::default(), Transform::from_translation(far_away)))
+            .id();
+
+        app.world_mut().send_event(DamageEvent(MAX_HEALTH / 2.0));
+        app.world_mut().run_system_once(apply_damage);
+        assert_eq!(app.world().resource::<Health>().current, MAX_HEALTH / 2.0);
+        assert!(!app.world().resource::<DamageFlash>().timer.finished());
+        assert_eq!(
+            app.world().get::<Transform>(camera).unwrap().translation,
+            far_away
+        );
+
+        app.world_mut().send_event(DamageEvent(MAX_HEALTH / 4.0));
+        app.world_mut().send_event(DamageEvent(MAX_HEALTH / 4.0));
+        app.world_mut().run_system_once(apply_damage);
+        assert_eq!(app.world().resource::<Health>().current, MAX_HEALTH);
+        assert_eq!(
+            app.world().get::<Transform>(camera).unwrap().translation,
+            spawn
+        );
+        assert_eq!(app.world().resource::<CameraVelocity>().vel, Vec3::ZERO);
+        let moved = app.world().resource::<Events<PlayerMovedEvent>>();
+        assert!(!moved.is_empty());
+    }
+}
@@ -1,11 +1,24 @@
-use bevy::prelude::*;
+use bevy::{
+    pbr::{
+        NotShadowCaster,
+        NotShadowReceiver,
+    },
+    prelude::*,
+    render::{
+        mesh::{
+            Indices,
+            PrimitiveTopology,
+        },
+        render_asset::RenderAssetUsages,
+    },
+};
 
 use crate::{
-    chunk::ChunkVoxels,
-    material::{
-        VoxelMaterial,
-        VoxelMaterialResource,
+    chunk::{
+        ChunkVoxels,
+        MAX_HEIGHT,
     },
+    input::BreakingState,
     voxel::VoxelPosition,
     world,
 };
@@ -20,52 +33,158 @@ pub struct SelectedVoxel {
 #[derive(Event)]
 pub struct UpdateHighlightedEvent;
 
+/// Searches for the selected voxel, which happen at most once a frame
+/// however many [`UpdateHighlightedEvent`]s are sent
+#[derive(Resource, Default)]
+pub struct HighlightUpdates {
+    /// Skip searching again when neither the camera nor any voxels have
+    /// changed since the last search
+    pub skip_unchanged: bool,
+    /// Camera the last search was made from
+    last_camera: Option<Transform>,
+    /// Number of searches made
+    pub searches: usize,
+}
+
+impl HighlightUpdates {
+    pub fn new(skip_unchanged: bool) -> Self {
+        Self {
+            skip_unchanged,
+            ..default()
+        }
+    }
+}
+
 const SELECT_DISTANCE: f32 = 16.0;
 
-fn draw_line(start: Vec3, direction: Vec3, distance: f32) -> impl Iterator<Item = IVec3> {
-    let end_pos = start + direction * distance;
+/// Outline drawn around the selected voxel, hidden when nothing is selected
+#[derive(Component)]
+pub struct SelectionOutline;
 
-    let mut voxel = start.floor();
-    let end_voxel = end_pos.floor();
+/// Size of the outline relative to a voxel. Slightly larger so its edges
+/// aren't hidden by the voxel's own faces
+const OUTLINE_SCALE: f32 = 1.005;
+const OUTLINE_COLOR: Color = Color::srgb(0.05, 0.05, 0.05);
+/// Colour the outline turns as the selected voxel is broken
+const OUTLINE_BREAKING_COLOR: Color = Color::srgb(0.9, 0.9, 0.9);
 
-    let step = direction.signum();
-    let t_delta = direction.recip().abs();
+/// The 12 edges of a unit cube centred on the origin
+fn outline_mesh() -> Mesh {
+    let corners: Vec<Vec3> = (0..8)
+        .map(|i| Vec3::new((i & 1) as f32, (i >> 1 & 1) as f32, (i >> 2 & 1) as f32) - 0.5)
+        .collect();
+    // Each edge joins two corners that differ along a single axis
+    let mut indices = Vec::new();
+    for corner in 0..8u16 {
+        for axis in [1, 2, 4] {
+            if corner & axis == 0 {
+                indices.extend([corner, corner | axis]);
+            }
+        }
+    }
+    Mesh::new(PrimitiveTopology::LineList, RenderAssetUsages::default())
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, corners)
+        .with_inserted_indices(Indices::U16(indices))
+}
 
-    let mask = Vec3::from(step.cmpge(Vec3::ZERO));
-    let mut t_max = ((voxel - start + mask) / direction).abs();
+pub fn spawn_selection_outline(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    commands.spawn((
+        Name::new("Selection outline"),
+        SelectionOutline,
+        PbrBundle {
+            mesh: meshes.add(outline_mesh()),
+            material: materials.add(StandardMaterial {
+                base_color: OUTLINE_COLOR,
+                unlit: true,
+                ..default()
+            }),
+            transform: Transform::from_scale(Vec3::splat(OUTLINE_SCALE)),
+            visibility: Visibility::Hidden,
+            ..default()
+        },
+        NotShadowCaster,
+        NotShadowReceiver,
+    ));
+}
+
+/// Voxels passed through by the line from `start` along `direction`, in
+/// order, starting with the voxel containing `start` and ending at the last
+/// voxel the line enters within `distance` lengths of `direction`
+fn draw_line(start: Vec3, direction: Vec3, distance: f32) -> impl Iterator<Item = IVec3> {
+    let mut voxel = start.floor().as_ivec3();
+    // Axes the line doesn't move along are never stepped along
+    let step = IVec3::from_array(direction.to_array().map(|d| match d {
+        d if d > 0.0 => 1,
+        d if d < 0.0 => -1,
+        _ => 0,
+    }));
+    // Distance along the line between crossing voxel boundaries on each axis
+    let t_delta = direction.recip().abs();
+    // Distance along the line at which the next boundary is crossed on each axis
+    let mut t_max = Vec3::from_array(std::array::from_fn(|axis| match step[axis] {
+        1 => (voxel[axis] as f32 + 1.0 - start[axis]) * t_delta[axis],
+        -1 => (start[axis] - voxel[axis] as f32) * t_delta[axis],
+        _ => f32::INFINITY,
+    }));
 
+    let mut next = Some(voxel);
     std::iter::from_fn(move || {
-        if Vec3::abs_diff_eq(voxel, end_voxel, f32::EPSILON) {
-            return None;
-        };
-        if t_max.x < t_max.y {
-            if t_max.x < t_max.z {
-                voxel.x += step.x;
-                t_max.x += t_delta.x;
-            } else {
-                voxel.z += step.z;
-                t_max.z += t_delta.z;
-            }
-        } else if t_max.y < t_max.z {
-            voxel.y += step.y;
-            t_max.y += t_delta.y;
+        let current = next?;
+        let axis = if t_max.x <= t_max.y && t_max.x <= t_max.z {
+            0
+        } else if t_max.y <= t_max.z {
+            1
         } else {
-            voxel.z += step.z;
-            t_max.z += t_delta.z;
-        }
-        Some(voxel.as_ivec3())
+            2
+        };
+        next = (t_max[axis] <= distance).then(|| {
+            voxel[axis] += step[axis];
+            t_max[axis] += t_delta[axis];
+            voxel
+        });
+        Some(current)
     })
 }
 
+/// Lighten the outline of the selected voxel as it is being broken
+pub fn show_break_progress(
+    breaking: Res<BreakingState>,
+    outline: Query<&Handle<StandardMaterial>, With<SelectionOutline>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for handle in &outline {
+        let mat = materials.get_mut(handle).unwrap();
+        mat.base_color = OUTLINE_COLOR.mix(&OUTLINE_BREAKING_COLOR, breaking.progress);
+    }
+}
+
 pub fn update_selected_voxel(
     world: Res<world::World>,
     mut selected: ResMut<SelectedVoxel>,
+    mut updates: ResMut<HighlightUpdates>,
+    mut ev_update: EventReader<UpdateHighlightedEvent>,
     player: Query<&Transform, With<Camera>>,
     chunks: Query<&ChunkVoxels>,
-    material_handle: Res<VoxelMaterialResource>,
-    mut materials: ResMut<Assets<VoxelMaterial>>,
+    edited: Query<(), Changed<ChunkVoxels>>,
+    mut outline: Query<
+        (&mut Transform, &mut Visibility),
+        (With<SelectionOutline>, Without<Camera>),
+    >,
 ) {
+    // Every event sent this frame is handled by the one search
+    ev_update.clear();
     let player_trans = player.get_single().expect("expected player object");
+    let unchanged = updates.last_camera == Some(*player_trans) && edited.is_empty();
+    if updates.skip_unchanged && unchanged {
+        return;
+    }
+    updates.last_camera = Some(*player_trans);
+    updates.searches += 1;
+
     let pos = player_trans.translation;
     let direction = player_trans.forward().as_vec3().normalize();
 
@@ -75,10 +194,14 @@ pub fn update_selected_voxel(
         match world.voxel_at(voxel_pos, &chunks) {
             Some(voxel) if voxel.should_mesh() => {
                 selected.to_break = Some(voxel_pos);
-                let mat = materials.get_mut(&material_handle.handle).unwrap();
-                mat.has_selected = 1;
-                mat.selected_voxel = voxel_pos.as_vec3();
-                selected.to_place = prev.map(VoxelPosition::new);
+                for (mut transform, mut visibility) in &mut outline {
+                    transform.translation = voxel_pos.as_vec3() + 0.5;
+                    *visibility = Visibility::Inherited;
+                }
+                // There's nowhere to place a voxel outside the world
+                selected.to_place = prev
+                    .filter(|prev: &IVec3| (0..MAX_HEIGHT as i32).contains(&prev.y))
+                    .map(VoxelPosition::new);
                 return;
             }
             _ => (),
@@ -86,9 +209,232 @@ pub fn update_selected_voxel(
         prev = Some(pos);
     }
 
-    if selected.to_break.is_some() {
-        let mat = materials.get_mut(&material_handle.handle).unwrap();
-        mat.has_selected = 0;
+    for (_, mut visibility) in &mut outline {
+        *visibility = Visibility::Hidden;
     }
     selected.to_break = None;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::voxel::LocalVoxelPosition;
+    use bevy::{
+        ecs::system::RunSystemOnce,
+        math::ivec3,
+        render::mesh::VertexAttributeValues,
+    };
+
+    #[test]
+    pub fn outline_mesh_covers_cube_edges() {
+        let mesh = outline_mesh();
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+        else {
+            panic!("outline mesh has no positions");
+        };
+        let indices: Vec<_> = mesh.indices().unwrap().iter().collect();
+        assert_eq!(indices.len(), 12 * 2);
+        let mut edges = Vec::new();
+        for edge in indices.chunks(2) {
+            let (a, b) = (
+                Vec3::from(positions[edge[0]]),
+                Vec3::from(positions[edge[1]]),
+            );
+            assert_eq!((b - a).length(), 1.0);
+            edges.push((a + b) / 2.0);
+        }
+        edges.sort_by(|a, b| a.to_array().partial_cmp(&b.to_array()).unwrap());
+        edges.dedup();
+        assert_eq!(edges.len(), 12);
+    }
+
+    #[test]
+    pub fn outline_follows_selected_voxel() {
+        use crate::{
+            chunk::{
+                ChunkPosition,
+                CHUNK_SIZE,
+            },
+            terrain::generate_chunk,
+        };
+
+        let mut world = world::World::flat();
+        let mut app = App::new();
+        let chunk_pos = ChunkPosition::new(0, 0);
+        let chunk = generate_chunk(chunk_pos, &world.generator, CHUNK_SIZE.ilog2());
+        let chunk_ent = app.world_mut().spawn(chunk).id();
+        world.add_chunk(chunk_pos, chunk_ent);
+        app.insert_resource(world)
+            .init_resource::<SelectedVoxel>()
+            .init_resource::<HighlightUpdates>()
+            .add_event::<UpdateHighlightedEvent>();
+        let camera = app
+            .world_mut()
+            .spawn(Camera3dBundle {
+                transform: Transform::from_xyz(4.5, 70.5, 4.5).looking_to(Vec3::NEG_Y, Vec3::X),
+                ..default()
+            })
+            .id();
+        let outline = app
+            .world_mut()
+            .spawn((SelectionOutline, SpatialBundle::HIDDEN_IDENTITY))
+            .id();
+
+        app.world_mut().run_system_once(update_selected_voxel);
+        // The grass at the top of the flat world
+        let selected = app.world().resource::<SelectedVoxel>().to_break.unwrap();
+        assert_eq!(selected.as_ivec3(), ivec3(4, 64, 4));
+        assert_eq!(
+            app.world().get::<Transform>(outline).unwrap().translation,
+            selected.as_vec3() + 0.5
+        );
+        assert_eq!(
+            app.world().get::<Visibility>(outline),
+            Some(&Visibility::Inherited)
+        );
+
+        // Looking up at the sky
+        *app.world_mut().get_mut::<Transform>(camera).unwrap() =
+            Transform::from_xyz(4.5, 70.5, 4.5).looking_to(Vec3::Y, Vec3::X);
+        app.world_mut().run_system_once(update_selected_voxel);
+        assert!(app.world().resource::<SelectedVoxel>().to_break.is_none());
+        assert_eq!(
+            app.world().get::<Visibility>(outline),
+            Some(&Visibility::Hidden)
+        );
+    }
+
+    #[test]
+    pub fn highlight_searched_once_per_frame() {
+        use crate::{
+            chunk::{
+                ChunkPosition,
+                CHUNK_SIZE,
+            },
+            terrain::generate_chunk,
+        };
+
+        let mut world = world::World::flat();
+        let mut app = App::new();
+        let chunk_pos = ChunkPosition::new(0, 0);
+        let chunk = generate_chunk(chunk_pos, &world.generator, CHUNK_SIZE.ilog2());
+        let chunk_ent = app.world_mut().spawn(chunk).id();
+        world.add_chunk(chunk_pos, chunk_ent);
+        app.insert_resource(world)
+            .init_resource::<SelectedVoxel>()
+            .insert_resource(HighlightUpdates::new(true))
+            .add_event::<UpdateHighlightedEvent>()
+            .add_systems(
+                Update,
+                update_selected_voxel.run_if(on_event::<UpdateHighlightedEvent>()),
+            );
+        let camera = app
+            .world_mut()
+            .spawn((
+                Camera::default(),
+                Transform::from_xyz(4.5, 70.5, 4.5).looking_to(Vec3::NEG_Y, Vec3::X),
+            ))
+            .id();
+        let update = |app: &mut App, events| {
+            for _ in 0..events {
+                app.world_mut().send_event(UpdateHighlightedEvent);
+            }
+            app.update();
+            app.world().resource::<HighlightUpdates>().searches
+        };
+
+        // Looking and moving in the same frame
+        assert_eq!(update(&mut app, 3), 1);
+        assert_eq!(update(&mut app, 0), 1);
+        // The camera hasn't moved, nor have any voxels changed
+        assert_eq!(update(&mut app, 2), 1);
+
+        app.world_mut()
+            .get_mut::<Transform>(camera)
+            .unwrap()
+            .translation
+            .x += 1.0;
+        assert_eq!(update(&mut app, 2), 2);
+
+        // Breaking the selected voxel doesn't move the camera
+        let selected = app.world().resource::<SelectedVoxel>().to_break.unwrap();
+        let local = LocalVoxelPosition::try_from(selected).unwrap();
+        app.world_mut()
+            .get_mut::<ChunkVoxels>(chunk_ent)
+            .unwrap()
+            .voxel_mut(local)
+            .clear();
+        assert_eq!(update(&mut app, 1), 3);
+        assert_eq!(
+            app.world()
+                .resource::<SelectedVoxel>()
+                .to_break
+                .unwrap()
+                .as_ivec3(),
+            selected.as_ivec3() - IVec3::Y
+        );
+    }
+
+    #[test]
+    pub fn line_straight_down() {
+        let line: Vec<_> = draw_line(Vec3::new(0.5, 10.5, 0.5), Vec3::NEG_Y, 3.0).collect();
+        assert_eq!(
+            line,
+            [
+                ivec3(0, 10, 0),
+                ivec3(0, 9, 0),
+                ivec3(0, 8, 0),
+                ivec3(0, 7, 0)
+            ]
+        );
+
+        // Standing exactly on the corner of a voxel
+        let line: Vec<_> = draw_line(Vec3::new(2.0, 10.0, -3.0), Vec3::NEG_Y, 2.5).collect();
+        assert_eq!(
+            line,
+            [
+                ivec3(2, 10, -3),
+                ivec3(2, 9, -3),
+                ivec3(2, 8, -3),
+                ivec3(2, 7, -3)
+            ]
+        );
+    }
+
+    #[test]
+    pub fn line_along_x() {
+        let line: Vec<_> = draw_line(Vec3::new(0.25, 0.5, 0.5), Vec3::X, 2.0).collect();
+        assert_eq!(line, [ivec3(0, 0, 0), ivec3(1, 0, 0), ivec3(2, 0, 0)]);
+
+        let line: Vec<_> = draw_line(Vec3::new(-1.0, 4.0, 7.0), Vec3::NEG_X, 1.5).collect();
+        assert_eq!(line, [ivec3(-1, 4, 7), ivec3(-2, 4, 7), ivec3(-3, 4, 7)]);
+    }
+
+    #[test]
+    pub fn line_diagonal() {
+        let line: Vec<_> =
+            draw_line(Vec3::new(0.5, 0.2, 0.5), Vec3::new(1.0, 0.5, 0.0), 2.0).collect();
+        assert_eq!(
+            line,
+            [
+                ivec3(0, 0, 0),
+                ivec3(1, 0, 0),
+                ivec3(2, 0, 0),
+                ivec3(2, 1, 0)
+            ]
+        );
+
+        let line: Vec<_> =
+            draw_line(Vec3::new(0.5, 0.5, 0.5), Vec3::new(0.0, -0.5, -1.0), 2.0).collect();
+        assert_eq!(
+            line,
+            [
+                ivec3(0, 0, 0),
+                ivec3(0, 0, -1),
+                ivec3(0, -1, -1),
+                ivec3(0, -1, -2)
+            ]
+        );
+    }
+}
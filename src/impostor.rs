@@ -0,0 +1,279 @@
+use bevy::{
+    math::{
+        ivec2,
+        vec3,
+    },
+    prelude::*,
+    render::mesh::PlaneMeshBuilder,
+    tasks::{
+        block_on,
+        futures_lite::future,
+        AsyncComputeTaskPool,
+        Task,
+    },
+    utils::HashMap,
+};
+
+use crate::{
+    chunk::{
+        ChunkPosition,
+        ChunkVoxels,
+        CHUNK_SIZE,
+        MAX_HEIGHT,
+    },
+    material::{
+        fog_params,
+        kind_color,
+    },
+    mesh::HasMesh,
+    player::PlayerMovedEvent,
+    terrain::spiral,
+    voxel::{
+        LocalVoxelPosition,
+        VoxelKind,
+    },
+    world::World,
+};
+
+/// Flat coloured quads standing in for chunks beyond the load distance, which
+/// fill the horizon without being meshed
+#[derive(Resource)]
+pub struct Impostors {
+    /// Number of chunks across the area impostors are shown in, centred on
+    /// the player
+    distance: usize,
+    /// Impostor of each distant chunk, including those still generating
+    chunks: HashMap<ChunkPosition, Entity>,
+    /// Shared by every impostor, and tinted by the colour of the sunlight
+    pub(crate) material: Handle<StandardMaterial>,
+}
+
+/// Average colour and height of the top of a chunk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkSurface {
+    pub color: Srgba,
+    /// Average height of the top of each column, or `0.0` for chunks of only
+    /// air
+    pub height: f32,
+}
+
+/// Generates a distant chunk to find its surface
+#[derive(Component)]
+pub struct ImpostorTask(Task<ChunkSurface>);
+
+/// Average the colour and height of the topmost voxel of each column of
+/// `chunk`
+pub fn chunk_surface(chunk: &ChunkVoxels) -> ChunkSurface {
+    let mut color = Vec3::ZERO;
+    let mut height = 0.0;
+    let mut columns = 0;
+    for x in 0..CHUNK_SIZE as u32 {
+        for z in 0..CHUNK_SIZE as u32 {
+            let top = (0..MAX_HEIGHT as u32)
+                .rev()
+                .map(|y| (y, chunk.voxel(LocalVoxelPosition::new(x, y, z)).kind()))
+                .find(|&(_, kind)| kind != VoxelKind::Air);
+            if let Some((y, kind)) = top {
+                let kind_color = kind_color(kind);
+                color += vec3(kind_color.red, kind_color.green, kind_color.blue);
+                height += (y + 1) as f32;
+                columns += 1;
+            }
+        }
+    }
+    if columns == 0 {
+        return ChunkSurface {
+            color: Srgba::NONE,
+            height: 0.0,
+        };
+    }
+    let color = color / columns as f32;
+    ChunkSurface {
+        color: Srgba::rgb(color.x, color.y, color.z),
+        height: height / columns as f32,
+    }
+}
+
+/// Create the impostor material and fog the impostors out towards the edge
+/// of their distance, like the voxel terrain
+pub fn setup_impostors(
+    mut commands: Commands,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    camera: Query<Entity, With<Camera>>,
+    settings: Res<crate::Settings>,
+) {
+    let material = materials.add(StandardMaterial {
+        unlit: true,
+        ..default()
+    });
+    commands.insert_resource(Impostors {
+        distance: settings.impostor_distance,
+        chunks: HashMap::new(),
+        material,
+    });
+    commands.entity(camera.single()).insert(FogSettings {
+        falloff: fog_falloff(fog_params(settings.fog_distance())),
+        ..default()
+    });
+}
+
+/// Fog of impostors matching the fog in the air of the voxel terrain, from
+/// its [`fog_params`]
+pub fn fog_falloff(fog: Vec4) -> FogFalloff {
+    FogFalloff::Linear {
+        start: fog.x,
+        end: fog.y,
+    }
+}
+
+/// Start generating impostors for chunks newly within the impostor distance,
+/// and remove those now too far away
+pub fn queue_impostors(
+    mut commands: Commands,
+    mut impostors: ResMut<Impostors>,
+    mut ev_movement: EventReader<PlayerMovedEvent>,
+    mut ev_settings: EventReader<crate::SettingsChangedEvent>,
+    world: Res<World>,
+    settings: Res<crate::Settings>,
+    player: Query<&Transform, With<Camera>>,
+) {
+    // A changed load distance moves the inner edge of the impostors
+    let settings_changed = ev_settings.read().count() > 0;
+    if !settings_changed
+        && !ev_movement.is_empty()
+        && !ev_movement.read().any(|mvmnt| mvmnt.changed_chunk())
+    {
+        return;
+    }
+    let pos: ChunkPosition = player.single().translation.as_ivec3().into();
+    let load_radius = (settings.load_distance as isize) / 2;
+    let radius = (impostors.distance as isize) / 2;
+    let task_pool = AsyncComputeTaskPool::get();
+
+    let mut to_despawn = impostors.chunks.clone();
+    for (chunk_x, chunk_z) in spiral(radius, radius) {
+        let chunk_pos = &pos
+            + ivec2(
+                (chunk_x * CHUNK_SIZE as isize) as i32,
+                (chunk_z * CHUNK_SIZE as isize) as i32,
+            );
+        if !world.border.contains_chunk(chunk_pos) {
+            continue;
+        }
+        if chunk_x.abs() <= load_radius && chunk_z.abs() <= load_radius {
+            // Impostors of chunks now being loaded are kept until the chunk
+            // has a mesh to replace them
+            to_despawn.remove(&chunk_pos);
+            continue;
+        }
+        if to_despawn.remove(&chunk_pos).is_some() {
+            continue;
+        }
+        let generator = world.generator.clone();
+        let max_depth = settings.octree_max_depth;
+        let task = async move {
+            chunk_surface(&crate::terrain::generate_chunk(
+                chunk_pos, &generator, max_depth,
+            ))
+        };
+        let ent = commands
+            .spawn((
+                Name::new("Impostor"),
+                ImpostorTask(task_pool.spawn(task)),
+                SpatialBundle::from_transform(Transform::from_translation(chunk_pos.as_vec3())),
+            ))
+            .id();
+        impostors.chunks.insert(chunk_pos, ent);
+    }
+
+    for (pos, ent) in to_despawn {
+        impostors.chunks.remove(&pos);
+        commands.entity(ent).despawn();
+    }
+}
+
+pub fn handle_impostor_tasks(
+    mut commands: Commands,
+    mut tasks: Query<(Entity, &mut ImpostorTask, &mut Transform)>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    impostors: Res<Impostors>,
+) {
+    for (ent, mut task, mut transform) in &mut tasks {
+        let Some(surface) = block_on(future::poll_once(&mut task.0)) else {
+            continue;
+        };
+        let mut impostor = commands.entity(ent);
+        impostor.remove::<ImpostorTask>();
+        if surface.height == 0.0 {
+            continue;
+        }
+        let mesh = PlaneMeshBuilder::from_length(CHUNK_SIZE as f32).build();
+        let colors = vec![LinearRgba::from(surface.color).to_f32_array(); mesh.count_vertices()];
+        impostor.insert((
+            meshes.add(mesh.with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, colors)),
+            impostors.material.clone(),
+        ));
+        // The quad is centred on the middle of the chunk
+        let half = CHUNK_SIZE as f32 / 2.0;
+        transform.translation += vec3(half, surface.height, half);
+    }
+}
+
+/// Remove the impostors of chunks that have been loaded and meshed
+pub fn remove_loaded_impostors(
+    mut commands: Commands,
+    mut impostors: ResMut<Impostors>,
+    meshed: Query<&ChunkPosition, Added<HasMesh>>,
+) {
+    for pos in &meshed {
+        if let Some(ent) = impostors.chunks.remove(pos) {
+            commands.entity(ent).despawn();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn surface_color_averages_top_voxels() {
+        let mut chunk = ChunkVoxels::with_max_depth(4);
+        let max = CHUNK_SIZE as u32 - 1;
+        chunk.fill_region(
+            LocalVoxelPosition::new(0, 0, 0),
+            LocalVoxelPosition::new(max, 63, max),
+            VoxelKind::Stone,
+        );
+        // Grass on one half, covering the stone, and snow higher up on the
+        // other
+        chunk.fill_region(
+            LocalVoxelPosition::new(0, 64, 0),
+            LocalVoxelPosition::new(max / 2, 64, max),
+            VoxelKind::Grass,
+        );
+        chunk.fill_region(
+            LocalVoxelPosition::new(max / 2 + 1, 64, 0),
+            LocalVoxelPosition::new(max, 72, max),
+            VoxelKind::Snow,
+        );
+        // Air pockets under the surface aren't seen
+        chunk.voxel_mut(LocalVoxelPosition::new(12, 70, 3)).kind = VoxelKind::Air;
+
+        let surface = chunk_surface(&chunk);
+        let grass = kind_color(VoxelKind::Grass);
+        let expected = (vec3(grass.red, grass.green, grass.blue) + Vec3::ONE) / 2.0;
+        let color = vec3(surface.color.red, surface.color.green, surface.color.blue);
+        assert!(color.abs_diff_eq(expected, 1e-5), "{color} != {expected}");
+        assert_eq!(surface.color.alpha, 1.0);
+        assert_eq!(surface.height, (65.0 + 73.0) / 2.0);
+
+        assert_eq!(
+            chunk_surface(&ChunkVoxels::with_max_depth(4)),
+            ChunkSurface {
+                color: Srgba::NONE,
+                height: 0.0,
+            }
+        );
+    }
+}
@@ -1,13 +1,25 @@
 use crate::{
+    audio::{
+        Sound,
+        SoundEvent,
+        SoundMaterial,
+    },
     chunk::ChunkVoxels,
     highlight::{
         SelectedVoxel,
         UpdateHighlightedEvent,
     },
-    mesh::HasMesh,
+    player,
     ui,
-    voxel::VoxelKind,
-    world,
+    voxel::{
+        LocalVoxelPosition,
+        VoxelKind,
+        VoxelPosition,
+    },
+    world::{
+        self,
+        ChunkEditedEvent,
+    },
 };
 use bevy::{
     prelude::*,
@@ -17,11 +29,13 @@ use bevy::{
 use bevy::{
     input::mouse::{
         MouseMotion,
+        MouseScrollUnit,
         MouseWheel,
     },
     window::{
         CursorGrabMode,
         PrimaryWindow,
+        WindowFocused,
     },
 };
 
@@ -34,8 +48,37 @@ pub struct InputState {
     pub space_pressed: bool,
     pub space_held: bool,
     pub shift_held: bool,
+    pub sprinting: bool,
+    /// Moving slowly without walking off edges, with shift while not flying
+    pub sneaking: bool,
     pub fly_hack: bool,
     pub selected_voxel: u8,
+    /// Notches scrolled but not yet turned into a change of toolbar slot
+    pub scroll: f32,
+}
+
+/// Emitted when a different toolbar slot is selected
+#[derive(Event)]
+pub struct SlotSelectedEvent {
+    pub slot: u8,
+}
+
+/// Pixels of scrolling, as reported by touchpads, treated as one notch of a
+/// mouse wheel
+const PIXELS_PER_SCROLL_NOTCH: f32 = 20.0;
+
+/// Slot of a toolbar with `slots` slots reached by scrolling from `slot`,
+/// wrapping around in both directions. `scroll` is the number of notches
+/// scrolled, where scrolling up moves towards the first slot. Each whole
+/// notch moves one slot, and any fraction is left in `scroll` to add to
+/// later scrolling
+pub fn scroll_slot(slot: u8, scroll: &mut f32, slots: usize) -> u8 {
+    let notches = scroll.trunc();
+    *scroll -= notches;
+    if slots == 0 {
+        return slot;
+    }
+    (slot as i64 - notches as i64).rem_euclid(slots as i64) as u8
 }
 
 pub fn hook_cursor(mut qwindow: Query<&mut Window, With<PrimaryWindow>>) {
@@ -44,29 +87,106 @@ pub fn hook_cursor(mut qwindow: Query<&mut Window, With<PrimaryWindow>>) {
     window.cursor.visible = false;
 }
 
+/// Radians of rotation per unit of mouse motion
+const LOOK_SENSITIVITY: f32 = 0.004;
+/// Interval at which accumulated mouse motion is applied when
+/// [`crate::Settings::fixed_rate_look`] is enabled
+const LOOK_TICK_SECONDS: f32 = 1.0 / 120.0;
+
+/// Mouse motion accumulated between fixed-rate look updates
+#[derive(Resource, Default)]
+pub struct LookAccumulator {
+    delta: Vec2,
+    elapsed: f32,
+}
+
+impl LookAccumulator {
+    pub fn add(&mut self, delta: Vec2) {
+        self.delta += delta;
+    }
+
+    /// Advance the accumulator by `dt` seconds, returning all motion
+    /// accumulated so far if a look tick has elapsed
+    pub fn tick(&mut self, dt: f32) -> Option<Vec2> {
+        self.elapsed += dt;
+        if self.elapsed < LOOK_TICK_SECONDS {
+            return None;
+        }
+        self.elapsed %= LOOK_TICK_SECONDS;
+        Some(std::mem::take(&mut self.delta))
+    }
+}
+
+/// Maximum pitch of the camera in either direction, just short of straight
+/// up/down
+pub const MAX_PITCH: f32 = 1.54;
+
+/// Rotate a camera by a mouse motion delta, clamping the pitch so the camera
+/// can't flip over
+fn apply_look(rotation: Quat, delta: Vec2) -> Quat {
+    let (mut yaw, mut pitch, _roll) = rotation.to_euler(EulerRot::YXZ);
+    yaw -= delta.x * LOOK_SENSITIVITY;
+    pitch -= delta.y * LOOK_SENSITIVITY;
+    pitch = pitch.clamp(-MAX_PITCH, MAX_PITCH);
+    Quat::from_axis_angle(Vec3::Y, yaw) * Quat::from_axis_angle(Vec3::X, pitch)
+}
+
 pub fn player_look(
     qwindow: Query<&Window, With<PrimaryWindow>>,
     mut mouse: EventReader<MouseMotion>,
     mut camera_transform: Query<&mut Transform, With<Camera>>,
     mut ev_update: EventWriter<UpdateHighlightedEvent>,
+    mut accumulator: ResMut<LookAccumulator>,
+    settings: Res<crate::Settings>,
+    time: Res<Time>,
 ) {
     let window = qwindow.single();
     let mut camera_transform = camera_transform.single_mut();
-    if window.cursor.grab_mode == CursorGrabMode::None {
-        // Consume events
+    if window.cursor.grab_mode == CursorGrabMode::None || !window.focused {
+        // Consume events, including any motion accumulated while focused
         mouse.read();
+        *accumulator = LookAccumulator::default();
+        return;
+    }
+    if settings.fixed_rate_look {
+        for ev in mouse.read() {
+            accumulator.add(ev.delta);
+        }
+        if let Some(delta) = accumulator.tick(time.delta_seconds()) {
+            if delta != Vec2::ZERO {
+                camera_transform.rotation = apply_look(camera_transform.rotation, delta);
+                ev_update.send(UpdateHighlightedEvent);
+            }
+        }
         return;
     }
     if !mouse.is_empty() {
         ev_update.send(UpdateHighlightedEvent);
     }
     for ev in mouse.read() {
-        let (mut yaw, mut pitch, mut _roll) = camera_transform.rotation.to_euler(EulerRot::YXZ);
-        yaw -= ev.delta.x * 0.002;
-        pitch -= ev.delta.y * 0.002;
-        pitch = pitch.clamp(-1.54, 1.54);
-        camera_transform.rotation =
-            Quat::from_axis_angle(Vec3::Y, yaw) * Quat::from_axis_angle(Vec3::X, pitch);
+        camera_transform.rotation = apply_look(camera_transform.rotation, ev.delta);
+    }
+}
+
+/// Grab the cursor again when the window regains focus if it was grabbed
+/// when focus was lost, as the window system may release it
+pub fn regrab_cursor_on_focus(
+    mut ev_focus: EventReader<WindowFocused>,
+    mut window: Query<&mut Window, With<PrimaryWindow>>,
+    mut grab_before_unfocus: Local<Option<CursorGrabMode>>,
+) {
+    let Ok(mut window) = window.get_single_mut() else {
+        return;
+    };
+    for ev in ev_focus.read() {
+        if !ev.focused {
+            *grab_before_unfocus = Some(window.cursor.grab_mode);
+        } else if let Some(grab_mode) = grab_before_unfocus.take() {
+            if grab_mode != CursorGrabMode::None {
+                window.cursor.grab_mode = grab_mode;
+                window.cursor.visible = false;
+            }
+        }
     }
 }
 
@@ -76,93 +196,136 @@ pub struct SaveEvent;
 #[derive(SystemSet, PartialEq, Eq, Hash, Debug, Clone)]
 pub struct InputSet;
 
+/// Progress towards breaking the selected voxel while the break button is held
+#[derive(Resource, Default)]
+pub struct BreakingState {
+    pub target: Option<VoxelPosition>,
+    /// Fraction of the way to breaking `target`, from 0 to 1
+    pub progress: f32,
+}
+
+/// Voxels the player has placed and broken this session
+#[derive(Resource, Default, Debug, PartialEq, Eq)]
+pub struct EditCounter {
+    pub placed: u64,
+    /// Setting off TNT counts as breaking it, but not the voxels around it
+    pub broken: u64,
+}
+
 pub fn handle_lmb(
-    mut commands: Commands,
     buttons: Res<ButtonInput<MouseButton>>,
     selected: Res<SelectedVoxel>,
     world: Res<world::World>,
     mut chunks: Query<&mut ChunkVoxels>,
+    mut breaking: ResMut<BreakingState>,
+    mut counter: ResMut<EditCounter>,
+    time: Res<Time>,
     mut ev_update: EventWriter<UpdateHighlightedEvent>,
+    mut ev_sound: EventWriter<SoundEvent>,
+    mut ev_edited: EventWriter<ChunkEditedEvent>,
 ) {
-    if !buttons.just_pressed(MouseButton::Left) {
-        return;
+    // Progress is lost on letting go or looking at another voxel
+    let target = selected
+        .to_break
+        .filter(|_| buttons.pressed(MouseButton::Left));
+    if breaking.target != target {
+        *breaking = BreakingState {
+            target,
+            progress: 0.0,
+        };
     }
-    if let Some(selected_voxel) = selected.to_break {
+    if let Some(selected_voxel) = target {
+        // Positions above or below the world can't be edited
+        let Ok(local_pos) = LocalVoxelPosition::try_from(selected_voxel) else {
+            return;
+        };
         let chunk = world
             .chunk_containing(selected_voxel)
             .expect("Selected voxel is not in a chunk");
-        let mut chunk_data = chunks.get_mut(chunk).expect("Chunk does not exist");
-        let voxel = chunk_data.voxel_mut(selected_voxel.into());
-        if voxel.breakable() {
-            voxel.clear();
-            commands
-                .entity(chunk)
-                .remove::<HasMesh>()
-                .insert(crate::UpdateSync);
-            // clear HasMesh flag from any adjacent chunk
-            for chunk_pos in selected_voxel
-                .neighbouring_chunks()
-                .all()
-                .iter()
-                .filter_map(|cp| *cp)
-            {
-                if let Some(adj_chunk) = world.chunk_at(chunk_pos) {
-                    commands
-                        .entity(adj_chunk)
-                        .remove::<HasMesh>()
-                        .insert(crate::UpdateSync);
-                }
-            }
-            ev_update.send(UpdateHighlightedEvent);
+        // Only read until the voxel breaks, so holding the button doesn't mark
+        // the chunk as changed every frame
+        let voxel = chunks
+            .get(chunk)
+            .expect("Chunk does not exist")
+            .voxel(local_pos);
+        if !voxel.breakable() {
+            return;
+        }
+        let kind = voxel.kind();
+        // Voxels with no hardness break as soon as they're clicked
+        let hardness = kind.hardness();
+        let progress = if hardness > 0.0 {
+            breaking.progress + time.delta_seconds() / hardness
+        } else {
+            1.0
+        };
+        if progress < 1.0 {
+            breaking.progress = progress;
+            return;
         }
+        breaking.progress = 0.0;
+        counter.broken += 1;
+        if let Some(material) = SoundMaterial::of(kind) {
+            ev_sound.send(SoundEvent(Sound::Break(material)));
+        }
+        if kind == VoxelKind::Tnt {
+            // TNT is set off rather than broken, clearing itself with the rest
+            // of the explosion
+            world.explode(
+                &mut chunks,
+                &mut ev_edited,
+                selected_voxel,
+                world::EXPLOSION_RADIUS,
+            );
+        } else {
+            chunks
+                .get_mut(chunk)
+                .expect("Chunk does not exist")
+                .voxel_mut(local_pos)
+                .clear();
+            ev_edited.send(ChunkEditedEvent {
+                position: selected_voxel,
+            });
+        }
+        ev_update.send(UpdateHighlightedEvent);
     }
 }
 
 pub fn handle_rmb(
-    mut commands: Commands,
     buttons: Res<ButtonInput<MouseButton>>,
     selected: Res<SelectedVoxel>,
     world: Res<world::World>,
     mut chunks: Query<&mut ChunkVoxels>,
     input_state: Res<InputState>,
+    toolbar: Res<ui::Toolbar>,
+    mut counter: ResMut<EditCounter>,
     mut ev_update: EventWriter<UpdateHighlightedEvent>,
+    mut ev_sound: EventWriter<SoundEvent>,
+    mut ev_edited: EventWriter<ChunkEditedEvent>,
 ) {
     if !buttons.just_pressed(MouseButton::Right) {
         return;
     }
+    let Some(&kind) = toolbar.kinds.get(input_state.selected_voxel as usize) else {
+        return;
+    };
     if let Some(selected_voxel) = selected.to_place {
+        let Ok(local_pos) = LocalVoxelPosition::try_from(selected_voxel) else {
+            return;
+        };
         let chunk = world
             .chunk_containing(selected_voxel)
             .expect("Selected voxel is not in a chunk");
         let mut chunk_data = chunks.get_mut(chunk).expect("Chunk does not exist");
-        let voxel = chunk_data.voxel_mut(selected_voxel.into());
-        voxel.kind = match input_state.selected_voxel {
-            0 => VoxelKind::Stone,
-            1 => VoxelKind::Grass,
-            2 => VoxelKind::Water,
-            3 => VoxelKind::Snow,
-            4 => VoxelKind::Dirt,
-            _ => panic!("Invalid selected voxel"),
-        };
-
-        commands
-            .entity(chunk)
-            .remove::<HasMesh>()
-            .insert(crate::UpdateSync);
-        // clear HasMesh flag from any adjacent chunk
-        for chunk_pos in selected_voxel
-            .neighbouring_chunks()
-            .all()
-            .iter()
-            .filter_map(|cp| *cp)
-        {
-            if let Some(adj_chunk) = world.chunk_at(chunk_pos) {
-                commands
-                    .entity(adj_chunk)
-                    .remove::<HasMesh>()
-                    .insert(crate::UpdateSync);
-            }
+        let voxel = chunk_data.voxel_mut(local_pos);
+        voxel.kind = kind;
+        counter.placed += 1;
+        if let Some(material) = SoundMaterial::of(kind) {
+            ev_sound.send(SoundEvent(Sound::Place(material)));
         }
+        ev_edited.send(ChunkEditedEvent {
+            position: selected_voxel,
+        });
         ev_update.send(UpdateHighlightedEvent);
     }
 }
@@ -172,6 +335,7 @@ pub fn handle_movement_keys(
     mut camera_velocity: ResMut<CameraVelocity>,
     camera_transform: Query<&Transform, With<Camera>>,
     mut input_state: ResMut<InputState>,
+    time: Res<Time>,
 ) {
     let camera_transform = camera_transform.single();
     let camera_velocity = &mut camera_velocity.vel;
@@ -180,12 +344,6 @@ pub fn handle_movement_keys(
     let camera_forward = rotate * Vec3::NEG_Z;
     let camera_right = rotate * Vec3::X;
 
-    let mut speed_factor = if keys.pressed(KeyCode::ControlLeft) {
-        12.5
-    } else {
-        7.5
-    };
-
     input_state.space_held = keys.pressed(KeyCode::Space);
     input_state.shift_held = keys.pressed(KeyCode::ShiftLeft);
     input_state.space_pressed = keys.just_pressed(KeyCode::Space);
@@ -194,9 +352,23 @@ pub fn handle_movement_keys(
     if keys.just_pressed(KeyCode::KeyF) {
         input_state.fly_hack = !input_state.fly_hack;
     }
+    // Shift descends while flying instead
+    input_state.sneaking = input_state.shift_held && !input_state.fly_hack;
+
+    let moving = keys.any_pressed([KeyCode::KeyW, KeyCode::KeyA, KeyCode::KeyS, KeyCode::KeyD]);
+    input_state.sprinting = moving && keys.pressed(KeyCode::ControlLeft) && !input_state.sneaking;
+    let mut speed_factor = if input_state.sprinting {
+        12.5
+    } else if input_state.sneaking {
+        2.5
+    } else {
+        7.5
+    };
     if input_state.fly_hack {
         speed_factor *= 2.0;
     }
+    // Accelerate so that the terminal velocity under damping is `speed_factor`
+    speed_factor *= player::HORIZONTAL_DAMPING * time.delta_seconds();
 
     for key in keys.get_pressed() {
         if *key == KeyCode::KeyW {
@@ -217,30 +389,20 @@ const QUIT_SECONDS: f32 = 1.0;
 #[derive(Resource, Default)]
 pub struct QuitCounter(f32);
 
-pub fn handle_special_keys(
+/// Toggle the pause menu with Escape, or quit if it is held
+pub fn handle_pause_key(
     keys: Res<ButtonInput<KeyCode>>,
-    mut window: Query<&mut Window, With<PrimaryWindow>>,
-    mut input_state: ResMut<InputState>,
-    mut scroll: EventReader<MouseWheel>,
-    mut selected_pos: Query<(&mut crate::ui::SelectedPosition, &mut Style)>,
-    mut ev_save: EventWriter<SaveEvent>,
-    mut color_overlay: Query<&mut BackgroundColor, With<ui::ColorOverlay>>,
+    state: Res<State<crate::AppState>>,
+    mut next_state: ResMut<NextState<crate::AppState>>,
     mut quit_counter: ResMut<QuitCounter>,
     time: Res<Time>,
     mut exit: EventWriter<AppExit>,
 ) {
-    let mut window = window.single_mut();
     if keys.just_pressed(KeyCode::Escape) {
-        let (grab_mode, visible, color) = match window.cursor.grab_mode {
-            CursorGrabMode::None => (CursorGrabMode::Locked, false, Color::NONE),
-            CursorGrabMode::Locked | CursorGrabMode::Confined => {
-                (CursorGrabMode::None, true, Color::BLACK.with_alpha(0.5))
-            }
-        };
-        window.cursor.grab_mode = grab_mode;
-        window.cursor.visible = visible;
-        let mut color_overlay = color_overlay.single_mut();
-        color_overlay.0 = color;
+        next_state.set(match state.get() {
+            crate::AppState::Playing => crate::AppState::Paused,
+            crate::AppState::Paused => crate::AppState::Playing,
+        });
     }
 
     if keys.pressed(KeyCode::Escape) {
@@ -251,7 +413,43 @@ pub fn handle_special_keys(
     } else {
         quit_counter.0 = 0.0;
     }
+}
+
+/// Free the cursor and dim the screen while paused
+pub fn release_cursor(
+    mut window: Query<&mut Window, With<PrimaryWindow>>,
+    mut color_overlay: Query<&mut BackgroundColor, With<ui::ColorOverlay>>,
+) {
+    let mut window = window.single_mut();
+    window.cursor.grab_mode = CursorGrabMode::None;
+    window.cursor.visible = true;
+    color_overlay.single_mut().0 = Color::BLACK.with_alpha(0.5);
+}
+
+/// Recapture the cursor when returning to the game
+pub fn grab_cursor(
+    mut window: Query<&mut Window, With<PrimaryWindow>>,
+    mut color_overlay: Query<&mut BackgroundColor, With<ui::ColorOverlay>>,
+    mut accumulator: ResMut<LookAccumulator>,
+) {
+    let mut window = window.single_mut();
+    window.cursor.grab_mode = CursorGrabMode::Locked;
+    window.cursor.visible = false;
+    color_overlay.single_mut().0 = Color::NONE;
+    // Don't apply any motion left over from before pausing
+    *accumulator = LookAccumulator::default();
+}
 
+pub fn handle_special_keys(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut window: Query<&mut Window, With<PrimaryWindow>>,
+    mut input_state: ResMut<InputState>,
+    mut scroll: EventReader<MouseWheel>,
+    toolbar: Res<ui::Toolbar>,
+    mut ev_slot: EventWriter<SlotSelectedEvent>,
+    mut ev_save: EventWriter<SaveEvent>,
+) {
+    let mut window = window.single_mut();
     if keys.just_pressed(KeyCode::F1) {
         window.mode = match window.mode {
             WindowMode::Windowed => WindowMode::BorderlessFullscreen,
@@ -260,33 +458,384 @@ pub fn handle_special_keys(
     }
 
     let mut new_selected = input_state.selected_voxel;
-    if keys.just_pressed(KeyCode::Digit1) {
-        new_selected = 0;
-    } else if keys.just_pressed(KeyCode::Digit2) {
-        new_selected = 1;
-    } else if keys.just_pressed(KeyCode::Digit3) {
-        new_selected = 2;
-    } else if keys.just_pressed(KeyCode::Digit4) {
-        new_selected = 3;
-    } else if keys.just_pressed(KeyCode::Digit5) {
-        new_selected = 4;
+    for (slot, digit) in [
+        KeyCode::Digit1,
+        KeyCode::Digit2,
+        KeyCode::Digit3,
+        KeyCode::Digit4,
+        KeyCode::Digit5,
+        KeyCode::Digit6,
+        KeyCode::Digit7,
+        KeyCode::Digit8,
+        KeyCode::Digit9,
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        if keys.just_pressed(digit) && slot < toolbar.kinds.len() {
+            new_selected = slot as u8;
+        }
     }
     for scr_event in scroll.read() {
-        if scr_event.y > 0.0 {
-            new_selected = new_selected.saturating_add(1);
-        } else if scr_event.y < 0.0 {
-            new_selected = new_selected.saturating_sub(1);
-        }
+        input_state.scroll += match scr_event.unit {
+            MouseScrollUnit::Line => scr_event.y,
+            MouseScrollUnit::Pixel => scr_event.y / PIXELS_PER_SCROLL_NOTCH,
+        };
     }
-    new_selected = new_selected.clamp(0, 4);
+    let slots = toolbar.kinds.len().min(u8::MAX as usize + 1);
+    new_selected = scroll_slot(new_selected, &mut input_state.scroll, slots);
     if new_selected != input_state.selected_voxel {
         input_state.selected_voxel = new_selected;
+        ev_slot.send(SlotSelectedEvent { slot: new_selected });
     }
 
-    let mut selected_pos = selected_pos.single_mut();
-    selected_pos.1.margin.left = Val::Px((input_state.selected_voxel as f32 - 2.0) * 156.0 + 8.0);
-
     if keys.just_pressed(KeyCode::F9) {
         ev_save.send(SaveEvent);
     }
 }
+
+/// Grow the load distance with `=` and shrink it with `-`, by a chunk on
+/// each side
+pub fn handle_load_distance_keys(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut settings: ResMut<crate::Settings>,
+    mut ev_changed: EventWriter<crate::SettingsChangedEvent>,
+) {
+    let load_distance = if keys.just_pressed(KeyCode::Equal) {
+        settings.load_distance + 2
+    } else if keys.just_pressed(KeyCode::Minus) {
+        settings.load_distance.saturating_sub(2)
+    } else {
+        return;
+    };
+    if settings.set_load_distance(load_distance) {
+        info!("Load distance set to {}", settings.load_distance);
+        ev_changed.send(crate::SettingsChangedEvent);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bevy::math::vec2;
+
+    #[test]
+    pub fn scrolling_wraps_around_toolbar() {
+        let mut scroll = 0.0;
+        assert_eq!(scroll_slot(3, &mut scroll, 7), 3);
+
+        // Scrolling down moves to later slots, wrapping past the last
+        scroll = -3.0;
+        assert_eq!(scroll_slot(5, &mut scroll, 7), 1);
+        assert_eq!(scroll, 0.0);
+        // Scrolling up wraps past the first slot
+        scroll = 1.0;
+        assert_eq!(scroll_slot(0, &mut scroll, 7), 6);
+        scroll = 16.0;
+        assert_eq!(scroll_slot(0, &mut scroll, 7), 5);
+
+        // Fractions of a notch add up over several scrolls
+        let mut slot = 2;
+        scroll = 0.0;
+        for _ in 0..3 {
+            scroll -= 0.4;
+            slot = scroll_slot(slot, &mut scroll, 7);
+        }
+        assert_eq!(slot, 3);
+        assert!((scroll + 0.2).abs() < 1e-5);
+        scroll += 0.3;
+        assert_eq!(scroll_slot(slot, &mut scroll, 7), 3);
+
+        // An empty toolbar has nothing to select
+        scroll = 2.5;
+        assert_eq!(scroll_slot(0, &mut scroll, 0), 0);
+        assert_eq!(scroll, 0.5);
+    }
+
+    #[test]
+    pub fn fixed_rate_look_is_batching_independent() {
+        let start = Quat::from_axis_angle(Vec3::Y, 0.5);
+
+        let mut single = LookAccumulator::default();
+        single.add(vec2(40.0, -25.0));
+        let single_rot = apply_look(start, single.tick(LOOK_TICK_SECONDS).unwrap());
+
+        // The same motion split over several events and frames
+        let mut split = LookAccumulator::default();
+        split.add(vec2(10.0, -5.0));
+        split.add(vec2(25.0, -15.0));
+        assert_eq!(split.tick(LOOK_TICK_SECONDS / 2.0), None);
+        split.add(vec2(5.0, -5.0));
+        let split_rot = apply_look(start, split.tick(LOOK_TICK_SECONDS / 2.0).unwrap());
+
+        assert!(single_rot.abs_diff_eq(split_rot, 1e-6));
+        assert!(!single_rot.abs_diff_eq(start, 1e-6));
+    }
+
+    #[test]
+    pub fn unfocused_window_ignores_look() {
+        for fixed_rate_look in [false, true] {
+            let mut app = App::new();
+            app.insert_resource(crate::Settings {
+                fixed_rate_look,
+                ..default()
+            })
+            .init_resource::<LookAccumulator>()
+            .init_resource::<Time>()
+            .add_event::<MouseMotion>()
+            .add_event::<UpdateHighlightedEvent>()
+            .add_event::<WindowFocused>()
+            .add_systems(Update, (regrab_cursor_on_focus, player_look).chain());
+            let mut window = Window::default();
+            window.cursor.grab_mode = CursorGrabMode::Locked;
+            let window = app.world_mut().spawn((window, PrimaryWindow)).id();
+            let camera = app
+                .world_mut()
+                .spawn((Camera::default(), Transform::default()))
+                .id();
+
+            let set_focus = |app: &mut App, focused| {
+                app.world_mut().get_mut::<Window>(window).unwrap().focused = focused;
+                app.world_mut()
+                    .send_event(WindowFocused { window, focused });
+            };
+            let look = |app: &mut App| {
+                app.world_mut().send_event(MouseMotion {
+                    delta: vec2(40.0, -25.0),
+                });
+                app.world_mut()
+                    .resource_mut::<Time>()
+                    .advance_by(std::time::Duration::from_secs_f32(LOOK_TICK_SECONDS));
+                app.update();
+                app.world().get::<Transform>(camera).unwrap().rotation
+            };
+
+            set_focus(&mut app, false);
+            for _ in 0..3 {
+                assert_eq!(look(&mut app), Quat::IDENTITY);
+            }
+
+            // The window system releases the cursor while unfocused
+            app.world_mut()
+                .get_mut::<Window>(window)
+                .unwrap()
+                .cursor
+                .grab_mode = CursorGrabMode::None;
+            set_focus(&mut app, true);
+            assert!(!look(&mut app).abs_diff_eq(Quat::IDENTITY, 1e-6));
+            let window = app.world().get::<Window>(window).unwrap();
+            assert_eq!(window.cursor.grab_mode, CursorGrabMode::Locked);
+        }
+    }
+
+    #[test]
+    pub fn escape_toggles_pause() {
+        let mut app = App::new();
+        app.add_plugins(bevy::state::app::StatesPlugin)
+            .init_state::<crate::AppState>()
+            .init_resource::<ButtonInput<KeyCode>>()
+            .init_resource::<QuitCounter>()
+            .init_resource::<Time>()
+            .add_event::<AppExit>()
+            .add_systems(Update, handle_pause_key);
+        let tap_escape = |app: &mut App| {
+            let mut keys = app.world_mut().resource_mut::<ButtonInput<KeyCode>>();
+            keys.press(KeyCode::Escape);
+            app.update();
+            let mut keys = app.world_mut().resource_mut::<ButtonInput<KeyCode>>();
+            keys.release(KeyCode::Escape);
+            keys.clear();
+            // State changes are applied at the start of the next update
+            app.update();
+            *app.world().resource::<State<crate::AppState>>().get()
+        };
+
+        assert_eq!(tap_escape(&mut app), crate::AppState::Paused);
+        assert_eq!(tap_escape(&mut app), crate::AppState::Playing);
+        assert!(app.world().resource::<Events<AppExit>>().is_empty());
+    }
+
+    #[test]
+    pub fn edit_at_world_height_limits() {
+        use crate::{
+            chunk::{
+                ChunkPosition,
+                CHUNK_SIZE,
+                MAX_HEIGHT,
+            },
+            terrain::generate_chunk,
+            voxel::{
+                VoxelKind,
+                VoxelPosition,
+            },
+        };
+
+        let mut world = world::World::flat();
+        let chunk_pos = ChunkPosition::new(0, 0);
+        let chunk = generate_chunk(chunk_pos, &world.generator, CHUNK_SIZE.ilog2());
+        let mut app = App::new();
+        let chunk_ent = app.world_mut().spawn(chunk).id();
+        world.add_chunk(chunk_pos, chunk_ent);
+        app.insert_resource(world)
+            .init_resource::<ButtonInput<MouseButton>>()
+            .init_resource::<SelectedVoxel>()
+            .init_resource::<InputState>()
+            .init_resource::<ui::Toolbar>()
+            .init_resource::<BreakingState>()
+            .init_resource::<EditCounter>()
+            .init_resource::<Time>()
+            .add_event::<UpdateHighlightedEvent>()
+            .add_event::<SoundEvent>()
+            .add_event::<ChunkEditedEvent>()
+            .add_systems(Update, (handle_lmb, handle_rmb));
+        let click = |app: &mut App, to_break: Option<IVec3>, to_place: Option<IVec3>| {
+            *app.world_mut().resource_mut::<SelectedVoxel>() = SelectedVoxel {
+                to_break: to_break.map(VoxelPosition::new),
+                to_place: to_place.map(VoxelPosition::new),
+            };
+            let mut buttons = app.world_mut().resource_mut::<ButtonInput<MouseButton>>();
+            buttons.press(MouseButton::Left);
+            buttons.press(MouseButton::Right);
+            app.update();
+            let mut buttons = app.world_mut().resource_mut::<ButtonInput<MouseButton>>();
+            buttons.release_all();
+            buttons.clear();
+        };
+        let kind_at = |app: &App, y: usize| {
+            app.world()
+                .get::<ChunkVoxels>(chunk_ent)
+                .unwrap()
+                .voxel(LocalVoxelPosition::new(3, y as _, 4))
+                .kind
+        };
+
+        let counter = |app: &App| {
+            let counter = app.world().resource::<EditCounter>();
+            (counter.placed, counter.broken)
+        };
+
+        let top = MAX_HEIGHT as i32 - 1;
+        click(&mut app, None, Some(IVec3::new(3, top, 4)));
+        assert_eq!(kind_at(&app, MAX_HEIGHT - 1), VoxelKind::Stone);
+        assert_eq!(counter(&app), (1, 0));
+        // TNT is the only voxel broken in a single frame, as `Time` is never
+        // advanced
+        let tnt_slot = app
+            .world()
+            .resource::<ui::Toolbar>()
+            .kinds
+            .iter()
+            .position(|&kind| kind == VoxelKind::Tnt)
+            .unwrap();
+        app.world_mut().resource_mut::<InputState>().selected_voxel = tnt_slot as u8;
+        click(&mut app, None, Some(IVec3::new(3, top - 1, 4)));
+        assert_eq!(kind_at(&app, MAX_HEIGHT - 2), VoxelKind::Tnt);
+        click(&mut app, Some(IVec3::new(3, top - 1, 4)), None);
+        assert_eq!(kind_at(&app, MAX_HEIGHT - 2), VoxelKind::Air);
+        // The stone caught in the explosion isn't counted
+        assert_eq!(kind_at(&app, MAX_HEIGHT - 1), VoxelKind::Air);
+        assert_eq!(counter(&app), (2, 1));
+
+        // Neither above nor below the world can be edited, but it isn't an error
+        click(
+            &mut app,
+            Some(IVec3::new(3, -1, 4)),
+            Some(IVec3::new(3, top + 1, 4)),
+        );
+        click(&mut app, Some(IVec3::new(3, 0, 4)), None);
+        assert_eq!(kind_at(&app, 0), VoxelKind::Bedrock);
+        assert_eq!(counter(&app), (2, 1));
+    }
+
+    #[test]
+    pub fn holding_breaks_by_hardness() {
+        use crate::{
+            chunk::{
+                ChunkPosition,
+                CHUNK_SIZE,
+            },
+            terrain::generate_chunk,
+        };
+        use std::time::Duration;
+
+        let mut world = world::World::flat();
+        let chunk_pos = ChunkPosition::new(0, 0);
+        let chunk = generate_chunk(chunk_pos, &world.generator, CHUNK_SIZE.ilog2());
+        let mut app = App::new();
+        let chunk_ent = app.world_mut().spawn(chunk).id();
+        world.add_chunk(chunk_pos, chunk_ent);
+        app.insert_resource(world)
+            .init_resource::<ButtonInput<MouseButton>>()
+            .init_resource::<SelectedVoxel>()
+            .init_resource::<BreakingState>()
+            .init_resource::<EditCounter>()
+            .init_resource::<Time>()
+            .add_event::<UpdateHighlightedEvent>()
+            .add_event::<SoundEvent>()
+            .add_event::<ChunkEditedEvent>()
+            .add_systems(Update, handle_lmb);
+        // Hold the button on a voxel for a frame of `secs` seconds
+        let hold = |app: &mut App, pos: IVec3, secs: f32| {
+            app.world_mut().resource_mut::<SelectedVoxel>().to_break =
+                Some(VoxelPosition::new(pos));
+            app.world_mut()
+                .resource_mut::<ButtonInput<MouseButton>>()
+                .press(MouseButton::Left);
+            app.world_mut()
+                .resource_mut::<Time>()
+                .advance_by(Duration::from_secs_f32(secs));
+            app.update();
+        };
+        let release = |app: &mut App| {
+            app.world_mut()
+                .resource_mut::<ButtonInput<MouseButton>>()
+                .release(MouseButton::Left);
+            app.update();
+        };
+        let kind_at = |app: &App, pos: IVec3| {
+            let local_pos = LocalVoxelPosition::new(pos.x as _, pos.y as _, pos.z as _);
+            app.world()
+                .get::<ChunkVoxels>(chunk_ent)
+                .unwrap()
+                .voxel(local_pos)
+                .kind
+        };
+        let progress = |app: &App| app.world().resource::<BreakingState>().progress;
+
+        let dirt = IVec3::new(2, 63, 2);
+        assert_eq!(kind_at(&app, dirt), VoxelKind::Dirt);
+        hold(&mut app, dirt, 0.3);
+        assert!(progress(&app) > 0.5);
+        // Letting go loses progress
+        release(&mut app);
+        assert_eq!(progress(&app), 0.0);
+        hold(&mut app, dirt, 0.3);
+        assert_eq!(kind_at(&app, dirt), VoxelKind::Dirt);
+        hold(&mut app, dirt, 0.3);
+        assert_eq!(kind_at(&app, dirt), VoxelKind::Air);
+
+        // Stone takes longer than dirt, and looking away loses progress
+        let stone = IVec3::new(2, 40, 2);
+        let last_changed = |app: &App| {
+            app.world()
+                .entity(chunk_ent)
+                .get_ref::<ChunkVoxels>()
+                .unwrap()
+                .last_changed()
+        };
+        let before = last_changed(&app);
+        hold(&mut app, stone, 1.0);
+        assert_eq!(kind_at(&app, stone), VoxelKind::Stone);
+        // The chunk is untouched until the voxel breaks
+        assert_eq!(last_changed(&app), before);
+        hold(&mut app, stone + IVec3::X, 0.0);
+        assert_eq!(progress(&app), 0.0);
+        hold(&mut app, stone, 1.0);
+        hold(&mut app, stone, 1.0);
+        assert_eq!(kind_at(&app, stone), VoxelKind::Air);
+
+        let bedrock = IVec3::new(2, 0, 2);
+        hold(&mut app, bedrock, 100.0);
+        assert_eq!(kind_at(&app, bedrock), VoxelKind::Bedrock);
+    }
+}
@@ -0,0 +1,405 @@
+use std::collections::VecDeque;
+
+use bevy::{
+    math::ivec3,
+    prelude::*,
+    utils::HashSet,
+};
+
+use crate::{
+    chunk::{
+        ChunkPosition,
+        ChunkVoxels,
+        CHUNK_SIZE,
+        CHUNK_SIZE_I,
+        MAX_HEIGHT,
+    },
+    mesh::HasMesh,
+    voxel::{
+        LocalVoxelPosition,
+        VoxelPosition,
+    },
+    world::{
+        self,
+        World,
+    },
+};
+
+/// Light of voxels open to the sky. Light drops by one for each voxel it
+/// spreads through, other than straight down from the sky
+pub const MAX_LIGHT: u8 = 15;
+
+const DIRECTIONS: [IVec3; 6] = [
+    IVec3::X,
+    IVec3::NEG_X,
+    IVec3::Y,
+    IVec3::NEG_Y,
+    IVec3::Z,
+    IVec3::NEG_Z,
+];
+
+/// Light reaching the next voxel in `direction` from a voxel lit at `level`
+fn spread_level(level: u8, direction: IVec3) -> u8 {
+    if level == MAX_LIGHT && direction == IVec3::NEG_Y {
+        MAX_LIGHT
+    } else {
+        level.saturating_sub(1)
+    }
+}
+
+/// Light a newly generated chunk as if it were the only chunk in the world.
+/// Light is spread across its borders by [`spread_light_between_chunks`] once
+/// it is loaded
+pub fn light_chunk(chunk: &mut ChunkVoxels) {
+    let index =
+        |pos: IVec3| (pos.y as usize * CHUNK_SIZE + pos.z as usize) * CHUNK_SIZE + pos.x as usize;
+    let in_chunk = |pos: IVec3| {
+        pos.cmpge(IVec3::ZERO).all()
+            && pos
+                .cmplt(ivec3(CHUNK_SIZE_I, MAX_HEIGHT as _, CHUNK_SIZE_I))
+                .all()
+    };
+
+    let mut opaque = vec![false; CHUNK_SIZE * CHUNK_SIZE * MAX_HEIGHT];
+    for ((x, y, z), voxel) in chunk.iter() {
+        opaque[index(ivec3(x as _, y as _, z as _))] = voxel.casts_shadow();
+    }
+
+    // Each column is fully lit down to the first voxel blocking the sky
+    let mut light = vec![0; opaque.len()];
+    let mut queue = VecDeque::new();
+    for x in 0..CHUNK_SIZE_I {
+        for z in 0..CHUNK_SIZE_I {
+            for y in (0..MAX_HEIGHT as i32).rev() {
+                let pos = ivec3(x, y, z);
+                if opaque[index(pos)] {
+                    break;
+                }
+                light[index(pos)] = MAX_LIGHT;
+                queue.push_back(pos);
+            }
+        }
+    }
+
+    while let Some(pos) = queue.pop_front() {
+        let level = light[index(pos)];
+        for direction in DIRECTIONS {
+            let next = pos + direction;
+            if !in_chunk(next) || opaque[index(next)] {
+                continue;
+            }
+            let next_level = spread_level(level, direction);
+            if next_level > light[index(next)] {
+                light[index(next)] = next_level;
+                queue.push_back(next);
+            }
+        }
+    }
+
+    chunk.set_all_light(|pos| light[index(pos.as_ivec3())]);
+}
+
+/// Light of every loaded chunk, which spreads across chunk borders
+struct LoadedLight<'a, 'w, 's, 'q> {
+    world: &'a World,
+    chunks: &'a mut Query<'w, 's, &'q mut ChunkVoxels>,
+    /// Chunks whose meshes show light that has changed
+    touched: HashSet<ChunkPosition>,
+}
+
+impl<'a, 'w, 's, 'q> LoadedLight<'a, 'w, 's, 'q> {
+    fn new(world: &'a World, chunks: &'a mut Query<'w, 's, &'q mut ChunkVoxels>) -> Self {
+        Self {
+            world,
+            chunks,
+            touched: HashSet::new(),
+        }
+    }
+
+    /// Whether the voxel at `pos` blocks light, and its light. `None` if it is
+    /// below the world or not loaded, while everything above the world is
+    /// lit by the sky
+    fn voxel(&self, pos: VoxelPosition) -> Option<(bool, u8)> {
+        if pos.y() >= MAX_HEIGHT as i32 {
+            return Some((false, MAX_LIGHT));
+        }
+        let local_pos = LocalVoxelPosition::try_from(pos).ok()?;
+        let chunk = self.chunks.get(self.world.chunk_containing(pos)?).ok()?;
+        Some((
+            chunk.voxel(local_pos).casts_shadow(),
+            chunk.light(local_pos),
+        ))
+    }
+
+    fn set_light(&mut self, pos: VoxelPosition, level: u8) {
+        let Ok(local_pos) = LocalVoxelPosition::try_from(pos) else {
+            return;
+        };
+        let Some(mut chunk) = self
+            .world
+            .chunk_containing(pos)
+            .and_then(|chunk| self.chunks.get_mut(chunk).ok())
+        else {
+            return;
+        };
+        chunk.set_light(local_pos, level);
+        self.touched.extend(world::chunks_meshing_voxel(pos));
+    }
+
+    /// Spread light outward from each voxel in `queue`
+    fn spread(&mut self, mut queue: VecDeque<VoxelPosition>) {
+        while let Some(pos) = queue.pop_front() {
+            let Some((_, level)) = self.voxel(pos) else {
+                continue;
+            };
+            for direction in DIRECTIONS {
+                let next = VoxelPosition::new(pos.as_ivec3() + direction);
+                let Some((false, next_light)) = self.voxel(next) else {
+                    continue;
+                };
+                let next_level = spread_level(level, direction);
+                if next_level > next_light {
+                    self.set_light(next, next_level);
+                    queue.push_back(next);
+                }
+            }
+        }
+    }
+
+    /// Darken every voxel that could have been lit by those in `removed`,
+    /// given the light each had before being darkened. Returns the lit voxels
+    /// bordering the darkened area, which light can be spread back in from
+    fn remove(&mut self, mut removed: VecDeque<(VoxelPosition, u8)>) -> VecDeque<VoxelPosition> {
+        let mut sources = VecDeque::new();
+        while let Some((pos, level)) = removed.pop_front() {
+            for direction in DIRECTIONS {
+                let next = VoxelPosition::new(pos.as_ivec3() + direction);
+                let Some((_, next_light)) = self.voxel(next) else {
+                    continue;
+                };
+                if next_light == 0 {
+                    continue;
+                }
+                if next_light <= spread_level(level, direction) {
+                    self.set_light(next, 0);
+                    removed.push_back((next, next_light));
+                } else {
+                    sources.push_back(next);
+                }
+            }
+        }
+        sources
+    }
+}
+
+/// Update the light around each voxel in `edited` after their kinds have
+/// changed, returning the chunks whose meshes show light that has changed.
+/// Only the area the edited voxels can light is updated
+pub fn relight(
+    world: &World,
+    chunks: &mut Query<&mut ChunkVoxels>,
+    edited: impl IntoIterator<Item = VoxelPosition>,
+) -> HashSet<ChunkPosition> {
+    let mut light = LoadedLight::new(world, chunks);
+    let mut removed = VecDeque::new();
+    let mut sources = VecDeque::new();
+    for pos in edited {
+        match light.voxel(pos) {
+            Some((true, level)) if level > 0 => {
+                light.set_light(pos, 0);
+                removed.push_back((pos, level));
+            }
+            Some((false, _)) => {
+                // Light spreads back in from around voxels that have been
+                // cleared
+                sources.extend(
+                    DIRECTIONS.map(|direction| VoxelPosition::new(pos.as_ivec3() + direction)),
+                );
+            }
+            _ => {}
+        }
+    }
+    sources.extend(light.remove(removed));
+    light.spread(sources);
+    light.touched
+}
+
+/// Spread light between newly generated chunks and the loaded chunks around
+/// them, as each was lit by [`light_chunk`] without the other
+pub fn spread_light_between_chunks(
+    mut commands: Commands,
+    world: Res<World>,
+    mut chunks: Query<&mut ChunkVoxels>,
+    meshed: Query<(), With<HasMesh>>,
+) {
+    let added: Vec<_> = world
+        .iter()
+        .filter(|&(_, chunk)| chunks.get_mut(chunk).is_ok_and(|chunk| chunk.is_added()))
+        .map(|(pos, _)| pos)
+        .collect();
+    if added.is_empty() {
+        return;
+    }
+
+    let mut light = LoadedLight::new(&world, &mut chunks);
+    let mut sources = VecDeque::new();
+    for chunk_pos in added {
+        for neighbour in chunk_pos.neighbouring_chunks().all() {
+            let direction = (neighbour.as_ivec3() - chunk_pos.as_ivec3()) / CHUNK_SIZE_I;
+            let edge = |coord: i32, along: i32| match coord {
+                1 => CHUNK_SIZE_I - 1,
+                -1 => 0,
+                _ => along,
+            };
+            for y in 0..MAX_HEIGHT as i32 {
+                for along in 0..CHUNK_SIZE_I {
+                    // A voxel on the edge of the new chunk, and the voxel
+                    // across the border from it
+                    let inside = chunk_pos.as_ivec3()
+                        + ivec3(edge(direction.x, along), y, edge(direction.z, along));
+                    let inside = VoxelPosition::new(inside);
+                    let outside = VoxelPosition::new(inside.as_ivec3() + direction);
+                    let (Some((_, inside_light)), Some((_, outside_light))) =
+                        (light.voxel(inside), light.voxel(outside))
+                    else {
+                        continue;
+                    };
+                    if inside_light > outside_light + 1 {
+                        sources.push_back(inside);
+                    } else if outside_light > inside_light + 1 {
+                        sources.push_back(outside);
+                    }
+                }
+            }
+        }
+    }
+    light.spread(sources);
+
+    // Chunks not yet meshed are meshed with the new light anyway
+    for chunk_pos in light.touched {
+        if let Some(chunk) = world.chunk_at(chunk_pos).filter(|&c| meshed.contains(c)) {
+            commands.entity(chunk).remove::<HasMesh>();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::voxel::VoxelKind;
+
+    /// Sky light below the given voxel column of `chunk`
+    fn column_light(chunk: &ChunkVoxels, x: u32, z: u32) -> Vec<u8> {
+        (0..MAX_HEIGHT as u32)
+            .map(|y| chunk.light(LocalVoxelPosition::new(x, y, z)))
+            .collect()
+    }
+
+    #[test]
+    pub fn light_fills_under_overhang() {
+        // Stone ground at Y 10, with a roof at Y 20 over X 0 to 7
+        let mut chunk = ChunkVoxels::from_fn(4, |pos| {
+            if pos.y() <= 10 || (pos.y() == 20 && pos.x() < 8) {
+                VoxelKind::Stone
+            } else {
+                VoxelKind::Air
+            }
+        });
+        light_chunk(&mut chunk);
+
+        // Open sky is fully lit right down to the ground
+        let open = column_light(&chunk, 12, 4);
+        assert!(open[11..].iter().all(|&l| l == MAX_LIGHT));
+        assert!(open[..=10].iter().all(|&l| l == 0));
+
+        // Light fades with distance under the roof
+        let light = |x| chunk.light(LocalVoxelPosition::new(x, 15, 4));
+        assert_eq!(light(8), MAX_LIGHT);
+        assert_eq!(light(7), MAX_LIGHT - 1);
+        assert_eq!(light(0), MAX_LIGHT - 8);
+        // Above the roof is open to the sky
+        assert_eq!(chunk.light(LocalVoxelPosition::new(0, 21, 4)), MAX_LIGHT);
+    }
+
+    #[test]
+    pub fn light_spreads_between_chunks_and_edits() {
+        use bevy::ecs::system::RunSystemOnce;
+
+        // A chunk roofed over entirely, next to an open one
+        let roofed = |pos: LocalVoxelPosition| {
+            if pos.y() <= 10 || pos.y() == 20 {
+                VoxelKind::Stone
+            } else {
+                VoxelKind::Air
+            }
+        };
+        let open = |pos: LocalVoxelPosition| {
+            if pos.y() <= 10 {
+                VoxelKind::Stone
+            } else {
+                VoxelKind::Air
+            }
+        };
+        let mut app = App::new();
+        let mut world = World::flat();
+        for (chunk_pos, kind_at) in [
+            (
+                ChunkPosition::new(0, 0),
+                &roofed as &dyn Fn(LocalVoxelPosition) -> VoxelKind,
+            ),
+            (ChunkPosition::new(16, 0), &open),
+        ] {
+            let mut chunk = ChunkVoxels::from_fn(4, kind_at);
+            light_chunk(&mut chunk);
+            world.add_chunk(chunk_pos, app.world_mut().spawn(chunk).id());
+        }
+        app.insert_resource(world)
+            .add_systems(Update, spread_light_between_chunks);
+        app.update();
+
+        let light_at = |app: &mut App, pos: IVec3| {
+            app.world_mut().run_system_once(
+                move |world: Res<World>, chunks: Query<&ChunkVoxels>| {
+                    let pos = VoxelPosition::new(pos);
+                    chunks
+                        .get(world.chunk_containing(pos).unwrap())
+                        .unwrap()
+                        .light(pos.try_into().unwrap())
+                },
+            )
+        };
+        // Light reaches in under the roof from the open chunk
+        assert_eq!(light_at(&mut app, ivec3(15, 15, 8)), MAX_LIGHT - 1);
+        assert_eq!(light_at(&mut app, ivec3(10, 15, 8)), MAX_LIGHT - 6);
+        assert_eq!(light_at(&mut app, ivec3(0, 15, 8)), 0);
+
+        let edit = |app: &mut App, pos: IVec3, kind: VoxelKind| {
+            app.world_mut().run_system_once(
+                move |world: Res<World>, mut chunks: Query<&mut ChunkVoxels>| {
+                    let pos = VoxelPosition::new(pos);
+                    let chunk = world.chunk_containing(pos).unwrap();
+                    chunks
+                        .get_mut(chunk)
+                        .unwrap()
+                        .voxel_mut(pos.try_into().unwrap())
+                        .kind = kind;
+                    relight(&world, &mut chunks, [pos]);
+                },
+            )
+        };
+        // Breaking a hole in the roof lets the sky in
+        edit(&mut app, ivec3(4, 20, 8), VoxelKind::Air);
+        assert_eq!(light_at(&mut app, ivec3(4, 11, 8)), MAX_LIGHT);
+        assert_eq!(light_at(&mut app, ivec3(0, 15, 8)), MAX_LIGHT - 4);
+        // Filling it in again leaves it as it was
+        edit(&mut app, ivec3(4, 20, 8), VoxelKind::Stone);
+        assert_eq!(light_at(&mut app, ivec3(4, 11, 8)), MAX_LIGHT - 12);
+        assert_eq!(light_at(&mut app, ivec3(0, 15, 8)), 0);
+        assert_eq!(light_at(&mut app, ivec3(10, 15, 8)), MAX_LIGHT - 6);
+        // Walling off the open chunk's light
+        for y in 11..20 {
+            edit(&mut app, ivec3(15, y, 8), VoxelKind::Stone);
+        }
+        assert_eq!(light_at(&mut app, ivec3(14, 15, 8)), MAX_LIGHT - 3);
+    }
+}
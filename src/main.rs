@@ -1,8 +1,19 @@
 // Bevy queries are necessarily verbose
 #![allow(clippy::too_many_arguments)]
 #![allow(clippy::type_complexity)]
+#![cfg_attr(all(test, feature = "bench"), feature(test))]
 
 mod args;
+/// Footstep, breaking, placing and splashing sounds
+mod audio;
+/// Combines neighbouring chunk meshes to reduce draw calls
+mod batch;
+#[cfg(all(test, feature = "bench"))]
+/// Benchmarks of meshing and terrain generation, run with
+/// `cargo bench --features bench`
+mod bench;
+/// Keeps unloaded chunks on disk to skip generating them again
+mod cache;
 mod chunk;
 mod mesh;
 mod octree;
@@ -15,6 +26,8 @@ mod debug;
 #[cfg(feature = "renderdoc")]
 mod renderdoc;
 
+/// Sky light spread through caves and under overhangs
+mod light;
 mod terrain;
 /// Keeps track of the whole world of chunks and voxels
 mod world;
@@ -22,18 +35,33 @@ mod world;
 /// Handles defining and creating materials for rendering
 mod material;
 
+/// Player health, and damage from falling
+mod health;
 /// Handles finding the currently 'selected' voxel and highlighting it
 mod highlight;
+/// Flat stand-ins for chunks beyond the load distance
+mod impostor;
 mod input;
 mod ui;
 
 mod player;
 mod save;
+/// Saves screenshots of the window with F2
+mod screenshot;
 mod sky;
+mod waypoint;
+/// Snowfall which settles on and melts from high ground
+mod weather;
+/// Keeps the size and position of the window between launches
+mod window;
 
 use bevy::{
     asset::embedded_asset,
-    render::primitives::Aabb,
+    math::Affine3A,
+    render::primitives::{
+        Aabb,
+        Frustum,
+    },
     tasks::{
         block_on,
         futures_lite::future,
@@ -46,6 +74,9 @@ use bevy::{
 use chunk::{
     ChunkPosition,
     ChunkVoxels,
+    CHUNK_SIZE,
+    CHUNK_SIZE_I,
+    MAX_HEIGHT,
 };
 use mesh::HasMesh;
 
@@ -56,7 +87,6 @@ use bevy::{
         WireframePlugin,
     },
     prelude::*,
-    window::PresentMode,
 };
 
 #[cfg(feature = "wireframe")]
@@ -78,14 +108,94 @@ use rand::{
     thread_rng,
     Rng,
 };
+use std::ops::RangeInclusive;
 
 #[derive(Resource)]
 struct Settings {
     load_distance: usize,
+    /// Accumulate mouse motion and apply it at a fixed rate
+    fixed_rate_look: bool,
+    /// Maximum number of asynchronous chunk meshing tasks to start each frame
+    mesh_tasks_per_frame: usize,
+    /// Maximum depth of chunk octrees before voxels are stored densely
+    octree_max_depth: u32,
+    /// Side length, in chunks, of groups of chunks generated by a single task
+    terrain_group_size: usize,
+    /// Distance within which distant chunks are drawn as impostors, if
+    /// larger than `load_distance`
+    impostor_distance: usize,
+    /// Draw voxels in the colours of the [`material::BlockPalette`] rather
+    /// than textured
+    flat_colors: bool,
+}
+
+/// Settings as though no arguments were given, for tests to override the
+/// ones they care about
+#[cfg(test)]
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            load_distance: 16,
+            fixed_rate_look: false,
+            mesh_tasks_per_frame: 8,
+            octree_max_depth: 4,
+            terrain_group_size: 1,
+            impostor_distance: 0,
+            flat_colors: false,
+        }
+    }
+}
+
+impl Settings {
+    /// Distance at which fog hides everything, past both the loaded chunks
+    /// and their impostors
+    fn fog_distance(&self) -> usize {
+        self.load_distance.max(self.impostor_distance)
+    }
+
+    /// Change the load distance, kept within [`LOAD_DISTANCE_RANGE`].
+    /// Returns whether it changed
+    fn set_load_distance(&mut self, load_distance: usize) -> bool {
+        let load_distance =
+            load_distance.clamp(*LOAD_DISTANCE_RANGE.start(), *LOAD_DISTANCE_RANGE.end());
+        let changed = load_distance != self.load_distance;
+        self.load_distance = load_distance;
+        changed
+    }
+}
+
+/// Load distances which can be set, small enough to still generate
+/// chunks faster than the player can fly
+const LOAD_DISTANCE_RANGE: RangeInclusive<usize> = 2..=64;
+
+/// Sent when [`Settings`] are changed while playing
+#[derive(Event)]
+struct SettingsChangedEvent;
+
+/// Whether the player is in the world or in the pause menu
+#[derive(States, Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum AppState {
+    #[default]
+    Playing,
+    Paused,
 }
 
 fn main() {
     let args = argh::from_env::<args::Arguments>();
+    let mesh_settings = mesh::MeshSettings {
+        normals: args.mesh_normals,
+        batched_culling: args.batched_culling,
+        cull_world_edges: args.cull_world_edges,
+        water: args.water_transparency,
+        stats: args.mesh_stats,
+    };
+    #[cfg(feature = "debug")]
+    if let Some(path) = &args.mesh_chunk_dump {
+        if let Err(err) = debug::mesh_chunk_dump(path, mesh_settings) {
+            eprintln!("Failed to mesh chunk dump `{}`: {err:#}", path.display());
+        }
+        return;
+    }
     let mut app = App::new();
 
     let mut default_plugins = DefaultPlugins.build();
@@ -104,14 +214,31 @@ fn main() {
         });
     }
 
+    let window_state = match window::WindowState::from_file(&args.window_state) {
+        Ok(state) => Some(state),
+        Err(err) => {
+            if args.window_state.exists() {
+                warn!(
+                    "Failed to restore window from `{}`: {err:#}",
+                    args.window_state.display()
+                );
+            }
+            None
+        }
+    };
+    let window = window_state.map_or_else(Window::default, |state| state.window());
+    app.insert_resource(window_state.unwrap_or(window::WindowState {
+        position: None,
+        size: Vec2::new(window.width(), window.height()),
+    }));
+    app.insert_resource(window::WindowStatePath(args.window_state.clone()));
+    app.insert_resource(screenshot::ScreenshotDir(args.screenshot_dir.clone()));
+    app.init_resource::<screenshot::ScreenshotResults>();
+    app.init_resource::<screenshot::HiddenForScreenshot>();
     default_plugins = default_plugins.set(WindowPlugin {
         primary_window: Some(Window {
-            present_mode: if args.enable_vsync {
-                PresentMode::AutoVsync
-            } else {
-                PresentMode::AutoNoVsync
-            },
-            ..default()
+            present_mode: args.present_mode(),
+            ..window
         }),
         ..default()
     });
@@ -120,44 +247,179 @@ fn main() {
 
     embedded_asset!(app, "../assets/images/blocks.png");
     embedded_asset!(app, "../assets/images/crosshair.png");
-    embedded_asset!(app, "../assets/images/toolbar.png");
     embedded_asset!(app, "../assets/images/selected.png");
     embedded_asset!(app, "../assets/shaders/voxel.wgsl");
     embedded_asset!(app, "../assets/shaders/sun.wgsl");
+    #[cfg(feature = "audio")]
+    audio::embed_sounds(&mut app);
 
     app.add_plugins(MaterialPlugin::<VoxelMaterial>::default());
     app.add_plugins(MaterialPlugin::<SunMaterial>::default());
+    app.init_resource::<health::Health>();
+    app.init_resource::<health::DamageFlash>();
+    app.init_resource::<health::FallTracker>();
+    app.insert_resource(player::WaterOverlay::new(args.water_fade));
     app.init_resource::<highlight::SelectedVoxel>();
+    app.init_resource::<input::BreakingState>();
+    app.init_resource::<input::EditCounter>();
     app.init_resource::<input::CameraVelocity>();
+    app.insert_resource(audio::AudioSettings::new(args.volume));
+    app.init_resource::<audio::PlayerSounds>();
     app.init_resource::<input::InputState>();
+    app.init_resource::<input::LookAccumulator>();
     app.init_resource::<input::QuitCounter>();
+    app.init_resource::<ui::Toolbar>();
+    app.init_resource::<player::CameraFov>();
+    app.insert_resource(sky::DayLength(args.day_length));
+    app.insert_resource(batch::MeshBatches::new(args.mesh_batch_size));
+    app.insert_resource(mesh_settings);
+    app.init_resource::<world::WorldDiagnostics>();
+    app.insert_resource(player::SpawnOrientation {
+        yaw: args.yaw.to_radians(),
+        pitch: args.pitch.to_radians(),
+    });
     app.insert_resource(Settings {
-        load_distance: args.load_distance,
+        load_distance: args
+            .load_distance
+            .clamp(*LOAD_DISTANCE_RANGE.start(), *LOAD_DISTANCE_RANGE.end()),
+        fixed_rate_look: args.fixed_rate_look,
+        mesh_tasks_per_frame: args.mesh_tasks_per_frame,
+        octree_max_depth: args.octree_max_depth,
+        terrain_group_size: args.terrain_group_size,
+        impostor_distance: args.impostor_distance,
+        flat_colors: args.flat_colors,
     });
+    app.insert_resource(args.block_colors.unwrap_or_default());
 
     app.add_systems(
         Update,
         (
-            terrain::queue_generate_chunk_terrain
-                .run_if(run_once().or_else(on_event::<player::PlayerMovedEvent>())),
+            terrain::queue_generate_chunk_terrain.run_if(
+                run_once()
+                    .or_else(on_event::<player::PlayerMovedEvent>())
+                    .or_else(on_event::<SettingsChangedEvent>()),
+            ),
             terrain::handle_generated_chunk_terrain,
+            light::spread_light_between_chunks.after(terrain::handle_generated_chunk_terrain),
         ),
     );
+    if args.impostor_distance > args.load_distance {
+        app.add_systems(Startup, impostor::setup_impostors.after(make_camera))
+            .add_systems(
+                Update,
+                (
+                    impostor::queue_impostors.run_if(
+                        run_once()
+                            .or_else(on_event::<player::PlayerMovedEvent>())
+                            .or_else(on_event::<SettingsChangedEvent>()),
+                    ),
+                    impostor::handle_impostor_tasks,
+                    impostor::remove_loaded_impostors,
+                ),
+            );
+    }
 
-    if let Some(save) = &args.save_file {
-        app.insert_resource(save::SaveData::from_file(save));
+    let save = args.save_file.as_ref().map(save::SaveData::from_file);
+    app.insert_resource(save::SaveSizeLimit {
+        // Limits too large to count in bytes are no limit at all
+        max_bytes: args.max_save_mib.saturating_mul(1024 * 1024),
+        refuse: args.refuse_large_saves,
+    });
+    app.insert_resource(save::SavePath(args.save_path.clone()));
+    app.init_resource::<save::SaveTask>();
+    if args.autosave > 0.0 {
+        app.insert_resource(save::Autosave::new(args.autosave));
+    }
+    if args.remesh_load_edges {
+        app.add_systems(
+            PostUpdate,
+            mesh::remesh_on_neighbour_load.before(queue_chunk_meshes),
+        );
+    }
+    if args.show_edit_counter {
+        app.add_systems(Startup, ui::spawn_edit_counter)
+            .add_systems(
+                Update,
+                ui::update_edit_counter.run_if(resource_changed::<input::EditCounter>),
+            );
+    }
+    if args.weather_interval > 0.0 {
+        app.insert_resource(weather::Weather::new(args.weather_interval))
+            .add_systems(
+                Update,
+                weather::update_weather.run_if(in_state(AppState::Playing)),
+            );
     }
 
-    let seed = if let Some(seed) = args.seed {
-        if args.save_file.is_some() {
-            error!("Both `seed` and `load` are set");
+    let mut world = if let Some(save) = &save {
+        // The save records how its terrain was generated
+        for (flag, set) in [
+            ("seed", args.seed.is_some()),
+            ("flat", args.flat),
+            ("density-terrain", args.density_terrain),
+            ("blend-seed", args.blend_seed.is_some()),
+        ] {
+            if set {
+                error!("Both `{flag}` and `load` are set");
+                return;
+            }
+        }
+        // Regenerate the same terrain the save was made against
+        save.world()
+    } else if args.flat {
+        if args.seed.is_some() {
+            error!("Both `seed` and `flat` are set");
+            return;
+        }
+        if args.density_terrain {
+            error!("Both `density-terrain` and `flat` are set");
+            return;
+        }
+        if args.blend_seed.is_some() {
+            error!("Both `blend-seed` and `flat` are set");
             return;
         }
-        seed
+        world::World::flat()
     } else {
-        thread_rng().gen()
+        let seed = args.seed.unwrap_or_else(|| thread_rng().gen());
+        if args.density_terrain {
+            if args.blend_seed.is_some() {
+                error!("Both `blend-seed` and `density-terrain` are set");
+                return;
+            }
+            world::World::density(seed)
+        } else if let Some(blend_seed) = args.blend_seed {
+            world::World::blended(seed, blend_seed)
+        } else {
+            world::World::from_seed(seed)
+        }
     };
-    app.insert_resource(world::World::from_seed(seed));
+    world.border = match (args.border, &save) {
+        (Some(border), _) => border,
+        (None, Some(save)) => match save.border() {
+            Ok(border) => border,
+            Err(err) => {
+                error!("Invalid save: {err}");
+                return;
+            }
+        },
+        (None, None) => default(),
+    };
+    if let Some(dir) = &args.chunk_cache {
+        let cache = cache::ChunkCache::new(dir, world.seed, &world.generator);
+        if let Err(err) = cache.clear() {
+            error!("Failed to clear the chunk cache: {err:#}");
+            return;
+        }
+        app.insert_resource(cache);
+    }
+    app.insert_resource(world);
+    if let Some(save) = save {
+        app.insert_resource(waypoint::Waypoints::new(save.waypoints().to_vec()));
+        app.insert_resource(save);
+    } else {
+        app.init_resource::<waypoint::Waypoints>();
+    }
 
     #[cfg(feature = "wireframe")]
     {
@@ -175,7 +437,8 @@ fn main() {
             make_camera,
             sky::add_sun,
             material::make_voxel_material,
-            ui::draw_ui,
+            ui::draw_ui.after(material::make_voxel_material),
+            highlight::spawn_selection_outline,
         ),
     )
     .add_systems(Update, material::process_block_texture)
@@ -186,42 +449,168 @@ fn main() {
             input::handle_rmb,
             input::handle_movement_keys,
             input::handle_special_keys,
-            input::player_look,
+            input::handle_load_distance_keys,
+            waypoint::handle_waypoint_keys,
+            audio::handle_mute_key,
+            screenshot::handle_screenshot_key.after(screenshot::restore_hidden_ui),
         )
             .in_set(input::InputSet),
     )
+    .add_event::<screenshot::ScreenshotSavedEvent>()
+    .add_systems(
+        Update,
+        (
+            screenshot::restore_hidden_ui,
+            screenshot::handle_screenshot_results.before(ui::update_saved_indicator),
+        ),
+    )
+    .add_event::<audio::SoundEvent>()
+    .add_event::<world::ChunkEditedEvent>()
+    .add_systems(
+        Update,
+        world::handle_chunk_edits
+            .after(input::InputSet)
+            .run_if(on_event::<world::ChunkEditedEvent>()),
+    )
+    .add_event::<input::SlotSelectedEvent>()
+    .add_systems(
+        Update,
+        ui::move_slot_highlight
+            .after(input::handle_special_keys)
+            .run_if(on_event::<input::SlotSelectedEvent>()),
+    )
+    .init_state::<AppState>()
+    .configure_sets(Update, input::InputSet.run_if(in_state(AppState::Playing)))
+    .add_systems(Update, input::handle_pause_key)
+    .add_systems(
+        OnEnter(AppState::Paused),
+        (input::release_cursor, ui::spawn_pause_menu),
+    )
+    .add_systems(
+        OnExit(AppState::Paused),
+        (input::grab_cursor, ui::despawn_pause_menu),
+    )
+    .add_systems(
+        Update,
+        ui::handle_pause_buttons.run_if(in_state(AppState::Paused)),
+    )
     .add_event::<input::SaveEvent>()
+    .add_event::<save::SavedEvent>()
+    .add_systems(
+        Update,
+        (
+            save::tick_autosave.run_if(resource_exists::<save::Autosave>),
+            ui::update_saved_indicator,
+        ),
+    )
     .add_systems(
         PostUpdate,
         (
             queue_chunk_meshes,
             handle_mesh_tasks,
+            batch::rebuild_mesh_batches.after(handle_mesh_tasks),
             world::process_save_events.run_if(on_event::<input::SaveEvent>()),
+            save::handle_save_task.after(world::process_save_events),
         ),
     )
+    .insert_resource(highlight::HighlightUpdates::new(
+        args.skip_unchanged_highlight,
+    ))
     .add_event::<highlight::UpdateHighlightedEvent>()
     .add_systems(
         Update,
         highlight::update_selected_voxel.run_if(on_event::<highlight::UpdateHighlightedEvent>()),
     )
-    .add_systems(Update, sky::update_sun_position.after(player::player_move))
+    .add_systems(
+        Update,
+        highlight::show_break_progress
+            .after(input::InputSet)
+            .run_if(resource_changed::<input::BreakingState>),
+    )
+    .add_event::<SettingsChangedEvent>()
+    .add_systems(
+        Update,
+        apply_view_distance
+            .after(input::InputSet)
+            .run_if(on_event::<SettingsChangedEvent>()),
+    )
+    .add_systems(
+        Update,
+        sky::update_sun_position
+            .after(player::player_move)
+            .after(apply_view_distance),
+    )
+    .add_event::<health::DamageEvent>()
+    .add_systems(
+        Update,
+        (
+            health::apply_damage.after(player::player_move),
+            health::show_damage_flash.after(player::player_move),
+            ui::update_health_bar
+                .after(health::apply_damage)
+                .run_if(resource_changed::<health::Health>),
+        ),
+    )
     .add_systems(Startup, input::hook_cursor)
+    .add_systems(
+        Update,
+        (window::restore_window_position, window::track_window_state),
+    )
+    .add_systems(Last, window::save_window_state)
     .add_systems(Update, input::player_look)
+    .add_systems(
+        Update,
+        input::regrab_cursor_on_focus.before(input::player_look),
+    )
     .add_event::<player::PlayerMovedEvent>()
-    .add_systems(Update, player::player_move.after(input::InputSet));
+    .add_systems(
+        Update,
+        player::player_move
+            .after(input::InputSet)
+            .run_if(in_state(AppState::Playing)),
+    )
+    .add_systems(Update, player::update_sprint_fov.after(input::InputSet));
+
+    #[cfg(feature = "audio")]
+    {
+        app.add_systems(Startup, audio::load_sounds).add_systems(
+            Update,
+            audio::play_sounds
+                .after(input::InputSet)
+                .after(player::player_move),
+        );
+    }
+    #[cfg(not(feature = "audio"))]
+    app.add_systems(
+        Update,
+        audio::log_sounds
+            .after(input::InputSet)
+            .after(player::player_move),
+    );
 
     #[cfg(feature = "debug")]
-    app.add_plugins(debug::DebugUiPlugins);
+    {
+        app.add_plugins(debug::DebugUiPlugins);
+        if let Some(length) = args.axis_gizmo {
+            app.insert_resource(debug::AxisGizmo { length });
+        }
+    }
 
     app.run();
 }
 
-fn make_camera(mut commands: Commands) {
+fn make_camera(
+    mut commands: Commands,
+    orientation: Res<player::SpawnOrientation>,
+    world: Res<world::World>,
+    settings: Res<Settings>,
+) {
     let bundle = Camera3dBundle {
-        transform: Transform::from_xyz(8.0, 4.5 + 128.0, 8.0),
+        transform: Transform::from_translation(player::spawn_position(&world.generator))
+            .with_rotation(orientation.rotation()),
         projection: Projection::Perspective(PerspectiveProjection {
             near: 0.1,
-            far: 4096.0,
+            far: far_plane(&settings),
             ..default()
         }),
         camera: Camera {
@@ -233,11 +622,39 @@ fn make_camera(mut commands: Commands) {
     commands.spawn(bundle);
 }
 
+/// Distance past which nothing is drawn, far enough to take in the corners
+/// of the fogged terrain from high above
+fn far_plane(settings: &Settings) -> f32 {
+    let fog_end = material::fog_params(settings.fog_distance()).y;
+    fog_end * 2.0 + MAX_HEIGHT as f32
+}
+
+/// Fit the fog and the far plane to a changed load distance
+fn apply_view_distance(
+    settings: Res<Settings>,
+    mut camera: Query<(&mut Projection, Option<&mut FogSettings>), With<Camera>>,
+    material_handle: Res<VoxelMaterialResource>,
+    mut materials: ResMut<Assets<VoxelMaterial>>,
+) {
+    let fog = material::fog_params(settings.fog_distance());
+    for handle in material_handle.handles() {
+        materials.get_mut(handle).unwrap().set_fog_params(fog);
+    }
+    let (mut projection, fog_settings) = camera.single_mut();
+    if let Projection::Perspective(projection) = projection.as_mut() {
+        projection.far = far_plane(&settings);
+    }
+    // Only impostors are drawn with Bevy's fog
+    if let Some(mut fog_settings) = fog_settings {
+        fog_settings.falloff = impostor::fog_falloff(fog);
+    }
+}
+
 #[derive(Component)]
 enum ChunkMeshingTask {
     Async {
         pos: ChunkPosition,
-        task: Task<Mesh>,
+        task: Task<mesh::ChunkMeshes>,
     },
     Sync {
         pos: ChunkPosition,
@@ -247,6 +664,10 @@ enum ChunkMeshingTask {
     },
 }
 
+/// Chunks this many chunks or fewer from the player's are meshed before the
+/// next frame, so the ground around the player never goes missing
+const SYNC_MESH_RANGE: usize = 2;
+
 /// Marker component for chunks indicating they should be updated synchronously
 /// (before the next frame)
 #[derive(Component)]
@@ -260,15 +681,21 @@ fn queue_chunk_meshes(
     >,
     all_chunks: Query<&ChunkVoxels>,
     world: Res<world::World>,
+    settings: Res<Settings>,
+    mesh_settings: Res<mesh::MeshSettings>,
+    camera: Query<(&Transform, &Frustum), With<Camera>>,
+    mut diagnostics: ResMut<world::WorldDiagnostics>,
 ) {
+    let mesh_settings = *mesh_settings;
     let task_pool = AsyncComputeTaskPool::get();
-    for (ent, chunk_pos, chunk, sync) in dirty_chunks
-        .iter()
-        .map(|(e, pos, c, sync)| (e, *pos, c.clone(), sync.is_some()))
-    {
-        // get all adjacent chunks
-        let mut adj_chunks = Vec::with_capacity(4);
-        for chunk_pos in chunk_pos.neighbouring_chunks().all() {
+    let (camera_transform, frustum) = camera.single();
+    let player_pos = camera_transform.translation.as_ivec3().with_y(0);
+    let player_chunk = ChunkPosition::from(player_pos);
+
+    // get all adjacent chunks
+    let adjacent_chunks = |chunk_pos: ChunkPosition| {
+        let mut adj_chunks = Vec::with_capacity(8);
+        for chunk_pos in chunk_pos.neighbouring_chunks().all8() {
             let Some(chunk) = world
                 .chunk_at(chunk_pos)
                 .and_then(|e| all_chunks.get(e).ok().cloned())
@@ -277,20 +704,58 @@ fn queue_chunk_meshes(
             };
             adj_chunks.push((chunk_pos, chunk));
         }
+        adj_chunks
+    };
 
-        if sync || chunk_pos.in_range_of_spawn(2) {
-            commands.entity(ent).insert(ChunkMeshingTask::Sync {
-                pos: chunk_pos,
-                voxels: Box::new(chunk),
-                adjacent: adj_chunks,
-            });
+    let mut async_chunks = Vec::new();
+    for (ent, &chunk_pos, chunk, sync) in dirty_chunks.iter() {
+        if sync.is_some() || chunk_pos.in_range_of(player_chunk, SYNC_MESH_RANGE) {
+            let adjacent = adjacent_chunks(chunk_pos);
+            commands.entity(ent).insert((
+                mesh::MissingNeighbours::of(chunk_pos, &adjacent),
+                ChunkMeshingTask::Sync {
+                    pos: chunk_pos,
+                    voxels: Box::new(chunk.clone()),
+                    adjacent,
+                },
+            ));
         } else {
-            let task = async move { mesh::from_chunk((chunk_pos, &chunk), &adj_chunks) };
-            commands.entity(ent).insert(ChunkMeshingTask::Async {
+            async_chunks.push((ent, chunk_pos, chunk));
+        }
+    }
+
+    // Mesh chunks the player can see first, nearest first, so the area in front
+    // of the player fills in before the rest
+    let chunk_aabb = Aabb::from_min_max(
+        Vec3::ZERO,
+        Vec3::new(CHUNK_SIZE as f32, MAX_HEIGHT as f32, CHUNK_SIZE as f32),
+    );
+    async_chunks.sort_by_cached_key(|(_, chunk_pos, _)| {
+        let visible = frustum.intersects_obb(
+            &chunk_aabb,
+            &Affine3A::from_translation(chunk_pos.as_vec3()),
+            true,
+            false,
+        );
+        let centre = chunk_pos.as_ivec3() + IVec3::new(CHUNK_SIZE_I / 2, 0, CHUNK_SIZE_I / 2);
+        (!visible, (centre - player_pos).length_squared())
+    });
+
+    // At least one task is started, or distant chunks would never be meshed
+    let tasks_per_frame = settings.mesh_tasks_per_frame.max(1);
+    diagnostics.awaiting_mesh = async_chunks.len().saturating_sub(tasks_per_frame);
+    for (ent, chunk_pos, chunk) in async_chunks.into_iter().take(tasks_per_frame) {
+        let chunk = chunk.clone();
+        let adj_chunks = adjacent_chunks(chunk_pos);
+        let missing = mesh::MissingNeighbours::of(chunk_pos, &adj_chunks);
+        let task = async move { mesh::from_chunk((chunk_pos, &chunk), &adj_chunks, mesh_settings) };
+        commands.entity(ent).insert((
+            missing,
+            ChunkMeshingTask::Async {
                 task: task_pool.spawn(task),
                 pos: chunk_pos,
-            });
-        };
+            },
+        ));
     }
 }
 
@@ -299,12 +764,19 @@ fn handle_mesh_tasks(
     mut tasks: Query<(Entity, &mut ChunkMeshingTask)>,
     mut meshes: ResMut<Assets<Mesh>>,
     materials: Res<VoxelMaterialResource>,
+    mesh_settings: Res<mesh::MeshSettings>,
+    mut batches: ResMut<batch::MeshBatches>,
+    mut diagnostics: ResMut<world::WorldDiagnostics>,
 ) {
+    let mut meshing_async = 0;
     let mut completed_tasks = tasks
         .iter_mut()
         .filter_map(|(chunk, mut task)| match &mut *task {
             ChunkMeshingTask::Async { pos, task } => {
-                let mesh = block_on(future::poll_once(task))?;
+                let Some(mesh) = block_on(future::poll_once(task)) else {
+                    meshing_async += 1;
+                    return None;
+                };
                 Some((mesh, chunk, *pos))
             }
             _ => None,
@@ -325,32 +797,74 @@ fn handle_mesh_tasks(
             }
         })
         .collect::<Vec<_>>();
+    diagnostics.meshing_async = meshing_async;
+    diagnostics.meshed_sync = sync_tasks.len();
     let sync_tasks = sync_tasks.par_splat_map(ComputeTaskPool::get(), None, |_, tasks| {
         tasks
             .iter()
             .map(|(ent, pos, voxels, adjacent)| {
-                let mesh = mesh::from_chunk((*pos, *voxels), adjacent);
+                let mesh = mesh::from_chunk((*pos, *voxels), adjacent, *mesh_settings);
                 (mesh, *ent, *pos)
             })
             .collect::<Vec<_>>()
     });
 
     completed_tasks.extend(sync_tasks.into_iter().flat_map(|v| v.into_iter()));
+    diagnostics.meshes_completed += completed_tasks.len() as u64;
+    for (chunk_meshes, ..) in &completed_tasks {
+        if let Some(stats) = chunk_meshes.stats {
+            diagnostics.mesh_stats += stats;
+        }
+    }
+
+    for (chunk_meshes, ent, pos) in completed_tasks {
+        let mut chunk = commands.entity(ent);
+        chunk.insert(HasMesh).remove::<ChunkMeshingTask>();
+        // Transparent voxels are drawn by a child of the chunk, replaced each
+        // time the chunk is meshed
+        chunk.despawn_descendants();
+        if let Some(transparent) = chunk_meshes.transparent {
+            let aabb = mesh::packed_aabb(&transparent);
+            chunk.with_children(|parent| {
+                parent.spawn((
+                    Name::new("Chunk Transparent"),
+                    MaterialMeshBundle {
+                        mesh: meshes.add(transparent),
+                        material: materials.transparent_handle.clone(),
+                        ..default()
+                    },
+                    aabb.expect("transparent mesh has vertices"),
+                ));
+            });
+        }
 
-    for (mesh, ent, pos) in completed_tasks {
-        let mesh = meshes.add(mesh);
+        let aabb = mesh::packed_aabb(&chunk_meshes.opaque);
+        let mesh = meshes.add(chunk_meshes.opaque);
+        if batches.enabled() {
+            batches.insert_mesh(pos, mesh);
+            // Transparent meshes aren't batched, so still need the chunk's
+            // transform
+            chunk.insert(SpatialBundle::from_transform(Transform::from_translation(
+                pos.as_vec3(),
+            )));
+            continue;
+        }
         let material = materials.handle.clone();
-        commands
-            .entity(ent)
-            .insert(MaterialMeshBundle {
-                mesh,
-                transform: Transform::from_translation(pos.as_vec3()),
-                material,
-                ..default()
-            })
-            .insert(HasMesh)
-            // Force AABB to be recalculated so we get correct frustrum culling
-            .remove::<Aabb>()
-            .remove::<ChunkMeshingTask>();
+        chunk.insert(MaterialMeshBundle {
+            mesh,
+            transform: Transform::from_translation(pos.as_vec3()),
+            material,
+            ..default()
+        });
+        // Bevy can't compute the AABB of packed positions, which is needed
+        // for correct frustrum culling. Empty meshes have nothing to cull
+        match aabb {
+            Some(aabb) => {
+                chunk.insert(aabb);
+            }
+            None => {
+                chunk.remove::<Aabb>();
+            }
+        }
     }
 }
@@ -1,4 +1,13 @@
-use crate::mesh;
+use std::str::FromStr;
+
+use crate::{
+    chunk::CHUNK_SIZE,
+    mesh::{
+        self,
+        MeshSettings,
+    },
+    voxel::VoxelKind,
+};
 use bevy::{
     asset::{
         Asset,
@@ -11,7 +20,9 @@ use bevy::{
     },
     math::{
         vec3,
+        vec4,
         Vec3,
+        Vec4,
     },
     pbr::{
         Material,
@@ -23,6 +34,7 @@ use bevy::{
         mesh::MeshVertexBufferLayoutRef,
         render_resource::{
             AsBindGroup,
+            Face,
             RenderPipelineDescriptor,
             ShaderRef,
             SpecializedMeshPipelineError,
@@ -34,19 +46,39 @@ use bevy::{
 pub fn make_voxel_material(
     mut commands: Commands,
     mut materials: ResMut<Assets<VoxelMaterial>>,
+    images: Res<Assets<Image>>,
     assets: Res<AssetServer>,
+    settings: Res<crate::Settings>,
+    mesh_settings: Res<MeshSettings>,
+    palette: Res<BlockPalette>,
 ) {
     let img_handle = assets.load("embedded://cormine/../assets/images/blocks.png");
-    let handle = materials.add(VoxelMaterial {
+    let icon_handle = images.reserve_handle();
+    let material = VoxelMaterial {
         light_color: Srgba::WHITE.into(),
         light_dir: vec3(1.0, 1.0, 1.0),
-        selected_voxel: Vec3::ZERO,
-        has_selected: 0,
         block_textures: img_handle.clone(),
+        camera_medium: CameraMedium::Air as u32,
+        fog_params: fog_params(settings.fog_distance()),
+        fog_color: LinearRgba::BLACK,
+        palette: palette.colors,
+        alpha_mode: AlphaMode::Opaque,
+        cull_backfaces: true,
+        flat_colors: settings.flat_colors,
+    };
+    // Transparent faces are seen from both sides, such as the far side of a
+    // glass voxel through its near side
+    let transparent_handle = materials.add(VoxelMaterial {
+        alpha_mode: mesh_settings.water.alpha_mode(),
+        cull_backfaces: false,
+        ..material.clone()
     });
+    let handle = materials.add(material);
     commands.insert_resource(VoxelMaterialResource {
         handle,
+        transparent_handle,
         img_handle,
+        icon_handle,
         textures_loaded: false,
     });
 }
@@ -62,40 +94,297 @@ pub fn process_block_texture(
     let Some(image) = images.get_mut(&material.img_handle) else {
         return;
     };
-    image.reinterpret_stacked_2d_as_array(6);
+    // Keep a plain 2D copy of the textures for use as UI icons
+    let icons = image.clone();
+    image.reinterpret_stacked_2d_as_array(BLOCK_TEXTURE_COUNT);
     image.sampler = ImageSampler::nearest();
+    images.insert(&material.icon_handle, icons);
     material.textures_loaded = true;
 }
 
+/// Number of textures stacked vertically in `blocks.png`
+const BLOCK_TEXTURE_COUNT: u32 = 11;
+
+/// Textures after the first one of each [`VoxelKind`], which is at the index
+/// of the kind itself.
+///
+/// Side faces sample their texture sideways, with the left edge of the tile
+/// at the top of the face
+const GRASS_SIDE_TEXTURE: u32 = 10;
+
+/// Index into `blocks.png` of the texture for each face of a voxel kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaceTextures {
+    pub top: u32,
+    pub side: u32,
+    pub bottom: u32,
+}
+
+impl FaceTextures {
+    pub fn of(kind: VoxelKind) -> Self {
+        match kind {
+            VoxelKind::Grass => Self {
+                top: VoxelKind::Grass as u32,
+                side: GRASS_SIDE_TEXTURE,
+                bottom: VoxelKind::Dirt as u32,
+            },
+            kind => Self {
+                top: kind as u32,
+                side: kind as u32,
+                bottom: kind as u32,
+            },
+        }
+    }
+
+    /// Texture of the face pointing in `direction`
+    pub fn for_face(&self, direction: IVec3) -> u32 {
+        match direction.y {
+            1 => self.top,
+            -1 => self.bottom,
+            _ => self.side,
+        }
+    }
+}
+
+/// Colour of each voxel kind from a distance, averaged from its texture
+pub fn kind_color(kind: VoxelKind) -> Srgba {
+    match kind {
+        VoxelKind::Stone => Srgba::rgb(0.52, 0.5, 0.53),
+        VoxelKind::Grass => Srgba::rgb(0.41, 0.74, 0.19),
+        VoxelKind::Water => Srgba::rgb(0.39, 0.61, 1.0),
+        VoxelKind::Snow => Srgba::WHITE,
+        VoxelKind::Dirt => Srgba::rgb(0.4, 0.22, 0.19),
+        VoxelKind::Bedrock => Srgba::rgb(0.11, 0.11, 0.11),
+        VoxelKind::Wood => Srgba::rgb(0.39, 0.29, 0.17),
+        VoxelKind::Leaves => Srgba::rgb(0.18, 0.42, 0.15),
+        VoxelKind::Tnt => Srgba::rgb(0.71, 0.38, 0.34),
+        VoxelKind::Glass => Srgba::rgb(0.76, 0.87, 0.91),
+        VoxelKind::Air => Srgba::NONE,
+    }
+}
+
+/// Number of colours in a [`BlockPalette`], enough for every meshed
+/// [`VoxelKind`]
+pub const PALETTE_SIZE: usize = 16;
+/// Opacity of transparent voxels drawn with flat colours
+const TRANSPARENT_PALETTE_ALPHA: f32 = 0.6;
+
+/// Solid colour each [`VoxelKind`] is drawn with instead of its textures,
+/// when flat colours are enabled
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct BlockPalette {
+    /// Indexed by voxel kind, as packed into each vertex
+    colors: [LinearRgba; PALETTE_SIZE],
+}
+
+impl Default for BlockPalette {
+    /// Colours averaged from each kind's textures
+    fn default() -> Self {
+        let mut palette = Self {
+            colors: [LinearRgba::NONE; PALETTE_SIZE],
+        };
+        for kind in meshed_kinds() {
+            let alpha = if kind.transparent() {
+                TRANSPARENT_PALETTE_ALPHA
+            } else {
+                1.0
+            };
+            palette.set(kind, kind_color(kind).with_alpha(alpha));
+        }
+        palette
+    }
+}
+
+impl BlockPalette {
+    #[cfg(test)]
+    pub fn color(&self, kind: VoxelKind) -> LinearRgba {
+        self.colors
+            .get(kind as usize)
+            .copied()
+            .unwrap_or(LinearRgba::NONE)
+    }
+
+    pub fn set(&mut self, kind: VoxelKind, color: impl Into<LinearRgba>) {
+        if let Some(slot) = self.colors.get_mut(kind as usize) {
+            *slot = color.into();
+        }
+    }
+}
+
+/// Every voxel kind which is meshed, and so needs a colour
+fn meshed_kinds() -> impl Iterator<Item = VoxelKind> {
+    (0..PALETTE_SIZE as u8).filter_map(|kind| VoxelKind::try_from(kind).ok())
+}
+
+impl FromStr for BlockPalette {
+    type Err = String;
+
+    /// Default palette with some colours replaced, written as
+    /// `<kind>=<hex colour>` separated by commas, such as `stone=#808080`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut palette = Self::default();
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let Some((name, color)) = entry.split_once('=') else {
+                return Err(format!("expected `<kind>=<colour>`, got `{entry}`"));
+            };
+            let kind = meshed_kinds()
+                .find(|kind| format!("{kind:?}").eq_ignore_ascii_case(name.trim()))
+                .ok_or_else(|| format!("unknown voxel kind `{}`", name.trim()))?;
+            let color = Srgba::hex(color.trim())
+                .map_err(|err| format!("invalid colour for {kind:?}: {err}"))?;
+            palette.set(kind, color);
+        }
+        Ok(palette)
+    }
+}
+
+/// What the camera is inside of, which decides how fog is drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum CameraMedium {
+    Air = 0,
+    Water = 1,
+}
+
+/// Distances at which fog starts and is complete, in the air as `x` and `y`
+/// and underwater as `z` and `w`. Fog in the air is complete at the edge of
+/// the loaded chunks, hiding them as they load in, or of their impostors
+pub fn fog_params(fog_distance: usize) -> Vec4 {
+    let fog_radius = (fog_distance / 2 * CHUNK_SIZE) as f32;
+    vec4(fog_radius * 0.6, fog_radius, 2.0, 24.0)
+}
+
 #[derive(Resource)]
 pub struct VoxelMaterialResource {
     pub(crate) handle: Handle<VoxelMaterial>,
+    /// Material of transparent voxels meshed separately from the rest of the
+    /// terrain, which is kept in sync with `handle`
+    pub(crate) transparent_handle: Handle<VoxelMaterial>,
     img_handle: Handle<Image>,
+    /// Copy of the block textures as a regular 2D image, for use in the UI
+    icon_handle: Handle<Image>,
     textures_loaded: bool,
 }
 
+impl VoxelMaterialResource {
+    /// Every voxel material, which share all of their uniforms
+    pub fn handles(&self) -> [&Handle<VoxelMaterial>; 2] {
+        [&self.handle, &self.transparent_handle]
+    }
+
+    pub fn icon_handle(&self) -> Handle<Image> {
+        self.icon_handle.clone()
+    }
+
+    pub fn texture_count(&self) -> u32 {
+        BLOCK_TEXTURE_COUNT
+    }
+}
+
 #[derive(AsBindGroup, Reflect, Asset, Debug, Clone)]
+#[bind_group_data(VoxelMaterialKey)]
 pub struct VoxelMaterial {
     #[uniform(1)]
     light_color: LinearRgba,
     #[uniform(2)]
     light_dir: Vec3,
-    #[uniform(3)]
-    pub selected_voxel: Vec3,
-    #[uniform(4)]
-    pub has_selected: u32,
     #[texture(5, dimension = "2d_array")]
     #[sampler(6)]
     block_textures: Handle<Image>,
+    /// A [`CameraMedium`]
+    #[uniform(7)]
+    camera_medium: u32,
+    #[uniform(8)]
+    fog_params: Vec4,
+    /// Colour of fog in the air. Fog underwater is always blue
+    #[uniform(9)]
+    fog_color: LinearRgba,
+    /// Colours of a [`BlockPalette`], drawn with `flat_colors`
+    #[uniform(10)]
+    palette: [LinearRgba; PALETTE_SIZE],
+    alpha_mode: AlphaMode,
+    /// Backfaces can be drawn to debug faces with the wrong winding
+    cull_backfaces: bool,
+    /// Draw each voxel in its palette colour rather than its textures
+    flat_colors: bool,
+}
+
+/// Settings of a [`VoxelMaterial`] its pipeline is specialized on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VoxelMaterialKey {
+    cull_backfaces: bool,
+    flat_colors: bool,
+}
+
+impl From<&VoxelMaterial> for VoxelMaterialKey {
+    fn from(material: &VoxelMaterial) -> Self {
+        Self {
+            cull_backfaces: material.cull_backfaces,
+            flat_colors: material.flat_colors,
+        }
+    }
+}
+
+impl VoxelMaterialKey {
+    fn specialize(&self, descriptor: &mut RenderPipelineDescriptor) {
+        descriptor.primitive.cull_mode = self.cull_backfaces.then_some(Face::Back);
+        if let (true, Some(fragment)) = (self.flat_colors, &mut descriptor.fragment) {
+            fragment.shader_defs.push("FLAT_COLORS".into());
+        }
+    }
 }
 
 impl VoxelMaterial {
     pub fn set_light_dir(&mut self, light_dir: Vec3) {
         self.light_dir = light_dir;
     }
+
+    pub fn set_light_color(&mut self, light_color: impl Into<LinearRgba>) {
+        self.light_color = light_color.into();
+    }
+
+    pub fn camera_medium(&self) -> CameraMedium {
+        match self.camera_medium {
+            1 => CameraMedium::Water,
+            _ => CameraMedium::Air,
+        }
+    }
+
+    pub fn set_camera_medium(&mut self, medium: CameraMedium) {
+        self.camera_medium = medium as u32;
+    }
+
+    pub fn set_fog_params(&mut self, fog_params: Vec4) {
+        self.fog_params = fog_params;
+    }
+
+    pub fn set_fog_color(&mut self, fog_color: impl Into<LinearRgba>) {
+        self.fog_color = fog_color.into();
+    }
+
+    pub fn cull_backfaces(&self) -> bool {
+        self.cull_backfaces
+    }
+
+    pub fn set_cull_backfaces(&mut self, cull_backfaces: bool) {
+        self.cull_backfaces = cull_backfaces;
+    }
+
+    #[cfg(test)]
+    pub fn set_flat_colors(&mut self, flat_colors: bool) {
+        self.flat_colors = flat_colors;
+    }
 }
 
 impl Material for VoxelMaterial {
+    fn alpha_mode(&self) -> AlphaMode {
+        self.alpha_mode
+    }
+
     fn vertex_shader() -> ShaderRef {
         "embedded://cormine/../assets/shaders/voxel.wgsl".into()
     }
@@ -106,12 +395,23 @@ impl Material for VoxelMaterial {
         _: &MaterialPipeline<Self>,
         descriptor: &mut RenderPipelineDescriptor,
         layout: &MeshVertexBufferLayoutRef,
-        _: MaterialPipelineKey<Self>,
+        key: MaterialPipelineKey<Self>,
     ) -> Result<(), SpecializedMeshPipelineError> {
-        let vtx_layout = layout.0.get_layout(&[
-            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
-            mesh::VOXEL_VERTEX_DATA.at_shader_location(1),
-        ])?;
+        key.bind_group_data.specialize(descriptor);
+        // Chunk meshes have packed positions, while batches of them combined
+        // into one mesh don't
+        let position = if layout.0.contains(mesh::VOXEL_PACKED_POSITION) {
+            descriptor
+                .vertex
+                .shader_defs
+                .push("PACKED_POSITIONS".into());
+            mesh::VOXEL_PACKED_POSITION.at_shader_location(0)
+        } else {
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0)
+        };
+        let vtx_layout = layout
+            .0
+            .get_layout(&[position, mesh::VOXEL_VERTEX_DATA.at_shader_location(1)])?;
         descriptor.vertex.buffers = vec![vtx_layout];
         Ok(())
     }
@@ -128,3 +428,113 @@ impl Material for SunMaterial {
         "embedded://cormine/../assets/shaders/sun.wgsl".into()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn fog_hides_edge_of_loaded_chunks() {
+        let fog = fog_params(16);
+        // Chunks are loaded 8 chunks out in each direction
+        assert_eq!(fog.y, 8.0 * CHUNK_SIZE as f32);
+        assert!(fog.x < fog.y);
+        // Fog underwater is much closer than in the air
+        assert!(fog.z < fog.w && fog.w < fog.x);
+        assert!(fog_params(32).y > fog.y);
+    }
+
+    #[test]
+    pub fn cull_mode_reaches_pipeline() {
+        use bevy::render::render_resource::{
+            FragmentState,
+            PrimitiveState,
+            VertexState,
+        };
+
+        let mut material = VoxelMaterial {
+            light_color: LinearRgba::WHITE,
+            light_dir: Vec3::ONE,
+            block_textures: Handle::default(),
+            camera_medium: CameraMedium::Air as u32,
+            fog_params: fog_params(16),
+            fog_color: LinearRgba::BLACK,
+            palette: BlockPalette::default().colors,
+            alpha_mode: AlphaMode::Opaque,
+            cull_backfaces: true,
+            flat_colors: false,
+        };
+        let mut descriptor = RenderPipelineDescriptor {
+            label: None,
+            layout: Vec::new(),
+            push_constant_ranges: Vec::new(),
+            vertex: VertexState {
+                shader: Handle::default(),
+                shader_defs: Vec::new(),
+                entry_point: "vertex".into(),
+                buffers: Vec::new(),
+            },
+            primitive: PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: Default::default(),
+            fragment: None,
+        };
+
+        VoxelMaterialKey::from(&material).specialize(&mut descriptor);
+        assert_eq!(descriptor.primitive.cull_mode, Some(Face::Back));
+        material.set_cull_backfaces(false);
+        VoxelMaterialKey::from(&material).specialize(&mut descriptor);
+        assert_eq!(descriptor.primitive.cull_mode, None);
+
+        let flat_colors = |material: &VoxelMaterial| {
+            let mut descriptor = descriptor.clone();
+            descriptor.fragment = Some(FragmentState {
+                shader: Handle::default(),
+                shader_defs: Vec::new(),
+                entry_point: "fragment".into(),
+                targets: Vec::new(),
+            });
+            VoxelMaterialKey::from(material).specialize(&mut descriptor);
+            descriptor
+                .fragment
+                .unwrap()
+                .shader_defs
+                .contains(&"FLAT_COLORS".into())
+        };
+        assert!(!flat_colors(&material));
+        material.set_flat_colors(true);
+        assert!(flat_colors(&material));
+    }
+
+    #[test]
+    pub fn block_palette_overrides_defaults() {
+        let default = BlockPalette::default();
+        assert_eq!(
+            default.color(VoxelKind::Stone),
+            kind_color(VoxelKind::Stone).into()
+        );
+        assert_eq!(default.color(VoxelKind::Air), LinearRgba::NONE);
+        assert!(default.color(VoxelKind::Glass).alpha < 1.0);
+
+        let palette: BlockPalette = "stone=#808080, Grass=#3a9d23ff".parse().unwrap();
+        assert_eq!(
+            palette.color(VoxelKind::Stone),
+            Srgba::rgb_u8(128, 128, 128).into()
+        );
+        assert_eq!(
+            palette.color(VoxelKind::Grass),
+            Srgba::rgb_u8(58, 157, 35).into()
+        );
+        for kind in
+            meshed_kinds().filter(|kind| !matches!(kind, VoxelKind::Stone | VoxelKind::Grass))
+        {
+            assert_eq!(palette.color(kind), default.color(kind));
+        }
+        assert_eq!("".parse::<BlockPalette>(), Ok(default));
+
+        assert!("stone".parse::<BlockPalette>().is_err());
+        assert!("lava=#ff0000".parse::<BlockPalette>().is_err());
+        assert!("air=#ff0000".parse::<BlockPalette>().is_err());
+        assert!("stone=grey".parse::<BlockPalette>().is_err());
+    }
+}
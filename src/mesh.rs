@@ -1,10 +1,14 @@
-use std::ops::Add;
+use std::{
+    ops::Add,
+    str::FromStr,
+};
 
 use bevy::{
     math::ivec3,
     prelude::*,
     render::{
         mesh::{
+            Indices,
             MeshVertexAttribute,
             PrimitiveTopology,
             VertexAttributeValues::{
@@ -12,6 +16,7 @@ use bevy::{
                 Float32x3,
             },
         },
+        primitives::Aabb,
         render_asset::RenderAssetUsages,
         render_resource::VertexFormat,
     },
@@ -27,54 +32,404 @@ use crate::{
         CHUNK_SIZE,
         MAX_HEIGHT,
     },
-    octree::OctantKind,
+    light::MAX_LIGHT,
+    material::{
+        FaceTextures,
+        PALETTE_SIZE,
+    },
     voxel::{
         Voxel,
         VoxelKind,
     },
+    world::World,
 };
 
 #[derive(Component)]
 /// Marker component indicating a mesh is present and up to date
 pub struct HasMesh;
 
+/// Neighbouring chunks which weren't loaded when a chunk was meshed, so the
+/// faces along them were culled and shaded as if next to air
+#[derive(Component, Debug, Default)]
+pub struct MissingNeighbours(pub Vec<ChunkPosition>);
+
+impl MissingNeighbours {
+    /// Neighbours of the chunk at `chunk_pos` left out of the `adjacent`
+    /// chunks it is meshed with
+    pub fn of(chunk_pos: ChunkPosition, adjacent: &[(ChunkPosition, ChunkVoxels)]) -> Self {
+        Self(
+            chunk_pos
+                .neighbouring_chunks()
+                .all8()
+                .into_iter()
+                .filter(|&pos| adjacent.iter().all(|&(adj, _)| adj != pos))
+                .collect(),
+        )
+    }
+}
+
+/// Remesh chunks once a neighbour that was missing when they were meshed has
+/// loaded, so they stop showing faces along the edge of the loaded area
+pub fn remesh_on_neighbour_load(
+    mut commands: Commands,
+    world: Res<World>,
+    meshed: Query<(Entity, &MissingNeighbours), With<HasMesh>>,
+    loaded: Query<(), With<ChunkVoxels>>,
+) {
+    for (chunk, missing) in &meshed {
+        // Chunks still generating have no voxels to mesh against yet
+        let arrived = missing
+            .0
+            .iter()
+            .any(|&pos| world.chunk_at(pos).is_some_and(|ent| loaded.contains(ent)));
+        if arrived {
+            commands
+                .entity(chunk)
+                .remove::<(HasMesh, MissingNeighbours)>();
+        }
+    }
+}
+
 pub const VOXEL_VERTEX_DATA: MeshVertexAttribute =
     MeshVertexAttribute::new("Vertex_Data", 0x3bbb0d7d, VertexFormat::Uint32);
 
+/// Vertex positions packed by [`pack_position`], used by chunk meshes in place
+/// of [`Mesh::ATTRIBUTE_POSITION`]
+pub const VOXEL_PACKED_POSITION: MeshVertexAttribute =
+    MeshVertexAttribute::new("Vertex_PackedPosition", 0x5e2a91c4, VertexFormat::Uint32);
+
+/// Options controlling the output of [`from_chunk`]
+#[derive(Resource, Clone, Copy, Default, Debug)]
+pub struct MeshSettings {
+    /// Also emit [`Mesh::ATTRIBUTE_NORMAL`], so chunk meshes can be used with
+    /// stock Bevy materials
+    pub normals: bool,
+    /// Decide which faces are visible a row of voxels at a time using
+    /// bitmasks, rather than checking each voxel's neighbours individually
+    pub batched_culling: bool,
+    /// Leave out faces on the bottom and top of the world, which can only be
+    /// seen from outside it
+    pub cull_world_edges: bool,
+    pub water: WaterTransparency,
+    /// Count [`MeshStats`] while meshing
+    pub stats: bool,
+}
+
+/// Whether the face of a cube of `size` voxels at `pos` facing `direction`
+/// lies on the bottom or top of the world
+fn on_world_edge(pos: IVec3, size: usize, direction: IVec3) -> bool {
+    let y = match direction.y {
+        1 => pos.y + size as i32,
+        -1 => pos.y - 1,
+        _ => return false,
+    };
+    !(0..MAX_HEIGHT as i32).contains(&y)
+}
+
+/// How water is drawn relative to the rest of the terrain. Glass is always
+/// drawn in its own mesh, alpha blended unless water uses alpha to coverage
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum WaterTransparency {
+    /// Water is opaque, and meshed along with every other voxel
+    #[default]
+    Opaque,
+    /// Water is alpha blended, in its own mesh per chunk so chunks' water is
+    /// sorted back to front
+    Blend,
+    /// Water is drawn in its own mesh using alpha to coverage, which needs no
+    /// sorting but relies on MSAA
+    AlphaToCoverage,
+}
+
+impl WaterTransparency {
+    /// Whether water faces go in their own mesh rather than with opaque faces
+    pub fn separate(self) -> bool {
+        self != WaterTransparency::Opaque
+    }
+
+    /// Whether faces of `kind` go in the mesh of transparent voxels rather
+    /// than with opaque faces
+    pub fn in_transparent_mesh(self, kind: VoxelKind) -> bool {
+        match kind {
+            VoxelKind::Glass => true,
+            VoxelKind::Water => self.separate(),
+            _ => false,
+        }
+    }
+
+    /// Alpha mode of the mesh of transparent voxels
+    pub fn alpha_mode(self) -> AlphaMode {
+        match self {
+            WaterTransparency::Opaque | WaterTransparency::Blend => AlphaMode::Blend,
+            WaterTransparency::AlphaToCoverage => AlphaMode::AlphaToCoverage,
+        }
+    }
+}
+
+impl FromStr for WaterTransparency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "opaque" => Ok(WaterTransparency::Opaque),
+            "blend" => Ok(WaterTransparency::Blend),
+            "coverage" => Ok(WaterTransparency::AlphaToCoverage),
+            _ => Err(format!(
+                "unknown water transparency `{s}`, expected `opaque`, `blend` or `coverage`"
+            )),
+        }
+    }
+}
+
+/// Meshes built from a single chunk
+pub struct ChunkMeshes {
+    pub opaque: Mesh,
+    /// Faces of voxels that [`WaterTransparency::in_transparent_mesh`], if
+    /// there are any
+    pub transparent: Option<Mesh>,
+    /// Only counted with [`MeshSettings::stats`]
+    pub stats: Option<MeshStats>,
+}
+
+/// How much work meshing chunks took, and how much it produced
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct MeshStats {
+    /// Triangles across both the opaque and transparent meshes
+    pub triangles: u64,
+    /// Leaf octants looked at, including those of air
+    pub octants_visited: u64,
+    /// Faces of meshed cubes left out as they can't be seen
+    pub faces_culled: u64,
+}
+
+impl std::ops::AddAssign for MeshStats {
+    fn add_assign(&mut self, other: Self) {
+        self.triangles += other.triangles;
+        self.octants_visited += other.octants_visited;
+        self.faces_culled += other.faces_culled;
+    }
+}
+
+/// Vertex attributes and indices of a chunk mesh as it is built
+struct MeshBuffers {
+    positions: Vec<u32>,
+    vertex_data: Vec<u32>,
+    normals: Option<Vec<[f32; 3]>>,
+    indices: Vec<u32>,
+}
+
+impl MeshBuffers {
+    fn new(normals: bool) -> Self {
+        Self {
+            positions: Vec::new(),
+            vertex_data: Vec::new(),
+            normals: normals.then(Vec::new),
+            indices: Vec::new(),
+        }
+    }
+
+    fn into_mesh(self) -> Mesh {
+        let mut mesh = Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::default(),
+        );
+        mesh.insert_indices(compact_indices(self.indices, self.positions.len()));
+        mesh.insert_attribute(
+            VOXEL_PACKED_POSITION,
+            VertexAttributeValues::Uint32(self.positions),
+        );
+        mesh.insert_attribute(
+            VOXEL_VERTEX_DATA,
+            VertexAttributeValues::Uint32(self.vertex_data),
+        );
+        if let Some(normals) = self.normals {
+            mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, Float32x3(normals));
+        }
+        mesh
+    }
+}
+
+/// Indices into `vertex_count` vertices, using 16 bit indices when they can
+/// address every vertex
+fn compact_indices(indices: Vec<u32>, vertex_count: usize) -> Indices {
+    if vertex_count <= u16::MAX as usize + 1 {
+        Indices::U16(indices.into_iter().map(|idx| idx as u16).collect())
+    } else {
+        Indices::U32(indices)
+    }
+}
+
+// packed position bitfield
+// X - X coordinate, 0 to CHUNK_SIZE
+// Y - Y coordinate, 0 to MAX_HEIGHT
+// Z - Z coordinate, 0 to CHUNK_SIZE
+// XXXXXXXX XXXXXZZZ ZZYYYYYY YYYXXXXX
+const PACKED_X: std::ops::Range<usize> = 0..5;
+const PACKED_Y: std::ops::Range<usize> = 5..14;
+const PACKED_Z: std::ops::Range<usize> = 14..19;
+
+/// Pack a vertex position within a chunk, which lies on a voxel corner, into
+/// a single integer
+pub fn pack_position(pos: IVec3) -> u32 {
+    debug_assert!(
+        pos.cmpge(IVec3::ZERO).all()
+            && pos
+                .cmple(ivec3(CHUNK_SIZE as _, MAX_HEIGHT as _, CHUNK_SIZE as _))
+                .all(),
+        "{pos:?} is outside of a chunk"
+    );
+    let mut packed = 0;
+    packed.set_bits(PACKED_X, pos.x as u32);
+    packed.set_bits(PACKED_Y, pos.y as u32);
+    packed.set_bits(PACKED_Z, pos.z as u32);
+    packed
+}
+
+/// Inverse of [`pack_position`], as done by the voxel shader
+pub fn unpack_position(packed: u32) -> IVec3 {
+    ivec3(
+        packed.get_bits(PACKED_X) as i32,
+        packed.get_bits(PACKED_Y) as i32,
+        packed.get_bits(PACKED_Z) as i32,
+    )
+}
+
+/// Bounding box of a chunk mesh, which Bevy can't compute itself from packed
+/// positions
+pub fn packed_aabb(mesh: &Mesh) -> Option<Aabb> {
+    let Some(VertexAttributeValues::Uint32(positions)) = mesh.attribute(VOXEL_PACKED_POSITION)
+    else {
+        return None;
+    };
+    let positions = positions.iter().map(|&p| unpack_position(p));
+    let min = positions.clone().reduce(IVec3::min)?;
+    let max = positions.reduce(IVec3::max)?;
+    Some(Aabb::from_min_max(min.as_vec3(), max.as_vec3()))
+}
+
+/// The 8 vertices making up a cube
+const VERTICES: [IVec3; 8] = [
+    ivec3(0, 0, 0),
+    ivec3(1, 0, 0),
+    ivec3(1, 1, 0),
+    ivec3(0, 1, 0),
+    ivec3(0, 0, 1),
+    ivec3(1, 0, 1),
+    ivec3(1, 1, 1),
+    ivec3(0, 1, 1),
+];
+
+/// The indices into [`VERTICES`] making up each face of the cube, in order
+/// around the face, as well as the direction of the face
+const FACES: [([usize; 4], IVec3); 6] = [
+    ([1, 2, 3, 0], IVec3::NEG_Z), // front
+    ([4, 7, 6, 5], IVec3::Z),     // back
+    ([0, 3, 7, 4], IVec3::NEG_X), // left
+    ([5, 6, 2, 1], IVec3::X),     // right
+    ([4, 5, 1, 0], IVec3::NEG_Y), // bottom
+    ([3, 2, 6, 7], IVec3::Y),     // top
+];
+
+/// Every voxel kind, in the order used for the bitmasks in [`FaceVisibility`]
+const KINDS: [VoxelKind; 11] = [
+    VoxelKind::Stone,
+    VoxelKind::Grass,
+    VoxelKind::Water,
+    VoxelKind::Snow,
+    VoxelKind::Dirt,
+    VoxelKind::Bedrock,
+    VoxelKind::Wood,
+    VoxelKind::Leaves,
+    VoxelKind::Tnt,
+    VoxelKind::Glass,
+    VoxelKind::Air,
+];
+
+/// One bitmask per entry of [`KINDS`], with bit `x` set if the voxel at `x`
+/// in a row of the padded voxel array is of that kind
+type KindMasks = [u32; KINDS.len()];
+
+/// Which faces of each voxel in a chunk should be meshed, computed by
+/// comparing whole rows of voxels along X against their neighbouring rows
+struct FaceVisibility {
+    /// Indexed by `y * CHUNK_SIZE + z`, then by face. Bit `x` is set if that
+    /// face of the voxel at `(x, y, z)` is visible
+    rows: Vec<[u16; FACES.len()]>,
+}
+
+impl FaceVisibility {
+    /// Build from the voxel array padded with the edges of adjacent chunks, as
+    /// built by [`from_chunk`]
+    fn new(voxels: &Array3<Voxel>, cull_world_edges: bool) -> Self {
+        const PADDED: usize = CHUNK_SIZE + 2;
+        // Rows outside the world are entirely air, unless faces against them
+        // are culled, in which case they have no voxels to be transparent
+        let outside_row = {
+            let mut row = KindMasks::default();
+            if !cull_world_edges {
+                row[KINDS.len() - 1] = (1 << PADDED) - 1;
+            }
+            row
+        };
+
+        let mut kind_rows = vec![KindMasks::default(); MAX_HEIGHT * PADDED];
+        for ((x, y, z), voxel) in voxels.indexed_iter() {
+            let kind = KINDS.iter().position(|&k| k == voxel.kind()).unwrap();
+            kind_rows[y * PADDED + z][kind] |= 1 << x;
+        }
+        let kind_row = |y: i32, z: i32| {
+            if (0..MAX_HEIGHT as i32).contains(&y) {
+                kind_rows[y as usize * PADDED + z as usize]
+            } else {
+                outside_row
+            }
+        };
+
+        let mut rows = vec![[0; FACES.len()]; MAX_HEIGHT * CHUNK_SIZE];
+        for y in 0..MAX_HEIGHT as i32 {
+            for z in 0..CHUNK_SIZE as i32 {
+                let this = kind_row(y, z + 1);
+                for (face, (_, direction)) in FACES.iter().enumerate() {
+                    let adjacent =
+                        kind_row(y + direction.y, z + 1 + direction.z).map(
+                            |mask| match direction.x {
+                                1 => mask >> 1,
+                                -1 => mask << 1,
+                                _ => mask,
+                            },
+                        );
+
+                    let mut transparent = 0;
+                    let mut same_kind = 0;
+                    for (kind, (this, adjacent)) in KINDS.iter().zip(this.iter().zip(adjacent)) {
+                        if kind.transparent() {
+                            transparent |= adjacent;
+                        }
+                        same_kind |= this & adjacent;
+                    }
+                    // Drop the padding so bit `x` is voxel `x` of this chunk
+                    let visible = (transparent & !same_kind) >> 1;
+                    rows[y as usize * CHUNK_SIZE + z as usize][face] = visible as u16;
+                }
+            }
+        }
+
+        Self { rows }
+    }
+
+    fn visible(&self, pos: IVec3, face: usize) -> bool {
+        let row = self.rows[pos.y as usize * CHUNK_SIZE + pos.z as usize][face];
+        row & (1 << pos.x) != 0
+    }
+}
+
 #[tracing::instrument(level = "trace", skip(chunk_voxels, adj_chunks))]
 pub fn from_chunk(
     (chunk_pos, chunk_voxels): (ChunkPosition, &ChunkVoxels),
     adj_chunks: &[(ChunkPosition, ChunkVoxels)],
-) -> Mesh {
+    settings: MeshSettings,
+) -> ChunkMeshes {
     trace!("meshing chunk @ {chunk_pos:?}");
-    let mut mesh = Mesh::new(
-        PrimitiveTopology::TriangleList,
-        RenderAssetUsages::default(),
-    );
-
-    /// The 8 vertices making up a cube
-    const VERTICES: [IVec3; 8] = [
-        ivec3(0, 0, 0),
-        ivec3(1, 0, 0),
-        ivec3(1, 1, 0),
-        ivec3(0, 1, 0),
-        ivec3(0, 0, 1),
-        ivec3(1, 0, 1),
-        ivec3(1, 1, 1),
-        ivec3(0, 1, 1),
-    ];
-
-    /// The indices into [`VERTICES`] making up each face of the cube, as
-    /// well as the direction of the face
-    // These are in a weird order just to make a quick fix to AO
-    const FACES: [([usize; 4], IVec3); 6] = [
-        ([1, 2, 3, 0], IVec3::NEG_Z), // front
-        ([4, 7, 6, 5], IVec3::Z),     // back
-        ([0, 3, 7, 4], IVec3::NEG_X), // left
-        ([5, 6, 2, 1], IVec3::X),     // right
-        ([4, 5, 1, 0], IVec3::NEG_Y), // bottom
-        ([3, 2, 6, 7], IVec3::Y),     // top
-    ];
 
     fn get_adjacent_voxel(map: &Array3<Voxel>, pos: IVec3, dir: IVec3) -> Voxel {
         // Offset the coord to account for the neighbouring chunks
@@ -88,16 +443,12 @@ pub fn from_chunk(
         })]
     }
 
-    /// Get the 6 directly adjacent voxels, returning [`Voxel::AIR`] if on a
-    /// chunk boundary
-    fn get_adjacent_voxels(map: &Array3<Voxel>, pos: IVec3) -> [Voxel; 6] {
-        FACES.map(|(_, direction)| get_adjacent_voxel(map, pos, direction))
-    }
-
-    let mut vertices = Vec::new();
-    let mut vertex_data = Vec::new();
+    let mut opaque = MeshBuffers::new(settings.normals);
+    let mut transparent = MeshBuffers::new(settings.normals);
 
     let mut voxels = Array3::from_elem((CHUNK_SIZE + 2, MAX_HEIGHT, CHUNK_SIZE + 2), Voxel::AIR);
+    // Missing chunks are lit as if open to the sky, as they are meshed as air
+    let mut light = Array3::from_elem(voxels.dim(), MAX_LIGHT);
     for (lvp, pos) in chunk_voxels.iter_local_pos() {
         // Offset the coord to account for the neighbouring chunks
         voxels[(
@@ -106,23 +457,29 @@ pub fn from_chunk(
             (lvp.z() + 1) as usize,
         )] = *pos;
     }
+    for ((x, y, z), level) in chunk_voxels.iter_light() {
+        light[(x + 1, y, z + 1)] = level;
+    }
 
     for (adj_chunk_pos, adj_chunk) in adj_chunks {
-        // Add edge voxels from each adjacent chunk for considering AO and culling
-        let filter = if adj_chunk_pos.x() < chunk_pos.x() {
-            |(x, _, _)| x == CHUNK_SIZE - 1
-        } else if adj_chunk_pos.x() > chunk_pos.x() {
-            |(x, _, _)| x == 0
-        } else if adj_chunk_pos.z() < chunk_pos.z() {
-            |(_, _, z)| z == CHUNK_SIZE - 1
-        } else if adj_chunk_pos.z() > chunk_pos.z() {
-            |(_, _, z)| z == 0
-        } else {
-            unreachable!("adj_chunk({adj_chunk_pos:?}) should be in one of the cardinal directions from chunk({chunk_pos:?})");
+        // Add edge voxels from each adjacent chunk for considering AO, culling
+        // and light. Diagonal chunks only contribute the single column touching
+        // our corner
+        let direction = (adj_chunk_pos.as_ivec3() - chunk_pos.as_ivec3()).signum();
+        assert!(
+            direction != IVec3::ZERO,
+            "adj_chunk({adj_chunk_pos:?}) should border chunk({chunk_pos:?})"
+        );
+        let on_edge = |coord: usize, direction: i32| match direction {
+            -1 => coord == CHUNK_SIZE - 1,
+            1 => coord == 0,
+            _ => true,
         };
-        for (adj_pos, adj_voxel) in adj_chunk.iter().filter(|&(pos, _)| filter(pos)) {
-            // Offset the coord to account for the neighbouring chunks
-            let new_pos = [
+        let filter =
+            |&(x, _, z): &(usize, usize, usize)| on_edge(x, direction.x) && on_edge(z, direction.z);
+        // Offset the coord to account for the neighbouring chunks
+        let padded_pos = |adj_pos: (usize, usize, usize)| -> [usize; 3] {
+            [
                 ((adj_chunk_pos.x() + adj_pos.0 as i32) - chunk_pos.x() + 1)
                     .try_into()
                     .unwrap_or_else(|_| {
@@ -134,138 +491,262 @@ pub fn from_chunk(
                     .unwrap_or_else(|_| {
                         panic!("{adj_chunk_pos:?} + {adj_pos:?} - {chunk_pos:?} underflow")
                     }),
-            ];
-            voxels[new_pos] = *adj_voxel;
+            ]
+        };
+        for (adj_pos, adj_voxel) in adj_chunk.iter().filter(|(pos, _)| filter(pos)) {
+            voxels[padded_pos(adj_pos)] = *adj_voxel;
+        }
+        for (adj_pos, level) in adj_chunk.iter_light().filter(|(pos, _)| filter(pos)) {
+            light[padded_pos(adj_pos)] = level;
+        }
+    }
+
+    /// Sky light at a corner of a face, averaged over the voxels in front of
+    /// the face which touch the corner so light is smooth across faces
+    fn light_at_vertex(
+        voxels: &Array3<Voxel>,
+        light: &Array3<u8>,
+        vertex: IVec3,
+        face_direction: IVec3,
+    ) -> u32 {
+        let (mut total, mut count) = (0, 0);
+        for corner in 0..4 {
+            // Along the face the voxels are either side of the vertex
+            let mut side = 0;
+            let pos = IVec3::from_array(std::array::from_fn(|axis| match face_direction[axis] {
+                1 => vertex[axis],
+                -1 => vertex[axis] - 1,
+                _ => {
+                    side += 1;
+                    vertex[axis] - (corner >> (side - 1) & 1)
+                }
+            }));
+            // Above the world is open to the sky, and below it is solid
+            if pos.y >= MAX_HEIGHT as i32 {
+                total += MAX_LIGHT as u32;
+                count += 1;
+                continue;
+            }
+            if pos.y < 0 {
+                continue;
+            }
+            // Offset the coord to account for the neighbouring chunks
+            let index = [(pos.x + 1) as usize, pos.y as usize, (pos.z + 1) as usize];
+            if voxels[index].casts_shadow() {
+                continue;
+            }
+            total += light[index] as u32;
+            count += 1;
         }
+        // Rounded to the nearest level
+        (total + count / 2).checked_div(count).unwrap_or(0)
     }
 
     fn add_cube(
         voxels: &Array3<Voxel>,
-        vertices: &mut Vec<[f32; 3]>,
-        vertex_data: &mut Vec<u32>,
+        light: &Array3<u8>,
+        buffers: &mut MeshBuffers,
+        visibility: Option<&FaceVisibility>,
+        cull_world_edges: bool,
         material: VoxelKind,
         pos: IVec3,
         size: usize,
-    ) {
-        let adjacent = get_adjacent_voxels(voxels, pos);
-        fn face_neighbour_offsets(direction: IVec3) -> [IVec3; 8] {
-            let (perp1, perp2) = match direction {
-                IVec3::NEG_Z => (IVec3::Y, IVec3::X),
-                IVec3::Z => (IVec3::Y, IVec3::NEG_X),
-
-                IVec3::NEG_Y => (IVec3::X, IVec3::Z),
-                IVec3::Y => (IVec3::X, IVec3::NEG_Z),
-
-                IVec3::NEG_X => (IVec3::Y, IVec3::NEG_Z),
-                IVec3::X => (IVec3::Y, IVec3::Z),
-                _ => unreachable!(),
-            };
-
-            [
-                direction + perp1,
-                direction + perp1 + perp2,
-                direction + perp2,
-                direction - perp1 + perp2,
-                direction - perp1,
-                direction - perp1 - perp2,
-                direction - perp2,
-                direction + perp1 - perp2,
-            ]
-        }
-
-        // Calculate the 4 AO values for a face. See:
+    ) -> usize {
+        // Don't render faces touching a solid voxel, or between multiple
+        // transparent blocks of the same type
+        let visible: [bool; 6] = match visibility {
+            Some(visibility) => std::array::from_fn(|face| visibility.visible(pos, face)),
+            None => FACES.map(|(_, direction)| {
+                if cull_world_edges && on_world_edge(pos, size, direction) {
+                    return false;
+                }
+                let adj = get_adjacent_voxel(voxels, pos, direction);
+                adj.transparent() && adj.kind() != material
+            }),
+        };
+        // Calculate the AO value for each vertex of a face, from the voxels in
+        // front of the face touching the vertex. See:
         // https://0fps.net/2013/07/03/ambient-occlusion-for-minecraft-like-worlds/
         // https://playspacefarer.com/ambient-occlusion/
-        // FIXME: Some of the values in this are wrong, leading to the AO looking a bit
-        // wonky
-        fn ao_values_for_face(map: &Array3<Voxel>, pos: IVec3, face_direction: IVec3) -> [u32; 4] {
-            // Offsets to neighbouring voxels of a face - starting at the 'middle left'
-            // and continuing anti-clockwise
-
-            let offsets = face_neighbour_offsets(face_direction);
-            let voxels = offsets.map(|off| get_adjacent_voxel(map, pos, off));
-            [[2, 3, 4], [0, 1, 2], [6, 7, 0], [4, 5, 6]]
-                .map(|[a, b, c]| [voxels[a], voxels[b], voxels[c]])
-                .map(|voxels| voxels.map(|v| v.casts_shadow()))
-                .map(|[s1, corner, s2]| match (s1, corner, s2) {
+        fn ao_values_for_face(
+            map: &Array3<Voxel>,
+            pos: IVec3,
+            size: usize,
+            face_vertices: [usize; 4],
+            face_direction: IVec3,
+        ) -> [u32; 4] {
+            face_vertices.map(|vertex| {
+                let vertex = VERTICES[vertex];
+                // The voxel of the cube the vertex is a corner of
+                let cube_corner = pos + vertex * (size as i32 - 1);
+                // Steps along each axis of the face towards the vertex
+                let towards = vertex * 2 - IVec3::ONE;
+                let mut sides = [IVec3::X, IVec3::Y, IVec3::Z]
+                    .into_iter()
+                    .filter(|axis| axis.dot(face_direction) == 0)
+                    .map(|axis| axis * towards);
+                let (side1, side2) = (sides.next().unwrap(), sides.next().unwrap());
+                let [s1, corner, s2] = [side1, side1 + side2, side2]
+                    .map(|side| get_adjacent_voxel(map, cube_corner, face_direction + side))
+                    .map(|v| v.casts_shadow());
+                match (s1, corner, s2) {
                     (true, _, true) => 0,
                     (true, true, false) | (false, true, true) => 1,
                     (false, false, false) => 3,
                     _ => 2,
-                })
+                }
+            })
         }
 
-        for (i, ((face_vertices, face_direction), adj)) in
-            FACES.into_iter().zip(adjacent.iter()).enumerate()
+        let textures = FaceTextures::of(material);
+        for (i, ((face_vertices, face_direction), visible)) in
+            FACES.into_iter().zip(visible).enumerate()
         {
             let mut per_vertex_data = VertexData::new();
             per_vertex_data.set_normal_idx(i as u32);
-            per_vertex_data.set_material(material);
-            // Don't render faces touching a solid voxel
-            if !adj.transparent() {
-                continue;
-            }
-            // Don't render faces between multiple transparent blocks of the same type
-            if adj.transparent() && adj.kind() == material {
+            per_vertex_data.set_texture(textures.for_face(face_direction));
+            per_vertex_data.set_kind(material as u32);
+            if !visible {
                 continue;
             }
 
-            let verts =
-                face_vertices.map(|f| (pos + VERTICES[f] * size as i32).as_vec3().to_array());
+            let corners = face_vertices.map(|f| pos + VERTICES[f] * size as i32);
+            let verts = corners.map(pack_position);
+            let light_vals =
+                corners.map(|corner| light_at_vertex(voxels, light, corner, face_direction));
             let ao_vals = if material.receives_shadow() {
-                ao_values_for_face(voxels, pos, face_direction)
+                ao_values_for_face(voxels, pos, size, face_vertices, face_direction)
             } else {
                 [3, 3, 3, 3]
             };
 
             let mut indices = [2, 1, 0, 3, 2, 0];
-            // Fix anisotropy
+            // Fix anisotropy by splitting the quad along its other diagonal
+            // when that one is brighter. Vertices go around the face in
+            // order, so opposite vertices are its diagonals
             if ao_vals[0] + ao_vals[2] < ao_vals[1] + ao_vals[3] {
                 indices[0] = 3;
                 indices[5] = 1;
             }
 
-            // TODO: It uses less memory (40 vs 24 bytes per face) to use vertices only and
-            // no indexes However, it should use less if we were to share
-            // vertices across the whole chunk
-            for idx in indices {
+            // Each corner has its own UV, so vertices can only be shared
+            // between the two triangles of a face
+            let first = buffers.positions.len() as u32;
+            buffers
+                .indices
+                .extend(indices.map(|idx| first + idx as u32));
+            for (idx, vertex) in verts.into_iter().enumerate() {
                 per_vertex_data.set_uv(idx as u32);
-                let vertex = verts[idx];
                 per_vertex_data.set_neighbours(ao_vals[idx]);
-                vertices.push(vertex);
-                vertex_data.push(per_vertex_data.to_u32());
+                per_vertex_data.set_light(light_vals[idx]);
+                buffers.positions.push(vertex);
+                buffers.vertex_data.push(per_vertex_data.to_u32());
+                if let Some(normals) = &mut buffers.normals {
+                    normals.push(face_direction.as_vec3().to_array());
+                }
             }
         }
+        visible.into_iter().filter(|&visible| visible).count()
     }
 
-    for (pos, size, voxel) in chunk_voxels.iter_octants().filter_map(|octant| {
-        if let OctantKind::Chunk(inner) = octant.kind {
-            if octant.size > 1 {
-                trace!("meshing superchunk: {}", octant.size);
+    let visibility = settings
+        .batched_culling
+        .then(|| FaceVisibility::new(&voxels, settings.cull_world_edges));
+    let mut stats = settings.stats.then(MeshStats::default);
+    for (offset, octant) in chunk_voxels.iter_octants() {
+        for (pos, size, voxel) in octant.leaves() {
+            let pos = offset + pos;
+            if let Some(stats) = &mut stats {
+                stats.octants_visited += 1;
             }
-            inner
-                .should_mesh()
-                .then_some((octant.position, octant.size, inner))
-        } else {
-            None
-        }
-    }) {
-        add_cube(
-            &voxels,
-            &mut vertices,
-            &mut vertex_data,
-            voxel.kind(),
-            pos.into(),
-            size,
-        );
+            if !voxel.should_mesh() {
+                continue;
+            }
+            if size > 1 {
+                trace!("meshing superchunk: {}", size);
+            }
+            let buffers = if settings.water.in_transparent_mesh(voxel.kind()) {
+                &mut transparent
+            } else {
+                &mut opaque
+            };
+            let drawn = add_cube(
+                &voxels,
+                &light,
+                buffers,
+                visibility.as_ref(),
+                settings.cull_world_edges,
+                voxel.kind(),
+                pos.into(),
+                size,
+            );
+            if let Some(stats) = &mut stats {
+                // Each face is drawn as two triangles
+                stats.triangles += 2 * drawn as u64;
+                stats.faces_culled += (FACES.len() - drawn) as u64;
+            }
+        }
     }
 
+    ChunkMeshes {
+        opaque: opaque.into_mesh(),
+        transparent: (!transparent.positions.is_empty()).then(|| transparent.into_mesh()),
+        stats,
+    }
+}
+
+/// Combine several chunk meshes into one, offsetting each by the given
+/// translation. Positions are unpacked, as packed positions can't address
+/// more than one chunk
+pub fn combine(meshes: &[(Vec3, &Mesh)]) -> Mesh {
+    let mut vertices = Vec::new();
+    let mut vertex_data = Vec::new();
+    let mut normals = Vec::new();
+    let mut indices = Vec::new();
+    for (offset, mesh) in meshes {
+        // Indices are offset past the vertices of the meshes before
+        let first = vertices.len() as u32;
+        if let Some(mesh_indices) = mesh.indices() {
+            indices.extend(mesh_indices.iter().map(|idx| first + idx as u32));
+        }
+        if let Some(Float32x3(positions)) = mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+            vertices.extend(
+                positions
+                    .iter()
+                    .map(|p| (Vec3::from(*p) + *offset).to_array()),
+            );
+        }
+        if let Some(VertexAttributeValues::Uint32(positions)) =
+            mesh.attribute(VOXEL_PACKED_POSITION)
+        {
+            vertices.extend(
+                positions
+                    .iter()
+                    .map(|&p| (unpack_position(p).as_vec3() + *offset).to_array()),
+            );
+        }
+        if let Some(VertexAttributeValues::Uint32(data)) = mesh.attribute(VOXEL_VERTEX_DATA) {
+            vertex_data.extend_from_slice(data);
+        }
+        if let Some(Float32x3(mesh_normals)) = mesh.attribute(Mesh::ATTRIBUTE_NORMAL) {
+            normals.extend_from_slice(mesh_normals);
+        }
+    }
+
+    let vertex_count = vertices.len();
+    let mut mesh = Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::default(),
+    );
     mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, Float32x3(vertices));
     mesh.insert_attribute(
         VOXEL_VERTEX_DATA,
         VertexAttributeValues::Uint32(vertex_data),
     );
-
+    if !normals.is_empty() {
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, Float32x3(normals));
+    }
+    mesh.insert_indices(compact_indices(indices, vertex_count));
     mesh
 }
 
@@ -284,19 +765,575 @@ impl VertexData {
         self.0.set_bits(0..3, idx);
     }
 
-    pub fn set_material(&mut self, kind: VoxelKind) {
-        self.0.set_bits(3..6, kind as u32);
-    }
-
     pub fn set_uv(&mut self, uv: u32) {
-        self.0.set_bits(6..8, uv);
+        self.0.set_bits(3..5, uv);
     }
 
     pub fn set_neighbours(&mut self, neighbours: u32) {
-        self.0.set_bits(8..10, neighbours);
+        self.0.set_bits(5..7, neighbours);
+    }
+
+    /// Index of the texture in the block texture array
+    pub fn set_texture(&mut self, texture: u32) {
+        self.0.set_bits(7..15, texture);
+    }
+
+    /// Sky light at the vertex, from 0 to [`MAX_LIGHT`]
+    pub fn set_light(&mut self, light: u32) {
+        self.0.set_bits(15..19, light);
+    }
+
+    /// Kind of the voxel, indexing the palette used for flat colours
+    pub fn set_kind(&mut self, kind: u32) {
+        debug_assert!(kind < PALETTE_SIZE as u32);
+        self.0.set_bits(19..23, kind);
     }
 
     pub fn to_u32(self) -> u32 {
         self.0
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::voxel::LocalVoxelPosition;
+
+    #[test]
+    pub fn normals_match_packed_indices() {
+        let mut chunk = ChunkVoxels::default();
+        chunk.voxel_mut(LocalVoxelPosition::new(4, 20, 7)).kind = VoxelKind::Stone;
+        chunk.voxel_mut(LocalVoxelPosition::new(4, 21, 7)).kind = VoxelKind::Dirt;
+        let mesh = from_chunk(
+            (ChunkPosition::new(0, 0), &chunk),
+            &[],
+            MeshSettings {
+                normals: true,
+                ..default()
+            },
+        )
+        .opaque;
+
+        let Some(VertexAttributeValues::Uint32(data)) = mesh.attribute(VOXEL_VERTEX_DATA) else {
+            panic!("missing vertex data");
+        };
+        let Some(Float32x3(normals)) = mesh.attribute(Mesh::ATTRIBUTE_NORMAL) else {
+            panic!("missing normals");
+        };
+        // 10 faces, as the faces between the two voxels are culled
+        assert_eq!(data.len(), 10 * 4);
+        assert_eq!(mesh.indices().unwrap().len(), 10 * 6);
+        assert_eq!(data.len(), normals.len());
+        for (data, normal) in data.iter().zip(normals) {
+            let idx = data.get_bits(0..3) as usize;
+            assert_eq!(FACES[idx].1.as_vec3().to_array(), *normal);
+        }
+
+        let mesh = from_chunk((ChunkPosition::new(0, 0), &chunk), &[], default()).opaque;
+        assert!(mesh.attribute(Mesh::ATTRIBUTE_NORMAL).is_none());
+    }
+
+    #[test]
+    pub fn overhangs_are_darker() {
+        // Stone ground at Y 10, with a roof at Y 20 over X 0 to 7
+        let mut chunk = ChunkVoxels::from_fn(4, |pos| {
+            if pos.y() <= 10 || (pos.y() == 20 && pos.x() < 8) {
+                VoxelKind::Stone
+            } else {
+                VoxelKind::Air
+            }
+        });
+        crate::light::light_chunk(&mut chunk);
+        let mesh = from_chunk((ChunkPosition::new(0, 0), &chunk), &[], default()).opaque;
+        let Some(VertexAttributeValues::Uint32(data)) = mesh.attribute(VOXEL_VERTEX_DATA) else {
+            panic!("missing vertex data");
+        };
+        let Some(VertexAttributeValues::Uint32(positions)) = mesh.attribute(VOXEL_PACKED_POSITION)
+        else {
+            panic!("missing positions");
+        };
+        // Light of the vertices along the top of the ground at each X. Those
+        // on the edge of the chunk are lit by the missing chunks around it
+        let mut ground = [None; CHUNK_SIZE + 1];
+        for (data, &pos) in data.iter().zip(positions) {
+            let pos = unpack_position(pos);
+            let top = FACES[data.get_bits(0..3) as usize].1 == IVec3::Y;
+            if top && pos.y == 11 && (1..CHUNK_SIZE as i32).contains(&pos.z) {
+                let light = data.get_bits(15..19);
+                let x = &mut ground[pos.x as usize];
+                assert!(
+                    x.is_none_or(|l| l == light),
+                    "uneven light along X {}",
+                    pos.x
+                );
+                *x = Some(light);
+            }
+        }
+        let ground = ground.map(Option::unwrap);
+
+        assert!(ground[9..].iter().all(|&l| l == MAX_LIGHT as u32));
+        // Light fades smoothly going in under the roof
+        assert!(ground[1..9].windows(2).all(|l| l[0] < l[1]), "{ground:?}");
+        assert_eq!(ground[1], MAX_LIGHT as u32 - 7);
+    }
+
+    #[test]
+    pub fn packed_positions_round_trip() {
+        for x in 0..=CHUNK_SIZE as i32 {
+            for y in 0..=MAX_HEIGHT as i32 {
+                for z in 0..=CHUNK_SIZE as i32 {
+                    let pos = ivec3(x, y, z);
+                    assert_eq!(unpack_position(pack_position(pos)), pos);
+                }
+            }
+        }
+
+        // Voxels in opposite corners of the chunk
+        let mut chunk = ChunkVoxels::default();
+        chunk.voxel_mut(LocalVoxelPosition::new(15, 255, 0)).kind = VoxelKind::Stone;
+        chunk.voxel_mut(LocalVoxelPosition::new(3, 0, 9)).kind = VoxelKind::Dirt;
+        let mesh = from_chunk((ChunkPosition::new(0, 0), &chunk), &[], default()).opaque;
+        assert!(mesh.attribute(Mesh::ATTRIBUTE_POSITION).is_none());
+        assert_eq!(mesh.count_vertices(), 2 * 6 * 4);
+        assert_eq!(
+            packed_aabb(&mesh),
+            Some(Aabb::from_min_max(
+                Vec3::new(3.0, 0.0, 0.0),
+                Vec3::new(16.0, 256.0, 10.0),
+            ))
+        );
+    }
+
+    #[test]
+    pub fn water_meshed_separately() {
+        let mut chunk = ChunkVoxels::default();
+        chunk.voxel_mut(LocalVoxelPosition::new(4, 20, 7)).kind = VoxelKind::Stone;
+        chunk.voxel_mut(LocalVoxelPosition::new(8, 20, 7)).kind = VoxelKind::Water;
+        let textures = |mesh: &Mesh| {
+            let Some(VertexAttributeValues::Uint32(data)) = mesh.attribute(VOXEL_VERTEX_DATA)
+            else {
+                panic!("missing vertex data");
+            };
+            data.iter().map(|d| d.get_bits(7..15)).collect::<Vec<_>>()
+        };
+
+        let meshes = from_chunk((ChunkPosition::new(0, 0), &chunk), &[], default());
+        assert!(meshes.transparent.is_none());
+        assert_eq!(meshes.opaque.count_vertices(), 2 * 6 * 4);
+
+        let meshes = from_chunk(
+            (ChunkPosition::new(0, 0), &chunk),
+            &[],
+            MeshSettings {
+                water: WaterTransparency::Blend,
+                ..default()
+            },
+        );
+        let opaque = textures(&meshes.opaque);
+        let transparent = textures(&meshes.transparent.unwrap());
+        assert_eq!(opaque.len(), 6 * 4);
+        assert!(opaque.iter().all(|&t| t == VoxelKind::Stone as u32));
+        assert_eq!(transparent.len(), 6 * 4);
+        assert!(transparent.iter().all(|&t| t == VoxelKind::Water as u32));
+
+        assert_eq!("coverage".parse(), Ok(WaterTransparency::AlphaToCoverage));
+        assert!("glass".parse::<WaterTransparency>().is_err());
+    }
+
+    #[test]
+    pub fn glass_meshed_transparent() {
+        let mut chunk = ChunkVoxels::default();
+        chunk.voxel_mut(LocalVoxelPosition::new(4, 20, 7)).kind = VoxelKind::Glass;
+        chunk.voxel_mut(LocalVoxelPosition::new(5, 20, 7)).kind = VoxelKind::Glass;
+        chunk.voxel_mut(LocalVoxelPosition::new(6, 20, 7)).kind = VoxelKind::Stone;
+        for batched_culling in [false, true] {
+            // Glass is transparent even when water isn't
+            let meshes = from_chunk(
+                (ChunkPosition::new(0, 0), &chunk),
+                &[],
+                MeshSettings {
+                    batched_culling,
+                    ..default()
+                },
+            );
+            // The stone is seen through the glass, but not the faces between
+            // the glass voxels or the glass face against the stone
+            assert_eq!(meshes.opaque.count_vertices(), 6 * 4);
+            assert_eq!(meshes.transparent.unwrap().count_vertices(), 9 * 4);
+        }
+        assert_eq!(
+            WaterTransparency::Opaque.alpha_mode(),
+            WaterTransparency::Blend.alpha_mode()
+        );
+    }
+
+    #[test]
+    pub fn grass_face_textures() {
+        let mut chunk = ChunkVoxels::default();
+        chunk.voxel_mut(LocalVoxelPosition::new(4, 20, 7)).kind = VoxelKind::Grass;
+        let mesh = from_chunk((ChunkPosition::new(0, 0), &chunk), &[], default()).opaque;
+        let Some(VertexAttributeValues::Uint32(data)) = mesh.attribute(VOXEL_VERTEX_DATA) else {
+            panic!("missing vertex data");
+        };
+        assert_eq!(data.len(), 6 * 4);
+
+        let grass = FaceTextures::of(VoxelKind::Grass);
+        assert_eq!(grass.bottom, VoxelKind::Dirt as u32);
+        assert!(grass.top != grass.side && grass.side != grass.bottom);
+        for data in data {
+            let direction = FACES[data.get_bits(0..3) as usize].1;
+            let expected = match direction {
+                IVec3::Y => grass.top,
+                IVec3::NEG_Y => grass.bottom,
+                _ => grass.side,
+            };
+            assert_eq!(data.get_bits(7..15), expected);
+            assert_eq!(data.get_bits(19..23), VoxelKind::Grass as u32);
+        }
+    }
+
+    #[test]
+    pub fn world_edge_faces_follow_setting() {
+        let top = MAX_HEIGHT as i32 - 1;
+        assert!(on_world_edge(IVec3::ZERO, 1, IVec3::NEG_Y));
+        assert!(!on_world_edge(IVec3::ZERO, 1, IVec3::Y));
+        assert!(!on_world_edge(IVec3::ZERO, 1, IVec3::X));
+        assert!(on_world_edge(ivec3(0, top, 0), 1, IVec3::Y));
+        assert!(!on_world_edge(ivec3(0, top, 0), 1, IVec3::NEG_Y));
+        // Merged cubes reach the top from further down
+        assert!(on_world_edge(ivec3(0, top - 3, 0), 4, IVec3::Y));
+        assert!(!on_world_edge(ivec3(0, 1, 0), 4, IVec3::NEG_Y));
+
+        let mut chunk = ChunkVoxels::default();
+        chunk.voxel_mut(LocalVoxelPosition::new(1, 0, 1)).kind = VoxelKind::Stone;
+        chunk
+            .voxel_mut(LocalVoxelPosition::new(5, top as u32, 5))
+            .kind = VoxelKind::Stone;
+        let pos = ChunkPosition::new(0, 0);
+        for batched_culling in [false, true] {
+            for (cull_world_edges, faces) in [(false, 12), (true, 10)] {
+                let mesh = from_chunk(
+                    (pos, &chunk),
+                    &[],
+                    MeshSettings {
+                        batched_culling,
+                        cull_world_edges,
+                        ..default()
+                    },
+                )
+                .opaque;
+                assert_eq!(mesh.count_vertices(), faces * 4);
+            }
+        }
+    }
+
+    #[test]
+    pub fn batched_culling_matches_scalar() {
+        // Small LCG so the test is deterministic without pulling in `rand`
+        let mut state = 0x2545_f491_u32;
+        let mut next_kind = move || {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            // Weighted towards air and water to get plenty of visible faces
+            match state >> 28 {
+                0..=5 => VoxelKind::Air,
+                6..=8 => VoxelKind::Water,
+                9 => VoxelKind::Grass,
+                10 => VoxelKind::Snow,
+                11 => VoxelKind::Dirt,
+                _ => VoxelKind::Stone,
+            }
+        };
+        let mut random_chunk = |heights: std::ops::Range<u32>| {
+            let mut chunk = ChunkVoxels::default();
+            for y in heights {
+                for x in 0..CHUNK_SIZE as u32 {
+                    for z in 0..CHUNK_SIZE as u32 {
+                        chunk.voxel_mut(LocalVoxelPosition::new(x, y, z)).kind = next_kind();
+                    }
+                }
+            }
+            chunk
+        };
+
+        let centre = ChunkPosition::new(0, 0);
+        let chunks = [
+            random_chunk(0..40),
+            random_chunk(60..70),
+            random_chunk(MAX_HEIGHT as u32 - 8..MAX_HEIGHT as u32),
+        ];
+        let adj = centre
+            .neighbouring_chunks()
+            .all()
+            .map(|pos| (pos, random_chunk(0..40)));
+
+        for chunk in &chunks {
+            for adj in [&adj[..], &[]] {
+                let scalar = from_chunk((centre, chunk), adj, default()).opaque;
+                let batched = from_chunk(
+                    (centre, chunk),
+                    adj,
+                    MeshSettings {
+                        batched_culling: true,
+                        ..default()
+                    },
+                )
+                .opaque;
+                assert!(scalar.count_vertices() > 0);
+                for attribute in [VOXEL_PACKED_POSITION, VOXEL_VERTEX_DATA] {
+                    assert_eq!(
+                        scalar.attribute(attribute.id).unwrap().get_bytes(),
+                        batched.attribute(attribute.id).unwrap().get_bytes(),
+                    );
+                }
+                assert_eq!(
+                    scalar.indices().unwrap().iter().collect::<Vec<_>>(),
+                    batched.indices().unwrap().iter().collect::<Vec<_>>(),
+                );
+            }
+        }
+    }
+
+    #[test]
+    pub fn faces_split_along_brighter_diagonal() {
+        // Voxels around and above a stone floor, shading its top faces
+        let mut chunk = ChunkVoxels::default();
+        for x in 2..8 {
+            for z in 2..8 {
+                chunk.voxel_mut(LocalVoxelPosition::new(x, 10, z)).kind = VoxelKind::Stone;
+            }
+        }
+        for (x, z) in [(3, 3), (5, 4), (6, 6), (2, 7)] {
+            chunk.voxel_mut(LocalVoxelPosition::new(x, 11, z)).kind = VoxelKind::Stone;
+        }
+        let mesh = from_chunk((ChunkPosition::new(0, 0), &chunk), &[], default()).opaque;
+        let Some(VertexAttributeValues::Uint32(data)) = mesh.attribute(VOXEL_VERTEX_DATA) else {
+            panic!("missing vertex data");
+        };
+        let Some(Indices::U16(indices)) = mesh.indices() else {
+            panic!("missing 16 bit indices");
+        };
+        assert_eq!(indices.len(), data.len() / 4 * 6);
+
+        let mut flipped = 0;
+        for (face, indices) in indices.chunks(6).enumerate() {
+            // Both triangles only use the 4 vertices of their own face
+            let first = face as u16 * 4;
+            assert!(indices.iter().all(|idx| (first..first + 4).contains(idx)));
+            let ao = |idx: u16| data[idx as usize].get_bits(5..7);
+            // The vertices shared by both triangles are the diagonal they're
+            // split along, which should be the brighter one
+            let (a, b) = (&indices[..3], &indices[3..]);
+            let shared: Vec<_> = a.iter().filter(|idx| b.contains(idx)).copied().collect();
+            let other: Vec<_> = (first..first + 4)
+                .filter(|idx| !shared.contains(idx))
+                .collect();
+            assert_eq!(shared.len(), 2);
+            assert!(
+                ao(shared[0]) + ao(shared[1]) >= ao(other[0]) + ao(other[1]),
+                "face {face} is split along its darker diagonal"
+            );
+            if shared.contains(&(first + 1)) {
+                flipped += 1;
+            }
+        }
+        assert!(flipped > 0);
+    }
+
+    #[test]
+    pub fn ao_of_each_vertex() {
+        let mut chunk = ChunkVoxels::default();
+        for (x, y, z) in [(4, 10, 4), (5, 11, 4), (3, 11, 3)] {
+            chunk.voxel_mut(LocalVoxelPosition::new(x, y, z)).kind = VoxelKind::Stone;
+        }
+        let mesh = from_chunk((ChunkPosition::new(0, 0), &chunk), &[], default()).opaque;
+        let ao: Vec<_> = voxel_vertex_data(&mesh, ivec3(4, 10, 4))
+            .into_iter()
+            .map(|data| data.get_bits(5..7))
+            .collect();
+        // Faces in the order of `FACES`, shaded by the voxel on the corner
+        // above -X and -Z, and the voxel to the side above +X
+        #[rustfmt::skip]
+        let expected = [
+            3, 3, 2, 3, // -Z
+            3, 3, 3, 3, // +Z
+            3, 2, 3, 3, // -X
+            3, 2, 2, 3, // +X
+            3, 3, 3, 3, // -Y
+            2, 2, 2, 3, // +Y
+        ];
+        assert_eq!(ao, expected);
+
+        // Merged cubes are shaded at each of their corners, rather than all
+        // by the voxels around their first corner
+        let mut chunk = ChunkVoxels::with_max_depth(4);
+        chunk.fill_region(
+            LocalVoxelPosition::new(0, 16, 0),
+            LocalVoxelPosition::new(7, 23, 7),
+            VoxelKind::Stone,
+        );
+        chunk.voxel_mut(LocalVoxelPosition::new(8, 15, 8)).kind = VoxelKind::Stone;
+        let mesh = from_chunk((ChunkPosition::new(0, 0), &chunk), &[], default()).opaque;
+        let Some(VertexAttributeValues::Uint32(positions)) = mesh.attribute(VOXEL_PACKED_POSITION)
+        else {
+            panic!("missing positions");
+        };
+        let Some(VertexAttributeValues::Uint32(data)) = mesh.attribute(VOXEL_VERTEX_DATA) else {
+            panic!("missing vertex data");
+        };
+        let bottom_ao = |vertex: IVec3| {
+            positions
+                .iter()
+                .zip(data)
+                .filter(|&(&p, &d)| {
+                    unpack_position(p) == vertex
+                        && FACES[d.get_bits(0..3) as usize].1 == IVec3::NEG_Y
+                })
+                .map(|(_, d)| d.get_bits(5..7))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(bottom_ao(ivec3(0, 16, 0)), [3]);
+        assert_eq!(bottom_ao(ivec3(8, 16, 0)), [3]);
+        assert_eq!(bottom_ao(ivec3(8, 16, 8)), [2]);
+    }
+
+    /// The packed vertex data of the faces lying on the surface of the voxel
+    /// at `pos`, in world space
+    fn voxel_vertex_data(mesh: &Mesh, pos: IVec3) -> Vec<u32> {
+        let Some(VertexAttributeValues::Uint32(positions)) = mesh.attribute(VOXEL_PACKED_POSITION)
+        else {
+            panic!("missing positions");
+        };
+        let Some(VertexAttributeValues::Uint32(data)) = mesh.attribute(VOXEL_VERTEX_DATA) else {
+            panic!("missing vertex data");
+        };
+        let (min, max) = (pos, pos + IVec3::ONE);
+        positions
+            .chunks(4)
+            .zip(data.chunks(4))
+            .filter(|(face, _)| {
+                face.iter().all(|&p| {
+                    let p = unpack_position(p);
+                    p.cmpge(min).all() && p.cmple(max).all()
+                })
+            })
+            .flat_map(|(_, data)| data.iter().copied())
+            .collect()
+    }
+
+    #[test]
+    pub fn remesh_once_missing_neighbour_loads() {
+        use bevy::ecs::system::RunSystemOnce;
+
+        let mut world = World::flat();
+        let centre = ChunkPosition::new(0, 0);
+        let east = ChunkPosition::new(16, 0);
+        let chunk = crate::terrain::generate_chunk(centre, &world.generator, 4);
+        let neighbour = crate::terrain::generate_chunk(east, &world.generator, 4);
+
+        // Faces along a missing neighbour are meshed as if it were air
+        let alone = from_chunk((centre, &chunk), &[], default()).opaque;
+        let adjacent = [(east, neighbour.clone())];
+        let beside = from_chunk((centre, &chunk), &adjacent, default()).opaque;
+        assert!(beside.count_vertices() < alone.count_vertices());
+        let missing = MissingNeighbours::of(centre, &adjacent);
+        assert_eq!(missing.0.len(), 7);
+        assert!(!missing.0.contains(&east));
+
+        let mut app = App::new();
+        let chunk_ent = app
+            .world_mut()
+            .spawn((chunk, HasMesh, MissingNeighbours::of(centre, &[])))
+            .id();
+        world.add_chunk(centre, chunk_ent);
+        // Still generating its terrain
+        let east_ent = app.world_mut().spawn(east).id();
+        world.add_chunk(east, east_ent);
+        app.insert_resource(world);
+
+        app.world_mut().run_system_once(remesh_on_neighbour_load);
+        assert!(app.world().get::<HasMesh>(chunk_ent).is_some());
+
+        app.world_mut().entity_mut(east_ent).insert(neighbour);
+        app.world_mut().run_system_once(remesh_on_neighbour_load);
+        assert!(app.world().get::<HasMesh>(chunk_ent).is_none());
+        assert!(app.world().get::<MissingNeighbours>(chunk_ent).is_none());
+    }
+
+    #[test]
+    pub fn ao_across_chunk_boundaries() {
+        const Y: u32 = 10;
+        let stone = |chunk: &mut ChunkVoxels, x, y, z| {
+            chunk.voxel_mut(LocalVoxelPosition::new(x, y, z)).kind = VoxelKind::Stone;
+        };
+
+        // A voxel with shadow casters above it: one diagonally, one to the side
+        let mut single = ChunkVoxels::default();
+        stone(&mut single, 7, Y, 7);
+        stone(&mut single, 8, Y + 1, 8);
+        stone(&mut single, 6, Y + 1, 7);
+        let single_mesh = from_chunk((ChunkPosition::new(0, 0), &single), &[], default()).opaque;
+        let expected = voxel_vertex_data(&single_mesh, ivec3(7, Y as i32, 7));
+        // Make sure the casters actually darken some vertices
+        assert!(expected.iter().any(|data| data.get_bits(5..7) != 3));
+
+        // The same geometry, with the voxel on the corner column of chunk (0, 0)
+        let centre = ChunkPosition::new(0, 0);
+        let mut chunk = ChunkVoxels::default();
+        stone(&mut chunk, 15, Y, 15);
+        stone(&mut chunk, 14, Y + 1, 15);
+        let mut diagonal = ChunkVoxels::default();
+        stone(&mut diagonal, 0, Y + 1, 0);
+        let adj = centre
+            .neighbouring_chunks()
+            .all()
+            .map(|pos| (pos, ChunkVoxels::default()))
+            .into_iter()
+            .chain([(ChunkPosition::new(16, 16), diagonal)])
+            .collect::<Vec<_>>();
+        let split_mesh = from_chunk((centre, &chunk), &adj, default()).opaque;
+        assert_eq!(
+            voxel_vertex_data(&split_mesh, ivec3(15, Y as i32, 15)),
+            expected
+        );
+    }
+
+    #[test]
+    pub fn stats_count_emitted_triangles() {
+        let mut chunk = ChunkVoxels::default();
+        chunk.fill_region(
+            LocalVoxelPosition::new(0, 0, 0),
+            LocalVoxelPosition::new(15, 10, 15),
+            VoxelKind::Stone,
+        );
+        chunk.fill_region(
+            LocalVoxelPosition::new(2, 11, 2),
+            LocalVoxelPosition::new(9, 11, 5),
+            VoxelKind::Water,
+        );
+        chunk.voxel_mut(LocalVoxelPosition::new(12, 11, 12)).kind = VoxelKind::Glass;
+
+        let pos = ChunkPosition::new(0, 0);
+        for batched_culling in [false, true] {
+            let settings = MeshSettings {
+                batched_culling,
+                stats: true,
+                ..default()
+            };
+            let meshes = from_chunk((pos, &chunk), &[], settings);
+            let stats = meshes.stats.unwrap();
+            let transparent = meshes.transparent.unwrap();
+            let indices =
+                meshes.opaque.indices().unwrap().len() + transparent.indices().unwrap().len();
+            assert_eq!(stats.triangles, indices as u64 / 3);
+            let leaves: usize = chunk
+                .iter_octants()
+                .map(|(_, octant)| octant.leaves().count())
+                .sum();
+            assert_eq!(stats.octants_visited, leaves as u64);
+            // Faces buried in the ground, or under the water and glass
+            assert!(stats.faces_culled > 0);
+        }
+
+        assert!(from_chunk((pos, &chunk), &[], default()).stats.is_none());
+    }
+}
@@ -4,10 +4,23 @@ use either::Either;
 #[derive(Debug, Clone)]
 pub struct Octree<const SZ: usize, T> {
     octants: Vec<Octant<T>>,
+    /// Octants at this depth are never split, instead storing each of their
+    /// values densely
+    max_depth: u32,
 }
 
 impl<const SZ: usize, T: Default> Default for Octree<SZ, T> {
     fn default() -> Self {
+        Self::with_max_depth(SZ.ilog2())
+    }
+}
+
+impl<const SZ: usize, T: Default> Octree<SZ, T> {
+    /// Create an octree which will split at most `max_depth` times, storing
+    /// values densely below that. A depth of `log2(SZ)` never stores values
+    /// densely
+    pub fn with_max_depth(max_depth: u32) -> Self {
+        assert!(SZ.is_power_of_two(), "octree size must be a power of two");
         Self {
             octants: vec![Octant {
                 kind: OctantKind::Chunk(T::default()),
@@ -15,6 +28,7 @@ impl<const SZ: usize, T: Default> Default for Octree<SZ, T> {
                 size: SZ,
                 enabled: true,
             }],
+            max_depth: max_depth.min(SZ.ilog2()),
         }
     }
 }
@@ -29,18 +43,15 @@ where
 
     /// Iterate over each value on the octree, in no specific order.
     pub fn iter(&self) -> impl Iterator<Item = (OctantPos, &T)> {
-        // FIXME: Use find_map to avoid needing to process Nodes and needing Either
-        self.iter_octants().flat_map(|octant| match &octant.kind {
-            OctantKind::Chunk(inner) => {
-                let start = octant.position;
-                let end = start + OctantPos(octant.size, octant.size, octant.size);
-                Either::Left((start.0..end.0).flat_map(move |x| {
+        self.iter_octants().flat_map(|octant| {
+            octant.leaves().flat_map(|(start, size, inner)| {
+                let end = start + OctantPos(size, size, size);
+                (start.0..end.0).flat_map(move |x| {
                     (start.1..end.1).flat_map(move |y| {
                         (start.2..end.2).map(move |z| (OctantPos(x, y, z), inner))
                     })
-                }))
-            }
-            OctantKind::Node(_) => Either::Right(std::iter::empty()),
+                })
+            })
         })
     }
 
@@ -61,28 +72,134 @@ where
     where
         T: std::fmt::Debug,
     {
+        let idx = self.writable_leaf_idx(pos);
+        let octant = &mut self.octants[idx];
+        let dense_idx = octant.dense_index(pos);
+        match &mut octant.kind {
+            OctantKind::Chunk(inner) => inner,
+            OctantKind::Dense(values) => &mut values[dense_idx],
+            OctantKind::Node(_) => unreachable!(),
+        }
+    }
+
+    /// Set the value at each position. Positions next to each other in the
+    /// tree are set without searching from the root again, so this is fastest
+    /// when `values` are sorted by [`OctantPos::morton`]
+    pub fn set_many(&mut self, values: impl IntoIterator<Item = (OctantPos, T)>) {
+        // Leaves returned by `writable_leaf_idx` are never split, so stay valid
+        let mut leaf: Option<usize> = None;
+        for (pos, value) in values {
+            let idx = match leaf {
+                Some(idx) if self.octants[idx].contains(pos) => idx,
+                _ => self.writable_leaf_idx(pos),
+            };
+            leaf = Some(idx);
+            let octant = &mut self.octants[idx];
+            let dense_idx = octant.dense_index(pos);
+            match &mut octant.kind {
+                OctantKind::Chunk(inner) => *inner = value,
+                OctantKind::Dense(values) => values[dense_idx] = value,
+                OctantKind::Node(_) => unreachable!(),
+            }
+        }
+    }
+
+    /// Set every value from `min` to `max` inclusive. Octants entirely within
+    /// the region are set as a whole, so only octants on its edge are split
+    pub fn fill(&mut self, min: OctantPos, max: OctantPos, value: T) {
+        self.fill_octant(0, min, max, &value);
+    }
+
+    fn fill_octant(&mut self, idx: usize, min: OctantPos, max: OctantPos, value: &T) {
+        let octant = &self.octants[idx];
+        if !octant.overlaps(min, max) {
+            return;
+        }
+        if octant.within(min, max) {
+            if let OctantKind::Node(subindexes) = octant.kind {
+                for sub in subindexes {
+                    self.disable_subtree(sub);
+                }
+            }
+            self.octants[idx].kind = OctantKind::Chunk(value.clone());
+            return;
+        }
+
+        let min_size = SZ >> self.max_depth;
+        let subindexes = match &octant.kind {
+            OctantKind::Node(subindexes) => *subindexes,
+            OctantKind::Chunk(_) if octant.size > min_size => self.split_chunk(idx),
+            OctantKind::Chunk(inner) => {
+                let values = vec![inner.clone(); octant.size.pow(3)];
+                self.octants[idx].kind = OctantKind::Dense(values);
+                return self.fill_octant(idx, min, max, value);
+            }
+            OctantKind::Dense(_) => {
+                let octant = &mut self.octants[idx];
+                let start = octant.position;
+                for x in min.0.max(start.0)..=max.0.min(start.0 + octant.size - 1) {
+                    for y in min.1.max(start.1)..=max.1.min(start.1 + octant.size - 1) {
+                        for z in min.2.max(start.2)..=max.2.min(start.2 + octant.size - 1) {
+                            let dense_idx = octant.dense_index(OctantPos(x, y, z));
+                            let OctantKind::Dense(values) = &mut octant.kind else {
+                                unreachable!()
+                            };
+                            values[dense_idx] = value.clone();
+                        }
+                    }
+                }
+                return;
+            }
+        };
+        for sub in subindexes {
+            self.fill_octant(sub, min, max, value);
+        }
+    }
+
+    /// Mark an octant and all of its children as orphans
+    fn disable_subtree(&mut self, idx: usize) {
+        self.octants[idx].enabled = false;
+        if let OctantKind::Node(subindexes) = self.octants[idx].kind {
+            for sub in subindexes {
+                self.disable_subtree(sub);
+            }
+        }
+    }
+
+    /// Index of the octant storing the value at `pos`, splitting the tree
+    /// down to its maximum depth and storing values densely there
+    fn writable_leaf_idx(&mut self, pos: OctantPos) -> usize {
         let mut idx = self.octants[0]
             .find_containing_chunk_idx(0, pos, &self.octants)
             .expect("given position out of range of tree");
 
-        while self.octants[idx].size > 1 {
-            if let OctantKind::Node(subidxs) = &self.octants[idx].kind {
-                idx = *subidxs
-                    .iter()
-                    .find(|&&subidx| self.octants[subidx].contains(pos))
-                    .unwrap();
-            } else {
-                let indexes = self.split_chunk(idx);
-                idx = *indexes
-                    .iter()
-                    .find(|&&subidx| self.octants[subidx].contains(pos))
-                    .unwrap();
+        let min_size = SZ >> self.max_depth;
+        while self.octants[idx].size > min_size {
+            match &self.octants[idx].kind {
+                OctantKind::Node(subidxs) => {
+                    idx = *subidxs
+                        .iter()
+                        .find(|&&subidx| self.octants[subidx].contains(pos))
+                        .unwrap();
+                }
+                OctantKind::Chunk(_) => {
+                    let indexes = self.split_chunk(idx);
+                    idx = *indexes
+                        .iter()
+                        .find(|&&subidx| self.octants[subidx].contains(pos))
+                        .unwrap();
+                }
+                OctantKind::Dense(_) => break,
             }
         }
-        let OctantKind::Chunk(inner) = &mut self.octants[idx].kind else {
-            unreachable!()
-        };
-        inner
+
+        let octant = &mut self.octants[idx];
+        if let OctantKind::Chunk(inner) = &octant.kind {
+            if octant.size > 1 {
+                octant.kind = OctantKind::Dense(vec![inner.clone(); octant.size.pow(3)]);
+            }
+        }
+        idx
     }
 
     fn split_chunk(&mut self, idx: usize) -> [usize; 8] {
@@ -92,6 +209,10 @@ where
                 kind: OctantKind::Node(_),
                 ..
             } => panic!("splitting already split chunk"),
+            Octant {
+                kind: OctantKind::Dense(_),
+                ..
+            } => panic!("splitting dense octant"),
             Octant {
                 kind: OctantKind::Chunk(inner),
                 position,
@@ -138,8 +259,18 @@ where
             if !self.octants[idx].enabled {
                 continue;
             }
-            let OctantKind::Node(subindexes) = self.octants[idx].kind else {
-                continue;
+            let subindexes = match &self.octants[idx].kind {
+                OctantKind::Node(subindexes) => *subindexes,
+                OctantKind::Dense(values) => {
+                    // Collapse dense octants which have become uniform
+                    let (first, rest) = values.split_first().unwrap();
+                    if rest.iter().all(|v| v == first) {
+                        any = true;
+                        self.octants[idx].kind = OctantKind::Chunk(first.clone());
+                    }
+                    continue;
+                }
+                OctantKind::Chunk(_) => continue,
             };
             let (first, rest) = subindexes.split_first().unwrap();
             let OctantKind::Chunk(first) = &self.octants[*first].kind else {
@@ -178,6 +309,18 @@ impl OctantPos {
     pub fn new_u32(x: u32, y: u32, z: u32) -> Self {
         Self(x as _, y as _, z as _)
     }
+
+    /// Position along a Z-order curve, which visits each octant of a tree
+    /// before moving on to the next
+    pub fn morton(self) -> usize {
+        let mut code = 0;
+        for bit in 0..usize::BITS as usize / 3 {
+            code |= ((self.0 >> bit) & 1) << (3 * bit + 2)
+                | ((self.1 >> bit) & 1) << (3 * bit + 1)
+                | ((self.2 >> bit) & 1) << (3 * bit);
+        }
+        code
+    }
 }
 
 impl std::ops::Add for OctantPos {
@@ -199,7 +342,7 @@ impl From<OctantPos> for IVec3 {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct Octant<T> {
     pub kind: OctantKind<T>,
     pub position: OctantPos,
@@ -210,15 +353,66 @@ pub struct Octant<T> {
     pub enabled: bool,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum OctantKind<T> {
     /// A contiguous chunk of elements (may be a single element)
     Chunk(T),
     /// A split chunk of 8 different kinds
     Node([usize; 8]),
+    /// A chunk at the maximum depth of the tree, storing every element. Indexed
+    /// by X, then Y, then Z
+    Dense(Vec<T>),
 }
 
 impl<T> Octant<T> {
+    /// Iterate over the uniform regions of this octant, returning their
+    /// position, side length and value
+    pub fn leaves(&self) -> impl Iterator<Item = (OctantPos, usize, &T)> {
+        let (position, size) = (self.position, self.size);
+        match &self.kind {
+            OctantKind::Chunk(inner) => Either::Left(std::iter::once((position, size, inner))),
+            OctantKind::Dense(values) => Either::Right(Either::Left(
+                values.iter().enumerate().map(move |(i, v)| {
+                    let offset = OctantPos(i / (size * size), (i / size) % size, i % size);
+                    (position + offset, 1, v)
+                }),
+            )),
+            OctantKind::Node(_) => Either::Right(Either::Right(std::iter::empty())),
+        }
+    }
+
+    /// Index of `pos` within the values of a [`OctantKind::Dense`] octant
+    fn dense_index(&self, pos: OctantPos) -> usize {
+        let x = pos.0 - self.position.0;
+        let y = pos.1 - self.position.1;
+        let z = pos.2 - self.position.2;
+        (x * self.size + y) * self.size + z
+    }
+
+    /// Whether any of the region from `min` to `max` inclusive is within
+    /// this octant
+    fn overlaps(&self, min: OctantPos, max: OctantPos) -> bool {
+        let end = self.position + OctantPos(self.size, self.size, self.size);
+        min.0 < end.0
+            && min.1 < end.1
+            && min.2 < end.2
+            && max.0 >= self.position.0
+            && max.1 >= self.position.1
+            && max.2 >= self.position.2
+    }
+
+    /// Whether this octant is entirely within the region from `min` to `max`
+    /// inclusive
+    fn within(&self, min: OctantPos, max: OctantPos) -> bool {
+        let last = self.position + OctantPos(self.size - 1, self.size - 1, self.size - 1);
+        min.0 <= self.position.0
+            && min.1 <= self.position.1
+            && min.2 <= self.position.2
+            && max.0 >= last.0
+            && max.1 >= last.1
+            && max.2 >= last.2
+    }
+
     fn contains(&self, pos: OctantPos) -> bool {
         (self.position.0..(self.position.0 + self.size)).contains(&pos.0)
             && (self.position.1..(self.position.1 + self.size)).contains(&pos.1)
@@ -229,6 +423,8 @@ impl<T> Octant<T> {
         match &self.kind {
             OctantKind::Chunk(inner) if self.contains(pos) => Some(inner),
             OctantKind::Chunk(_) => None,
+            OctantKind::Dense(values) if self.contains(pos) => Some(&values[self.dense_index(pos)]),
+            OctantKind::Dense(_) => None,
             OctantKind::Node(child_idxs) if self.contains(pos) => child_idxs
                 .iter()
                 .find_map(|&idx| nodes[idx].find_child_contents(pos, nodes)),
@@ -243,8 +439,8 @@ impl<T> Octant<T> {
         nodes: &[Octant<T>],
     ) -> Option<usize> {
         match &self.kind {
-            OctantKind::Chunk(_) if self.contains(pos) => Some(self_idx),
-            OctantKind::Chunk(_) => None,
+            OctantKind::Chunk(_) | OctantKind::Dense(_) if self.contains(pos) => Some(self_idx),
+            OctantKind::Chunk(_) | OctantKind::Dense(_) => None,
             OctantKind::Node(child_idxs) if self.contains(pos) => child_idxs
                 .iter()
                 .find_map(|&idx| nodes[idx].find_containing_chunk_idx(idx, pos, nodes)),
@@ -271,4 +467,54 @@ mod test {
         eprintln!("{elts:#?}");
         assert_eq!(elts.len(), 4 * 4 * 4);
     }
+
+    #[test]
+    pub fn max_depth() {
+        let mut tree: Octree<16, u8> = Octree::with_max_depth(1);
+        for x in 0..16 {
+            for z in 0..16 {
+                *tree.get_mut(OctantPos(x, x, z)) = (x + z) as u8 + 1;
+            }
+        }
+        for x in 0..16 {
+            for z in 0..16 {
+                assert_eq!(*tree.get(OctantPos(x, x, z)), (x + z) as u8 + 1);
+                assert_eq!(*tree.get(OctantPos(x, 15 - x, z)), 0);
+            }
+        }
+        // Root plus its 8 children, none of which split any further
+        assert_eq!(tree.iter_octants().count(), 9);
+        assert_eq!(tree.iter().count(), 16 * 16 * 16);
+
+        // Dense octants collapse back once they're uniform again
+        for x in 0..16 {
+            for z in 0..16 {
+                *tree.get_mut(OctantPos(x, x, z)) = 0;
+            }
+        }
+        while tree.merge() {}
+        assert_eq!(tree.iter_octants().count(), 1);
+    }
+
+    #[test]
+    pub fn fill() {
+        let mut tree: Octree<16, u8> = Octree::new();
+        tree.fill(OctantPos(2, 3, 4), OctantPos(12, 9, 4), 1);
+        *tree.get_mut(OctantPos(5, 5, 4)) = 2;
+        tree.fill(OctantPos(5, 5, 0), OctantPos(15, 15, 15), 3);
+        for (pos, &value) in tree.iter() {
+            let expected = if (5..16).contains(&pos.1) && pos.0 >= 5 {
+                3
+            } else if (2..=12).contains(&pos.0) && (3..=9).contains(&pos.1) && pos.2 == 4 {
+                1
+            } else {
+                0
+            };
+            assert_eq!(value, expected, "at {pos:?}");
+        }
+
+        // Filling everything replaces the whole tree with a single octant
+        tree.fill(OctantPos(0, 0, 0), OctantPos(15, 15, 15), 4);
+        assert_eq!(tree.iter_octants().count(), 1);
+    }
 }
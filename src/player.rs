@@ -1,15 +1,39 @@
 use crate::{
+    audio::{
+        PlayerSounds,
+        Sound,
+        SoundEvent,
+        SoundMaterial,
+    },
     chunk::{
         ChunkPosition,
         ChunkVoxels,
+        CHUNK_SIZE,
+        MAX_HEIGHT,
+    },
+    health::{
+        self,
+        DamageEvent,
+        FallTracker,
     },
     highlight::UpdateHighlightedEvent,
     input::{
         CameraVelocity,
         InputState,
+        MAX_PITCH,
+    },
+    material::{
+        CameraMedium,
+        VoxelMaterial,
+        VoxelMaterialResource,
+    },
+    terrain::{
+        self,
+        WorldGenerator,
     },
     ui,
     voxel::{
+        LocalVoxelPosition,
         Voxel,
         VoxelKind,
         VoxelPosition,
@@ -17,7 +41,10 @@ use crate::{
     world::World,
 };
 use bevy::{
-    math::vec3,
+    math::{
+        ivec3,
+        vec3,
+    },
     prelude::*,
 };
 
@@ -26,10 +53,114 @@ const JUMP_VELOCITY: f32 = 10.0;
 const FLY_SPEED_VERTICAL: f32 = 15.0;
 const WATER_ACCELERATION: f32 = 0.5;
 const MAX_SWIM_UP_SPEED: f32 = 4.0;
-const PLAYER_HEIGHT: f32 = 2.0;
-const PLAYER_CAMERA_HEIGHT: f32 = 1.8;
+const PLAYER_HEIGHT: f32 = 1.8;
+const PLAYER_CAMERA_HEIGHT: f32 = 1.62;
 const _: () = assert!(PLAYER_HEIGHT >= PLAYER_CAMERA_HEIGHT);
-const PLAYER_SIDE_LENGTH: f32 = 0.3;
+/// Half of the width of the player's collision box along X and Z
+const PLAYER_HALF_WIDTH: f32 = 0.3;
+/// Margin keeping the collision box from counting voxels it only touches
+const COLLISION_EPSILON: f32 = 1e-3;
+/// How far below the player to look for ground to jump from
+const GROUND_PROBE_DISTANCE: f32 = 0.01;
+/// How far the camera is lowered while sneaking
+const SNEAK_CAMERA_DROP: f32 = 0.3;
+/// Rate at which horizontal velocity decays, per second
+pub const HORIZONTAL_DAMPING: f32 = 10.0;
+/// How much wider the field of view gets while sprinting
+const SPRINT_FOV_SCALE: f32 = 1.15;
+/// Approximate time taken to ease into or out of the sprinting field of view
+const SPRINT_FOV_SECONDS: f32 = 0.15;
+
+/// The field of view of the camera when not sprinting
+#[derive(Resource)]
+pub struct CameraFov {
+    pub base: f32,
+}
+
+impl Default for CameraFov {
+    fn default() -> Self {
+        Self {
+            base: PerspectiveProjection::default().fov,
+        }
+    }
+}
+
+/// Blue tint over the screen while the player's head is underwater
+const WATER_OVERLAY_COLOR: Color = Color::linear_rgba(0.0, 0.0, 0.5, 0.5);
+
+/// How much of the water overlay is shown, fading in on going underwater and
+/// out on surfacing
+#[derive(Resource)]
+pub struct WaterOverlay {
+    /// Seconds taken to fade fully in or out, or 0 to show or hide it at once
+    pub fade_secs: f32,
+    /// From 0 when hidden to 1 when fully shown
+    shown: f32,
+}
+
+impl WaterOverlay {
+    pub fn new(fade_secs: f32) -> Self {
+        Self {
+            fade_secs,
+            shown: 0.0,
+        }
+    }
+
+    /// Move towards being fully shown while `underwater`, or hidden
+    /// otherwise, over `dt` seconds. Returns the colour to tint the screen,
+    /// if any
+    pub fn fade(&mut self, underwater: bool, dt: f32) -> Option<Color> {
+        let target = if underwater { 1.0 } else { 0.0 };
+        self.shown = if self.fade_secs > 0.0 {
+            let step = dt / self.fade_secs;
+            self.shown + (target - self.shown).clamp(-step, step)
+        } else {
+            target
+        };
+        (self.shown > 0.0)
+            .then(|| WATER_OVERLAY_COLOR.with_alpha(WATER_OVERLAY_COLOR.alpha() * self.shown))
+    }
+}
+
+/// Column of the spawn chunk the player spawns in
+const SPAWN_COLUMN: (u32, u32) = (8, 8);
+/// Blocks between the player's feet and the ground they spawn above
+const SPAWN_CLEARANCE: f32 = 2.0;
+
+/// Height of the top of the highest voxel that isn't air in a column of
+/// `chunk`, or 0 if there is none. Water counts as the surface, so the player
+/// spawns on top of lakes rather than at their bottom
+fn surface_height(chunk: &ChunkVoxels, x: u32, z: u32) -> u32 {
+    (0..MAX_HEIGHT as u32)
+        .rev()
+        .find(|&y| chunk.voxel(LocalVoxelPosition::new(x, y, z)).kind != VoxelKind::Air)
+        .map_or(0, |y| y + 1)
+}
+
+/// Camera position the player spawns at, above the terrain of a fresh world
+pub fn spawn_position(generator: &WorldGenerator) -> Vec3 {
+    let chunk_pos = ChunkPosition::new(0, 0);
+    let chunk = terrain::generate_chunk(chunk_pos, generator, CHUNK_SIZE.ilog2());
+    let (x, z) = SPAWN_COLUMN;
+    let feet = surface_height(&chunk, x, z) as f32 + SPAWN_CLEARANCE;
+    chunk_pos.as_vec3() + vec3(x as f32, feet + PLAYER_CAMERA_HEIGHT, z as f32)
+}
+
+/// Direction the camera faces when the player spawns, in radians
+#[derive(Resource, Default, Clone, Copy)]
+pub struct SpawnOrientation {
+    pub yaw: f32,
+    pub pitch: f32,
+}
+
+impl SpawnOrientation {
+    /// Camera rotation matching the yaw/pitch convention used by
+    /// [`crate::input::player_look`]
+    pub fn rotation(&self) -> Quat {
+        let pitch = self.pitch.clamp(-MAX_PITCH, MAX_PITCH);
+        Quat::from_axis_angle(Vec3::Y, self.yaw) * Quat::from_axis_angle(Vec3::X, pitch)
+    }
+}
 
 #[derive(Event)]
 pub struct PlayerMovedEvent {
@@ -43,6 +174,20 @@ impl PlayerMovedEvent {
     }
 }
 
+/// Move the player straight to `to`, stopping them and loading the chunks
+/// around their new position
+pub fn teleport(
+    transform: &mut Transform,
+    velocity: &mut CameraVelocity,
+    ev_move: &mut EventWriter<PlayerMovedEvent>,
+    to: Vec3,
+) {
+    let from = transform.translation;
+    transform.translation = to;
+    velocity.vel = Vec3::ZERO;
+    ev_move.send(PlayerMovedEvent { from, to });
+}
+
 pub fn player_move(
     mut camera_velocity: ResMut<CameraVelocity>,
     mut camera_transform: Query<&mut Transform, With<Camera>>,
@@ -51,28 +196,46 @@ pub fn player_move(
     chunks: Query<&ChunkVoxels>,
     input_state: Res<InputState>,
     mut color_overlay: Query<&mut BackgroundColor, With<ui::ColorOverlay>>,
+    mut water_overlay: ResMut<WaterOverlay>,
+    material_handle: Res<VoxelMaterialResource>,
+    mut materials: ResMut<Assets<VoxelMaterial>>,
     mut ev_update: EventWriter<UpdateHighlightedEvent>,
     mut ev_move: EventWriter<PlayerMovedEvent>,
+    mut fall: ResMut<FallTracker>,
+    mut ev_damage: EventWriter<DamageEvent>,
+    (mut sounds, mut ev_sound): (ResMut<PlayerSounds>, EventWriter<SoundEvent>),
+    mut camera_drop: Local<f32>,
 ) {
     let vel = &mut camera_velocity.vel;
     let pos: &mut Vec3 = &mut camera_transform.single_mut().translation;
     let start_pos = *pos;
-    let get_voxel = |player_pos: Vec3, offset: Vec3| -> Option<&Voxel> {
-        let check_pos = player_pos + offset + vec3(0.0, -PLAYER_HEIGHT, 0.0);
-        let voxel_pos = VoxelPosition::new(check_pos.as_ivec3());
+    // Physics acts on the player standing up, and the camera is lowered
+    // again afterwards while sneaking
+    *pos += Vec3::Y * *camera_drop;
+    let get_voxel = |voxel_pos: IVec3| -> Option<&Voxel> {
+        let voxel_pos = VoxelPosition::new(voxel_pos);
+        let local_pos = voxel_pos.try_into().ok()?;
 
         let chunk_ent = world.chunk_containing(voxel_pos)?;
 
         // Prevent moving into not-yet-loaded chunks
         if let Ok(chunk) = chunks.get(chunk_ent) {
-            Some(chunk.voxel(voxel_pos.into()))
+            Some(chunk.voxel(local_pos))
         } else {
             Some(&Voxel::BEDROCK)
         }
     };
+    // The world border is a solid wall
+    let is_solid = |voxel_pos: IVec3| {
+        !world.border.contains(voxel_pos) || get_voxel(voxel_pos).is_some_and(Voxel::has_collision)
+    };
+    let is_water = |point: Vec3| {
+        get_voxel(point.floor().as_ivec3())
+            .is_some_and(|voxel| matches!(voxel.kind, VoxelKind::Water))
+    };
 
-    let is_in_water =
-        get_voxel(*pos, Vec3::NEG_Y).map_or(false, |voxel| matches!(voxel.kind, VoxelKind::Water));
+    let feet = *pos - Vec3::Y * PLAYER_CAMERA_HEIGHT;
+    let is_in_water = is_water(feet + Vec3::Y * COLLISION_EPSILON);
 
     if !input_state.fly_hack {
         let (g_accel, max_vel) = if is_in_water {
@@ -83,55 +246,7 @@ pub fn player_move(
         vel.y = (vel.y - g_accel * time.delta_seconds()).max(max_vel);
     }
 
-    let has_collision = |player_pos: Vec3, offset: Vec3| -> bool {
-        let Some(voxel) = get_voxel(player_pos, offset) else {
-            return false;
-        };
-        voxel.has_collision()
-    };
-
-    #[derive(Default, Debug)]
-    struct Collisions {
-        neg_x: bool,
-        neg_y: bool,
-        neg_z: bool,
-        x: bool,
-        y: bool,
-        z: bool,
-    }
-    let collisions = {
-        let mut collisions = Collisions::default();
-
-        for base_pos in [
-            *pos + Vec3::NEG_Y * PLAYER_CAMERA_HEIGHT,
-            *pos,
-            *pos + Vec3::Y * (PLAYER_HEIGHT - PLAYER_CAMERA_HEIGHT),
-            *pos + Vec3::Y * 2.0,
-        ] {
-            if has_collision(base_pos, Vec3::NEG_X * PLAYER_SIDE_LENGTH) {
-                collisions.neg_x = true;
-            }
-            if has_collision(base_pos, Vec3::X * PLAYER_SIDE_LENGTH) {
-                collisions.x = true;
-            }
-
-            if has_collision(base_pos, Vec3::NEG_Y) {
-                collisions.neg_y = true;
-            }
-            if has_collision(base_pos, Vec3::Y) {
-                collisions.y = true;
-            }
-
-            if has_collision(base_pos, Vec3::NEG_Z * PLAYER_SIDE_LENGTH) {
-                collisions.neg_z = true;
-            }
-            if has_collision(base_pos, Vec3::Z * PLAYER_SIDE_LENGTH) {
-                collisions.z = true;
-            }
-        }
-        collisions
-    };
-    let is_on_ground = collisions.neg_y;
+    let is_on_ground = on_ground(*pos, is_solid);
 
     if input_state.fly_hack {
         vel.y = if input_state.space_held {
@@ -152,44 +267,72 @@ pub fn player_move(
         }
     }
 
-    let mut water_overlay = color_overlay.single_mut();
-    let is_head_in_water = get_voxel(*pos, vec3(0.0, PLAYER_HEIGHT, 0.0))
-        .map_or(false, |voxel| matches!(voxel.kind, VoxelKind::Water));
-    const WATER_OVERLAY_COLOR: Color = Color::linear_rgba(0.0, 0.0, 0.5, 0.5);
-    if is_head_in_water {
-        water_overlay.0 = WATER_OVERLAY_COLOR
-    } else if water_overlay.0 == WATER_OVERLAY_COLOR {
-        water_overlay.0 = Color::NONE;
+    let head_in_water = is_water(*pos);
+    // Other overlays are left alone unless the water overlay is showing or
+    // has just faded out
+    let was_showing = water_overlay.shown > 0.0;
+    match water_overlay.fade(head_in_water, time.delta_seconds()) {
+        Some(color) => color_overlay.single_mut().0 = color,
+        None if was_showing => color_overlay.single_mut().0 = Color::NONE,
+        None => {}
     }
-
-    if vel.y < 0.0 && collisions.neg_y {
-        vel.y = 0.0;
+    let medium = if head_in_water {
+        CameraMedium::Water
+    } else {
+        CameraMedium::Air
+    };
+    // Only touch the material when it changes, as that re-uploads it
+    if materials
+        .get(&material_handle.handle)
+        .is_some_and(|material| material.camera_medium() != medium)
+    {
+        for handle in material_handle.handles() {
+            let material = materials.get_mut(handle).unwrap();
+            material.set_camera_medium(medium);
+        }
     }
 
-    if vel.y > 0.0 && collisions.y {
-        vel.y = 0.0;
+    // Sneaking keeps the player from walking off the edge of what they're
+    // standing on, but not from jumping or swimming off it
+    if input_state.sneaking && is_on_ground && !is_in_water && vel.y <= 0.0 {
+        stop_at_edges(*pos, vel, time.delta_seconds(), is_solid);
     }
 
-    // Collision in 4 cardinal directions
-    if vel.x > 0.0 && collisions.x {
-        vel.x = 0.0;
+    let falling = vel.y < 0.0;
+    let before_move = *pos;
+    let blocked = move_and_collide(pos, vel, time.delta_seconds(), is_solid);
+    let feet = *pos - Vec3::Y * PLAYER_CAMERA_HEIGHT;
+    let cushioned = input_state.fly_hack || is_water(feet + Vec3::Y * COLLISION_EPSILON);
+    if let Some(fallen) = fall.track(pos.y - before_move.y, falling && blocked.y, cushioned) {
+        let damage = health::fall_damage(fallen);
+        if damage > 0.0 {
+            ev_damage.send(DamageEvent(damage));
+        }
     }
-    if vel.x < 0.0 && collisions.neg_x {
-        vel.x = 0.0;
+    if sounds.splash(cushioned && !input_state.fly_hack) {
+        ev_sound.send(SoundEvent(Sound::Splash));
     }
-
-    if vel.z > 0.0 && collisions.z {
-        vel.z = 0.0;
-    }
-    if vel.z < 0.0 && collisions.neg_z {
-        vel.z = 0.0;
+    // Gravity pulls the player into the ground every frame they stand on it
+    let moved = (*pos - before_move).xz();
+    let grounded = !input_state.fly_hack && blocked.y && falling;
+    if sounds.step(moved, time.delta_seconds(), grounded) {
+        let below = get_voxel((feet - Vec3::Y * 0.5).floor().as_ivec3());
+        if let Some(material) = below.and_then(|voxel| SoundMaterial::of(voxel.kind)) {
+            ev_sound.send(SoundEvent(Sound::Step(material)));
+        }
     }
-
-    *pos += *vel * time.delta_seconds();
     // velocity decay
-    vel.x *= 0.9 * (time.delta_seconds() / 1000.0);
-    vel.z *= 0.9 * (time.delta_seconds() / 1000.0);
+    let damping = (-HORIZONTAL_DAMPING * time.delta_seconds()).exp();
+    vel.x *= damping;
+    vel.z *= damping;
 
+    *camera_drop = if input_state.sneaking {
+        SNEAK_CAMERA_DROP
+    } else {
+        0.0
+    };
+    *pos -= Vec3::Y * *camera_drop;
+    // Sneaking moves the camera, and with it where voxels are selected from
     if *pos != start_pos {
         ev_update.send(UpdateHighlightedEvent);
         ev_move.send(PlayerMovedEvent {
@@ -198,3 +341,543 @@ pub fn player_move(
         });
     }
 }
+
+/// Bounds of the player's collision box, given the camera position
+fn player_aabb(camera_pos: Vec3) -> (Vec3, Vec3) {
+    let feet = camera_pos - Vec3::Y * PLAYER_CAMERA_HEIGHT;
+    let half_extents = vec3(PLAYER_HALF_WIDTH, 0.0, PLAYER_HALF_WIDTH);
+    (
+        feet - half_extents,
+        feet + half_extents + Vec3::Y * PLAYER_HEIGHT,
+    )
+}
+
+/// Whether the player is standing on a solid voxel, given the camera
+/// position
+fn on_ground(camera_pos: Vec3, is_solid: impl Fn(IVec3) -> bool) -> bool {
+    let (min, max) = player_aabb(camera_pos);
+    let (_, blocked) = sweep_aabb(min, max, Vec3::NEG_Y * GROUND_PROBE_DISTANCE, is_solid);
+    blocked.y
+}
+
+/// Cancel the horizontal velocity along any axis where moving by it over
+/// `dt` seconds would leave the player with no ground beneath them. Moving
+/// along X is checked first, then along Z from wherever that leaves them,
+/// which also rules out stepping off a corner diagonally
+fn stop_at_edges(camera_pos: Vec3, vel: &mut Vec3, dt: f32, is_solid: impl Fn(IVec3) -> bool) {
+    let mut moved = Vec3::ZERO;
+    for axis in [Vec3::X, Vec3::Z] {
+        let step = *vel * axis * dt;
+        if step == Vec3::ZERO {
+            continue;
+        }
+        if on_ground(camera_pos + moved + step, &is_solid) {
+            moved += step;
+        } else {
+            *vel *= Vec3::ONE - axis;
+        }
+    }
+}
+
+/// Move the player by `vel` over `dt` seconds, stopping at solid voxels and
+/// cancelling the velocity along any blocked axis, which are returned
+fn move_and_collide(
+    pos: &mut Vec3,
+    vel: &mut Vec3,
+    dt: f32,
+    is_solid: impl Fn(IVec3) -> bool,
+) -> BVec3 {
+    let (min, max) = player_aabb(*pos);
+    let (moved, blocked) = sweep_aabb(min, max, *vel * dt, is_solid);
+    *pos += moved;
+    *vel = Vec3::select(blocked, Vec3::ZERO, *vel);
+    blocked
+}
+
+/// Move an axis-aligned box from `min` to `max` by `delta`, stopping at the
+/// first solid voxel it would hit and sliding along it with whatever movement
+/// is left. Collisions are resolved in the order they happen rather than one
+/// axis at a time, so the result doesn't depend on the direction the box
+/// approaches from.
+///
+/// Returns the distance actually moved, and which axes were blocked by a solid
+/// voxel.
+fn sweep_aabb(
+    min: Vec3,
+    max: Vec3,
+    delta: Vec3,
+    is_solid: impl Fn(IVec3) -> bool,
+) -> (Vec3, BVec3) {
+    let mut moved = Vec3::ZERO;
+    let mut blocked = BVec3::FALSE;
+    let mut remaining = delta;
+
+    // Each impact blocks at least one more axis
+    for _ in 0..3 {
+        if remaining == Vec3::ZERO {
+            break;
+        }
+        let Some(impact) = first_impact(min + moved, max + moved, remaining, &is_solid) else {
+            moved += remaining;
+            break;
+        };
+        let step = remaining * impact.time;
+        moved = Vec3::select(impact.axes, moved + impact.contact, moved + step);
+        remaining = Vec3::select(impact.axes, Vec3::ZERO, remaining - step);
+        blocked |= impact.axes;
+    }
+
+    (moved, blocked)
+}
+
+/// Earliest collision of a box moving through the voxels
+struct Impact {
+    /// Fraction of the movement made before the collision
+    time: f32,
+    /// Axes the box was stopped along, which is more than one when it hits
+    /// an edge or corner, or several voxels, at the same time
+    axes: BVec3,
+    /// Distance along each blocked axis to be touching the voxel it hit
+    contact: Vec3,
+}
+
+/// Find the first solid voxel a box from `min` to `max` hits moving by
+/// `delta`, if any. Voxels the box only touches, or already overlaps by more
+/// than [`COLLISION_EPSILON`], aren't hit
+fn first_impact(
+    min: Vec3,
+    max: Vec3,
+    delta: Vec3,
+    is_solid: impl Fn(IVec3) -> bool,
+) -> Option<Impact> {
+    // Every voxel the box passes through
+    let lo = (min.min(min + delta) + COLLISION_EPSILON)
+        .floor()
+        .as_ivec3();
+    let hi = (max.max(max + delta) - COLLISION_EPSILON)
+        .floor()
+        .as_ivec3();
+
+    let mut first: Option<Impact> = None;
+    for x in lo.x..=hi.x {
+        for y in lo.y..=hi.y {
+            for z in lo.z..=hi.z {
+                let cell = ivec3(x, y, z);
+                if !is_solid(cell) {
+                    continue;
+                }
+                let Some((time, axes, contact)) = voxel_impact(min, max, delta, cell) else {
+                    continue;
+                };
+                match &mut first {
+                    Some(first) if (time - first.time).abs() <= IMPACT_TIME_EPSILON => {
+                        first.axes |= axes;
+                        first.contact = Vec3::select(axes, contact, first.contact);
+                    }
+                    Some(first) if time > first.time => {}
+                    _ => {
+                        first = Some(Impact {
+                            time,
+                            axes,
+                            contact,
+                        })
+                    }
+                }
+            }
+        }
+    }
+    first
+}
+
+/// Impacts closer together than this are treated as happening at once, so
+/// moving exactly diagonally into an edge blocks both axes
+const IMPACT_TIME_EPSILON: f32 = 1e-5;
+
+/// When a box moving by `delta` starts overlapping the voxel at `cell`,
+/// which axes it enters the voxel along, and the distance along those axes
+/// to be touching it
+fn voxel_impact(min: Vec3, max: Vec3, delta: Vec3, cell: IVec3) -> Option<(f32, BVec3, Vec3)> {
+    let cell_min = cell.as_vec3();
+    let cell_max = cell_min + Vec3::ONE;
+    let mut entry = [f32::NEG_INFINITY; 3];
+    let mut exit = f32::INFINITY;
+    let mut contact = Vec3::ZERO;
+    for axis in 0..3 {
+        let d = delta[axis];
+        if d == 0.0 {
+            // Has to overlap the voxel the whole time
+            if min[axis] + COLLISION_EPSILON >= cell_max[axis]
+                || max[axis] - COLLISION_EPSILON <= cell_min[axis]
+            {
+                return None;
+            }
+            continue;
+        }
+        // Distances to the face of the voxel the box reaches first, and to
+        // the one it leaves through
+        let (near, far) = if d > 0.0 {
+            (cell_min[axis] - max[axis], cell_max[axis] - min[axis])
+        } else {
+            (cell_max[axis] - min[axis], cell_min[axis] - max[axis])
+        };
+        let epsilon = COLLISION_EPSILON * d.signum();
+        entry[axis] = (near + epsilon) / d;
+        exit = exit.min((far - epsilon) / d);
+        contact[axis] = near;
+    }
+    let time = entry.into_iter().fold(f32::NEG_INFINITY, f32::max);
+    if !(0.0..1.0).contains(&time) || time >= exit {
+        return None;
+    }
+    let axes = BVec3::from(entry.map(|entry| time - entry <= IMPACT_TIME_EPSILON));
+    // Stop as the box touches the voxel, rather than once it's overlapping
+    let contact_time = (0..3)
+        .filter(|&axis| axes.test(axis))
+        .map(|axis| contact[axis] / delta[axis])
+        .fold(f32::INFINITY, f32::min)
+        .max(0.0);
+    Some((contact_time, axes, contact))
+}
+
+pub fn update_sprint_fov(
+    mut projection: Query<&mut Projection, With<Camera>>,
+    input_state: Res<InputState>,
+    fov: Res<CameraFov>,
+    time: Res<Time>,
+) {
+    let Projection::Perspective(projection) = projection.single_mut().into_inner() else {
+        return;
+    };
+    let target = if input_state.sprinting {
+        fov.base * SPRINT_FOV_SCALE
+    } else {
+        fov.base
+    };
+    // Exponential ease which covers ~95% of the distance in `SPRINT_FOV_SECONDS`
+    let t = 1.0 - (-3.0 * time.delta_seconds() / SPRINT_FOV_SECONDS).exp();
+    projection.fov += (target - projection.fov) * t;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DT: f32 = 1.0 / 60.0;
+
+    fn solid_in(chunk: &ChunkVoxels) -> impl Fn(IVec3) -> bool + '_ {
+        |pos| {
+            let in_chunk = pos.cmpge(IVec3::ZERO).all()
+                && pos.cmplt(IVec3::new(16, MAX_HEIGHT as i32, 16)).all();
+            in_chunk
+                && chunk
+                    .voxel(LocalVoxelPosition::new(pos.x as _, pos.y as _, pos.z as _))
+                    .has_collision()
+        }
+    }
+
+    fn place(chunk: &mut ChunkVoxels, x: u32, y: u32, z: u32) {
+        chunk.voxel_mut(LocalVoxelPosition::new(x, y, z)).kind = VoxelKind::Stone;
+    }
+
+    /// A floor of stone at y = 0, spanning the whole chunk
+    fn floored_chunk() -> ChunkVoxels {
+        let mut chunk = ChunkVoxels::default();
+        for x in 0..16 {
+            for z in 0..16 {
+                place(&mut chunk, x, 0, z);
+            }
+        }
+        chunk
+    }
+
+    /// Step the physics with gravity and a constant horizontal velocity
+    fn walk(chunk: &ChunkVoxels, pos: &mut Vec3, horizontal: Vec3, steps: usize) {
+        let mut vel = Vec3::ZERO;
+        for _ in 0..steps {
+            vel.x = horizontal.x;
+            vel.z = horizontal.z;
+            vel.y -= GRAVITY * DT;
+            move_and_collide(pos, &mut vel, DT, solid_in(chunk));
+        }
+    }
+
+    #[test]
+    pub fn wall_collision() {
+        let mut chunk = floored_chunk();
+        for z in 0..16 {
+            place(&mut chunk, 8, 1, z);
+        }
+        let mut pos = vec3(4.5, 1.0 + PLAYER_CAMERA_HEIGHT, 4.5);
+        walk(&chunk, &mut pos, vec3(5.0, 0.0, 0.0), 120);
+
+        let (min, max) = player_aabb(pos);
+        assert!((max.x - 8.0).abs() < 1e-4, "stopped at {max}");
+        assert!((min.y - 1.0).abs() < 1e-4, "standing at {min}");
+    }
+
+    #[test]
+    pub fn diagonal_into_corner() {
+        // Walls meeting at an exterior corner, with the corner voxel at
+        // (8, 1, 8) and nothing between them
+        let mut chunk = floored_chunk();
+        for i in 8..16 {
+            place(&mut chunk, 8, 1, i);
+            place(&mut chunk, i, 1, 8);
+        }
+        let mut pos = vec3(6.0, 1.0 + PLAYER_CAMERA_HEIGHT, 6.0);
+        walk(&chunk, &mut pos, vec3(5.0, 0.0, 5.0), 120);
+
+        let (min, max) = player_aabb(pos);
+        // Must not overlap the corner voxel on both axes at once
+        assert!(
+            max.x <= 8.0 + 1e-4 || max.z <= 8.0 + 1e-4,
+            "clipped into corner: {min} {max}"
+        );
+    }
+
+    /// Where walking from `start` with `horizontal` velocity ends up, and the
+    /// same for the start and velocity mirrored across X = Z
+    fn mirrored_walks(
+        chunk: &ChunkVoxels,
+        start: Vec3,
+        horizontal: Vec3,
+        steps: usize,
+    ) -> (Vec3, Vec3) {
+        let mut pos = start;
+        walk(chunk, &mut pos, horizontal, steps);
+        let mut mirrored = vec3(start.z, start.y, start.x);
+        walk(
+            chunk,
+            &mut mirrored,
+            vec3(horizontal.z, 0.0, horizontal.x),
+            steps,
+        );
+        (pos, vec3(mirrored.z, mirrored.y, mirrored.x))
+    }
+
+    #[test]
+    pub fn corners_stop_symmetrically() {
+        // An inside corner, where walls along X = 8 and Z = 8 meet
+        let mut inside = floored_chunk();
+        for i in 0..=8 {
+            place(&mut inside, 8, 1, i);
+            place(&mut inside, i, 1, 8);
+        }
+        // An outside corner, with the corner voxel at (8, 1, 8)
+        let mut outside = floored_chunk();
+        for i in 8..16 {
+            place(&mut outside, 8, 1, i);
+            place(&mut outside, i, 1, 8);
+        }
+
+        // Few enough steps that sliding off the outside corner doesn't reach
+        // the edge of the chunk
+        for (chunk, start, steps) in [
+            (&inside, vec3(3.5, 0.0, 5.0), 120),
+            (&outside, vec3(5.2, 0.0, 6.0), 40),
+            (&outside, vec3(6.0, 0.0, 6.0), 40),
+        ] {
+            let start = start + Vec3::Y * (1.0 + PLAYER_CAMERA_HEIGHT);
+            for horizontal in [
+                vec3(5.0, 0.0, 5.0),
+                vec3(5.0, 0.0, 4.0),
+                vec3(3.0, 0.0, 6.0),
+            ] {
+                let (pos, mirrored) = mirrored_walks(chunk, start, horizontal, steps);
+                assert!(
+                    pos.abs_diff_eq(mirrored, 1e-4),
+                    "stopped at {pos} but {mirrored} when mirrored, walking {horizontal}"
+                );
+            }
+        }
+
+        // Walking into the inside corner ends up wedged into it
+        let start = vec3(3.5, 1.0 + PLAYER_CAMERA_HEIGHT, 5.0);
+        let (pos, _) = mirrored_walks(&inside, start, vec3(5.0, 0.0, 4.0), 120);
+        let (_, max) = player_aabb(pos);
+        assert!(
+            max.xz().abs_diff_eq(Vec2::splat(8.0), 1e-4),
+            "stopped at {max}"
+        );
+    }
+
+    #[test]
+    pub fn walk_off_ledge() {
+        // Raised platform for x < 8
+        let mut chunk = floored_chunk();
+        for x in 0..8 {
+            for z in 0..16 {
+                place(&mut chunk, x, 1, z);
+            }
+        }
+        let mut pos = vec3(6.0, 2.0 + PLAYER_CAMERA_HEIGHT, 8.0);
+        walk(&chunk, &mut pos, vec3(4.0, 0.0, 0.0), 60);
+
+        let (min, _) = player_aabb(pos);
+        assert!(min.x > 8.0, "didn't leave the ledge: {min}");
+        assert!(
+            (min.y - 1.0).abs() < 1e-4,
+            "didn't fall to the floor: {min}"
+        );
+    }
+
+    #[test]
+    pub fn sneak_stops_at_ledge() {
+        // Raised platform for x < 8 and z < 8
+        let mut chunk = floored_chunk();
+        for x in 0..8 {
+            for z in 0..8 {
+                place(&mut chunk, x, 1, z);
+            }
+        }
+        let sneak = |pos: &mut Vec3, horizontal: Vec3| {
+            let mut vel = Vec3::ZERO;
+            for _ in 0..60 {
+                vel.x = horizontal.x;
+                vel.z = horizontal.z;
+                vel.y -= GRAVITY * DT;
+                if on_ground(*pos, solid_in(&chunk)) {
+                    stop_at_edges(*pos, &mut vel, DT, solid_in(&chunk));
+                }
+                move_and_collide(pos, &mut vel, DT, solid_in(&chunk));
+            }
+        };
+
+        // Hangs over the edge, but still stands on the platform
+        let mut pos = vec3(6.0, 2.0 + PLAYER_CAMERA_HEIGHT, 4.0);
+        sneak(&mut pos, vec3(4.0, 0.0, 0.0));
+        let (min, max) = player_aabb(pos);
+        assert!(min.x < 8.0 && max.x > 8.0, "stopped at {min}");
+        assert!((min.y - 2.0).abs() < 1e-4, "fell off: {min}");
+
+        // Walking diagonally slides along the edge, into the corner
+        let mut pos = vec3(6.0, 2.0 + PLAYER_CAMERA_HEIGHT, 4.0);
+        sneak(&mut pos, vec3(4.0, 0.0, 4.0));
+        let (min, max) = player_aabb(pos);
+        assert!(min.xz().cmplt(Vec2::splat(8.0)).all(), "stopped at {min}");
+        assert!(max.xz().cmpgt(Vec2::splat(8.0)).all(), "stopped at {max}");
+        assert!((min.y - 2.0).abs() < 1e-4, "fell off: {min}");
+
+        // Moving along the ground away from the edge is unaffected
+        let mut pos = vec3(6.0, 2.0 + PLAYER_CAMERA_HEIGHT, 4.0);
+        sneak(&mut pos, vec3(-1.0, 0.0, 0.0));
+        assert!((pos.x - 5.0).abs() < 0.1, "moved to {pos}");
+    }
+
+    #[test]
+    pub fn swim_into_ceiling() {
+        let mut chunk = floored_chunk();
+        for x in 0..16 {
+            for z in 0..16 {
+                place(&mut chunk, x, 4, z);
+            }
+        }
+        let mut pos = vec3(8.5, 1.0 + PLAYER_CAMERA_HEIGHT, 8.5);
+        let mut vel = Vec3::ZERO;
+        for _ in 0..120 {
+            vel.y = (vel.y + WATER_ACCELERATION).clamp(-5.0, MAX_SWIM_UP_SPEED);
+            move_and_collide(&mut pos, &mut vel, DT, solid_in(&chunk));
+        }
+
+        let (_, max) = player_aabb(pos);
+        assert!((max.y - 4.0).abs() < 1e-4, "head at {max}");
+        assert_eq!(vel.y, 0.0);
+    }
+
+    #[test]
+    pub fn spawn_on_surface() {
+        let mut chunk = ChunkVoxels::default();
+        assert_eq!(surface_height(&chunk, 8, 8), 0);
+        chunk.fill_region(
+            LocalVoxelPosition::new(0, 0, 0),
+            LocalVoxelPosition::new(15, 40, 15),
+            VoxelKind::Stone,
+        );
+        assert_eq!(surface_height(&chunk, 8, 8), 41);
+        // Lakes are spawned on top of
+        chunk.fill_region(
+            LocalVoxelPosition::new(4, 41, 4),
+            LocalVoxelPosition::new(12, 50, 12),
+            VoxelKind::Water,
+        );
+        assert_eq!(surface_height(&chunk, 8, 8), 51);
+        assert_eq!(surface_height(&chunk, 0, 0), 41);
+
+        // Flat worlds have grass at Y 64
+        let flat = spawn_position(&WorldGenerator::Flat);
+        assert_eq!(flat.y, 65.0 + SPAWN_CLEARANCE + PLAYER_CAMERA_HEIGHT);
+
+        // The player spawns clear of the ground, trees included
+        for seed in 0..8 {
+            let generator = WorldGenerator::from_seed(seed);
+            let chunk = terrain::generate_chunk(ChunkPosition::new(0, 0), &generator, 4);
+            let feet = (spawn_position(&generator) - Vec3::Y * PLAYER_CAMERA_HEIGHT)
+                .round()
+                .as_uvec3();
+            let kind_at = |y| chunk.voxel(LocalVoxelPosition::new(feet.x, y, feet.z)).kind;
+            assert_eq!(kind_at(feet.y - 1), VoxelKind::Air);
+            assert_eq!(kind_at(feet.y - 2), VoxelKind::Air);
+            assert_ne!(kind_at(feet.y - 3), VoxelKind::Air);
+        }
+    }
+
+    #[test]
+    pub fn spawn_orientation() {
+        let facing = SpawnOrientation {
+            yaw: 90f32.to_radians(),
+            pitch: 0.0,
+        };
+        assert!(Transform::from_rotation(facing.rotation())
+            .forward()
+            .abs_diff_eq(Vec3::NEG_X, 1e-6));
+
+        let facing = SpawnOrientation {
+            yaw: 30f32.to_radians(),
+            pitch: -45f32.to_radians(),
+        };
+        let expected =
+            Quat::from_euler(EulerRot::YXZ, 30f32.to_radians(), -45f32.to_radians(), 0.0);
+        assert!(facing.rotation().abs_diff_eq(expected, 1e-6));
+
+        // Pitch is clamped just like mouse look
+        let facing = SpawnOrientation {
+            yaw: 0.0,
+            pitch: 90f32.to_radians(),
+        };
+        let (_, pitch, _) = facing.rotation().to_euler(EulerRot::YXZ);
+        assert!((pitch - MAX_PITCH).abs() < 1e-6);
+    }
+
+    #[test]
+    pub fn water_overlay_fades() {
+        let alpha = |color: Option<Color>| color.map_or(0.0, |color| color.alpha());
+        let dt = 0.05;
+        let mut overlay = WaterOverlay::new(0.2);
+        assert_eq!(overlay.fade(false, dt), None);
+
+        // Ramps up over 4 frames rather than appearing at once
+        let mut last = 0.0;
+        for _ in 0..4 {
+            let shown = alpha(overlay.fade(true, dt));
+            assert!(shown > last);
+            last = shown;
+        }
+        assert!((last - WATER_OVERLAY_COLOR.alpha()).abs() < 1e-5);
+        assert!((alpha(overlay.fade(true, dt)) - last).abs() < 1e-5);
+
+        // Surfacing halfway through fading back out
+        alpha(overlay.fade(false, dt));
+        let half = alpha(overlay.fade(false, dt));
+        assert!((half - WATER_OVERLAY_COLOR.alpha() / 2.0).abs() < 1e-5);
+        assert!(alpha(overlay.fade(true, dt)) > half);
+        for _ in 0..4 {
+            overlay.fade(false, dt);
+        }
+        assert_eq!(overlay.fade(false, dt), None);
+
+        // Without fading the overlay snaps in and out
+        let mut instant = WaterOverlay::new(0.0);
+        assert_eq!(instant.fade(true, dt), Some(WATER_OVERLAY_COLOR));
+        assert_eq!(instant.fade(false, dt), None);
+    }
+}
@@ -1,39 +1,560 @@
-use std::path::Path;
+use std::{
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::Duration,
+};
 
 use crate::{
-    chunk::ChunkVoxels,
-    world::World,
+    cache::ChunkCache,
+    chunk::{
+        ChunkPosition,
+        ChunkVoxels,
+        CHUNK_SIZE,
+    },
+    input::SaveEvent,
+    terrain::WorldGenerator,
+    waypoint::Waypoints,
+    weather::Weather,
+    world::{
+        World,
+        WorldBorder,
+    },
 };
 
-use bevy::prelude::*;
-use cormine_shared::save::SaveData as SaveDataInner;
+use bevy::{
+    prelude::*,
+    tasks::{
+        block_on,
+        futures_lite::future,
+        Task,
+    },
+    utils::HashSet,
+};
+use cormine_shared::save::{
+    SaveData as SaveDataInner,
+    SavedGenerator,
+    Waypoint,
+};
 
 #[derive(Resource)]
 pub struct SaveData(SaveDataInner);
 
+/// File saves are written to
+#[derive(Resource)]
+pub struct SavePath(pub PathBuf);
+
+/// Path the previous save at `path` is kept at when saving over it
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    backup.into()
+}
+
+/// Emitted once the game has been written to disk
+#[derive(Event)]
+pub struct SavedEvent;
+
+/// Copy of the world as it was when a save was requested. Edits made to
+/// loaded chunks while the save is being written aren't included in it
+pub struct WorldSnapshot {
+    seed: u32,
+    generator: WorldGenerator,
+    chunks: Vec<(ChunkPosition, ChunkVoxels)>,
+    /// Cache holding the edits of chunks that have been unloaded, which are
+    /// read from it as the save is written
+    cache: Option<ChunkCache>,
+    waypoints: Vec<Waypoint>,
+    border: WorldBorder,
+}
+
+impl WorldSnapshot {
+    pub fn new(
+        query: Query<&ChunkVoxels>,
+        world: &World,
+        waypoints: &Waypoints,
+        weather: Option<&Weather>,
+        cache: Option<&ChunkCache>,
+    ) -> Self {
+        let chunks = world
+            .iter()
+            // Chunks still generating terrain have nothing to save
+            .filter_map(|(pos, chunk)| {
+                let mut chunk = query.get(chunk).ok()?.clone();
+                if let Some(weather) = weather {
+                    weather.melt_copy(pos, &mut chunk);
+                }
+                Some((pos, chunk))
+            })
+            .collect();
+        Self {
+            seed: world.seed,
+            generator: world.generator.clone(),
+            chunks,
+            cache: cache.cloned(),
+            waypoints: waypoints.list.clone(),
+            border: world.border,
+        }
+    }
+
+    /// Write the snapshot to `path` unless it is too large, returning whether
+    /// it was written
+    pub fn save(&self, path: &Path, limit: SaveSizeLimit) -> anyhow::Result<bool> {
+        let save = SaveData::from_snapshot(self);
+        let size = save.serialized_size();
+        match limit.check(size) {
+            SaveSizeCheck::Ok => {}
+            SaveSizeCheck::Warn => {
+                warn!(
+                    "Save is {size} bytes, over the limit of {} bytes",
+                    limit.max_bytes
+                );
+            }
+            SaveSizeCheck::Refuse => {
+                error!(
+                    "Not saving: save would be {size} bytes, over the limit of {} bytes",
+                    limit.max_bytes
+                );
+                return Ok(false);
+            }
+        }
+        save.to_file_with_backup(path)?;
+        Ok(true)
+    }
+}
+
+/// Save being written in the background, if any
+#[derive(Resource, Default)]
+pub struct SaveTask {
+    pub task: Option<Task<anyhow::Result<bool>>>,
+    /// Another save was requested while this one was being written
+    pub queued: bool,
+    /// Quit the game once the save has been written
+    pub quit_when_done: bool,
+}
+
+pub fn handle_save_task(
+    mut saving: ResMut<SaveTask>,
+    path: Res<SavePath>,
+    mut ev_save: EventWriter<SaveEvent>,
+    mut ev_saved: EventWriter<SavedEvent>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let Some(task) = &mut saving.task else {
+        return;
+    };
+    let Some(result) = block_on(future::poll_once(task)) else {
+        return;
+    };
+    saving.task = None;
+    match result {
+        Ok(true) => {
+            info!("Saved to `{}`", path.0.display());
+            ev_saved.send(SavedEvent);
+        }
+        Ok(false) => {
+            // Refused for being too large, so quitting would lose the changes
+            saving.quit_when_done = false;
+        }
+        Err(err) => {
+            warn!("Failed to save to `{}`: {err:#}", path.0.display());
+            // Give the player a chance to save again rather than lose their
+            // changes
+            saving.quit_when_done = false;
+        }
+    }
+    if std::mem::take(&mut saving.queued) {
+        ev_save.send(SaveEvent);
+    } else if saving.quit_when_done {
+        exit.send(AppExit::Success);
+    }
+}
+
+/// Periodically saves the game, through the same [`SaveEvent`] as manual
+/// saves
+#[derive(Resource)]
+pub struct Autosave {
+    timer: Timer,
+}
+
+impl Autosave {
+    pub fn new(interval_secs: f32) -> Self {
+        Self {
+            timer: Timer::new(Duration::from_secs_f32(interval_secs), TimerMode::Repeating),
+        }
+    }
+}
+
+pub fn tick_autosave(
+    time: Res<Time>,
+    mut autosave: ResMut<Autosave>,
+    mut ev_save: EventWriter<SaveEvent>,
+) {
+    if autosave.timer.tick(time.delta()).just_finished() {
+        ev_save.send(SaveEvent);
+    }
+}
+
+/// Size above which saving the game warns, or refuses to save at all
+#[derive(Resource, Clone, Copy)]
+pub struct SaveSizeLimit {
+    pub max_bytes: u64,
+    /// Refuse to write oversized saves instead of just warning about them
+    pub refuse: bool,
+}
+
+/// Outcome of checking a save against a [`SaveSizeLimit`]
+#[derive(Debug, PartialEq, Eq)]
+pub enum SaveSizeCheck {
+    Ok,
+    Warn,
+    Refuse,
+}
+
+impl SaveSizeLimit {
+    pub fn check(&self, size: u64) -> SaveSizeCheck {
+        if size <= self.max_bytes {
+            SaveSizeCheck::Ok
+        } else if self.refuse {
+            SaveSizeCheck::Refuse
+        } else {
+            SaveSizeCheck::Warn
+        }
+    }
+}
+
 impl SaveData {
-    pub fn from_world(query: Query<&ChunkVoxels>, world: &World) -> Self {
-        let noise_map = crate::terrain::generate_noise_map(1024, 1024, world.seed);
+    pub fn from_snapshot(snapshot: &WorldSnapshot) -> Self {
         let mut voxels = Vec::new();
-        for (chunk_pos, chunk) in world.iter() {
-            let chunk = query.get(chunk).expect("invalid chunk in world");
+        let mut add_edits = |chunk_pos, chunk: &ChunkVoxels| {
+            let generated =
+                crate::terrain::generate_chunk(chunk_pos, &snapshot.generator, CHUNK_SIZE.ilog2());
             for (vox_pos, vox) in chunk.iter_world_pos(chunk_pos) {
-                if crate::terrain::block_at_position(vox_pos, &noise_map) != vox.kind() {
+                let local_pos = vox_pos
+                    .try_into()
+                    .expect("chunk voxels are within the world height");
+                if generated.voxel(local_pos).kind() != vox.kind() {
                     voxels.push((vox_pos.as_ivec3(), vox.kind()));
                 }
             }
+        };
+        for (chunk_pos, chunk) in &snapshot.chunks {
+            add_edits(*chunk_pos, chunk);
+        }
+        if let Some(cache) = &snapshot.cache {
+            // Chunks both loaded and cached were loaded from the cache, and
+            // may have been edited since
+            let loaded: HashSet<_> = snapshot.chunks.iter().map(|&(pos, _)| pos).collect();
+            for chunk_pos in cache.positions() {
+                if loaded.contains(&chunk_pos) {
+                    continue;
+                }
+                if let Some(chunk) = cache.load(chunk_pos, CHUNK_SIZE.ilog2()) {
+                    add_edits(chunk_pos, &chunk);
+                }
+            }
         }
         Self(SaveDataInner {
-            seed: world.seed,
+            seed: snapshot.seed,
             voxels,
+            boxes: Vec::new(),
+            waypoints: snapshot.waypoints.clone(),
+            width: snapshot.border.width,
+            length: snapshot.border.length,
+            generator: match &snapshot.generator {
+                WorldGenerator::Noise { .. } => match snapshot.generator.blend_seed() {
+                    Some(blend_seed) => SavedGenerator::Blended { blend_seed },
+                    None => SavedGenerator::Noise,
+                },
+                WorldGenerator::Density(_) => SavedGenerator::Density,
+                WorldGenerator::Flat => SavedGenerator::Flat,
+            },
         })
     }
 
+    pub fn serialized_size(&self) -> u64 {
+        self.0.serialized_size().expect("measuring save size")
+    }
+
+    /// Seed of the world the save was made in
+    #[cfg(test)]
+    pub fn seed(&self) -> u32 {
+        self.0.seed
+    }
+
+    /// An empty world generating the same terrain the save was made against
+    pub fn world(&self) -> World {
+        match self.0.generator {
+            SavedGenerator::Noise => World::from_seed(self.0.seed),
+            SavedGenerator::Blended { blend_seed } => World::blended(self.0.seed, blend_seed),
+            SavedGenerator::Density => World::density(self.0.seed),
+            SavedGenerator::Flat => World::flat(),
+        }
+    }
+
+    /// Border of the area the save is played in, or an error if it is too
+    /// large
+    pub fn border(&self) -> Result<WorldBorder, String> {
+        WorldBorder::new(self.0.width, self.0.length)
+    }
+
+    pub fn waypoints(&self) -> &[Waypoint] {
+        &self.0.waypoints
+    }
+
     pub fn from_file<P: AsRef<Path>>(path: P) -> Self {
         Self(SaveDataInner::from_file(path).expect("loading save from file"))
     }
 
-    pub fn to_file<P: AsRef<Path>>(&self, path: P, replace: bool) {
-        self.0.to_file(path, replace).expect("saving game to disk");
+    /// Write the save to `path`, first moving any save already there to its
+    /// [`backup_path`]
+    pub fn to_file_with_backup(&self, path: &Path) -> anyhow::Result<()> {
+        if path.exists() {
+            std::fs::rename(path, backup_path(path))?;
+        }
+        self.0.to_file(path, false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::voxel::VoxelKind;
+
+    #[test]
+    pub fn save_size_limit() {
+        let save = SaveData(SaveDataInner {
+            seed: 0,
+            voxels: (0..1000)
+                .map(|x| (IVec3::new(x, 64, 0), VoxelKind::Stone))
+                .collect(),
+            boxes: Vec::new(),
+            waypoints: Vec::new(),
+            width: 0,
+            length: 0,
+            generator: SavedGenerator::Noise,
+        });
+        let size = save.serialized_size();
+        assert!(size > 1000);
+
+        let mut limit = SaveSizeLimit {
+            max_bytes: size,
+            refuse: false,
+        };
+        assert_eq!(limit.check(size), SaveSizeCheck::Ok);
+        limit.max_bytes = 1000;
+        assert_eq!(limit.check(size), SaveSizeCheck::Warn);
+        limit.refuse = true;
+        assert_eq!(limit.check(size), SaveSizeCheck::Refuse);
+    }
+
+    #[test]
+    pub fn previous_save_kept_as_backup() {
+        let dir = std::env::temp_dir().join(format!("cormine-backup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("world.cms");
+        assert_eq!(backup_path(&path), dir.join("world.cms.bak"));
+
+        let save_with_seed = |seed| {
+            SaveData(SaveDataInner {
+                seed,
+                voxels: Vec::new(),
+                boxes: Vec::new(),
+                waypoints: Vec::new(),
+                width: 0,
+                length: 0,
+                generator: SavedGenerator::Noise,
+            })
+        };
+        save_with_seed(1).to_file_with_backup(&path).unwrap();
+        assert!(!backup_path(&path).exists());
+        save_with_seed(2).to_file_with_backup(&path).unwrap();
+        save_with_seed(3).to_file_with_backup(&path).unwrap();
+        assert_eq!(SaveData::from_file(&path).seed(), 3);
+        assert_eq!(SaveData::from_file(backup_path(&path)).seed(), 2);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    pub fn refused_save_does_not_quit() {
+        use bevy::tasks::AsyncComputeTaskPool;
+
+        let mut app = App::new();
+        app.add_plugins(TaskPoolPlugin::default())
+            .insert_resource(SavePath(PathBuf::from("world.cms")))
+            .init_resource::<SaveTask>()
+            .add_event::<SaveEvent>()
+            .add_event::<SavedEvent>()
+            .add_event::<AppExit>()
+            .add_systems(Update, handle_save_task);
+        // Save & Quit with a save over the size limit
+        *app.world_mut().resource_mut::<SaveTask>() = SaveTask {
+            task: Some(AsyncComputeTaskPool::get().spawn(async { Ok(false) })),
+            queued: false,
+            quit_when_done: true,
+        };
+        while app.world().resource::<SaveTask>().task.is_some() {
+            app.update();
+        }
+        app.update();
+
+        assert!(!app.world().resource::<SaveTask>().quit_when_done);
+        assert!(app.world().resource::<Events<AppExit>>().is_empty());
+        assert!(app.world().resource::<Events<SavedEvent>>().is_empty());
+    }
+
+    #[test]
+    pub fn save_ignores_later_edits() {
+        use crate::voxel::LocalVoxelPosition;
+
+        let dir = std::env::temp_dir().join(format!("cormine-snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("world.cms");
+
+        let mut world = World::flat();
+        let chunk_pos = ChunkPosition::new(0, 0);
+        let mut chunk = crate::terrain::generate_chunk(chunk_pos, &world.generator, 4);
+        chunk.voxel_mut(LocalVoxelPosition::new(1, 100, 1)).kind = VoxelKind::Stone;
+        let mut app = App::new();
+        let chunk_ent = app.world_mut().spawn(chunk).id();
+        world.add_chunk(chunk_pos, chunk_ent);
+        app.add_plugins(TaskPoolPlugin::default())
+            .insert_resource(world)
+            .insert_resource(SaveSizeLimit {
+                max_bytes: u64::MAX,
+                refuse: false,
+            })
+            .insert_resource(SavePath(path.clone()))
+            .init_resource::<SaveTask>()
+            .init_resource::<Waypoints>()
+            .add_event::<SaveEvent>()
+            .add_event::<SavedEvent>()
+            .add_event::<AppExit>()
+            .add_systems(
+                Update,
+                (
+                    crate::world::process_save_events.run_if(on_event::<SaveEvent>()),
+                    handle_save_task.after(crate::world::process_save_events),
+                ),
+            );
+
+        app.world_mut().send_event(SaveEvent);
+        app.update();
+        // Edited after the save started
+        app.world_mut()
+            .get_mut::<ChunkVoxels>(chunk_ent)
+            .unwrap()
+            .voxel_mut(LocalVoxelPosition::new(2, 100, 2))
+            .kind = VoxelKind::Stone;
+        while app.world().resource::<SaveTask>().task.is_some() {
+            app.update();
+        }
+
+        let saved = SaveData::from_file(&path);
+        assert_eq!(
+            saved.0.voxels,
+            vec![(IVec3::new(1, 100, 1), VoxelKind::Stone)]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    pub fn cached_edits_saved() {
+        use crate::{
+            terrain::generate_chunk,
+            voxel::LocalVoxelPosition,
+        };
+
+        let dir = std::env::temp_dir().join(format!("cormine-cached-save-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("world.cms");
+        let mut world = World::flat();
+        let cache = ChunkCache::new(&dir, world.seed, &world.generator);
+        let edited = |pos, edits: &[(u32, VoxelKind)]| {
+            let mut chunk = generate_chunk(pos, &world.generator, 4);
+            for &(x, kind) in edits {
+                chunk.voxel_mut(LocalVoxelPosition::new(x, 100, x)).kind = kind;
+            }
+            chunk
+        };
+        // Edited, then unloaded into the cache as the player moved away
+        let unloaded = ChunkPosition::new(32, 0);
+        cache
+            .store(unloaded, &edited(unloaded, &[(1, VoxelKind::Stone)]))
+            .unwrap();
+        // Loaded back from the cache, and edited again since
+        let loaded = ChunkPosition::new(0, 0);
+        cache
+            .store(loaded, &edited(loaded, &[(2, VoxelKind::Stone)]))
+            .unwrap();
+        let mut app = App::new();
+        let chunk_ent = app
+            .world_mut()
+            .spawn(edited(loaded, &[(3, VoxelKind::Wood)]))
+            .id();
+        world.add_chunk(loaded, chunk_ent);
+        app.add_plugins(TaskPoolPlugin::default())
+            .insert_resource(world)
+            .insert_resource(cache)
+            .insert_resource(SaveSizeLimit {
+                max_bytes: u64::MAX,
+                refuse: false,
+            })
+            .insert_resource(SavePath(path.clone()))
+            .init_resource::<SaveTask>()
+            .init_resource::<Waypoints>()
+            .add_event::<SaveEvent>()
+            .add_event::<SavedEvent>()
+            .add_event::<AppExit>()
+            .add_systems(
+                Update,
+                (
+                    crate::world::process_save_events.run_if(on_event::<SaveEvent>()),
+                    handle_save_task.after(crate::world::process_save_events),
+                ),
+            );
+
+        app.world_mut().send_event(SaveEvent);
+        app.update();
+        while app.world().resource::<SaveTask>().task.is_some() {
+            app.update();
+        }
+
+        let mut voxels = SaveData::from_file(&path).0.voxels;
+        voxels.sort_by_key(|(pos, _)| pos.to_array());
+        assert_eq!(
+            voxels,
+            [
+                (IVec3::new(3, 100, 3), VoxelKind::Wood),
+                (IVec3::new(33, 100, 1), VoxelKind::Stone),
+            ]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    pub fn saved_generator_round_trip() {
+        for world in [
+            World::from_seed(3),
+            World::blended(3, 4),
+            World::density(3),
+            World::flat(),
+        ] {
+            let snapshot = WorldSnapshot {
+                seed: world.seed,
+                generator: world.generator.clone(),
+                chunks: Vec::new(),
+                waypoints: Vec::new(),
+                border: world.border,
+                cache: None,
+            };
+            let bytes = SaveData::from_snapshot(&snapshot).0.to_bytes().unwrap();
+            let loaded = SaveData(SaveDataInner::from_bytes(&bytes).unwrap()).world();
+            assert_eq!(loaded.seed, world.seed);
+            assert_eq!(loaded.generator.name(), world.generator.name());
+            assert_eq!(loaded.generator.blend_seed(), world.generator.blend_seed());
+        }
     }
 }
@@ -0,0 +1,185 @@
+use std::{
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        mpsc::{
+            self,
+            Receiver,
+            Sender,
+        },
+        Mutex,
+    },
+    time::{
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+use bevy::{
+    prelude::*,
+    render::view::screenshot::ScreenshotManager,
+    window::PrimaryWindow,
+};
+
+use crate::ui::ColorOverlay;
+
+/// Directory screenshots are written to
+#[derive(Resource)]
+pub struct ScreenshotDir(pub PathBuf);
+
+/// Emitted once a screenshot has been written to disk
+#[derive(Event)]
+pub struct ScreenshotSavedEvent;
+
+/// Carries the outcome of each screenshot back from the thread it is written
+/// on
+#[derive(Resource)]
+pub struct ScreenshotResults {
+    sender: Sender<anyhow::Result<PathBuf>>,
+    receiver: Mutex<Receiver<anyhow::Result<PathBuf>>>,
+}
+
+impl Default for ScreenshotResults {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            sender,
+            receiver: Mutex::new(receiver),
+        }
+    }
+}
+
+/// UI hidden for a screenshot, shown again the frame after it is taken
+#[derive(Resource, Default)]
+pub struct HiddenForScreenshot(Vec<Entity>);
+
+/// Name of a screenshot taken at `time`, as `cormine_YYYY-MM-DD_HH-MM-SS.png`
+/// in UTC
+pub fn screenshot_name(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // Civil date from days since 1970-01-01, see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months counted from March, so leap days fall at the end of the year
+    let march_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * march_month + 2) / 5 + 1;
+    let month = if march_month < 10 {
+        march_month + 3
+    } else {
+        march_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    format!(
+        "cormine_{year:04}-{month:02}-{day:02}_{:02}-{:02}-{:02}.png",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Write `image` to `path` as a PNG, creating its directory if needed.
+/// Transparency is dropped, as the alpha channel holds brightness when HDR
+/// is enabled
+fn save_screenshot(image: Image, path: &Path) -> anyhow::Result<()> {
+    let image = image.try_into_dynamic()?.to_rgb8();
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    image.save_with_format(path, image::ImageFormat::Png)?;
+    Ok(())
+}
+
+/// Capture the window with F2, or without the toolbar and crosshair with
+/// Shift+F2. The image is encoded and written off the main thread
+pub fn handle_screenshot_key(
+    keys: Res<ButtonInput<KeyCode>>,
+    window: Query<Entity, With<PrimaryWindow>>,
+    mut screenshots: ResMut<ScreenshotManager>,
+    dir: Res<ScreenshotDir>,
+    results: Res<ScreenshotResults>,
+    mut hidden: ResMut<HiddenForScreenshot>,
+    mut ui: Query<(Entity, &mut Visibility), (With<Node>, Without<Parent>, Without<ColorOverlay>)>,
+) {
+    if !keys.just_pressed(KeyCode::F2) {
+        return;
+    }
+    let Ok(window) = window.get_single() else {
+        return;
+    };
+    let path = dir.0.join(screenshot_name(SystemTime::now()));
+    let sender = results.sender.clone();
+    let requested = screenshots.take_screenshot(window, move |image| {
+        let result = save_screenshot(image, &path).map(|()| path);
+        // The game may have exited while the screenshot was being written
+        let _ = sender.send(result);
+    });
+    if requested.is_err() {
+        // Already taking one this frame
+        return;
+    }
+    if keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+        for (entity, mut visibility) in &mut ui {
+            if *visibility != Visibility::Hidden {
+                *visibility = Visibility::Hidden;
+                hidden.0.push(entity);
+            }
+        }
+    }
+}
+
+/// Show UI hidden for a screenshot again, now the frame it was hidden in has
+/// been captured
+pub fn restore_hidden_ui(mut hidden: ResMut<HiddenForScreenshot>, mut ui: Query<&mut Visibility>) {
+    for entity in hidden.0.drain(..) {
+        if let Ok(mut visibility) = ui.get_mut(entity) {
+            *visibility = Visibility::Inherited;
+        }
+    }
+}
+
+/// Report screenshots that have finished being written
+pub fn handle_screenshot_results(
+    results: Res<ScreenshotResults>,
+    mut ev_saved: EventWriter<ScreenshotSavedEvent>,
+) {
+    let receiver = results.receiver.lock().unwrap();
+    for result in receiver.try_iter() {
+        match result {
+            Ok(path) => {
+                info!("Screenshot saved to `{}`", path.display());
+                ev_saved.send(ScreenshotSavedEvent);
+            }
+            Err(err) => warn!("Failed to save screenshot: {err:#}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    pub fn screenshot_names() {
+        let at = |secs| screenshot_name(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0), "cormine_1970-01-01_00-00-00.png");
+        assert_eq!(at(1_700_000_000), "cormine_2023-11-14_22-13-20.png");
+        // Leap day, and the second before the next
+        assert_eq!(at(1_709_164_800), "cormine_2024-02-29_00-00-00.png");
+        assert_eq!(at(1_709_251_199), "cormine_2024-02-29_23-59-59.png");
+        assert_eq!(at(1_709_251_200), "cormine_2024-03-01_00-00-00.png");
+        assert_eq!(at(951_782_400), "cormine_2000-02-29_00-00-00.png");
+        assert_eq!(at(4_107_542_400), "cormine_2100-03-01_00-00-00.png");
+    }
+}
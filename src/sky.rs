@@ -4,12 +4,16 @@ use bevy::{
         NotShadowReceiver,
     },
     prelude::*,
+    render::camera::CameraProjection,
 };
 
-use crate::material::{
-    SunMaterial,
-    VoxelMaterial,
-    VoxelMaterialResource,
+use crate::{
+    impostor::Impostors,
+    material::{
+        SunMaterial,
+        VoxelMaterial,
+        VoxelMaterialResource,
+    },
 };
 
 #[derive(Component)]
@@ -32,21 +36,96 @@ pub fn add_sun(
         .insert((NotShadowReceiver, NotShadowCaster));
 }
 
+/// Fraction of the camera's far plane the sun is drawn at, behind all of
+/// the terrain
+const SUN_FAR_FRACTION: f32 = 0.9;
+/// Distance from the camera at which the sun is drawn at its mesh's size.
+/// It is scaled with its distance so it looks the same size whatever the
+/// far plane is
+const SUN_SIZE_DISTANCE: f32 = 1000.0;
+
+/// Length of a full day/night cycle, in seconds. `0` freezes the sun at noon
+#[derive(Resource)]
+pub struct DayLength(pub f32);
+
+const DAY_LIGHT: Color = Color::WHITE;
+const DUSK_LIGHT: Color = Color::linear_rgb(1.0, 0.55, 0.25);
+const NIGHT_LIGHT: Color = Color::linear_rgb(0.2, 0.25, 0.45);
+
+const DAY_SKY: Color = Color::linear_rgb(0.13, 0.65, 0.92);
+const DUSK_SKY: Color = Color::linear_rgb(0.85, 0.4, 0.2);
+const NIGHT_SKY: Color = Color::linear_rgb(0.01, 0.01, 0.04);
+
+/// Height of the sun (Y component of its direction) above which it is full
+/// daylight
+const DAY_SUN_HEIGHT: f32 = 0.3;
+/// Height of the sun below which it is full night
+const NIGHT_SUN_HEIGHT: f32 = -0.2;
+
+/// Fraction of the way through the day, where `0.0` is noon and `0.5` is
+/// midnight
+fn time_of_day(elapsed: f32, day_length: f32) -> f32 {
+    if day_length <= 0.0 {
+        0.0
+    } else {
+        (elapsed / day_length).fract()
+    }
+}
+
+/// Interpolate between the night, dusk and day colours based on the height of
+/// the sun
+fn sky_gradient(sun_height: f32, night: Color, dusk: Color, day: Color) -> Color {
+    if sun_height >= 0.0 {
+        let t = (sun_height / DAY_SUN_HEIGHT).min(1.0);
+        dusk.mix(&day, t)
+    } else {
+        let t = (sun_height / NIGHT_SUN_HEIGHT).min(1.0);
+        dusk.mix(&night, t)
+    }
+}
+
 pub fn update_sun_position(
     mut sun: Query<&mut Transform, (With<Sun>, Without<Camera>)>,
-    player: Query<&Transform, With<Camera>>,
+    mut player: Query<(
+        &Transform,
+        &mut Camera,
+        &Projection,
+        Option<&mut FogSettings>,
+    )>,
     material_handle: Res<VoxelMaterialResource>,
     mut materials: ResMut<Assets<VoxelMaterial>>,
+    impostors: Option<Res<Impostors>>,
+    mut impostor_materials: ResMut<Assets<StandardMaterial>>,
+    day_length: Res<DayLength>,
+    time: Res<Time>,
 ) {
     let mut sun = sun.single_mut();
-    let player = player.single();
-    let mut sun_dir = Transform::from_translation(Vec3::new(0.0, 1.0, 1.0));
-    sun_dir.rotate_x(f32::to_radians(90.0));
-    sun.translation = player.translation + sun_dir.forward().as_vec3() * 1000.0;
+    let (player, mut camera, projection, fog) = player.single_mut();
+    let angle = time_of_day(time.elapsed_seconds(), day_length.0) * std::f32::consts::TAU;
+    let sun_dir = Vec3::new(0.0, angle.cos(), angle.sin());
+    let sun_distance = projection.far() * SUN_FAR_FRACTION;
+    sun.translation = player.translation + sun_dir * sun_distance;
+    sun.scale = Vec3::splat(sun_distance / SUN_SIZE_DISTANCE);
     let up = sun.up();
     sun.look_at(player.translation, up);
 
-    // FIXME: We don't really need to update this every frame
-    let material = materials.get_mut(&material_handle.handle).unwrap();
-    material.set_light_dir(sun_dir.forward().as_vec3());
+    let sky = sky_gradient(sun_dir.y, NIGHT_SKY, DUSK_SKY, DAY_SKY);
+    camera.clear_color = ClearColorConfig::Custom(sky);
+
+    let light_color = sky_gradient(sun_dir.y, NIGHT_LIGHT, DUSK_LIGHT, DAY_LIGHT);
+    for handle in material_handle.handles() {
+        let material = materials.get_mut(handle).unwrap();
+        // Distant terrain fades into the sky
+        material.set_fog_color(sky);
+        material.set_light_dir(sun_dir);
+        material.set_light_color(light_color);
+    }
+    if let Some(mut fog) = fog {
+        fog.color = sky;
+    }
+    // Impostors are unlit, so are only tinted by the sunlight
+    if let Some(impostors) = impostors {
+        let material = impostor_materials.get_mut(&impostors.material).unwrap();
+        material.base_color = light_color;
+    }
 }
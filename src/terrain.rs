@@ -4,21 +4,30 @@ use std::{
 };
 
 use crate::{
+    batch::MeshBatches,
+    cache::ChunkCache,
     chunk::{
         ChunkPosition,
         ChunkVoxels,
         CHUNK_SIZE,
+        CHUNK_SIZE_I,
         MAX_HEIGHT,
     },
+    mesh::HasMesh,
     player::PlayerMovedEvent,
     voxel::{
         LocalVoxelPosition,
         VoxelKind,
         VoxelPosition,
     },
+    weather::Weather,
+    world::WorldDiagnostics,
 };
 use bevy::{
-    math::ivec2,
+    math::{
+        ivec2,
+        ivec3,
+    },
     prelude::*,
     tasks::{
         block_on,
@@ -26,19 +35,19 @@ use bevy::{
         AsyncComputeTaskPool,
         Task,
     },
+    utils::HashMap,
 };
 use noise::{
-    utils::{
-        NoiseMap,
-        NoiseMapBuilder,
-        PlaneMapBuilder,
-    },
     BasicMulti,
+    Fbm,
+    NoiseFn,
     Perlin,
     ScalePoint,
 };
 
-pub fn generate_noise_map(width: usize, height: usize, seed: u32) -> NoiseMap {
+type SeedNoise = ScalePoint<BasicMulti<Perlin>>;
+
+fn seed_noise(seed: u32) -> SeedNoise {
     let mut basic_multi = BasicMulti::<Perlin>::new(seed);
     basic_multi.octaves = 4;
     basic_multi.persistence = 0.5;
@@ -47,12 +56,163 @@ pub fn generate_noise_map(width: usize, height: usize, seed: u32) -> NoiseMap {
     let mut scaled_bm: ScalePoint<_> = ScalePoint::new(basic_multi);
     scaled_bm.x_scale = 4.5;
     scaled_bm.y_scale = 4.5;
+    scaled_bm
+}
+
+/// Noise the height of noise terrain comes from, optionally blended with
+/// the terrain of a second seed
+pub struct HeightNoise {
+    noise: SeedNoise,
+    blend: Option<SeedBlend>,
+}
+
+pub fn height_noise(seed: u32) -> HeightNoise {
+    HeightNoise {
+        noise: seed_noise(seed),
+        blend: None,
+    }
+}
+
+impl HeightNoise {
+    /// Terrain of `seed` meeting that of `blend_seed`, each covering
+    /// continent sized regions of the world
+    pub fn blended(seed: u32, blend_seed: u32) -> Self {
+        Self {
+            noise: seed_noise(seed),
+            blend: Some(SeedBlend {
+                seed: blend_seed,
+                noise: seed_noise(blend_seed),
+                mask: Perlin::new(seed ^ blend_seed.rotate_left(16)),
+            }),
+        }
+    }
+}
+
+/// Voxels per unit of the mask deciding which seed's terrain is where, so
+/// regions of each are thousands of voxels across
+const BLEND_MASK_SCALE: f64 = 1.0 / 2048.0;
+/// Mask values either side of 0 between which the two seeds' terrain is
+/// blended. Past them the terrain is wholly one seed's or the other's
+const BLEND_WIDTH: f64 = 0.2;
+
+/// Terrain of a second seed, blended in across large regions by a low
+/// frequency mask
+struct SeedBlend {
+    seed: u32,
+    noise: SeedNoise,
+    mask: Perlin,
+}
+
+impl SeedBlend {
+    /// Share of the second seed's terrain in the column at `x`, `z`, from 0
+    /// to 1
+    fn weight(&self, x: i32, z: i32) -> f64 {
+        let mask = self
+            .mask
+            .get([x as f64 * BLEND_MASK_SCALE, z as f64 * BLEND_MASK_SCALE]);
+        let t = (mask / BLEND_WIDTH * 0.5 + 0.5).clamp(0.0, 1.0);
+        // Eased so the slope of the terrain doesn't jump at the edges of the
+        // blend
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+/// Units of [`HeightNoise`] input per voxel. Terrain used to come from a
+/// 1024×1024 noise map spanning -1 to 1, which this matches
+const HEIGHT_NOISE_SCALE: f64 = 1.0 / 512.0;
+
+/// Height of the grass layer in flat worlds
+const FLAT_GROUND_HEIGHT: usize = 64;
 
-    PlaneMapBuilder::<_, 3>::new(&scaled_bm)
-        .set_size(width, height)
-        .build()
+/// Lowest ground height of noise terrain, which is always at or below
+/// [`SEA_LEVEL`]
+const MIN_GROUND_HEIGHT: usize = 64;
+/// Highest voxel filled with water in noise terrain. Columns with ground
+/// below it are lakes, filled with water up to sea level, while all other
+/// ground reaches at least sea level so water never sits above the land
+/// beside it
+const SEA_LEVEL: usize = MIN_GROUND_HEIGHT + 1;
+/// Lowest floor of lakes in noise terrain
+const MIN_LAKE_FLOOR: usize = 32;
+
+type DensityNoise = ScalePoint<Fbm<Perlin>>;
+
+/// Method used to decide which voxel is at each position in a fresh world
+#[derive(Clone)]
+pub enum WorldGenerator {
+    /// Rolling hills derived from Perlin noise, dotted with trees
+    Noise { noise: Arc<HeightNoise>, seed: u32 },
+    /// Terrain where each voxel is solid based on 3D noise, allowing for
+    /// overhangs and caves
+    Density(Arc<DensityNoise>),
+    /// Flat layers of bedrock, stone, dirt and grass
+    Flat,
 }
 
+impl WorldGenerator {
+    pub fn from_seed(seed: u32) -> Self {
+        Self::Noise {
+            noise: Arc::new(height_noise(seed)),
+            seed,
+        }
+    }
+
+    /// Noise terrain of `seed` blended with that of `blend_seed`
+    pub fn blended_from_seeds(seed: u32, blend_seed: u32) -> Self {
+        Self::Noise {
+            noise: Arc::new(HeightNoise::blended(seed, blend_seed)),
+            seed,
+        }
+    }
+
+    pub fn density_from_seed(seed: u32) -> Self {
+        let mut fbm = Fbm::<Perlin>::new(seed);
+        fbm.octaves = 4;
+        fbm.persistence = 0.5;
+        fbm.lacunarity = 2.0;
+
+        let noise = ScalePoint::new(fbm)
+            .set_x_scale(DENSITY_HORIZONTAL_SCALE)
+            .set_y_scale(DENSITY_VERTICAL_SCALE)
+            .set_z_scale(DENSITY_HORIZONTAL_SCALE);
+        Self::Density(Arc::new(noise))
+    }
+
+    /// Second seed blended into the terrain, if any
+    pub fn blend_seed(&self) -> Option<u32> {
+        match self {
+            Self::Noise { noise, .. } => noise.blend.as_ref().map(|blend| blend.seed),
+            _ => None,
+        }
+    }
+
+    /// Short name of the kind of terrain generated
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Noise { .. } => "noise",
+            Self::Density(_) => "density",
+            Self::Flat => "flat",
+        }
+    }
+}
+
+/// Height around which density terrain's surface lies
+const DENSITY_SURFACE_HEIGHT: f64 = 80.0;
+/// Number of voxels over which the surface of density terrain can vary above
+/// or below [`DENSITY_SURFACE_HEIGHT`]
+const DENSITY_SURFACE_RANGE: f64 = 32.0;
+const DENSITY_HORIZONTAL_SCALE: f64 = 1.0 / 64.0;
+const DENSITY_VERTICAL_SCALE: f64 = 1.0 / 32.0;
+/// Depth of dirt under the surface of density terrain
+const DENSITY_DIRT_DEPTH: usize = 3;
+
+/// Chance, out of 256, of a grass column growing a tree
+const TREE_CHANCE: u32 = 3;
+const TREE_MIN_HEIGHT: i32 = 4;
+const TREE_MAX_HEIGHT: i32 = 6;
+/// How far a tree's leaves reach horizontally from its trunk
+const CANOPY_RADIUS: i32 = 2;
+
 pub fn ground_height_to_voxel(height: usize, is_top_level: bool) -> VoxelKind {
     if height > 100 && is_top_level {
         return VoxelKind::Snow;
@@ -99,22 +259,75 @@ pub fn spiral(max_x: isize, max_y: isize) -> impl Iterator<Item = (isize, isize)
     })
 }
 
+/// Generates the terrain of a group of chunks, which may have been unloaded by
+/// the time it finishes
 #[derive(Component)]
-pub struct TerrainGenerationTask(Task<(Entity, ChunkVoxels)>);
+pub struct TerrainGenerationTask {
+    task: Task<Vec<(Entity, ChunkVoxels)>>,
+    /// Entities of the chunks being generated
+    chunks: Vec<Entity>,
+}
+
+/// Generate and light the voxels of several chunks at once, for a single task.
+/// Chunks found in the `cache` are read from it instead of being generated
+pub fn generate_chunk_group(
+    chunks: &[(Entity, ChunkPosition)],
+    generator: &WorldGenerator,
+    max_depth: u32,
+    cache: Option<&ChunkCache>,
+) -> Vec<(Entity, ChunkVoxels)> {
+    chunks
+        .iter()
+        .map(|&(ent, pos)| {
+            let mut voxels = cache
+                .and_then(|cache| cache.load(pos, max_depth))
+                .unwrap_or_else(|| generate_chunk(pos, generator, max_depth));
+            crate::light::light_chunk(&mut voxels);
+            (ent, voxels)
+        })
+        .collect()
+}
+
+/// Square of `group_size` by `group_size` chunks which a chunk is generated
+/// along with
+fn terrain_group_of(pos: ChunkPosition, group_size: usize) -> IVec2 {
+    let group_width = CHUNK_SIZE_I * group_size as i32;
+    ivec2(
+        pos.x().div_euclid(group_width),
+        pos.z().div_euclid(group_width),
+    )
+}
 
 pub fn queue_generate_chunk_terrain(
     mut commands: Commands,
     mut world: ResMut<crate::world::World>,
     mut ev_movement: EventReader<PlayerMovedEvent>,
+    mut ev_settings: EventReader<crate::SettingsChangedEvent>,
     settings: Res<crate::Settings>,
     player: Query<&Transform, With<Camera>>,
+    mut batches: ResMut<MeshBatches>,
+    mut diagnostics: ResMut<WorldDiagnostics>,
+    cache: Option<Res<ChunkCache>>,
+    weather: Option<Res<Weather>>,
+    loaded: Query<&ChunkVoxels>,
 ) {
-    if !ev_movement.is_empty() && !ev_movement.read().any(|mvmnt| mvmnt.changed_chunk()) {
+    // A changed load distance brings chunks into or out of range without
+    // the player moving
+    let settings_changed = ev_settings.read().count() > 0;
+    if !settings_changed
+        && !ev_movement.is_empty()
+        && !ev_movement.read().any(|mvmnt| mvmnt.changed_chunk())
+    {
         return;
     }
     let pos: ChunkPosition = player.single().translation.as_ivec3().into();
     let radius = (settings.load_distance as isize) / 2;
     let task_pool = AsyncComputeTaskPool::get();
+    let group_size = settings.terrain_group_size.max(1);
+
+    // Chunks to generate, grouped so nearby groups are still generated first
+    let mut groups: Vec<Vec<(Entity, ChunkPosition)>> = Vec::new();
+    let mut group_indices = HashMap::new();
 
     // This all leads to a lot of hitching. Can we make it so the player has to be
     // further than `load_distance` to make a chunk unload?
@@ -126,71 +339,223 @@ pub fn queue_generate_chunk_terrain(
                 (chunk_x * CHUNK_SIZE as isize) as i32,
                 (chunk_z * CHUNK_SIZE as isize) as i32,
             );
+        if !world.border.contains_chunk(chunk_pos) {
+            continue;
+        }
+        // Chunks still generating are already in the map, so they are never
+        // queued twice
         if world.chunk_at(chunk_pos).is_some() {
             chunks_to_despawn.remove(&chunk_pos);
             continue;
         }
-        let mut chunk = commands.spawn((Name::new("Chunk"), chunk_pos));
-        let chunk_id = chunk.id();
-        let noise_map = Arc::clone(&world.noise_map);
-        let task = async move {
-            let mut voxels = ChunkVoxels::new();
-            for x in 0..CHUNK_SIZE {
-                for y in 0..MAX_HEIGHT {
-                    for z in 0..CHUNK_SIZE {
-                        let local_pos = LocalVoxelPosition::new(x as _, y as _, z as _);
-                        let global_pos = &chunk_pos + local_pos;
-                        voxels.voxel_mut(local_pos).kind =
-                            block_at_position(global_pos, &noise_map);
-                    }
-                }
-            }
-            (chunk_id, voxels)
-        };
-        chunk.insert(TerrainGenerationTask(task_pool.spawn(task)));
+        let chunk_id = commands.spawn((Name::new("Chunk"), chunk_pos)).id();
         world.add_chunk(chunk_pos, chunk_id);
+        let group = *group_indices
+            .entry(terrain_group_of(chunk_pos, group_size))
+            .or_insert_with(|| {
+                groups.push(Vec::new());
+                groups.len() - 1
+            });
+        groups[group].push((chunk_id, chunk_pos));
+    }
+
+    for group in groups {
+        let generator = world.generator.clone();
+        let max_depth = settings.octree_max_depth;
+        let chunks = group.iter().map(|&(ent, _)| ent).collect();
+        let cache = cache.as_deref().cloned();
+        let task =
+            async move { generate_chunk_group(&group, &generator, max_depth, cache.as_ref()) };
+        commands.spawn((
+            Name::new("Terrain Task"),
+            TerrainGenerationTask {
+                task: task_pool.spawn(task),
+                chunks,
+            },
+        ));
     }
 
     for (pos, ent) in chunks_to_despawn {
+        // Chunks still generating are no different from a fresh copy
+        if let (Some(cache), Ok(voxels)) = (&cache, loaded.get(ent)) {
+            let mut voxels = voxels.clone();
+            if let Some(weather) = &weather {
+                weather.melt_copy(pos, &mut voxels);
+            }
+            cache.store_in_background(pos, voxels);
+        }
         world.remove_chunk(pos);
-        commands.entity(ent).despawn();
+        batches.remove_chunk(pos);
+        commands.entity(ent).despawn_recursive();
     }
+    diagnostics.loaded_chunks = world.chunk_map.len();
 }
 
+/// Add the voxels of chunks once generated, and have the loaded chunks beside
+/// them meshed again, as they were meshed as if these chunks were air
 pub fn handle_generated_chunk_terrain(
     mut commands: Commands,
-    mut tasks: Query<&mut TerrainGenerationTask>,
+    mut tasks: Query<(Entity, &mut TerrainGenerationTask)>,
+    loaded: Query<&ChunkPosition>,
+    meshed: Query<(), With<HasMesh>>,
+    world: Res<crate::world::World>,
+    mut diagnostics: ResMut<WorldDiagnostics>,
 ) {
-    for mut task in tasks.iter_mut() {
-        if let Some((ent, voxels)) = block_on(future::poll_once(&mut task.0)) {
-            commands
-                .entity(ent)
-                .remove::<TerrainGenerationTask>()
-                .insert(voxels);
+    diagnostics.awaiting_terrain = 0;
+    for (task_ent, mut task) in tasks.iter_mut() {
+        if !task.chunks.iter().any(|&chunk| loaded.contains(chunk)) {
+            // Every chunk was unloaded before being generated. Dropping the
+            // task cancels it
+            commands.entity(task_ent).despawn();
+            continue;
+        }
+        if let Some(chunks) = block_on(future::poll_once(&mut task.task)) {
+            for (ent, voxels) in chunks {
+                // Skip chunks unloaded while they were generating, including
+                // by commands queued this frame
+                if let Some(mut chunk) = commands.get_entity(ent) {
+                    chunk.try_insert(voxels);
+                }
+                let Ok(pos) = loaded.get(ent) else {
+                    continue;
+                };
+                // Only the neighbours sharing a face, to bound how many
+                // chunks are remeshed
+                for neighbour in pos.neighbouring_chunks().all() {
+                    if let Some(neighbour) =
+                        world.chunk_at(neighbour).filter(|&c| meshed.contains(c))
+                    {
+                        commands.entity(neighbour).remove::<HasMesh>();
+                    }
+                }
+            }
+            commands.entity(task_ent).despawn();
+        } else {
+            diagnostics.awaiting_terrain += task
+                .chunks
+                .iter()
+                .filter(|&&chunk| loaded.contains(chunk))
+                .count();
+        }
+    }
+}
+
+/// Generate the voxels of a chunk as it would be in a fresh world
+pub fn generate_chunk(
+    chunk_pos: ChunkPosition,
+    generator: &WorldGenerator,
+    max_depth: u32,
+) -> ChunkVoxels {
+    if let WorldGenerator::Density(noise) = generator {
+        let mut voxels = ChunkVoxels::with_max_depth(max_depth);
+        // Surfaces can only be found by scanning down each column
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                let column =
+                    density_column(chunk_pos.x() + x as i32, chunk_pos.z() + z as i32, noise);
+                let column: Vec<_> = column
+                    .into_iter()
+                    .enumerate()
+                    .map(|(y, kind)| (LocalVoxelPosition::new(x as _, y as _, z as _), kind))
+                    .collect();
+                voxels.set_many(&column);
+            }
+        }
+        return voxels;
+    }
+    if let WorldGenerator::Flat = generator {
+        let mut voxels = ChunkVoxels::with_max_depth(max_depth);
+        // Every column is the same, so fill each layer at once
+        let mut bottom = 0;
+        while bottom < MAX_HEIGHT {
+            let kind = flat_block_at_position(VoxelPosition::new(ivec3(0, bottom as i32, 0)));
+            let top = (bottom..MAX_HEIGHT)
+                .take_while(|&y| {
+                    flat_block_at_position(VoxelPosition::new(ivec3(0, y as i32, 0))) == kind
+                })
+                .last()
+                .unwrap();
+            voxels.fill_region(
+                LocalVoxelPosition::new(0, bottom as _, 0),
+                LocalVoxelPosition::new(CHUNK_SIZE as u32 - 1, top as _, CHUNK_SIZE as u32 - 1),
+                kind,
+            );
+            bottom = top + 1;
         }
+        return voxels;
+    }
+    let mut voxels = ChunkVoxels::from_fn(max_depth, |local_pos| {
+        block_at_position(&chunk_pos + local_pos, generator)
+    });
+    if let WorldGenerator::Noise { noise, seed } = generator {
+        place_trees(&mut voxels, chunk_pos, noise, *seed);
+    }
+    voxels
+}
+
+/// Terrain for a single voxel, not including anything placed on top of it
+/// such as trees
+pub fn block_at_position(pos: VoxelPosition, generator: &WorldGenerator) -> VoxelKind {
+    match generator {
+        WorldGenerator::Noise { noise, .. } => noise_block_at_position(pos, noise),
+        WorldGenerator::Density(noise) => density_column(pos.x(), pos.z(), noise)[pos.y() as usize],
+        WorldGenerator::Flat => flat_block_at_position(pos),
     }
 }
 
-pub fn block_at_position(pos: VoxelPosition, noise: &NoiseMap) -> VoxelKind {
-    let normalized_x = pos.x() + (noise.size().0 / 2) as i32;
-    let normalized_z = pos.z() + (noise.size().1 / 2) as i32;
-    let noise_val = noise.get_value(normalized_x as usize, normalized_z as usize);
-    let mut height = (noise_val.powf(2.0) * MAX_HEIGHT as f64) as usize;
+fn flat_block_at_position(pos: VoxelPosition) -> VoxelKind {
+    let y = pos.y() as usize;
+    match y {
+        0 => VoxelKind::Bedrock,
+        _ if y < FLAT_GROUND_HEIGHT - 3 => VoxelKind::Stone,
+        _ if y < FLAT_GROUND_HEIGHT => VoxelKind::Dirt,
+        FLAT_GROUND_HEIGHT => VoxelKind::Grass,
+        _ => VoxelKind::Air,
+    }
+}
+
+/// Noise value and terrain height of the column at `x`, `z`
+fn noise_column(x: i32, z: i32, noise: &HeightNoise) -> (f64, usize) {
+    let column = |noise: &SeedNoise| {
+        let noise_val = noise.get([
+            x as f64 * HEIGHT_NOISE_SCALE,
+            z as f64 * HEIGHT_NOISE_SCALE,
+            0.0,
+        ]);
+        // The highest peaks would reach past the top of the world
+        let height = ((noise_val.powf(2.0) * MAX_HEIGHT as f64) as usize + MIN_GROUND_HEIGHT)
+            .min(MAX_HEIGHT - 1);
+        (noise_val, height)
+    };
+    let (noise_val, height) = column(&noise.noise);
+    let Some(blend) = &noise.blend else {
+        return (noise_val, height);
+    };
+    // Heights are blended rather than the noise, as heights grow with the
+    // square of the noise and would dip between two high seeds
+    let weight = blend.weight(x, z);
+    let (other_val, other_height) = column(&blend.noise);
+    let lerp = |a: f64, b: f64| a * (1.0 - weight) + b * weight;
+    (
+        lerp(noise_val, other_val),
+        lerp(height as f64, other_height as f64).round() as usize,
+    )
+}
+
+fn noise_block_at_position(pos: VoxelPosition, noise: &HeightNoise) -> VoxelKind {
+    let (noise_val, height) = noise_column(pos.x(), pos.z(), noise);
     let y = pos.y() as usize;
-    height += 64;
-    if height == 64 {
-        let water_floor: usize = ((noise_val.powf(1.2) * MAX_HEIGHT as f64) as usize).max(32);
+    if height < SEA_LEVEL {
+        let water_floor =
+            ((noise_val.max(0.0).powf(1.2) * MAX_HEIGHT as f64) as usize).max(MIN_LAKE_FLOOR);
         if y < water_floor {
             VoxelKind::Stone
-        } else if y < height + 2 {
+        } else if y <= SEA_LEVEL {
             VoxelKind::Water
         } else {
             VoxelKind::Air
         }
     } else {
-        if height > MAX_HEIGHT - 1 {
-            height = MAX_HEIGHT - 1;
-        }
         match y.cmp(&height) {
             Ordering::Less => ground_height_to_voxel(y, false),
             Ordering::Equal => ground_height_to_voxel(height, true),
@@ -198,3 +563,560 @@ pub fn block_at_position(pos: VoxelPosition, noise: &NoiseMap) -> VoxelKind {
         }
     }
 }
+
+/// Whether the voxel at `x`, `y`, `z` is solid in density terrain. The noise
+/// is biased towards solid below [`DENSITY_SURFACE_HEIGHT`] and air above it
+fn density_is_solid(x: i32, y: usize, z: i32, noise: &DensityNoise) -> bool {
+    let bias = (DENSITY_SURFACE_HEIGHT - y as f64) / DENSITY_SURFACE_RANGE;
+    noise.get([x as f64, y as f64, z as f64]) + bias > 0.0
+}
+
+/// Voxels of a whole column of density terrain, from the bottom of the world
+/// up
+fn density_column(x: i32, z: i32, noise: &DensityNoise) -> [VoxelKind; MAX_HEIGHT] {
+    // Far enough from the surface that the noise can't change the outcome
+    let always_solid = (DENSITY_SURFACE_HEIGHT - DENSITY_SURFACE_RANGE * 2.0).max(1.0) as usize;
+    let never_solid =
+        ((DENSITY_SURFACE_HEIGHT + DENSITY_SURFACE_RANGE * 2.0) as usize).min(MAX_HEIGHT);
+
+    let mut column = [VoxelKind::Air; MAX_HEIGHT];
+    column[0] = VoxelKind::Bedrock;
+    // Scan downwards so each voxel knows how far it is below the nearest air
+    let mut depth = 0;
+    for y in (1..never_solid).rev() {
+        let solid = y < always_solid || density_is_solid(x, y, z, noise);
+        if !solid {
+            depth = 0;
+            continue;
+        }
+        column[y] = match depth {
+            0 => ground_height_to_voxel(y, true),
+            _ if depth <= DENSITY_DIRT_DEPTH => ground_height_to_voxel(y, false),
+            _ => VoxelKind::Stone,
+        };
+        depth += 1;
+    }
+    column
+}
+
+/// Deterministic hash of a column of the world, used to place trees
+fn column_hash(seed: u32, x: i32, z: i32) -> u32 {
+    // Fold each coordinate in, followed by the `lowbias32` finaliser
+    let mut hash = seed;
+    for value in [x as u32, z as u32] {
+        hash ^= value
+            .wrapping_add(0x9e37_79b9)
+            .wrapping_add(hash << 6)
+            .wrapping_add(hash >> 2);
+        hash ^= hash >> 16;
+        hash = hash.wrapping_mul(0x7feb_352d);
+        hash ^= hash >> 15;
+        hash = hash.wrapping_mul(0x846c_a68b);
+        hash ^= hash >> 16;
+    }
+    hash
+}
+
+/// The height of the ground and trunk of the tree growing from the column at
+/// `x`, `z`, if there is one
+fn tree_at(x: i32, z: i32, noise: &HeightNoise, seed: u32) -> Option<(i32, i32)> {
+    let hash = column_hash(seed, x, z);
+    if hash & 0xff >= TREE_CHANCE {
+        return None;
+    }
+    let (_, ground) = noise_column(x, z, noise);
+    // Columns below sea level are filled with water
+    if ground < SEA_LEVEL || ground_height_to_voxel(ground, true) != VoxelKind::Grass {
+        return None;
+    }
+    let trunk =
+        TREE_MIN_HEIGHT + ((hash >> 8) % (TREE_MAX_HEIGHT - TREE_MIN_HEIGHT + 1) as u32) as i32;
+    Some((ground as i32, trunk))
+}
+
+/// Voxels making up a tree with a trunk `height` voxels tall, relative to the
+/// ground voxel it grows from
+fn tree_voxels(height: i32) -> impl Iterator<Item = (IVec3, VoxelKind)> {
+    let trunk = (1..=height).map(|y| (ivec3(0, y, 0), VoxelKind::Wood));
+    let leaves = (height - 1..=height + 1).flat_map(move |y| {
+        let radius = if y <= height { CANOPY_RADIUS } else { 1 };
+        (-radius..=radius).flat_map(move |x| {
+            (-radius..=radius)
+                // Round off the corners of each layer
+                .filter(move |z| x.abs() != radius || z.abs() != radius)
+                .map(move |z| (ivec3(x, y, z), VoxelKind::Leaves))
+        })
+    });
+    trunk.chain(leaves)
+}
+
+/// Add the parts of any trees overlapping a chunk. Trees are placed purely
+/// from the seed and the position of their trunk, so neighbouring chunks
+/// each generate their own share of a tree crossing between them
+fn place_trees(voxels: &mut ChunkVoxels, chunk_pos: ChunkPosition, noise: &HeightNoise, seed: u32) {
+    for x in chunk_pos.x() - CANOPY_RADIUS..chunk_pos.x() + CHUNK_SIZE as i32 + CANOPY_RADIUS {
+        for z in chunk_pos.z() - CANOPY_RADIUS..chunk_pos.z() + CHUNK_SIZE as i32 + CANOPY_RADIUS {
+            let Some((ground, height)) = tree_at(x, z, noise, seed) else {
+                continue;
+            };
+            for (offset, kind) in tree_voxels(height) {
+                let pos = ivec3(x, ground, z) + offset - chunk_pos.as_ivec3();
+                let in_chunk = (0..CHUNK_SIZE as i32).contains(&pos.x)
+                    && (0..MAX_HEIGHT as i32).contains(&pos.y)
+                    && (0..CHUNK_SIZE as i32).contains(&pos.z);
+                if !in_chunk {
+                    continue;
+                }
+                let local_pos = LocalVoxelPosition::new(pos.x as _, pos.y as _, pos.z as _);
+                // Leaves only grow into air, and trunks only replace air or leaves,
+                // so overlapping trees come out the same in whichever order they're
+                // placed
+                let replace = match kind {
+                    VoxelKind::Leaves => voxels.voxel(local_pos).kind == VoxelKind::Air,
+                    _ => matches!(
+                        voxels.voxel(local_pos).kind,
+                        VoxelKind::Air | VoxelKind::Leaves
+                    ),
+                };
+                if replace {
+                    voxels.voxel_mut(local_pos).kind = kind;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn trees_cross_chunk_boundaries() {
+        let seed = 1234;
+        let noise = Arc::new(height_noise(seed));
+        let generator = WorldGenerator::Noise {
+            noise: noise.clone(),
+            seed,
+        };
+
+        // Find a tree whose trunk is just inside the chunk at x = 16, so its
+        // canopy overhangs into the chunk at x = 0
+        let (z, ground, height) = (-100..100)
+            .find_map(|z| {
+                let (ground, height) = tree_at(16, z, &noise, seed)?;
+                Some((z, ground, height))
+            })
+            .expect("no tree found along the chunk boundary");
+        let west = ChunkPosition::new(0, z.div_euclid(CHUNK_SIZE as i32) * CHUNK_SIZE as i32);
+        let east = ChunkPosition::new(16, west.z());
+        let local_z = z.rem_euclid(CHUNK_SIZE as i32) as u32;
+
+        let east_voxels = generate_chunk(east, &generator, 4);
+        let trunk = LocalVoxelPosition::new(0, (ground + 1) as u32, local_z);
+        assert_eq!(east_voxels.voxel(trunk).kind, VoxelKind::Wood);
+        let trunk_top = LocalVoxelPosition::new(0, (ground + height) as u32, local_z);
+        assert_eq!(east_voxels.voxel(trunk_top).kind, VoxelKind::Wood);
+
+        let west_voxels = generate_chunk(west, &generator, 4);
+        for x in [14, 15] {
+            let overhang = LocalVoxelPosition::new(x, (ground + height) as u32, local_z);
+            assert!(
+                matches!(
+                    west_voxels.voxel(overhang).kind,
+                    VoxelKind::Leaves | VoxelKind::Wood
+                ),
+                "missing leaves at {overhang:?}"
+            );
+        }
+
+        // Generation doesn't depend on anything but the seed and position
+        let again = generate_chunk(west, &generator, 2);
+        assert!(west_voxels
+            .iter_local_pos()
+            .all(|(pos, voxel)| again.voxel(pos) == voxel));
+    }
+
+    #[test]
+    pub fn water_never_above_land() {
+        let generator = WorldGenerator::from_seed(1234);
+        let kind = |x, y, z| block_at_position(VoxelPosition::new(ivec3(x, y, z)), &generator);
+
+        // A strip of hills and lakes, checking every voxel of water that
+        // could be at the surface
+        let mut shore = 0;
+        for x in -512..512 {
+            for z in 0..2 {
+                for y in MIN_LAKE_FLOOR as i32..=SEA_LEVEL as i32 + 1 {
+                    if kind(x, y, z) != VoxelKind::Water {
+                        continue;
+                    }
+                    assert!(
+                        y <= SEA_LEVEL as i32,
+                        "water above sea level at {x} {y} {z}"
+                    );
+                    assert_ne!(
+                        kind(x, y - 1, z),
+                        VoxelKind::Air,
+                        "water over air at {x} {y} {z}"
+                    );
+                    for (dx, dz) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                        let beside = kind(x + dx, y, z + dz);
+                        assert_ne!(
+                            beside,
+                            VoxelKind::Air,
+                            "water above land beside {x} {y} {z}"
+                        );
+                        if beside != VoxelKind::Water {
+                            shore += 1;
+                        }
+                    }
+                }
+            }
+        }
+        assert!(shore > 0, "no lakes to check");
+    }
+
+    #[test]
+    pub fn density_overhangs() {
+        let WorldGenerator::Density(noise) = WorldGenerator::density_from_seed(1234) else {
+            unreachable!();
+        };
+        // Find solid ground, then air, then solid ground again above it
+        let noise = noise.as_ref();
+        let overhang = (0..64)
+            .flat_map(|x| (0..64).map(move |z| density_column(x, z, noise)))
+            .find_map(|column| {
+                let gap = column.iter().position(|k| !k.has_collision())?;
+                column[gap..]
+                    .iter()
+                    .any(|k| k.has_collision())
+                    .then_some((column, gap))
+            });
+        let (column, gap) = overhang.expect("no overhangs generated");
+
+        // The ground under the overhang still gets a surface
+        assert_eq!(column[gap - 1], ground_height_to_voxel(gap - 1, true));
+        assert_eq!(column[0], VoxelKind::Bedrock);
+    }
+
+    #[test]
+    pub fn far_terrain_varies() {
+        let seed = 42;
+        let generator = WorldGenerator::from_seed(seed);
+        let WorldGenerator::Noise { noise, .. } = &generator else {
+            unreachable!();
+        };
+        for corner in [ivec2(10_000, 10_000), ivec2(-10_000, -10_000)] {
+            // Past the edge of the old noise map, every column was the same height
+            let mut surfaces = Vec::new();
+            for offset in [ivec2(0, 0), ivec2(256, 0), ivec2(0, 256), ivec2(256, 256)] {
+                let pos = corner + offset;
+                let chunk = generate_chunk(ChunkPosition::new(pos.x, pos.y), &generator, 4);
+                for (x, z) in (0..16).flat_map(|x| (0..16).map(move |z| (x, z))) {
+                    let surface = (0..MAX_HEIGHT as u32)
+                        .rev()
+                        .find(|&y| {
+                            chunk.voxel(LocalVoxelPosition::new(x, y, z)).kind != VoxelKind::Air
+                        })
+                        .unwrap();
+                    // More than the bedrock at the bottom of the world
+                    assert!(surface > 0);
+                    surfaces.push(surface);
+                }
+            }
+            assert!(surfaces.iter().any(|&y| y != surfaces[0]));
+
+            // Even the highest peaks stay within the world
+            for (x, z) in (-50..50).flat_map(|x| (-50..50).map(move |z| (x, z))) {
+                let pos = corner + ivec2(x, z) * 97;
+                assert!(noise_column(pos.x, pos.y, noise).1 < MAX_HEIGHT);
+            }
+        }
+    }
+
+    #[test]
+    pub fn blended_seeds_meet_smoothly() {
+        let (seed, blend_seed) = (7, 8);
+        let generator = WorldGenerator::blended_from_seeds(seed, blend_seed);
+        let WorldGenerator::Noise { noise, .. } = &generator else {
+            unreachable!();
+        };
+        assert_eq!(generator.blend_seed(), Some(blend_seed));
+        assert_eq!(WorldGenerator::from_seed(seed).blend_seed(), None);
+        let blend = noise.blend.as_ref().unwrap();
+        let own = WorldGenerator::from_seed(seed);
+        let other = WorldGenerator::from_seed(blend_seed);
+        let column_of = |generator: &WorldGenerator, x, z| {
+            let WorldGenerator::Noise { noise, .. } = generator else {
+                unreachable!();
+            };
+            noise_column(x, z, noise)
+        };
+
+        // Wholly one seed, wholly the other, and blended
+        let mut found = [false; 3];
+        let z = 1234;
+        let mut last_weight = blend.weight(-20_000, z);
+        for x in -20_000..20_000 {
+            let weight = blend.weight(x, z);
+            assert!((weight - last_weight).abs() < 0.01, "jumped at x = {x}");
+            last_weight = weight;
+            if x % 61 != 0 {
+                continue;
+            }
+
+            let (noise_val, height) = noise_column(x, z, noise);
+            let (own_val, own_height) = column_of(&own, x, z);
+            let (other_val, other_height) = column_of(&other, x, z);
+            let matching = if weight == 0.0 {
+                Some(&own)
+            } else if weight == 1.0 {
+                Some(&other)
+            } else {
+                None
+            };
+            if let Some(matching) = matching {
+                found[(weight == 1.0) as usize] = true;
+                assert_eq!((noise_val, height), column_of(matching, x, z));
+                for y in [height - 1, height, height + 1, SEA_LEVEL] {
+                    let pos = VoxelPosition::new(ivec3(x, y as i32, z));
+                    assert_eq!(
+                        block_at_position(pos, &generator),
+                        block_at_position(pos, matching)
+                    );
+                }
+            } else {
+                found[2] = true;
+                assert!(noise_val >= own_val.min(other_val));
+                assert!(noise_val <= own_val.max(other_val));
+                assert!(height >= own_height.min(other_height));
+                assert!(height <= own_height.max(other_height));
+            }
+        }
+        assert_eq!(found, [true; 3]);
+    }
+
+    #[test]
+    pub fn grouped_generation_matches_single() {
+        let seed = 99;
+        let generator = WorldGenerator::from_seed(seed);
+        let chunks: Vec<_> = [(-32, -32), (-32, -16), (-16, -32), (-16, -16)]
+            .into_iter()
+            .enumerate()
+            .map(|(i, (x, z))| (Entity::from_raw(i as u32), ChunkPosition::new(x, z)))
+            .collect();
+        assert!(chunks
+            .iter()
+            .all(|&(_, pos)| terrain_group_of(pos, 2) == ivec2(-1, -1)));
+        assert_ne!(
+            terrain_group_of(chunks[0].1, 1),
+            terrain_group_of(chunks[3].1, 1)
+        );
+
+        let grouped = generate_chunk_group(&chunks, &generator, 4, None);
+        assert_eq!(grouped.len(), chunks.len());
+        for ((ent, pos), (grouped_ent, grouped_voxels)) in chunks.into_iter().zip(grouped) {
+            assert_eq!(ent, grouped_ent);
+            let single = generate_chunk(pos, &generator, 4);
+            assert!(single
+                .iter()
+                .zip(grouped_voxels.iter())
+                .all(|((a_pos, a), (b_pos, b))| a_pos == b_pos && a.kind == b.kind));
+        }
+    }
+
+    #[test]
+    pub fn loaded_chunks_dirty_neighbour_meshes() {
+        use crate::world::World;
+
+        let mut app = App::new();
+        app.add_plugins(TaskPoolPlugin::default())
+            .init_resource::<WorldDiagnostics>()
+            .add_systems(Update, handle_generated_chunk_terrain);
+        let mut world = World::flat();
+        let mut meshed = Vec::new();
+        for pos in [ChunkPosition::new(16, 0), ChunkPosition::new(-16, 16)] {
+            let voxels = generate_chunk(pos, &world.generator, 4);
+            let ent = app.world_mut().spawn((pos, voxels, HasMesh)).id();
+            world.add_chunk(pos, ent);
+            meshed.push(ent);
+        }
+        let centre = ChunkPosition::new(0, 0);
+        let chunk = app.world_mut().spawn(centre).id();
+        world.add_chunk(centre, chunk);
+
+        let generator = world.generator.clone();
+        app.insert_resource(world);
+        let group = vec![(chunk, centre)];
+        let task = AsyncComputeTaskPool::get()
+            .spawn(async move { generate_chunk_group(&group, &generator, 4, None) });
+        app.world_mut().spawn(TerrainGenerationTask {
+            task,
+            chunks: vec![chunk],
+        });
+        for _ in 0..1000 {
+            if app.world().get::<ChunkVoxels>(chunk).is_some() {
+                break;
+            }
+            app.update();
+        }
+        assert!(app.world().get::<ChunkVoxels>(chunk).is_some());
+        // Diagonal neighbours only share a column of voxels, so are left be
+        assert!(app.world().get::<HasMesh>(meshed[0]).is_none());
+        assert!(app.world().get::<HasMesh>(meshed[1]).is_some());
+    }
+
+    #[test]
+    pub fn fast_movement_loads_chunks_cleanly() {
+        use crate::{
+            input::CameraVelocity,
+            player,
+            world::World,
+        };
+        use bevy::ecs::system::RunSystemOnce;
+
+        let mut app = App::new();
+        app.add_plugins(TaskPoolPlugin::default())
+            .insert_resource(World::flat())
+            .insert_resource(MeshBatches::new(1))
+            .insert_resource(crate::Settings {
+                load_distance: 4,
+                terrain_group_size: 2,
+                ..default()
+            })
+            .init_resource::<CameraVelocity>()
+            .init_resource::<WorldDiagnostics>()
+            .add_event::<PlayerMovedEvent>()
+            .add_event::<crate::SettingsChangedEvent>()
+            .add_systems(
+                Update,
+                (queue_generate_chunk_terrain, handle_generated_chunk_terrain),
+            );
+        app.world_mut()
+            .spawn((Camera::default(), Transform::from_xyz(8.0, 80.0, 8.0)));
+
+        // Fly back and forth across chunks, many times each frame, so chunks
+        // are unloaded while still generating
+        for frame in 0..30 {
+            let fly = move |mut camera: Query<&mut Transform, With<Camera>>,
+                            mut velocity: ResMut<CameraVelocity>,
+                            mut ev_move: EventWriter<PlayerMovedEvent>| {
+                let mut transform = camera.single_mut();
+                for step in 0..10 {
+                    let x = ((frame * 7 + step * 3) % 40 - 20) as f32 * CHUNK_SIZE as f32;
+                    player::teleport(
+                        &mut transform,
+                        &mut velocity,
+                        &mut ev_move,
+                        Vec3::new(x, 80.0, 8.0),
+                    );
+                }
+            };
+            app.world_mut().run_system_once(fly);
+            app.update();
+        }
+        let mut tasks = app.world_mut().query::<&TerrainGenerationTask>();
+        while tasks.iter(app.world()).next().is_some() {
+            app.update();
+        }
+
+        let world = app.world().resource::<World>();
+        // Load distance 4 loads a 5x5 square of chunks around the player
+        assert_eq!(world.chunk_map.len(), 25);
+        let diagnostics = app.world().resource::<WorldDiagnostics>();
+        assert_eq!(diagnostics.loaded_chunks, 25);
+        assert_eq!(diagnostics.awaiting_terrain, 0);
+        for (_, chunk) in world.iter() {
+            assert!(app.world().get::<ChunkVoxels>(chunk).is_some());
+        }
+        let (pos, chunk) = world.iter().next().unwrap();
+        let mut world = World::flat();
+        assert!(world.add_chunk(pos, chunk));
+        assert!(!world.add_chunk(pos, Entity::PLACEHOLDER));
+        assert_eq!(world.chunk_at(pos), Some(chunk));
+    }
+
+    #[test]
+    pub fn load_distance_changes_without_moving() {
+        use crate::{
+            world::World,
+            SettingsChangedEvent,
+        };
+
+        let mut app = App::new();
+        app.add_plugins(TaskPoolPlugin::default())
+            .insert_resource(World::flat())
+            .insert_resource(MeshBatches::new(1))
+            .insert_resource(crate::Settings {
+                load_distance: 4,
+                ..default()
+            })
+            .init_resource::<WorldDiagnostics>()
+            .add_event::<PlayerMovedEvent>()
+            .add_event::<SettingsChangedEvent>()
+            .add_systems(
+                Update,
+                (
+                    queue_generate_chunk_terrain.run_if(
+                        run_once()
+                            .or_else(on_event::<PlayerMovedEvent>())
+                            .or_else(on_event::<SettingsChangedEvent>()),
+                    ),
+                    handle_generated_chunk_terrain,
+                )
+                    .chain(),
+            );
+        app.world_mut()
+            .spawn((Camera::default(), Transform::from_xyz(8.0, 80.0, 8.0)));
+        let set_load_distance = |app: &mut App, load_distance| {
+            let mut settings = app.world_mut().resource_mut::<crate::Settings>();
+            let changed = settings.set_load_distance(load_distance);
+            if changed {
+                app.world_mut().send_event(SettingsChangedEvent);
+            }
+            app.update();
+            changed
+        };
+        let loaded = |app: &mut App| {
+            let chunks = app
+                .world_mut()
+                .query::<&ChunkPosition>()
+                .iter(app.world())
+                .count();
+            let world = app.world().resource::<World>();
+            // No chunk is ever spawned twice
+            assert_eq!(world.chunk_map.len(), chunks);
+            chunks
+        };
+
+        app.update();
+        assert_eq!(loaded(&mut app), 5 * 5);
+        assert!(set_load_distance(&mut app, 8));
+        assert_eq!(loaded(&mut app), 9 * 9);
+        assert!(set_load_distance(&mut app, 2));
+        assert_eq!(loaded(&mut app), 3 * 3);
+
+        // Toggling back and forth every frame, while chunks are still
+        // generating
+        for load_distance in [6, 4, 6, 4, 6] {
+            set_load_distance(&mut app, load_distance);
+        }
+        assert_eq!(loaded(&mut app), 7 * 7);
+        let mut tasks = app.world_mut().query::<&TerrainGenerationTask>();
+        while tasks.iter(app.world()).next().is_some() {
+            app.update();
+        }
+        for (_, chunk) in app.world().resource::<World>().iter() {
+            assert!(app.world().get::<ChunkVoxels>(chunk).is_some());
+        }
+
+        // Absurd distances are clamped
+        let mut settings = app.world_mut().resource_mut::<crate::Settings>();
+        assert!(settings.set_load_distance(1000));
+        assert_eq!(settings.load_distance, 64);
+        assert!(!settings.set_load_distance(65));
+        assert!(set_load_distance(&mut app, 0));
+        assert_eq!(app.world().resource::<crate::Settings>().load_distance, 2);
+        assert_eq!(loaded(&mut app), 3 * 3);
+    }
+}
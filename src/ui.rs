@@ -1,12 +1,106 @@
 use bevy::prelude::*;
 
+use crate::{
+    health::{
+        Health,
+        MAX_HEALTH,
+    },
+    input::{
+        EditCounter,
+        SaveEvent,
+        SlotSelectedEvent,
+    },
+    material::VoxelMaterialResource,
+    save::{
+        SaveTask,
+        SavedEvent,
+    },
+    screenshot::ScreenshotSavedEvent,
+    voxel::VoxelKind,
+};
+
+/// Side length of a toolbar slot, matching `selected.png`
+const SLOT_SIZE: f32 = 72.0;
+/// Space between toolbar slots
+const SLOT_GAP: f32 = 6.0;
+/// Space between the edge of the toolbar and its slots
+const TOOLBAR_PADDING: f32 = 8.0;
+/// Height of the health bar above the toolbar
+const HEALTH_BAR_HEIGHT: f32 = 12.0;
+const HEALTH_COLOR: Color = Color::srgb(0.8, 0.1, 0.1);
+
+/// The voxel kinds the player can place, in toolbar order
+#[derive(Resource)]
+pub struct Toolbar {
+    pub kinds: Vec<VoxelKind>,
+}
+
+impl Default for Toolbar {
+    fn default() -> Self {
+        Self {
+            kinds: vec![
+                VoxelKind::Stone,
+                VoxelKind::Grass,
+                VoxelKind::Water,
+                VoxelKind::Snow,
+                VoxelKind::Dirt,
+                VoxelKind::Glass,
+                VoxelKind::Tnt,
+            ],
+        }
+    }
+}
+
+/// Horizontal offset of a toolbar slot from the left edge of the toolbar
+pub fn slot_offset(slot: usize) -> f32 {
+    TOOLBAR_PADDING + slot as f32 * (SLOT_SIZE + SLOT_GAP)
+}
+
 #[derive(Component)]
 pub struct SelectedPosition;
 
 #[derive(Component)]
 pub struct ColorOverlay;
 
-pub fn draw_ui(mut commands: Commands, asset_server: Res<AssetServer>) {
+/// Filled part of the health bar, as wide as the share of health left
+#[derive(Component)]
+pub struct HealthBar;
+
+/// Text showing the voxels placed and broken this session
+#[derive(Component)]
+pub struct EditCounterText;
+
+/// Text shown briefly after the game is saved or a screenshot is taken
+#[derive(Component)]
+pub struct SavedIndicator {
+    timer: Timer,
+}
+
+/// How long the saved indicator stays on screen
+const SAVED_INDICATOR_SECONDS: f32 = 2.0;
+
+/// Root node of the pause menu
+#[derive(Component)]
+pub struct PauseMenu;
+
+/// Action taken by each button of the pause menu
+#[derive(Component, Clone, Copy)]
+pub enum PauseButton {
+    Resume,
+    Save,
+    Quit,
+}
+
+const BUTTON_COLOR: Color = Color::srgb(0.3, 0.3, 0.3);
+const BUTTON_HOVERED_COLOR: Color = Color::srgb(0.4, 0.4, 0.4);
+
+pub fn draw_ui(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    toolbar: Res<Toolbar>,
+    material: Res<VoxelMaterialResource>,
+    mut atlas_layouts: ResMut<Assets<TextureAtlasLayout>>,
+) {
     commands.spawn((
         NodeBundle {
             style: Style {
@@ -22,6 +116,27 @@ pub fn draw_ui(mut commands: Commands, asset_server: Res<AssetServer>) {
         ColorOverlay,
     ));
 
+    commands.spawn((
+        TextBundle::from_section(
+            "Saved",
+            TextStyle {
+                font_size: 32.0,
+                color: Color::WHITE,
+                ..Default::default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(TOOLBAR_PADDING),
+            right: Val::Px(TOOLBAR_PADDING),
+            ..Default::default()
+        }),
+        Visibility::Hidden,
+        SavedIndicator {
+            timer: Timer::from_seconds(SAVED_INDICATOR_SECONDS, TimerMode::Once),
+        },
+    ));
+
     let crosshair_handle = asset_server.load("embedded://cormine/../assets/images/crosshair.png");
     commands
         .spawn(NodeBundle {
@@ -41,50 +156,268 @@ pub fn draw_ui(mut commands: Commands, asset_server: Res<AssetServer>) {
             });
         });
 
-    let toolbar_handle = asset_server.load("embedded://cormine/../assets/images/toolbar.png");
+    // Each block texture is a tile in a single column
+    let icon_layout = atlas_layouts.add(TextureAtlasLayout::from_grid(
+        UVec2::splat(16),
+        1,
+        material.texture_count(),
+        None,
+        None,
+    ));
+    let selected_handle = asset_server.load("embedded://cormine/../assets/images/selected.png");
     commands
         .spawn(NodeBundle {
             style: Style {
-                width: Val::Px(392.0),
-                height: Val::Px(88.0),
-                align_self: AlignSelf::FlexEnd,
-                justify_self: JustifySelf::Center,
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                flex_direction: FlexDirection::Column,
+                justify_content: JustifyContent::FlexEnd,
+                align_items: AlignItems::Center,
                 ..Default::default()
             },
             background_color: BackgroundColor(Color::NONE),
             ..Default::default()
         })
         .with_children(|parent| {
-            parent.spawn(ImageBundle {
-                image: UiImage::new(toolbar_handle),
-                ..Default::default()
-            });
+            parent
+                .spawn(NodeBundle {
+                    style: Style {
+                        width: Val::Px(
+                            slot_offset(toolbar.kinds.len()) - SLOT_GAP + TOOLBAR_PADDING,
+                        ),
+                        height: Val::Px(HEALTH_BAR_HEIGHT),
+                        margin: UiRect::bottom(Val::Px(SLOT_GAP)),
+                        ..Default::default()
+                    },
+                    background_color: BackgroundColor(Color::BLACK.with_alpha(0.5)),
+                    ..Default::default()
+                })
+                .with_children(|parent| {
+                    parent.spawn((
+                        NodeBundle {
+                            style: Style {
+                                width: Val::Percent(100.0),
+                                height: Val::Percent(100.0),
+                                ..Default::default()
+                            },
+                            background_color: BackgroundColor(HEALTH_COLOR),
+                            ..Default::default()
+                        },
+                        HealthBar,
+                    ));
+                });
+            parent
+                .spawn(NodeBundle {
+                    style: Style {
+                        padding: UiRect::all(Val::Px(TOOLBAR_PADDING)),
+                        column_gap: Val::Px(SLOT_GAP),
+                        ..Default::default()
+                    },
+                    background_color: BackgroundColor(Color::BLACK.with_alpha(0.5)),
+                    ..Default::default()
+                })
+                .with_children(|parent| {
+                    for kind in &toolbar.kinds {
+                        parent
+                            .spawn(NodeBundle {
+                                style: Style {
+                                    width: Val::Px(SLOT_SIZE),
+                                    height: Val::Px(SLOT_SIZE),
+                                    padding: UiRect::all(Val::Px(12.0)),
+                                    ..Default::default()
+                                },
+                                background_color: BackgroundColor(Color::srgb(0.3, 0.3, 0.3)),
+                                ..Default::default()
+                            })
+                            .with_children(|parent| {
+                                parent.spawn((
+                                    ImageBundle {
+                                        style: Style {
+                                            width: Val::Percent(100.0),
+                                            height: Val::Percent(100.0),
+                                            ..Default::default()
+                                        },
+                                        image: UiImage::new(material.icon_handle()),
+                                        ..Default::default()
+                                    },
+                                    TextureAtlas {
+                                        layout: icon_layout.clone(),
+                                        index: *kind as usize,
+                                    },
+                                ));
+                            });
+                    }
+
+                    parent.spawn((
+                        ImageBundle {
+                            style: Style {
+                                position_type: PositionType::Absolute,
+                                left: Val::Px(slot_offset(0)),
+                                top: Val::Px(TOOLBAR_PADDING),
+                                width: Val::Px(SLOT_SIZE),
+                                height: Val::Px(SLOT_SIZE),
+                                ..Default::default()
+                            },
+                            image: UiImage::new(selected_handle),
+                            ..Default::default()
+                        },
+                        SelectedPosition,
+                    ));
+                });
         });
+}
 
-    let selected_handle = asset_server.load("embedded://cormine/../assets/images/selected.png");
+fn edit_counter_text(counter: &EditCounter) -> String {
+    format!("Placed: {}\nBroken: {}", counter.placed, counter.broken)
+}
+
+pub fn spawn_edit_counter(mut commands: Commands, counter: Res<EditCounter>) {
+    commands.spawn((
+        TextBundle::from_section(
+            edit_counter_text(&counter),
+            TextStyle {
+                font_size: 24.0,
+                color: Color::WHITE,
+                ..Default::default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(TOOLBAR_PADDING),
+            left: Val::Px(TOOLBAR_PADDING),
+            ..Default::default()
+        }),
+        EditCounterText,
+    ));
+}
+
+pub fn update_edit_counter(
+    counter: Res<EditCounter>,
+    mut text: Query<&mut Text, With<EditCounterText>>,
+) {
+    for mut text in &mut text {
+        text.sections[0].value = edit_counter_text(&counter);
+    }
+}
+
+pub fn update_health_bar(health: Res<Health>, mut bar: Query<&mut Style, With<HealthBar>>) {
+    for mut style in &mut bar {
+        style.width = Val::Percent(100.0 * health.current.max(0.0) / MAX_HEALTH);
+    }
+}
+
+/// Move the highlight over the toolbar to the newly selected slot
+pub fn move_slot_highlight(
+    mut ev_slot: EventReader<SlotSelectedEvent>,
+    mut highlight: Query<&mut Style, With<SelectedPosition>>,
+) {
+    let Some(ev) = ev_slot.read().last() else {
+        return;
+    };
+    for mut style in &mut highlight {
+        style.left = Val::Px(slot_offset(ev.slot as usize));
+    }
+}
+
+pub fn update_saved_indicator(
+    time: Res<Time>,
+    mut ev_saved: EventReader<SavedEvent>,
+    mut ev_screenshot: EventReader<ScreenshotSavedEvent>,
+    mut indicator: Query<(&mut SavedIndicator, &mut Visibility, &mut Text)>,
+) {
+    let saved = ev_saved.read().count() > 0;
+    let screenshot = ev_screenshot.read().count() > 0;
+    for (mut indicator, mut visibility, mut text) in &mut indicator {
+        if saved || screenshot {
+            text.sections[0].value = if saved { "Saved" } else { "Screenshot saved" }.into();
+            indicator.timer.reset();
+            *visibility = Visibility::Inherited;
+        } else if indicator.timer.tick(time.delta()).just_finished() {
+            *visibility = Visibility::Hidden;
+        }
+    }
+}
+
+pub fn spawn_pause_menu(mut commands: Commands) {
     commands
         .spawn((
             NodeBundle {
                 style: Style {
-                    width: Val::Px(72.0),
-                    height: Val::Px(72.0),
-                    align_self: AlignSelf::FlexEnd,
-                    justify_self: JustifySelf::Center,
-                    margin: UiRect {
-                        bottom: Val::Px(8.0),
-                        ..Default::default()
-                    },
+                    position_type: PositionType::Absolute,
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    flex_direction: FlexDirection::Column,
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    row_gap: Val::Px(SLOT_GAP * 2.0),
                     ..Default::default()
                 },
-                background_color: BackgroundColor(Color::NONE),
                 ..Default::default()
             },
-            SelectedPosition,
+            PauseMenu,
         ))
         .with_children(|parent| {
-            parent.spawn(ImageBundle {
-                image: UiImage::new(selected_handle),
-                ..Default::default()
-            });
+            for (button, label) in [
+                (PauseButton::Resume, "Resume"),
+                (PauseButton::Save, "Save"),
+                (PauseButton::Quit, "Save and Quit"),
+            ] {
+                parent
+                    .spawn((
+                        ButtonBundle {
+                            style: Style {
+                                width: Val::Px(SLOT_SIZE * 4.0),
+                                height: Val::Px(SLOT_SIZE),
+                                align_items: AlignItems::Center,
+                                justify_content: JustifyContent::Center,
+                                ..Default::default()
+                            },
+                            background_color: BackgroundColor(BUTTON_COLOR),
+                            ..Default::default()
+                        },
+                        button,
+                    ))
+                    .with_children(|parent| {
+                        parent.spawn(TextBundle::from_section(
+                            label,
+                            TextStyle {
+                                font_size: 32.0,
+                                color: Color::WHITE,
+                                ..Default::default()
+                            },
+                        ));
+                    });
+            }
         });
 }
+
+pub fn despawn_pause_menu(mut commands: Commands, menu: Query<Entity, With<PauseMenu>>) {
+    for menu in &menu {
+        commands.entity(menu).despawn_recursive();
+    }
+}
+
+pub fn handle_pause_buttons(
+    mut buttons: Query<(&Interaction, &PauseButton, &mut BackgroundColor), Changed<Interaction>>,
+    mut next_state: ResMut<NextState<crate::AppState>>,
+    mut ev_save: EventWriter<SaveEvent>,
+    mut saving: ResMut<SaveTask>,
+) {
+    for (interaction, button, mut color) in &mut buttons {
+        match interaction {
+            Interaction::Pressed => match button {
+                PauseButton::Resume => next_state.set(crate::AppState::Playing),
+                PauseButton::Save => {
+                    ev_save.send(SaveEvent);
+                }
+                PauseButton::Quit => {
+                    // The game exits once the save has been written
+                    ev_save.send(SaveEvent);
+                    saving.quit_when_done = true;
+                }
+            },
+            Interaction::Hovered => color.0 = BUTTON_HOVERED_COLOR,
+            Interaction::None => color.0 = BUTTON_COLOR,
+        }
+    }
+}
@@ -14,7 +14,7 @@ use crate::chunk::{
 pub use cormine_shared::voxel::VoxelKind;
 
 /// X, Y and Z coordinate of voxel within the world
-#[derive(Copy, Clone, Debug, Hash)]
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
 pub struct VoxelPosition(IVec3);
 
 impl VoxelPosition {
@@ -73,10 +73,31 @@ impl NeighbouringChunks {
     pub fn all(&self) -> [Option<ChunkPosition>; 4] {
         [self.neg_x, self.x, self.neg_z, self.z]
     }
+
+    /// The chunks diagonally across from the voxel, if it lies on a corner
+    /// column of its chunk
+    pub fn diagonals(&self) -> [Option<ChunkPosition>; 4] {
+        let corner = |x: Option<ChunkPosition>, z: Option<ChunkPosition>| {
+            Some(ChunkPosition::new(x?.x(), z?.z()))
+        };
+        [
+            corner(self.neg_x, self.neg_z),
+            corner(self.x, self.neg_z),
+            corner(self.neg_x, self.z),
+            corner(self.x, self.z),
+        ]
+    }
+
+    /// All chunks bordering the voxel, including diagonally
+    pub fn all8(&self) -> [Option<ChunkPosition>; 8] {
+        let [a, b, c, d] = self.all();
+        let [e, f, g, h] = self.diagonals();
+        [a, b, c, d, e, f, g, h]
+    }
 }
 
-/// Position of a voxel within a chunk. Will all be within [0,
-/// CHUNK_DIMENSION_SIZE]
+/// Position of a voxel within a chunk. X and Z will be within [0,
+/// CHUNK_SIZE), and Y within the height of the chunk
 #[derive(Copy, Clone, Debug, Hash)]
 pub struct LocalVoxelPosition {
     x: u8,
@@ -87,7 +108,7 @@ pub struct LocalVoxelPosition {
 impl LocalVoxelPosition {
     pub fn new(x: u32, y: u32, z: u32) -> Self {
         debug_assert!(
-            (x as usize) < CHUNK_SIZE && (y as usize) < MAX_HEIGHT && (z as usize) < CHUNK_SIZE,
+            (x as usize) < CHUNK_SIZE && (z as usize) < CHUNK_SIZE,
             "({x}, {y}, {z}) is not within chunk ranges"
         );
         Self {
@@ -114,18 +135,24 @@ impl LocalVoxelPosition {
     }
 }
 
-impl From<VoxelPosition> for LocalVoxelPosition {
-    fn from(position: VoxelPosition) -> Self {
+/// A [`VoxelPosition`] above or below the world, so not within any chunk
+#[derive(Copy, Clone, Debug)]
+pub struct OutsideWorldHeight(pub VoxelPosition);
+
+impl TryFrom<VoxelPosition> for LocalVoxelPosition {
+    type Error = OutsideWorldHeight;
+
+    fn try_from(position: VoxelPosition) -> Result<Self, Self::Error> {
+        if !(0..MAX_HEIGHT as i32).contains(&position.y()) {
+            return Err(OutsideWorldHeight(position));
+        }
         let chunk_pos: ChunkPosition = position.into();
         let x = position.x() - chunk_pos.x();
         let y = position.y();
         let z = position.z() - chunk_pos.z();
-        debug_assert!(
-            x >= 0 && y >= 0 && z >= 0,
-            "({x}, {y}, {z}) is not positive"
-        );
+        debug_assert!(x >= 0 && z >= 0, "({x}, {y}, {z}) is not positive");
 
-        LocalVoxelPosition::new(x as _, y as _, z as _)
+        Ok(LocalVoxelPosition::new(x as _, y as _, z as _))
     }
 }
 
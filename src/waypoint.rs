@@ -0,0 +1,116 @@
+use bevy::prelude::*;
+use cormine_shared::save::Waypoint;
+
+use crate::{
+    highlight::UpdateHighlightedEvent,
+    input::CameraVelocity,
+    player::{
+        self,
+        PlayerMovedEvent,
+    },
+};
+
+/// Positions saved in this world which the player can teleport back to
+#[derive(Resource, Default)]
+pub struct Waypoints {
+    pub list: Vec<Waypoint>,
+    /// Index of the waypoint to teleport to next
+    next: usize,
+}
+
+impl Waypoints {
+    pub fn new(list: Vec<Waypoint>) -> Self {
+        Self { list, next: 0 }
+    }
+
+    /// Save `position` as a new waypoint, named after its number
+    pub fn add(&mut self, position: Vec3) -> &Waypoint {
+        let name = format!("Waypoint {}", self.list.len() + 1);
+        self.list.push(Waypoint { name, position });
+        self.list.last().unwrap()
+    }
+
+    /// Each waypoint in turn, wrapping back around to the first
+    pub fn cycle(&mut self) -> Option<&Waypoint> {
+        if self.list.is_empty() {
+            return None;
+        }
+        let idx = self.next % self.list.len();
+        self.next = idx + 1;
+        Some(&self.list[idx])
+    }
+}
+
+/// Save a waypoint with F7, and teleport to each waypoint in turn with F8
+pub fn handle_waypoint_keys(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut waypoints: ResMut<Waypoints>,
+    mut camera: Query<&mut Transform, With<Camera>>,
+    mut velocity: ResMut<CameraVelocity>,
+    mut ev_move: EventWriter<PlayerMovedEvent>,
+    mut ev_update: EventWriter<UpdateHighlightedEvent>,
+) {
+    let mut camera = camera.single_mut();
+    if keys.just_pressed(KeyCode::F7) {
+        let waypoint = waypoints.add(camera.translation);
+        info!("Added `{}` at {:.1}", waypoint.name, waypoint.position);
+    }
+    if keys.just_pressed(KeyCode::F8) {
+        let Some(waypoint) = waypoints.cycle() else {
+            info!("No waypoints to teleport to");
+            return;
+        };
+        info!("Teleporting to `{}`", waypoint.name);
+        player::teleport(&mut camera, &mut velocity, &mut ev_move, waypoint.position);
+        ev_update.send(UpdateHighlightedEvent);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn teleport_to_waypoint() {
+        let mut app = App::new();
+        app.init_resource::<ButtonInput<KeyCode>>()
+            .init_resource::<Waypoints>()
+            .init_resource::<CameraVelocity>()
+            .add_event::<PlayerMovedEvent>()
+            .add_event::<UpdateHighlightedEvent>()
+            .add_systems(Update, handle_waypoint_keys);
+        let camera = app
+            .world_mut()
+            .spawn((Camera::default(), Transform::from_xyz(1.0, 80.0, -2.0)))
+            .id();
+        let tap = |app: &mut App, key| {
+            app.world_mut()
+                .resource_mut::<ButtonInput<KeyCode>>()
+                .press(key);
+            app.update();
+            let mut keys = app.world_mut().resource_mut::<ButtonInput<KeyCode>>();
+            keys.release(key);
+            keys.clear();
+        };
+        let position = |app: &App| app.world().get::<Transform>(camera).unwrap().translation;
+
+        tap(&mut app, KeyCode::F7);
+        assert_eq!(
+            app.world().resource::<Waypoints>().list[0].position,
+            Vec3::new(1.0, 80.0, -2.0)
+        );
+
+        app.world_mut()
+            .get_mut::<Transform>(camera)
+            .unwrap()
+            .translation = Vec3::new(500.0, 60.0, 300.0);
+        app.world_mut().resource_mut::<CameraVelocity>().vel = Vec3::NEG_Y * 20.0;
+        tap(&mut app, KeyCode::F8);
+        assert_eq!(position(&app), Vec3::new(1.0, 80.0, -2.0));
+        assert_eq!(app.world().resource::<CameraVelocity>().vel, Vec3::ZERO);
+        let moved = app.world().resource::<Events<PlayerMovedEvent>>();
+        assert!(moved
+            .iter_current_update_events()
+            .any(|ev| ev.changed_chunk()));
+    }
+}
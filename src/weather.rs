@@ -0,0 +1,311 @@
+use std::time::Duration;
+
+use bevy::{
+    prelude::*,
+    utils::{
+        HashMap,
+        HashSet,
+    },
+};
+use rand::{
+    thread_rng,
+    Rng,
+};
+
+use crate::{
+    chunk::{
+        ChunkPosition,
+        ChunkVoxels,
+        CHUNK_SIZE,
+        MAX_HEIGHT,
+    },
+    voxel::{
+        LocalVoxelPosition,
+        VoxelKind,
+        VoxelPosition,
+    },
+    world::World,
+};
+
+/// Lowest surface that is cold enough for snow to settle on during snowfall.
+/// Surfaces above `ground_height_to_voxel`'s snow line are always snowy
+const SNOW_LINE: i32 = 80;
+/// Seconds between each round of snow settling or melting
+const WEATHER_TICK_SECONDS: f32 = 1.0;
+/// Columns of each loaded chunk that snow settles on or melts from each
+/// round, so snow builds up and clears gradually
+const COLUMNS_PER_TICK: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WeatherKind {
+    Clear,
+    Snowfall,
+}
+
+/// Current weather, which alternates between clear skies and snowfall
+#[derive(Resource)]
+pub struct Weather {
+    pub kind: WeatherKind,
+    /// Time until the weather changes
+    change: Timer,
+    /// Time until the next round of snow settling or melting
+    tick: Timer,
+    /// Grass covered by snowfall in each chunk, which melts back to grass
+    /// once the weather clears. Snow that was generated or placed is never
+    /// melted
+    snowed: HashMap<ChunkPosition, HashSet<VoxelPosition>>,
+}
+
+impl Weather {
+    /// Clear weather, changing every `interval_secs`
+    pub fn new(interval_secs: f32) -> Self {
+        Self {
+            kind: WeatherKind::Clear,
+            change: Timer::new(Duration::from_secs_f32(interval_secs), TimerMode::Repeating),
+            tick: Timer::from_seconds(WEATHER_TICK_SECONDS, TimerMode::Repeating),
+            snowed: HashMap::new(),
+        }
+    }
+
+    /// Snow to settle on, or melt from, the top of the column at `x`, `z` of
+    /// the chunk at `chunk_pos`, depending on the weather. Only the topmost
+    /// voxel of a column is open to the sky. Returns the voxel to change and
+    /// the kind to change it to, if any
+    pub fn weather_column(
+        &mut self,
+        chunk_pos: ChunkPosition,
+        chunk: &ChunkVoxels,
+        x: u32,
+        z: u32,
+    ) -> Option<(LocalVoxelPosition, VoxelKind)> {
+        let top = (0..MAX_HEIGHT as u32)
+            .rev()
+            .map(|y| LocalVoxelPosition::new(x, y, z))
+            .find(|&pos| chunk.voxel(pos).kind() != VoxelKind::Air)?;
+        let world_pos = VoxelPosition::new(chunk_pos.as_ivec3() + top.as_ivec3());
+        match (self.kind, chunk.voxel(top).kind()) {
+            (WeatherKind::Snowfall, VoxelKind::Grass) if world_pos.y() >= SNOW_LINE => {
+                self.snowed.entry(chunk_pos).or_default().insert(world_pos);
+                Some((top, VoxelKind::Snow))
+            }
+            (WeatherKind::Clear, VoxelKind::Snow)
+                if self
+                    .snowed
+                    .get_mut(&chunk_pos)
+                    .is_some_and(|snowed| snowed.remove(&world_pos)) =>
+            {
+                Some((top, VoxelKind::Grass))
+            }
+            _ => None,
+        }
+    }
+
+    /// Melt the snow from snowfall in `chunk`, a copy of the chunk at
+    /// `chunk_pos` about to be saved or cached, so the weather isn't kept as
+    /// if the player had placed it
+    pub fn melt_copy(&self, chunk_pos: ChunkPosition, chunk: &mut ChunkVoxels) {
+        for &pos in self.snowed.get(&chunk_pos).into_iter().flatten() {
+            let pos = LocalVoxelPosition::try_from(pos).expect("snow is within the world height");
+            // Snow broken since is left as it is
+            if chunk.voxel(pos).kind() == VoxelKind::Snow {
+                chunk.voxel_mut(pos).kind = VoxelKind::Grass;
+            }
+        }
+    }
+}
+
+/// Change the weather once its time is up, and settle or melt snow on a few
+/// random columns of each loaded chunk
+pub fn update_weather(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut weather: ResMut<Weather>,
+    world: Res<World>,
+    mut chunks: Query<&mut ChunkVoxels>,
+) {
+    if weather.change.tick(time.delta()).just_finished() {
+        weather.kind = match weather.kind {
+            WeatherKind::Clear => WeatherKind::Snowfall,
+            WeatherKind::Snowfall => WeatherKind::Clear,
+        };
+        info!("Weather is now {:?}", weather.kind);
+    }
+    if !weather.tick.tick(time.delta()).just_finished() {
+        return;
+    }
+    // Nothing can melt when there is no snow from snowfall
+    if weather.kind == WeatherKind::Clear && weather.snowed.values().all(HashSet::is_empty) {
+        return;
+    }
+
+    // Snow in chunks that have been unloaded is forgotten, rather than kept
+    // for as long as the game runs. Unloaded chunks are cached melted
+    weather
+        .snowed
+        .retain(|&pos, _| world.chunk_at(pos).is_some());
+
+    let mut rng = thread_rng();
+    let mut changed = Vec::new();
+    for (chunk_pos, chunk) in world.iter() {
+        // Chunks still generating have no voxels yet
+        let Ok(mut chunk) = chunks.get_mut(chunk) else {
+            continue;
+        };
+        for _ in 0..COLUMNS_PER_TICK {
+            let x = rng.gen_range(0..CHUNK_SIZE as u32);
+            let z = rng.gen_range(0..CHUNK_SIZE as u32);
+            // Only borrowed mutably to change a voxel, so chunks the weather
+            // leaves alone aren't marked as changed
+            if let Some((top, kind)) = weather.weather_column(chunk_pos, &chunk, x, z) {
+                chunk.voxel_mut(top).kind = kind;
+                changed.push(chunk_pos);
+            }
+        }
+    }
+    // Snow and grass block light alike, so only the textures change
+    world.invalidate_meshes(&mut commands, changed);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn snow_settles_and_melts() {
+        let chunk_pos = ChunkPosition::new(16, -32);
+        let mut chunk = ChunkVoxels::with_max_depth(4);
+        let max = CHUNK_SIZE as u32 - 1;
+        let ground = SNOW_LINE as u32 + 2;
+        chunk.fill_region(
+            LocalVoxelPosition::new(0, 0, 0),
+            LocalVoxelPosition::new(max, ground, max),
+            VoxelKind::Grass,
+        );
+        // Sheltered by an overhang
+        chunk
+            .voxel_mut(LocalVoxelPosition::new(3, ground + 4, 3))
+            .kind = VoxelKind::Stone;
+        // Lower down, where it is too warm for snow
+        for y in SNOW_LINE as u32 - 2..=ground {
+            chunk.voxel_mut(LocalVoxelPosition::new(9, y, 9)).kind = VoxelKind::Air;
+        }
+        // Snow generated on the ground
+        chunk.voxel_mut(LocalVoxelPosition::new(6, ground, 6)).kind = VoxelKind::Snow;
+
+        let mut weather = Weather::new(60.0);
+        // Change a column as `update_weather` does, returning whether it changed
+        let weather_column = |weather: &mut Weather, chunk: &mut ChunkVoxels, x, z| {
+            let change = weather.weather_column(chunk_pos, chunk, x, z);
+            if let Some((top, kind)) = change {
+                chunk.voxel_mut(top).kind = kind;
+            }
+            change.is_some()
+        };
+        let kind_at =
+            |chunk: &ChunkVoxels, x, y, z| chunk.voxel(LocalVoxelPosition::new(x, y, z)).kind();
+        // Nothing happens in clear weather
+        assert!(!weather_column(&mut weather, &mut chunk, 1, 1));
+
+        weather.kind = WeatherKind::Snowfall;
+        assert!(weather_column(&mut weather, &mut chunk, 1, 1));
+        assert_eq!(kind_at(&chunk, 1, ground, 1), VoxelKind::Snow);
+        assert_eq!(kind_at(&chunk, 1, ground - 1, 1), VoxelKind::Grass);
+        assert!(!weather_column(&mut weather, &mut chunk, 1, 1));
+        for (x, z) in [(3, 3), (9, 9)] {
+            assert!(!weather_column(&mut weather, &mut chunk, x, z));
+        }
+        assert_eq!(kind_at(&chunk, 3, ground, 3), VoxelKind::Grass);
+        assert_eq!(
+            kind_at(&chunk, 9, SNOW_LINE as u32 - 3, 9),
+            VoxelKind::Grass
+        );
+
+        // Only snow from snowfall melts
+        weather.kind = WeatherKind::Clear;
+        assert!(!weather_column(&mut weather, &mut chunk, 6, 6));
+        assert_eq!(kind_at(&chunk, 6, ground, 6), VoxelKind::Snow);
+        assert!(weather_column(&mut weather, &mut chunk, 1, 1));
+        assert_eq!(kind_at(&chunk, 1, ground, 1), VoxelKind::Grass);
+        assert!(weather.snowed.values().all(HashSet::is_empty));
+    }
+
+    #[test]
+    pub fn copies_melt_snow_from_snowfall() {
+        let chunk_pos = ChunkPosition::new(-16, 0);
+        let max = CHUNK_SIZE as u32 - 1;
+        let ground = SNOW_LINE as u32 + 2;
+        let mut chunk = ChunkVoxels::with_max_depth(4);
+        chunk.fill_region(
+            LocalVoxelPosition::new(0, 0, 0),
+            LocalVoxelPosition::new(max, ground, max),
+            VoxelKind::Grass,
+        );
+        // Placed by the player
+        chunk.voxel_mut(LocalVoxelPosition::new(5, ground, 5)).kind = VoxelKind::Snow;
+
+        let mut weather = Weather::new(60.0);
+        weather.kind = WeatherKind::Snowfall;
+        for (x, z) in [(1, 1), (2, 2)] {
+            let (top, kind) = weather.weather_column(chunk_pos, &chunk, x, z).unwrap();
+            chunk.voxel_mut(top).kind = kind;
+        }
+        // Broken by the player after settling
+        chunk.voxel_mut(LocalVoxelPosition::new(2, ground, 2)).kind = VoxelKind::Air;
+
+        let mut copy = chunk.clone();
+        weather.melt_copy(chunk_pos, &mut copy);
+        let kind_at =
+            |chunk: &ChunkVoxels, x| chunk.voxel(LocalVoxelPosition::new(x, ground, x)).kind();
+        assert_eq!(kind_at(&copy, 1), VoxelKind::Grass);
+        assert_eq!(kind_at(&copy, 2), VoxelKind::Air);
+        assert_eq!(kind_at(&copy, 5), VoxelKind::Snow);
+        // The loaded chunk keeps its snow until the weather clears
+        assert_eq!(kind_at(&chunk, 1), VoxelKind::Snow);
+        weather.melt_copy(ChunkPosition::new(0, 0), &mut chunk);
+        assert_eq!(kind_at(&chunk, 1), VoxelKind::Snow);
+    }
+
+    #[test]
+    pub fn snow_forgotten_in_unloaded_chunks() {
+        use crate::terrain::generate_chunk;
+
+        let mut world = World::flat();
+        let chunk_pos = ChunkPosition::new(0, 0);
+        let chunk = generate_chunk(chunk_pos, &world.generator, CHUNK_SIZE.ilog2());
+        let mut app = App::new();
+        let chunk_ent = app.world_mut().spawn(chunk).id();
+        world.add_chunk(chunk_pos, chunk_ent);
+
+        let mut weather = Weather::new(60.0);
+        let loaded = VoxelPosition::new(IVec3::new(1, SNOW_LINE + 10, 1));
+        let unloaded = VoxelPosition::new(IVec3::new(100, SNOW_LINE + 10, 100));
+        for pos in [loaded, unloaded] {
+            weather
+                .snowed
+                .entry(ChunkPosition::from(pos))
+                .or_default()
+                .insert(pos);
+        }
+        app.insert_resource(world)
+            .insert_resource(weather)
+            .init_resource::<Time>()
+            .add_systems(Update, update_weather);
+        let last_changed = |app: &App| {
+            app.world()
+                .entity(chunk_ent)
+                .get_ref::<ChunkVoxels>()
+                .unwrap()
+                .last_changed()
+        };
+        let before = last_changed(&app);
+        app.world_mut()
+            .resource_mut::<Time>()
+            .advance_by(Duration::from_secs_f32(WEATHER_TICK_SECONDS));
+        app.update();
+
+        let snowed = &app.world().resource::<Weather>().snowed;
+        assert_eq!(snowed.keys().collect::<Vec<_>>(), [&chunk_pos]);
+        // The flat world has no snow to melt, so its chunk is left untouched
+        assert_eq!(last_changed(&app), before);
+    }
+}
@@ -0,0 +1,227 @@
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+use anyhow::anyhow;
+use bevy::{
+    math::IRect,
+    prelude::*,
+    window::{
+        PrimaryWindow,
+        WindowMoved,
+        WindowResized,
+        WindowResolution,
+    },
+    winit::WinitWindows,
+};
+
+/// File the window's size and position are kept in between launches
+#[derive(Resource)]
+pub struct WindowStatePath(pub PathBuf);
+
+/// Size and position of the primary window, kept up to date as it is moved
+/// and resized
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct WindowState {
+    /// Top left corner, in physical pixels
+    pub position: Option<IVec2>,
+    /// Size in logical pixels
+    pub size: Vec2,
+}
+
+impl WindowState {
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        Ok(std::fs::write(path, self.to_string())?)
+    }
+
+    /// Parse a state written by [`WindowState::to_string`], of the width and
+    /// height followed by the position if it is known
+    fn parse(state: &str) -> anyhow::Result<Self> {
+        let values = state
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<Result<Vec<f32>, _>>()?;
+        let (size, position) = match values[..] {
+            [width, height] => (Vec2::new(width, height), None),
+            [width, height, x, y] => (Vec2::new(width, height), Some(IVec2::new(x as _, y as _))),
+            _ => return Err(anyhow!("invalid window state `{}`", state.trim())),
+        };
+        if !(size.cmpgt(Vec2::ZERO).all() && size.is_finite()) {
+            return Err(anyhow!("invalid window size {size}"));
+        }
+        Ok(Self { position, size })
+    }
+
+    /// Primary window restored to this state. Its position is set once the
+    /// monitors it could be on are known
+    pub fn window(&self) -> Window {
+        Window {
+            resolution: WindowResolution::new(self.size.x, self.size.y),
+            ..default()
+        }
+    }
+}
+
+impl std::fmt::Display for WindowState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.size.x, self.size.y)?;
+        if let Some(position) = self.position {
+            write!(f, " {} {}", position.x, position.y)?;
+        }
+        Ok(())
+    }
+}
+
+/// Move a window with its top left corner at `position` so that it can be
+/// dragged back into place. Windows whose title bar is on a monitor are left
+/// alone, while the rest are moved to be entirely on the nearest monitor
+pub fn clamp_to_monitors(position: IVec2, size: UVec2, monitors: &[IRect]) -> IVec2 {
+    let title = position + IVec2::new(size.x as i32 / 2, 0);
+    if monitors.is_empty() || monitors.iter().any(|monitor| monitor.contains(title)) {
+        return position;
+    }
+    let distance = |monitor: &&IRect| (monitor.center() - title).length_squared();
+    let monitor = monitors.iter().min_by_key(distance).unwrap();
+    // Windows larger than the monitor keep their top left corner on it
+    let max = (monitor.max - size.as_ivec2()).max(monitor.min);
+    position.clamp(monitor.min, max)
+}
+
+/// Move the primary window to its restored position once it has been
+/// created, clamped to be on one of the monitors
+pub fn restore_window_position(
+    mut window: Query<(Entity, &mut Window), With<PrimaryWindow>>,
+    winit_windows: NonSend<WinitWindows>,
+    state: Res<WindowState>,
+    mut restored: Local<bool>,
+) {
+    let Some(position) = state.position.filter(|_| !*restored) else {
+        return;
+    };
+    let Ok((entity, mut window)) = window.get_single_mut() else {
+        return;
+    };
+    let Some(winit_window) = winit_windows.get_window(entity) else {
+        return;
+    };
+    let monitors: Vec<_> = winit_window
+        .available_monitors()
+        .map(|monitor| {
+            let min = IVec2::new(monitor.position().x, monitor.position().y);
+            let size = IVec2::new(monitor.size().width as _, monitor.size().height as _);
+            IRect::from_corners(min, min + size)
+        })
+        .collect();
+    let size = UVec2::new(window.physical_width(), window.physical_height());
+    window.position = WindowPosition::At(clamp_to_monitors(position, size, &monitors));
+    *restored = true;
+}
+
+/// Keep [`WindowState`] in sync with the primary window, so it can be saved
+/// even after the window is closed
+pub fn track_window_state(
+    window: Query<Entity, With<PrimaryWindow>>,
+    mut ev_moved: EventReader<WindowMoved>,
+    mut ev_resized: EventReader<WindowResized>,
+    mut state: ResMut<WindowState>,
+) {
+    let Ok(primary) = window.get_single() else {
+        return;
+    };
+    for ev in ev_moved.read().filter(|ev| ev.window == primary) {
+        state.position = Some(ev.position);
+    }
+    for ev in ev_resized.read().filter(|ev| ev.window == primary) {
+        state.size = Vec2::new(ev.width, ev.height);
+    }
+}
+
+pub fn save_window_state(
+    mut exit: EventReader<AppExit>,
+    state: Res<WindowState>,
+    path: Res<WindowStatePath>,
+) {
+    if exit.read().count() == 0 {
+        return;
+    }
+    if let Err(err) = state.save(&path.0) {
+        warn!(
+            "Failed to save window state to `{}`: {err:#}",
+            path.0.display()
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn restored_window_kept_on_screen() {
+        let left = IRect::new(-1920, 0, 0, 1080);
+        let right = IRect::new(0, 0, 2560, 1440);
+        let monitors = [left, right];
+        let size = UVec2::new(800, 600);
+
+        // Windows with their title bar on a monitor aren't moved, even when
+        // they span monitors or hang off the bottom
+        for position in [
+            IVec2::new(100, 100),
+            IVec2::new(-300, 50),
+            IVec2::new(-1800, 1000),
+        ] {
+            assert_eq!(clamp_to_monitors(position, size, &monitors), position);
+        }
+
+        // Off to the right of every monitor
+        assert_eq!(
+            clamp_to_monitors(IVec2::new(4000, 200), size, &monitors),
+            IVec2::new(1760, 200)
+        );
+        // Above the left monitor
+        assert_eq!(
+            clamp_to_monitors(IVec2::new(-1000, -700), size, &monitors),
+            IVec2::new(-1000, 0)
+        );
+        // On a monitor that has since been unplugged
+        assert_eq!(
+            clamp_to_monitors(IVec2::new(-3000, 300), size, &monitors[1..]),
+            IVec2::new(0, 300)
+        );
+        // Larger than the monitor it is moved to
+        assert_eq!(
+            clamp_to_monitors(IVec2::new(5000, 5000), UVec2::new(3000, 2000), &monitors),
+            IVec2::new(0, 0)
+        );
+        // Nothing is known about monitors
+        assert_eq!(
+            clamp_to_monitors(IVec2::new(5000, 5000), size, &[]),
+            IVec2::new(5000, 5000)
+        );
+    }
+
+    #[test]
+    pub fn window_state_round_trip() {
+        let state = WindowState {
+            position: Some(IVec2::new(-1200, 80)),
+            size: Vec2::new(1280.0, 720.5),
+        };
+        assert_eq!(WindowState::parse(&state.to_string()).unwrap(), state);
+        let unpositioned = WindowState {
+            position: None,
+            ..state
+        };
+        assert_eq!(
+            WindowState::parse(&unpositioned.to_string()).unwrap(),
+            unpositioned
+        );
+        assert!(WindowState::parse("1280").is_err());
+        assert!(WindowState::parse("0 720").is_err());
+        assert!(WindowState::parse("wide 720").is_err());
+    }
+}
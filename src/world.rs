@@ -1,44 +1,191 @@
-use std::sync::Arc;
-
 use crate::{
+    cache::ChunkCache,
     chunk::{
         ChunkPosition,
         ChunkVoxels,
+        CHUNK_SIZE,
+    },
+    light,
+    mesh::{
+        HasMesh,
+        MeshStats,
     },
     save,
+    terrain::WorldGenerator,
     voxel::{
+        LocalVoxelPosition,
         Voxel,
+        VoxelKind,
         VoxelPosition,
     },
+    waypoint::Waypoints,
+    weather::Weather,
 };
 use bevy::{
     prelude::*,
-    utils::HashMap,
+    tasks::AsyncComputeTaskPool,
+    utils::{
+        HashMap,
+        HashSet,
+    },
 };
-use noise::utils::NoiseMap;
+
+/// Radius in voxels of the sphere cleared by exploding TNT
+pub const EXPLOSION_RADIUS: i32 = 4;
+
+/// Chunks whose meshes include faces of the voxel at `pos`: the chunk
+/// containing it, and any chunk it borders
+pub fn chunks_meshing_voxel(pos: VoxelPosition) -> impl Iterator<Item = ChunkPosition> {
+    std::iter::once(pos.into()).chain(pos.neighbouring_chunks().all8().into_iter().flatten())
+}
+
+/// Sent when the kind of a voxel is changed, to update the light around it
+/// and remesh the chunks showing it
+#[derive(Event, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkEditedEvent {
+    pub position: VoxelPosition,
+}
+
+/// Update the light around the voxels edited this frame, and remesh each
+/// chunk showing them or light that changed. Chunks are remeshed once
+/// however many of their voxels were edited
+pub fn handle_chunk_edits(
+    mut commands: Commands,
+    world: Res<World>,
+    mut chunks: Query<&mut ChunkVoxels>,
+    mut ev_edited: EventReader<ChunkEditedEvent>,
+) {
+    let edited: Vec<_> = ev_edited.read().map(|edit| edit.position).collect();
+    let mut touched: HashSet<_> = edited
+        .iter()
+        .copied()
+        .flat_map(chunks_meshing_voxel)
+        .collect();
+    touched.extend(light::relight(&world, &mut chunks, edited));
+    world.invalidate_meshes(&mut commands, touched);
+}
+
+/// Area the world is played in, in chunks centred on the origin. Chunks
+/// outside it are never generated, and the player can't leave it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WorldBorder {
+    /// Size along X, or `0` for no border along X
+    pub width: u32,
+    /// Size along Z, or `0` for no border along Z
+    pub length: u32,
+}
+
+impl WorldBorder {
+    /// Largest size along either axis, so voxel coordinates within the border
+    /// fit in an `i32`
+    pub const MAX_SIZE: u32 = i32::MAX as u32 / CHUNK_SIZE as u32;
+
+    /// Border `width` by `length` chunks, unless either is over
+    /// [`WorldBorder::MAX_SIZE`]
+    pub fn new(width: u32, length: u32) -> Result<Self, String> {
+        if width > Self::MAX_SIZE || length > Self::MAX_SIZE {
+            return Err(format!(
+                "border `{width}x{length}` is too large, sizes can be at most {}",
+                Self::MAX_SIZE
+            ));
+        }
+        Ok(Self { width, length })
+    }
+
+    /// Voxel coordinates within the border along an axis `size` chunks long
+    fn range(size: u32) -> std::ops::Range<i32> {
+        if size == 0 {
+            return i32::MIN..i32::MAX;
+        }
+        // Sizes are checked by `new`, but one that slipped past is clamped to
+        // the coordinates that exist rather than overflowing
+        let min = -i64::from(size / 2) * CHUNK_SIZE as i64;
+        let max = min + i64::from(size) * CHUNK_SIZE as i64;
+        let clamp = |coord: i64| coord.clamp(i32::MIN.into(), i32::MAX.into()) as i32;
+        clamp(min)..clamp(max)
+    }
+
+    /// Whether the voxel at `pos` is within the border
+    pub fn contains(&self, pos: IVec3) -> bool {
+        Self::range(self.width).contains(&pos.x) && Self::range(self.length).contains(&pos.z)
+    }
+
+    pub fn contains_chunk(&self, pos: ChunkPosition) -> bool {
+        self.contains(pos.as_ivec3())
+    }
+}
+
+impl std::str::FromStr for WorldBorder {
+    type Err = String;
+
+    /// Parse a border given as `<width>x<length>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid border `{s}`, expected `<width>x<length>` in chunks");
+        let (width, length) = s.split_once('x').ok_or_else(invalid)?;
+        Self::new(
+            width.parse().map_err(|_| invalid())?,
+            length.parse().map_err(|_| invalid())?,
+        )
+    }
+}
 
 #[derive(Resource)]
 pub struct World {
     pub seed: u32,
     pub chunk_map: HashMap<ChunkPosition, Entity>,
-    pub noise_map: Arc<NoiseMap>,
+    pub generator: WorldGenerator,
+    pub border: WorldBorder,
 }
 
 impl World {
     pub fn from_seed(seed: u32) -> Self {
-        let noise_map = crate::terrain::generate_noise_map(1024, 1024, seed);
         Self {
             seed,
             chunk_map: default(),
-            noise_map: Arc::new(noise_map),
+            generator: WorldGenerator::from_seed(seed),
+            border: default(),
         }
     }
 
-    pub fn add_chunk(&mut self, pos: ChunkPosition, entity: Entity) {
-        assert!(
-            self.chunk_map.insert(pos, entity).is_none(),
-            "Overwriting chunk in map"
-        );
+    /// A world whose terrain blends between that of `seed` and `blend_seed`
+    /// across large regions
+    pub fn blended(seed: u32, blend_seed: u32) -> Self {
+        Self {
+            seed,
+            chunk_map: default(),
+            generator: WorldGenerator::blended_from_seeds(seed, blend_seed),
+            border: default(),
+        }
+    }
+
+    /// A world generated from 3D noise rather than a heightmap
+    pub fn density(seed: u32) -> Self {
+        Self {
+            seed,
+            chunk_map: default(),
+            generator: WorldGenerator::density_from_seed(seed),
+            border: default(),
+        }
+    }
+
+    /// A flat world, which has no use for a seed so always stores `0`
+    pub fn flat() -> Self {
+        Self {
+            seed: 0,
+            chunk_map: default(),
+            generator: WorldGenerator::Flat,
+            border: default(),
+        }
+    }
+
+    /// Register the entity of the chunk at `pos`. Returns `false`, leaving the
+    /// map unchanged, if there is already a chunk there
+    pub fn add_chunk(&mut self, pos: ChunkPosition, entity: Entity) -> bool {
+        if self.chunk_map.contains_key(&pos) {
+            return false;
+        }
+        self.chunk_map.insert(pos, entity);
+        true
     }
 
     pub fn remove_chunk(&mut self, pos: ChunkPosition) -> Entity {
@@ -64,19 +211,308 @@ impl World {
         let chunk_base: ChunkPosition = pos.into();
         let chunk = self.chunk_map.get(&chunk_base).copied()?;
         let chunk = chunks.get(chunk).ok()?;
-        let local_coord = pos.into();
+        let local_coord = pos.try_into().ok()?;
 
         Some(chunk.voxel(local_coord))
     }
 
+    /// Mark each loaded chunk in `chunks` to be meshed again
+    pub fn invalidate_meshes(
+        &self,
+        commands: &mut Commands,
+        chunks: impl IntoIterator<Item = ChunkPosition>,
+    ) {
+        for chunk_pos in chunks {
+            if let Some(chunk) = self.chunk_at(chunk_pos) {
+                commands
+                    .entity(chunk)
+                    .remove::<HasMesh>()
+                    .insert(crate::UpdateSync);
+            }
+        }
+    }
+
+    /// Clear every breakable voxel within `radius` of `center`, returning how
+    /// many were cleared. Voxels in chunks that aren't loaded, or are still
+    /// generating, are left alone
+    pub fn explode(
+        &self,
+        chunks: &mut Query<&mut ChunkVoxels>,
+        ev_edited: &mut EventWriter<ChunkEditedEvent>,
+        center: VoxelPosition,
+        radius: i32,
+    ) -> usize {
+        let mut by_chunk: HashMap<ChunkPosition, Vec<(VoxelPosition, LocalVoxelPosition)>> =
+            HashMap::new();
+        for x in -radius..=radius {
+            for y in -radius..=radius {
+                for z in -radius..=radius {
+                    let offset = IVec3::new(x, y, z);
+                    if offset.length_squared() > radius * radius {
+                        continue;
+                    }
+                    let pos = VoxelPosition::new(center.as_ivec3() + offset);
+                    let Ok(local_pos) = LocalVoxelPosition::try_from(pos) else {
+                        continue;
+                    };
+                    by_chunk
+                        .entry(pos.into())
+                        .or_default()
+                        .push((pos, local_pos));
+                }
+            }
+        }
+
+        let mut cleared = 0;
+        for (chunk_pos, voxels) in by_chunk {
+            let Some(mut chunk) = self
+                .chunk_at(chunk_pos)
+                .and_then(|c| chunks.get_mut(c).ok())
+            else {
+                continue;
+            };
+            let edits: Vec<_> = voxels
+                .into_iter()
+                .filter(|(_, local_pos)| {
+                    let kind = chunk.voxel(*local_pos).kind();
+                    kind != VoxelKind::Air && kind.breakable()
+                })
+                .map(|(pos, local_pos)| {
+                    ev_edited.send(ChunkEditedEvent { position: pos });
+                    (local_pos, VoxelKind::Air)
+                })
+                .collect();
+            cleared += edits.len();
+            chunk.set_many(&edits);
+        }
+        cleared
+    }
+
     /// Iterate over each chunk entity and it's position
     pub fn iter(&self) -> impl Iterator<Item = (ChunkPosition, Entity)> + '_ {
         self.chunk_map.iter().map(|(p, e)| (*p, *e))
     }
 }
 
-pub fn process_save_events(query: Query<&ChunkVoxels>, world: Res<World>) {
-    let save = save::SaveData::from_world(query, &world);
-    save.to_file("game.cms", true);
-    info!("Saved to `game.cms`");
+/// Counts of chunks at each stage of loading, for profiling hitches
+#[derive(Resource, Default)]
+pub struct WorldDiagnostics {
+    /// Chunks in the world, including those still generating
+    pub loaded_chunks: usize,
+    /// Chunks whose terrain is still being generated
+    pub awaiting_terrain: usize,
+    /// Chunks waiting to be given a meshing task
+    pub awaiting_mesh: usize,
+    /// Chunks being meshed asynchronously
+    pub meshing_async: usize,
+    /// Chunks meshed synchronously in the last frame
+    pub meshed_sync: usize,
+    /// Meshes completed since the game started
+    pub meshes_completed: u64,
+    /// Totals over meshes completed since the game started, if they are
+    /// counted
+    pub mesh_stats: MeshStats,
+}
+
+pub fn process_save_events(
+    query: Query<&ChunkVoxels>,
+    world: Res<World>,
+    limit: Res<save::SaveSizeLimit>,
+    path: Res<save::SavePath>,
+    waypoints: Res<Waypoints>,
+    weather: Option<Res<Weather>>,
+    cache: Option<Res<ChunkCache>>,
+    mut saving: ResMut<save::SaveTask>,
+) {
+    if saving.task.is_some() {
+        // Saving again once the current save is written picks up any edits
+        // made since it started
+        saving.queued = true;
+        return;
+    }
+    let snapshot = save::WorldSnapshot::new(
+        query,
+        &world,
+        &waypoints,
+        weather.as_deref(),
+        cache.as_deref(),
+    );
+    let path = path.0.clone();
+    let limit = *limit;
+    let task = AsyncComputeTaskPool::get().spawn(async move { snapshot.save(&path, limit) });
+    saving.task = Some(task);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        chunk::CHUNK_SIZE,
+        terrain::generate_chunk,
+    };
+    use bevy::ecs::system::RunSystemOnce;
+
+    #[test]
+    pub fn explosion_spans_chunks() {
+        let mut world = World::flat();
+        let mut app = App::new();
+        let mut chunk_ents = Vec::new();
+        // The chunk at -16 is left unloaded
+        for x in [0, 16] {
+            let chunk_pos = ChunkPosition::new(x, 0);
+            let chunk = generate_chunk(chunk_pos, &world.generator, CHUNK_SIZE.ilog2());
+            let chunk_ent = app.world_mut().spawn((chunk, HasMesh)).id();
+            world.add_chunk(chunk_pos, chunk_ent);
+            chunk_ents.push(chunk_ent);
+        }
+        app.insert_resource(world).add_event::<ChunkEditedEvent>();
+
+        let explode_at = |app: &mut App, center: IVec3| {
+            let cleared = app.world_mut().run_system_once(
+                move |world: Res<World>,
+                      mut chunks: Query<&mut ChunkVoxels>,
+                      mut ev_edited: EventWriter<ChunkEditedEvent>| {
+                    world.explode(
+                        &mut chunks,
+                        &mut ev_edited,
+                        VoxelPosition::new(center),
+                        EXPLOSION_RADIUS,
+                    )
+                },
+            );
+            app.world_mut().run_system_once(handle_chunk_edits);
+            cleared
+        };
+        let kind_at = |app: &mut App, pos: IVec3| {
+            app.world_mut()
+                .run_system_once(move |world: Res<World>, chunks: Query<&ChunkVoxels>| {
+                    world
+                        .voxel_at(VoxelPosition::new(pos), &chunks)
+                        .map(Voxel::kind)
+                })
+                .unwrap()
+        };
+
+        let center = IVec3::new(15, 2, 8);
+        assert!(explode_at(&mut app, center) > 0);
+        for ent in &chunk_ents {
+            assert!(app.world().get::<HasMesh>(*ent).is_none());
+        }
+        for offset in [
+            IVec3::ZERO,
+            IVec3::X * 4,
+            IVec3::NEG_X * 4,
+            IVec3::new(1, -1, 1),
+        ] {
+            assert_eq!(kind_at(&mut app, center + offset), VoxelKind::Air);
+        }
+        // Bedrock within the radius survives, and voxels outside it are kept
+        assert_eq!(kind_at(&mut app, IVec3::new(15, 0, 8)), VoxelKind::Bedrock);
+        assert_eq!(kind_at(&mut app, IVec3::new(15, 2, 13)), VoxelKind::Stone);
+
+        // Reaching into an unloaded chunk only clears the loaded part
+        let center = IVec3::new(1, 2, 8);
+        assert!(explode_at(&mut app, center) > 0);
+        assert_eq!(kind_at(&mut app, center), VoxelKind::Air);
+    }
+
+    #[test]
+    pub fn edits_remesh_chunks_showing_them() {
+        let mut world = World::flat();
+        let mut app = App::new();
+        let mut chunk_ents = HashMap::new();
+        for x in [-16, 0, 16] {
+            for z in [-16, 0, 16] {
+                let chunk_pos = ChunkPosition::new(x, z);
+                let chunk = generate_chunk(chunk_pos, &world.generator, CHUNK_SIZE.ilog2());
+                let chunk_ent = app.world_mut().spawn((chunk, HasMesh)).id();
+                world.add_chunk(chunk_pos, chunk_ent);
+                chunk_ents.insert(chunk_pos, chunk_ent);
+            }
+        }
+        app.insert_resource(world)
+            .add_event::<ChunkEditedEvent>()
+            .add_systems(Update, handle_chunk_edits);
+
+        // Stone placed on the ground in the corner of a chunk, on both its X
+        // and Z edges, and beside it in the same frame
+        for pos in [IVec3::new(15, 65, 15), IVec3::new(14, 65, 15)] {
+            let position = VoxelPosition::new(pos);
+            let chunk = chunk_ents[&ChunkPosition::from(position)];
+            app.world_mut()
+                .get_mut::<ChunkVoxels>(chunk)
+                .unwrap()
+                .voxel_mut(position.try_into().unwrap())
+                .kind = VoxelKind::Stone;
+            app.world_mut().send_event(ChunkEditedEvent { position });
+        }
+        app.update();
+
+        let mut remeshed: Vec<_> = chunk_ents
+            .iter()
+            .filter(|(_, &ent)| app.world().get::<HasMesh>(ent).is_none())
+            .map(|(pos, _)| (pos.x(), pos.z()))
+            .collect();
+        remeshed.sort();
+        // Including the chunk diagonally across the corner
+        assert_eq!(remeshed, [(0, 0), (0, 16), (16, 0), (16, 16)]);
+        for ent in chunk_ents.values() {
+            let remeshed = app.world().get::<HasMesh>(*ent).is_none();
+            assert_eq!(
+                app.world().get::<crate::UpdateSync>(*ent).is_some(),
+                remeshed
+            );
+        }
+    }
+
+    #[test]
+    pub fn border_centred_on_origin() {
+        let border: WorldBorder = "16x3".parse().unwrap();
+        assert_eq!(
+            border,
+            WorldBorder {
+                width: 16,
+                length: 3
+            }
+        );
+        for (x, inside) in [(-129, false), (-128, true), (127, true), (128, false)] {
+            assert_eq!(border.contains(IVec3::new(x, 64, 0)), inside, "x = {x}");
+        }
+        // Odd sizes have the extra chunk on the positive side
+        for (z, inside) in [(-17, false), (-16, true), (31, true), (32, false)] {
+            assert_eq!(border.contains(IVec3::new(0, 64, z)), inside, "z = {z}");
+        }
+        assert!(border.contains_chunk(ChunkPosition::new(-128, 16)));
+        assert!(!border.contains_chunk(ChunkPosition::new(128, 16)));
+
+        // No border along an axis of size 0
+        let unbounded: WorldBorder = "0x2".parse().unwrap();
+        assert!(unbounded.contains(IVec3::new(i32::MAX / 2, 0, -16)));
+        assert!(!unbounded.contains(IVec3::new(0, 0, 16)));
+        assert!(WorldBorder::default().contains(IVec3::new(-100_000, 0, 100_000)));
+
+        for invalid in [
+            "16",
+            "16x",
+            "x8",
+            "-1x4",
+            "ax4",
+            "4294967295x4",
+            "4x134217728",
+        ] {
+            assert!(invalid.parse::<WorldBorder>().is_err(), "{invalid}");
+        }
+
+        // The largest border spans almost every voxel coordinate
+        let max = WorldBorder::MAX_SIZE;
+        let largest: WorldBorder = format!("{max}x{max}").parse().unwrap();
+        assert!(largest.contains(IVec3::new(i32::MAX / 2, 0, i32::MIN / 2 + 16)));
+        assert!(!largest.contains(IVec3::new(i32::MAX / 2 + 1, 0, 0)));
+        // Borders too large to parse are clamped rather than overflowing
+        let huge = WorldBorder {
+            width: u32::MAX,
+            length: u32::MAX - 1,
+        };
+        assert!(huge.contains(IVec3::new(i32::MIN, 0, i32::MAX - 1)));
+    }
 }